@@ -7,7 +7,6 @@ use crdt_richtext::{
 use criterion::{criterion_group, criterion_main, Criterion};
 use pprof::flamegraph::{Direction, Options};
 use rand::{Rng, SeedableRng};
-use string_cache::DefaultAtom;
 
 struct PProfGuard {
     path: String,
@@ -51,7 +50,7 @@ fn a(n: u64) -> Annotation {
             },
         },
         behavior: Behavior::Merge,
-        type_: DefaultAtom::from(""),
+        type_: "".into(),
         value: serde_json::Value::Null,
     }
 }
@@ -0,0 +1,251 @@
+//! Run the B1–B4 editing traces on crdt-richtext, yrs and automerge, and print
+//! the results as CSV.
+//!
+//! ```bash
+//! cargo bench --bench compare --features compare > compare.csv
+//! ```
+//!
+//! - B1: append N chars one by one
+//! - B2: insert N chars at random positions
+//! - B3: two users insert N / 2 chars at random positions concurrently, then
+//!   they merge the changes of each other
+//! - B4: apply the real-world editing dataset
+
+use std::time::{Duration, Instant};
+
+use automerge::{transaction::Transactable, ActorId, AutoCommit, ObjId, ObjType, ReadDoc, ROOT};
+use crdt_richtext::rich_text::RichText;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use yrs::{updates::decoder::Decode, Doc, ReadTxn, StateVector, Text, TextRef, Transact, Update};
+
+#[path = "automerge.rs"]
+mod trace;
+use trace::{get_automerge_actions, TextAction};
+
+const N: usize = 6000;
+const SAMPLES: u32 = 5;
+const SEED: u64 = 0;
+
+trait Subject {
+    const NAME: &'static str;
+    fn new(client: u64) -> Self;
+    /// Create a new peer with the same state
+    fn fork(&mut self, client: u64) -> Self;
+    fn insert(&mut self, pos: usize, text: &str);
+    fn delete(&mut self, pos: usize, len: usize);
+    fn len(&self) -> usize;
+    fn merge(&mut self, other: &Self);
+    fn encode(&self) -> Vec<u8>;
+}
+
+impl Subject for RichText {
+    const NAME: &'static str = "crdt-richtext";
+
+    fn new(client: u64) -> Self {
+        RichText::new(client)
+    }
+
+    fn fork(&mut self, client: u64) -> Self {
+        let mut ans = RichText::new(client);
+        ans.import(&self.export(&Default::default())).unwrap();
+        ans
+    }
+
+    fn insert(&mut self, pos: usize, text: &str) {
+        RichText::insert(self, pos, text)
+    }
+
+    fn delete(&mut self, pos: usize, len: usize) {
+        RichText::delete(self, pos..pos + len)
+    }
+
+    fn len(&self) -> usize {
+        RichText::len(self)
+    }
+
+    fn merge(&mut self, other: &Self) {
+        RichText::merge(self, other)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.export(&Default::default())
+    }
+}
+
+struct Yrs {
+    doc: Doc,
+    text: TextRef,
+}
+
+impl Subject for Yrs {
+    const NAME: &'static str = "yrs";
+
+    fn new(client: u64) -> Self {
+        let doc = Doc::with_client_id(client);
+        let text = doc.get_or_insert_text("text");
+        Yrs { doc, text }
+    }
+
+    fn fork(&mut self, client: u64) -> Self {
+        let mut ans = Yrs::new(client);
+        ans.merge(self);
+        ans
+    }
+
+    fn insert(&mut self, pos: usize, text: &str) {
+        let mut txn = self.doc.transact_mut();
+        self.text.insert(&mut txn, pos as u32, text);
+    }
+
+    fn delete(&mut self, pos: usize, len: usize) {
+        let mut txn = self.doc.transact_mut();
+        self.text.remove_range(&mut txn, pos as u32, len as u32);
+    }
+
+    fn len(&self) -> usize {
+        self.text.len(&self.doc.transact()) as usize
+    }
+
+    fn merge(&mut self, other: &Self) {
+        let vv = self.doc.transact().state_vector();
+        let update = other.doc.transact().encode_state_as_update_v1(&vv);
+        self.doc
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default())
+    }
+}
+
+struct Automerge {
+    doc: AutoCommit,
+    text: ObjId,
+}
+
+impl Subject for Automerge {
+    const NAME: &'static str = "automerge";
+
+    fn new(client: u64) -> Self {
+        let mut doc = AutoCommit::new().with_actor(ActorId::from(client.to_be_bytes().to_vec()));
+        let text = doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        Automerge { doc, text }
+    }
+
+    fn fork(&mut self, client: u64) -> Self {
+        Automerge {
+            doc: self
+                .doc
+                .fork()
+                .with_actor(ActorId::from(client.to_be_bytes().to_vec())),
+            text: self.text.clone(),
+        }
+    }
+
+    fn insert(&mut self, pos: usize, text: &str) {
+        self.doc.splice_text(&self.text, pos, 0, text).unwrap();
+    }
+
+    fn delete(&mut self, pos: usize, len: usize) {
+        self.doc
+            .splice_text(&self.text, pos, len as isize, "")
+            .unwrap();
+    }
+
+    fn len(&self) -> usize {
+        self.doc.length(&self.text)
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.doc.merge(&mut other.doc.clone()).unwrap();
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.doc.clone().save()
+    }
+}
+
+fn b1<S: Subject>() -> S {
+    let mut doc = S::new(1);
+    for _ in 0..N {
+        let len = doc.len();
+        doc.insert(len, "a");
+    }
+    doc
+}
+
+fn b2<S: Subject>() -> S {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut doc = S::new(1);
+    for _ in 0..N {
+        let pos = rng.gen_range(0..=doc.len());
+        doc.insert(pos, "a");
+    }
+    doc
+}
+
+fn b3<S: Subject>() -> S {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut a = S::new(1);
+    let mut b = a.fork(2);
+    for _ in 0..N / 2 {
+        let pos = rng.gen_range(0..=a.len());
+        a.insert(pos, "a");
+        let pos = rng.gen_range(0..=b.len());
+        b.insert(pos, "b");
+    }
+    a.merge(&b);
+    b.merge(&a);
+    a
+}
+
+fn b4<S: Subject>(actions: &[TextAction]) -> S {
+    let mut doc = S::new(1);
+    for action in actions.iter() {
+        if action.del > 0 {
+            doc.delete(action.pos, action.del);
+        }
+        if !action.ins.is_empty() {
+            doc.insert(action.pos, &action.ins);
+        }
+    }
+    doc
+}
+
+/// Print the average time of the samples and the encoded size of the result
+fn run<S: Subject>(name: &str, f: impl Fn() -> S) {
+    let mut total = Duration::ZERO;
+    let mut size = 0;
+    for _ in 0..SAMPLES {
+        let start = Instant::now();
+        let doc = f();
+        total += start.elapsed();
+        size = doc.encode().len();
+    }
+
+    println!(
+        "{},{},{:.3},{}",
+        name,
+        S::NAME,
+        total.as_secs_f64() * 1000. / SAMPLES as f64,
+        size
+    );
+}
+
+fn run_all<S: Subject>(actions: &[TextAction]) {
+    run("B1", b1::<S>);
+    run("B2", b2::<S>);
+    run("B3", b3::<S>);
+    run("B4", || b4::<S>(actions));
+}
+
+fn main() {
+    let actions = get_automerge_actions();
+    println!("benchmark,library,time_ms,doc_size");
+    run_all::<RichText>(&actions);
+    run_all::<Yrs>(&actions);
+    run_all::<Automerge>(&actions);
+}
@@ -1,6 +1,6 @@
 use automerge::get_automerge_actions;
-use crdt_richtext::rich_text::RichText;
-use criterion::{criterion_group, criterion_main, Criterion};
+use crdt_richtext::{rich_text::RichText, Style};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
 mod automerge;
 
 pub fn bench(c: &mut Criterion) {
@@ -19,6 +19,79 @@ pub fn bench(c: &mut Criterion) {
         })
     });
 
+    c.bench_function("insert at the end", |b| {
+        b.iter(|| {
+            let mut text = RichText::new(1);
+            for _ in 0..10_000 {
+                text.insert(text.len(), "a");
+            }
+        })
+    });
+
+    c.bench_function("append", |b| {
+        b.iter(|| {
+            let mut text = RichText::new(1);
+            for _ in 0..10_000 {
+                text.append("a");
+            }
+        })
+    });
+
+    c.bench_function("typing in annotated text with listener", |b| {
+        b.iter(|| {
+            let mut text = RichText::new(1);
+            text.observe(Box::new(|_| {}));
+            text.insert(0, &"a".repeat(1000));
+            for i in 0..100 {
+                text.annotate(
+                    i * 10..i * 10 + 5,
+                    Style::new_bold_like("bold".into(), true.into()),
+                );
+            }
+            for i in 0..1000 {
+                text.insert(i % 1000, "b");
+            }
+        })
+    });
+
+    c.bench_function("updates for hundreds of peers", |b| {
+        let mut text = RichText::new(1);
+        let mut versions = Vec::new();
+        for i in 0..300 {
+            let mut peer = RichText::new(i + 2);
+            peer.merge(&text);
+            peer.insert((i as usize * 7) % (peer.len() + 1), "hello ");
+            text.merge(&peer);
+            versions.push(text.version());
+        }
+        text.offload_history().unwrap();
+
+        b.iter(|| {
+            for vv in versions.iter() {
+                black_box(text.updates_for(vv).unwrap());
+            }
+        })
+    });
+
+    c.bench_function("query fragmented", |b| {
+        let text = fragmented_text();
+        b.iter(|| {
+            for i in 0..1000 {
+                black_box(text.get_line((i * 97) % text.lines()));
+            }
+        })
+    });
+
+    c.bench_function("query after defragment", |b| {
+        let mut text = fragmented_text();
+        text.defragment(usize::MAX);
+        b.iter(|| {
+            for i in 0..1000 {
+                black_box(text.get_line((i * 97) % text.lines()));
+            }
+        })
+    });
+
     c.bench_function("automerge apply", |bench| {
         let actions = get_automerge_actions();
         let mut a = RichText::new(1);
@@ -38,5 +111,24 @@ pub fn bench(c: &mut Criterion) {
     });
 }
 
+/// Replay the start of the trace on a peer that receives the edits one by
+/// one, so the remote deletions leave the leaves fragmented
+fn fragmented_text() -> RichText {
+    let actions = get_automerge_actions();
+    let mut a = RichText::new(1);
+    let mut b = RichText::new(2);
+    for action in actions.iter().take(10_000) {
+        if action.del > 0 {
+            a.delete(action.pos..action.pos + action.del);
+        }
+        if !action.ins.is_empty() {
+            a.insert(action.pos, &action.ins);
+        }
+        b.merge(&a);
+    }
+
+    b
+}
+
 criterion_group!(benches, bench);
 criterion_main!(benches);
@@ -0,0 +1,104 @@
+//! Inspect and convert the files of the updates exported by
+//! [`RichText::export`].
+//!
+//! It's built on the public APIs of `crdt-richtext` only.
+
+use std::{error::Error, fs, process::ExitCode};
+
+use crdt_richtext::{RichText, VersionVector};
+
+const USAGE: &str = "\
+Usage: crdt-richtext <command> [args]
+
+Commands:
+  ops <file>...                         Print the ops in the order of their lamports
+  spans <file>...                       Print the spans of the document as JSON lines
+  version <out> <file>...               Write the version of the document
+  convert <out> <file>... [--since <version>]
+                                        Merge the files into a single file, or only
+                                        keep the updates after the version
+  gc <client-id> <out> <file>...        Write a new document with the current text
+                                        and styles but without the history
+  diff <from> <to>                      Print the text that is changed from the
+                                        first file to both files merged
+";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let Some((command, args)) = args.split_first() else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+
+    match (command.as_str(), args) {
+        ("ops", files) if !files.is_empty() => {
+            let doc = load(0, files)?;
+            for op in doc.export_ops(&Default::default())? {
+                println!("{:?}", op);
+            }
+        }
+        ("spans", files) if !files.is_empty() => {
+            let doc = load(0, files)?;
+            for span in doc.iter() {
+                println!("{}", serde_json::to_string(&span)?);
+            }
+        }
+        ("version", [out, files @ ..]) if !files.is_empty() => {
+            let doc = load(0, files)?;
+            fs::write(out, doc.version().encode())?;
+        }
+        ("convert", [out, rest @ ..]) => {
+            let (files, since) = match rest {
+                [files @ .., flag, version] if flag.as_str() == "--since" => {
+                    (files, VersionVector::decode(&fs::read(version)?)?)
+                }
+                files => (files, VersionVector::default()),
+            };
+            if files.is_empty() {
+                return Err(USAGE.into());
+            }
+
+            let doc = load(0, files)?;
+            fs::write(out, doc.try_export(&since)?)?;
+        }
+        ("gc", [client, out, files @ ..]) if !files.is_empty() => {
+            let doc = load(client.parse()?, files)?;
+            let doc = doc.extract(..);
+            fs::write(out, doc.try_export(&Default::default())?)?;
+        }
+        ("diff", [from, to]) => {
+            let from_doc = load(0, std::slice::from_ref(from))?;
+            let doc = load(0, &[from.clone(), to.clone()])?;
+            for span in doc.diff_overlay(&from_doc.version(), &doc.version())? {
+                println!("{}", serde_json::to_string(&span)?);
+            }
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    Ok(())
+}
+
+/// Import the files in order. The updates of a file should only depend on the
+/// updates of the files before it.
+fn load(client: u64, files: &[String]) -> Result<RichText> {
+    let mut doc = RichText::new(client);
+    for file in files {
+        doc.import(&fs::read(file)?)
+            .map_err(|e| format!("failed to import {}: {}", file, e))?;
+    }
+
+    Ok(doc)
+}
@@ -1,15 +1,28 @@
-use std::{cell::RefCell, collections::HashMap, panic};
+use std::{
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::{HashMap, VecDeque},
+    ops::{Deref, DerefMut},
+    panic,
+    rc::Rc,
+};
 
 use crdt_richtext::{
-    rich_text::{DeltaItem, IndexType, RichText as RichTextInner},
+    rich_text::{DeltaItem, ImportOptions, IndexType, RichText as RichTextInner},
     Behavior, Expand, Style,
 };
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
+/// The observers are not called while the document is being modified. The
+/// events are queued and the observers are called after the modification is
+/// done, so an observer can read the document or modify it again. The events
+/// of the modifications made by an observer are delivered after the events
+/// that are already in the queue.
 #[wasm_bindgen]
 pub struct RichText {
     inner: RefCell<RichTextInner>,
+    events: Rc<RefCell<VecDeque<(js_sys::Function, JsValue)>>>,
+    flushing: Cell<bool>,
 }
 
 #[wasm_bindgen]
@@ -26,59 +39,161 @@ struct AnnRange {
     inclusive: Option<bool>,
 }
 
-#[wasm_bindgen]
+/// Deliver the queued events after the document is released
+struct InnerMut<'a> {
+    text: &'a RichText,
+    guard: Option<RefMut<'a, RichTextInner>>,
+}
+
+impl Deref for InnerMut<'_> {
+    type Target = RichTextInner;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for InnerMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl Drop for InnerMut<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.text.flush_events();
+    }
+}
+
 impl RichText {
-    #[wasm_bindgen(constructor)]
-    pub fn new(id: u64) -> Self {
-        let mut text = RichTextInner::new(id);
+    fn from_inner(mut text: RichTextInner) -> Self {
         text.set_event_index_type(IndexType::Utf16);
         Self {
             inner: RefCell::new(text),
+            events: Default::default(),
+            flushing: Cell::new(false),
         }
     }
 
-    pub fn id(&self) -> u64 {
-        self.inner.borrow().id()
+    fn inner(&self) -> Result<Ref<'_, RichTextInner>, JsError> {
+        self.inner
+            .try_borrow()
+            .map_err(|_| JsError::new("RichText is being modified, it cannot be read"))
+    }
+
+    fn inner_mut(&self) -> Result<InnerMut<'_>, JsError> {
+        let guard = self
+            .inner
+            .try_borrow_mut()
+            .map_err(|_| JsError::new("RichText is being accessed, it cannot be modified"))?;
+        Ok(InnerMut {
+            text: self,
+            guard: Some(guard),
+        })
+    }
+
+    /// Call the observers with the queued events. The events queued by the
+    /// observers are delivered by the outermost call.
+    fn flush_events(&self) {
+        if self.flushing.replace(true) {
+            return;
+        }
+
+        loop {
+            // the queue must not be borrowed while the observer is running
+            let next = self.events.borrow_mut().pop_front();
+            match next {
+                Some((f, event)) => {
+                    let _ = f.call1(&JsValue::NULL, &event);
+                }
+                None => break,
+            }
+        }
+
+        self.flushing.set(false);
+    }
+}
+
+#[wasm_bindgen]
+impl RichText {
+    #[wasm_bindgen(constructor)]
+    pub fn new(id: u64) -> Self {
+        Self::from_inner(RichTextInner::new(id))
+    }
+
+    /// Create a document from the updates exported by another document
+    #[wasm_bindgen(js_name = "fromSnapshot")]
+    pub fn from_snapshot(id: u64, data: &[u8]) -> Result<RichText, JsError> {
+        let text = Self::new(id);
+        text.import(data)?;
+        Ok(text)
+    }
+
+    /// Create a copy of the document with a new id. The observers are not copied.
+    #[wasm_bindgen(js_name = "clone")]
+    pub fn clone_with_id(&self, new_id: u64) -> Result<RichText, JsError> {
+        Ok(Self::from_inner(self.inner()?.fork(new_id)?))
+    }
+
+    pub fn id(&self) -> Result<u64, JsError> {
+        Ok(self.inner()?.id())
+    }
+
+    #[wasm_bindgen(js_name = "setReadonly")]
+    pub fn set_readonly(&self, readonly: bool) -> Result<(), JsError> {
+        self.inner_mut()?.set_readonly(readonly);
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "isReadonly")]
+    pub fn is_readonly(&self) -> Result<bool, JsError> {
+        Ok(self.inner()?.is_readonly())
     }
 
+    /// The observer is called after the modification is done, see [`RichText`]
     #[wasm_bindgen(skip_typescript)]
-    pub fn observe(&self, f: js_sys::Function) {
-        self.inner.borrow_mut().observe(Box::new(move |event| {
+    pub fn observe(&self, f: js_sys::Function) -> Result<(), JsError> {
+        let events = self.events.clone();
+        self.inner_mut()?.observe(Box::new(move |event| {
             let serializer = serde_wasm_bindgen::Serializer::json_compatible();
-            let _ = f.call1(&JsValue::NULL, &event.serialize(&serializer).unwrap());
+            if let Ok(event) = event.serialize(&serializer) {
+                events.borrow_mut().push_back((f.clone(), event));
+            }
         }));
+        Ok(())
     }
 
     pub fn insert(&self, index: usize, text: &str) -> Result<(), JsError> {
-        if index > self.length() {
+        if index > self.length()? {
             return Err(JsError::new("index out of range"));
         }
 
-        self.inner.borrow_mut().insert_utf16(index, text);
+        self.inner_mut()?.try_insert_utf16(index, text)?;
         Ok(())
     }
 
     pub fn delete(&self, index: usize, length: usize) -> Result<(), JsError> {
-        if index + length > self.length() {
-            return Err(JsError::new("index out of range"));
+        match index.checked_add(length) {
+            Some(end) if end <= self.length()? => {}
+            _ => return Err(JsError::new("index out of range")),
         }
 
-        self.inner.borrow_mut().delete_utf16(index..index + length);
+        self.inner_mut()?.try_delete_utf16(index..index + length)?;
         Ok(())
     }
 
     #[allow(clippy::inherent_to_string)]
     #[wasm_bindgen(js_name = "toString")]
-    pub fn to_string(&self) -> String {
-        self.inner.borrow().to_string()
+    pub fn to_string(&self) -> Result<String, JsError> {
+        Ok(self.inner()?.to_string())
     }
 
     #[wasm_bindgen(skip_typescript)]
     pub fn annotate(&self, range: JsValue, ann_name: &str, value: JsValue) -> Result<(), JsError> {
         let range: AnnRange = serde_wasm_bindgen::from_value(range)?;
-
-        if range.end > self.length() {
-            return Err(JsError::new("index out of range"));
+        if !range.check(self.length()?)? {
+            return Ok(());
         }
 
         let expand: Expand = range
@@ -100,9 +215,8 @@ impl RichText {
             value,
         };
 
-        self.inner
-            .borrow_mut()
-            .annotate_utf16(range.start..range.end, style);
+        self.inner_mut()?
+            .try_annotate_utf16(range.start..range.end, style)?;
         Ok(())
     }
 
@@ -110,9 +224,8 @@ impl RichText {
     #[wasm_bindgen(js_name = "eraseAnn", skip_typescript)]
     pub fn erase_ann(&self, range: JsValue, ann_name: &str) -> Result<(), JsError> {
         let range: AnnRange = serde_wasm_bindgen::from_value(range)?;
-
-        if range.end > self.length() {
-            return Err(JsError::new("index out of range"));
+        if !range.check(self.length()?)? {
+            return Ok(());
         }
 
         let expand: Expand = range
@@ -131,48 +244,123 @@ impl RichText {
             value: serde_json::Value::Null,
         };
 
-        self.inner
-            .borrow_mut()
-            .annotate_utf16(range.start..range.end, style);
+        self.inner_mut()?
+            .try_annotate_utf16(range.start..range.end, style)?;
+        Ok(())
+    }
+
+    /// Annotate the range with the expand preset of the type, e.g. "bold"
+    /// expands after the range and "link" doesn't expand.
+    ///
+    /// The `expand` and `inclusive` fields of the range are ignored.
+    #[wasm_bindgen(skip_typescript)]
+    pub fn format(&self, range: JsValue, ann_name: &str, value: JsValue) -> Result<(), JsError> {
+        let range: AnnRange = serde_wasm_bindgen::from_value(range)?;
+        if !range.check(self.length()?)? {
+            return Ok(());
+        }
+
+        let style = Style {
+            expand: Expand::infer_insert_expand(ann_name),
+            behavior: Behavior::Merge,
+            type_: ann_name.into(),
+            value: serde_wasm_bindgen::from_value(value)?,
+        };
+        self.inner_mut()?
+            .try_annotate_utf16(range.start..range.end, style)?;
+        Ok(())
+    }
+
+    /// Erase the style of the type in the range, using the expand preset of the type
+    #[wasm_bindgen(skip_typescript)]
+    pub fn unformat(&self, range: JsValue, ann_name: &str) -> Result<(), JsError> {
+        let range: AnnRange = serde_wasm_bindgen::from_value(range)?;
+        if !range.check(self.length()?)? {
+            return Ok(());
+        }
+
+        let style = Style {
+            expand: Expand::infer_delete_expand(ann_name),
+            behavior: Behavior::Delete,
+            type_: ann_name.into(),
+            value: serde_json::Value::Null,
+        };
+        self.inner_mut()?
+            .try_annotate_utf16(range.start..range.end, style)?;
         Ok(())
     }
 
     #[wasm_bindgen(js_name = "getAnnSpans", skip_typescript)]
-    pub fn get_ann_spans(&self) -> Vec<JsValue> {
+    pub fn get_ann_spans(&self) -> Result<Vec<JsValue>, JsError> {
         let mut ans = Vec::new();
-        for span in self.inner.borrow().iter() {
+        for span in self.inner()?.iter() {
             let serializer = serde_wasm_bindgen::Serializer::json_compatible();
-            ans.push(span.serialize(&serializer).unwrap());
+            ans.push(span.serialize(&serializer)?);
         }
 
-        ans
+        Ok(ans)
+    }
+
+    /// Serialize the spans to a compact JSON for the server-side rendering
+    #[wasm_bindgen(js_name = "getSpansSerialized")]
+    pub fn get_spans_serialized(&self) -> Result<String, JsError> {
+        Ok(self.inner()?.get_spans_serialized())
     }
 
     #[wasm_bindgen(js_name = "getLine", skip_typescript)]
-    pub fn get_line(&self, line: usize) -> Vec<JsValue> {
+    pub fn get_line(&self, line: usize) -> Result<Vec<JsValue>, JsError> {
         let mut ans = Vec::new();
-        for span in self.inner.borrow().get_line(line) {
+        for span in self.inner()?.get_line(line) {
             let serializer = serde_wasm_bindgen::Serializer::json_compatible();
-            ans.push(span.serialize(&serializer).unwrap());
+            ans.push(span.serialize(&serializer)?);
         }
 
-        ans
+        Ok(ans)
     }
 
     #[wasm_bindgen(js_name = "sliceString")]
-    pub fn slice_str(&self, start: usize, end: usize) -> String {
-        self.inner.borrow().slice_str(start..end, IndexType::Utf16)
+    pub fn slice_str(&self, start: usize, end: usize) -> Result<String, JsError> {
+        if start > end || end > self.length()? {
+            return Err(JsError::new("index out of range"));
+        }
+
+        Ok(self.inner()?.slice_str(start..end, IndexType::Utf16))
     }
 
     #[wasm_bindgen(js_name = "chatAt")]
-    pub fn char_at(&self, index: usize) -> String {
-        self.inner
-            .borrow()
-            .slice_str(index..index + 1, IndexType::Utf16)
+    pub fn char_at(&self, index: usize) -> Result<String, JsError> {
+        if index >= self.length()? {
+            return Err(JsError::new("index out of range"));
+        }
+
+        Ok(self.inner()?.slice_str(index..index + 1, IndexType::Utf16))
+    }
+
+    pub fn lines(&self) -> Result<usize, JsError> {
+        Ok(self.inner()?.lines())
+    }
+
+    /// The utf16 length of the line, excluding its trailing line break
+    #[wasm_bindgen(js_name = "lineLength")]
+    pub fn line_len(&self, line: usize) -> Result<usize, JsError> {
+        self.inner()?
+            .line_len(line, IndexType::Utf16)
+            .ok_or_else(|| JsError::new("line out of range"))
+    }
+
+    #[wasm_bindgen(js_name = "wordCount")]
+    pub fn word_count(&self) -> Result<usize, JsError> {
+        Ok(self.inner()?.word_count())
     }
 
-    pub fn lines(&self) -> usize {
-        self.inner.borrow().lines()
+    /// The number of the words in the utf16 range
+    #[wasm_bindgen(js_name = "wordCountIn")]
+    pub fn word_count_in(&self, start: usize, end: usize) -> Result<usize, JsError> {
+        if start > end || end > self.length()? {
+            return Err(JsError::new("range out of bounds"));
+        }
+
+        Ok(self.inner()?.word_count_in(start..end, IndexType::Utf16))
     }
 
     #[wasm_bindgen(js_name = "applyDelta", skip_typescript)]
@@ -183,22 +371,21 @@ impl RichText {
             return Ok(());
         }
 
-        self.inner
-            .borrow_mut()
-            .apply_delta(delta.into_iter(), IndexType::Utf16);
+        check_delta(&delta, self.length()?)?;
+        self.inner_mut()?
+            .try_apply_delta(delta.into_iter(), IndexType::Utf16)?;
         Ok(())
     }
 
-    pub fn version(&self) -> Vec<u8> {
-        self.inner.borrow().version().encode()
+    pub fn version(&self) -> Result<Vec<u8>, JsError> {
+        Ok(self.inner()?.version().encode())
     }
 
     #[wasm_bindgen(js_name = "versionDebugMap")]
     pub fn version_map(&self) -> Result<JsValue, JsError> {
         let serializer = serde_wasm_bindgen::Serializer::json_compatible();
         let v = self
-            .inner
-            .borrow()
+            .inner()?
             .version()
             .vv
             .into_iter()
@@ -207,22 +394,125 @@ impl RichText {
         Ok(v.serialize(&serializer)?)
     }
 
-    pub fn export(&self, version: &[u8]) -> Vec<u8> {
+    pub fn export(&self, version: &[u8]) -> Result<Vec<u8>, JsError> {
         if version.is_empty() {
-            self.inner.borrow().export(&Default::default())
+            Ok(self.inner()?.export(&Default::default()))
         } else {
-            let vv = crdt_richtext::VersionVector::decode(version);
-            self.inner.borrow().export(&vv)
+            let vv = crdt_richtext::VersionVector::decode(version)?;
+            Ok(self.inner()?.export(&vv))
+        }
+    }
+
+    /// Export the updates into a standalone `ArrayBuffer` that is not backed
+    /// by the wasm memory, so it can be transferred to another thread by
+    /// `postMessage(buffer, [buffer])` without copying.
+    #[wasm_bindgen(js_name = "exportBuffer")]
+    pub fn export_buffer(&self, version: &[u8]) -> Result<js_sys::ArrayBuffer, JsError> {
+        let data = self.export(version)?;
+        let ans = js_sys::Uint8Array::new_with_length(data.len() as u32);
+        ans.copy_from(&data);
+        Ok(ans.buffer())
+    }
+
+    /// Export the updates into the buffer owned by the caller, and return the
+    /// number of bytes written. The buffer can be reused across the exports.
+    ///
+    /// It throws if the buffer is too small, and nothing is written.
+    #[wasm_bindgen(js_name = "exportInto")]
+    pub fn export_into(&self, version: &[u8], buffer: &js_sys::Uint8Array) -> Result<u32, JsError> {
+        let data = self.export(version)?;
+        let len = data.len() as u32;
+        if buffer.length() < len {
+            return Err(JsError::new(&format!(
+                "buffer too small, {} bytes are needed",
+                len
+            )));
         }
+
+        buffer.subarray(0, len).copy_from(&data);
+        Ok(len)
+    }
+
+    /// Import the updates from an `ArrayBuffer`, e.g. the one received from
+    /// another thread by `postMessage`
+    #[wasm_bindgen(js_name = "importBuffer")]
+    pub fn import_buffer(&self, buffer: &js_sys::ArrayBuffer) -> Result<(), JsError> {
+        self.import(&js_sys::Uint8Array::new(buffer).to_vec())
+    }
+
+    pub fn import(&self, data: &[u8]) -> Result<(), JsError> {
+        self.inner_mut()?.import(data)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "importWithOptions")]
+    pub fn import_with_options(
+        &self,
+        data: &[u8],
+        emit_events: bool,
+        origin: Option<String>,
+    ) -> Result<(), JsError> {
+        self.inner_mut()?.import_with_options(
+            data,
+            &ImportOptions {
+                emit_events,
+                origin,
+            },
+        )?;
+        Ok(())
     }
 
-    pub fn import(&self, data: &[u8]) {
-        self.inner.borrow_mut().import(data);
+    pub fn length(&self) -> Result<usize, JsError> {
+        Ok(self.inner()?.len_utf16())
     }
+}
+
+impl AnnRange {
+    /// Returns false if the range is empty and there is nothing to annotate
+    fn check(&self, len: usize) -> Result<bool, JsError> {
+        if self.start > self.end || self.end > len {
+            return Err(JsError::new("index out of range"));
+        }
 
-    pub fn length(&self) -> usize {
-        self.inner.borrow().len_utf16()
+        Ok(self.start < self.end)
+    }
+}
+
+/// Make sure the delta can be applied to a document with the given utf16 length
+fn check_delta(delta: &[DeltaItem], mut len: usize) -> Result<(), JsError> {
+    let mut index: usize = 0;
+    for item in delta {
+        match item {
+            DeltaItem::Retain {
+                retain, attributes, ..
+            } => {
+                index = index
+                    .checked_add(*retain)
+                    .ok_or_else(|| JsError::new("index out of range"))?;
+                if attributes.is_some() {
+                    // formatting beyond the end appends line breaks to the doc
+                    len = len.max(index);
+                }
+            }
+            DeltaItem::Insert { insert, .. } => {
+                if index > len {
+                    return Err(JsError::new("index out of range"));
+                }
+                let insert_len = insert.encode_utf16().count();
+                index += insert_len;
+                len += insert_len;
+            }
+            DeltaItem::Delete { delete, .. } => {
+                match index.checked_add(*delete) {
+                    Some(end) if end <= len => {}
+                    _ => return Err(JsError::new("index out of range")),
+                }
+                len -= *delete;
+            }
+        }
     }
+
+    Ok(())
 }
 
 #[wasm_bindgen(js_name = setPanicHook)]
@@ -261,6 +551,7 @@ export interface Event {
     ops: DeltaItem[],
     is_local: boolean,
     index_type: "Utf8" | "Utf16",
+    origin?: string,
 }
 
 export interface RichText {
@@ -275,6 +566,15 @@ export interface RichText {
     range: AnnRange,
     ann_name: string,
   );
+  format(
+    range: AnnRange,
+    ann_name: string,
+    value: null|boolean|number|string|object,
+  );
+  unformat(
+    range: AnnRange,
+    ann_name: string,
+  );
   observe(cb: (event: Event) => void): void;
   applyDelta(delta: DeltaItem[]): void;
 }
@@ -15,6 +15,30 @@ describe("basic ops", () => {
     expect(b.toString()).toBe("123");
   });
 
+  it("import malformed data", () => {
+    const text = new RichText(BigInt(1));
+    text.insert(0, "123");
+    expect(() => text.import(new Uint8Array([1, 2, 3]))).toThrow();
+    expect(() => text.delete(2, 10)).toThrow();
+    expect(() => text.sliceString(2, 10)).toThrow();
+    expect(text.toString()).toBe("123");
+  });
+
+  it("export into buffers", () => {
+    const text = new RichText(BigInt(1));
+    text.insert(0, "123");
+    const b = new RichText(BigInt(2));
+    b.importBuffer(text.exportBuffer(new Uint8Array()));
+    expect(b.toString()).toBe("123");
+
+    const buffer = new Uint8Array(1024);
+    const len = text.exportInto(new Uint8Array(), buffer);
+    const c = new RichText(BigInt(3));
+    c.import(buffer.subarray(0, len));
+    expect(c.toString()).toBe("123");
+    expect(() => text.exportInto(new Uint8Array(), new Uint8Array(1))).toThrow();
+  });
+
   it("bold", () => {
     const text = new RichText(BigInt(2));
     text.insert(0, "123");
@@ -122,4 +146,83 @@ describe("Observable", () => {
     expect(s).toBe(text.toString());
     expect(s).toBe(b.toString());
   });
+
+  it("modify inside observer", () => {
+    const text = new RichText(BigInt(1));
+    const lengths: number[] = [];
+    text.observe((event) => {
+      lengths.push(text.length());
+      // close the bracket after it's typed
+      if (event.is_local && event.ops.some((op) => op.insert === "(")) {
+        text.insert(text.length(), ")");
+      }
+    });
+    text.insert(0, "f(");
+    expect(text.toString()).toBe("f()");
+    expect(lengths).toStrictEqual([2, 3]);
+  });
+
+  it("readonly", () => {
+    const a = new RichText(BigInt(1));
+    a.insert(0, "123");
+    const b = new RichText(BigInt(2));
+    b.setReadonly(true);
+    expect(() => b.insert(0, "x")).toThrow();
+    b.import(a.export(new Uint8Array()));
+    expect(b.toString()).toBe("123");
+    expect(() => b.delete(0, 1)).toThrow();
+    expect(b.isReadonly()).toBe(true);
+  });
+
+  it("format and unformat", () => {
+    const text = new RichText(BigInt(1));
+    text.insert(0, "123");
+    text.format({ start: 0, end: 3 }, "bold", true);
+    text.format({ start: 0, end: 3 }, "link", "url");
+    // bold expands after the range but link doesn't
+    text.insert(3, "4");
+    expect(text.getAnnSpans()).toStrictEqual([
+      { insert: "123", attributes: { bold: true, link: "url" } },
+      { insert: "4", attributes: { bold: true } },
+    ]);
+    text.unformat({ start: 0, end: 4 }, "bold");
+    expect(text.getAnnSpans()).toStrictEqual([
+      { insert: "123", attributes: { link: "url" } },
+      { insert: "4", attributes: {} },
+    ]);
+  });
+
+  it("fromSnapshot and clone", () => {
+    const a = new RichText(BigInt(1));
+    a.insert(0, "123");
+    const b = RichText.fromSnapshot(BigInt(2), a.export(new Uint8Array()));
+    expect(b.toString()).toBe("123");
+    const c = b.clone(BigInt(3));
+    c.insert(0, "x");
+    expect(c.toString()).toBe("x123");
+    expect(b.toString()).toBe("123");
+    b.import(c.export(b.version()));
+    expect(b.toString()).toBe("x123");
+  });
+
+  it("getSpansSerialized", () => {
+    const text = new RichText(BigInt(1));
+    text.insert(0, "Hello world");
+    text.format({ start: 0, end: 5 }, "bold", true);
+    expect(JSON.parse(text.getSpansSerialized())).toStrictEqual({
+      v: 1,
+      text: "Hello world",
+      runs: [[5, 0], [6, null]],
+      attrs: [{ bold: true }],
+    });
+  });
+
+  it("wordCount", () => {
+    const text = new RichText(BigInt(1));
+    text.insert(0, "Hello 😀 world");
+    expect(text.wordCount()).toBe(3);
+    expect(text.wordCountIn(0, 8)).toBe(2);
+    text.delete(5, 4);
+    expect(text.wordCount()).toBe(1);
+  });
 });
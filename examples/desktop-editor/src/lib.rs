@@ -0,0 +1,315 @@
+//! A native editor loop built on the public APIs of `crdt-richtext` only,
+//! e.g. for a Tauri or desktop app:
+//!
+//! - the local edits replace the selection, like typing in an editor
+//! - every change is appended to a [`Wal`] before it's sent, so nothing is
+//!   lost when the app crashes
+//! - a [`Relay`] on a background thread forwards the updates between the
+//!   editors
+//! - [`UndoStack`] reverts the local edits only, and it's mapped through the
+//!   remote changes together with the selection
+//!
+//! The tests of this crate check that the public APIs are enough for such a
+//! loop. When something is missing, it should be added to `crdt-richtext`
+//! rather than worked around here.
+
+use std::{cell::RefCell, ops::Range, path::Path, rc::Rc};
+
+use crdt_richtext::{
+    rich_text::{Error, Event, IndexType},
+    RichText, Style, VersionVector,
+};
+
+pub mod sync;
+pub mod undo;
+pub mod wal;
+
+pub use sync::{Connection, Relay};
+pub use undo::{transform_index, Edit, UndoItem, UndoStack};
+pub use wal::Wal;
+
+/// The selection in utf8 indexes. `anchor == head` for a caret.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Selection {
+    pub anchor: usize,
+    pub head: usize,
+}
+
+impl Selection {
+    pub fn caret(index: usize) -> Self {
+        Self {
+            anchor: index,
+            head: index,
+        }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.anchor.min(self.head)..self.anchor.max(self.head)
+    }
+
+    pub fn is_caret(&self) -> bool {
+        self.anchor == self.head
+    }
+}
+
+pub struct Editor {
+    doc: RichText,
+    events: Rc<RefCell<Vec<Event>>>,
+    wal: Wal,
+    undo: UndoStack,
+    selection: Selection,
+    /// The counter after the own ops that are sent to the relay
+    sent: u32,
+    connection: Option<Connection>,
+}
+
+impl Editor {
+    /// Open the document in the WAL at `path`, or create it. The same
+    /// `client` should be used when the document is opened again.
+    pub fn open(path: impl AsRef<Path>, client: u64) -> Result<Self, Error> {
+        let (wal, records) = Wal::open(path)?;
+        let mut doc = RichText::new(client);
+        doc.import_many(&records, |_, _| {})?;
+        let events: Rc<RefCell<Vec<Event>>> = Default::default();
+        let queue = events.clone();
+        doc.observe(Box::new(move |event| {
+            queue.borrow_mut().push(event.clone())
+        }));
+        Ok(Self {
+            doc,
+            events,
+            wal,
+            undo: UndoStack::default(),
+            selection: Selection::default(),
+            // the relay imports the updates idempotently, so the own ops are
+            // sent again after reopening
+            sent: 0,
+            connection: None,
+        })
+    }
+
+    pub fn doc(&self) -> &RichText {
+        &self.doc
+    }
+
+    pub fn text(&self) -> String {
+        self.doc.to_string()
+    }
+
+    pub fn wal(&self) -> &Wal {
+        &self.wal
+    }
+
+    pub fn selection(&self) -> Selection {
+        self.selection
+    }
+
+    /// # Panic
+    ///
+    /// Panics if the selection is out of the document
+    pub fn set_selection(&mut self, selection: Selection) {
+        assert!(selection.range().end <= self.doc.len());
+        self.selection = selection;
+    }
+
+    /// Replace the selection with the text, like typing or pasting
+    pub fn type_text(&mut self, text: &str) -> Result<(), Error> {
+        let range = self.selection.range();
+        let edits = vec![
+            Edit::Delete {
+                range: range.clone(),
+            },
+            Edit::Insert {
+                index: range.start,
+                text: text.to_string(),
+            },
+        ];
+        let inverse = self.apply(&edits)?;
+        self.undo.record(UndoItem { edits: inverse });
+        Ok(())
+    }
+
+    /// Delete the selection, or the char before the caret
+    pub fn delete_backward(&mut self) -> Result<(), Error> {
+        let mut range = self.selection.range();
+        if range.is_empty() {
+            if range.start == 0 {
+                return Ok(());
+            }
+
+            // step back a whole char
+            let utf16 = self
+                .doc
+                .convert_index(range.start, IndexType::Utf8, IndexType::Utf16);
+            let start = self
+                .doc
+                .resolve_range(utf16 - 1..utf16, IndexType::Utf16)
+                .start;
+            range.start = self
+                .doc
+                .convert_index(start, IndexType::Utf16, IndexType::Utf8);
+        }
+
+        let inverse = self.apply(&[Edit::Delete { range }])?;
+        self.undo.record(UndoItem { edits: inverse });
+        Ok(())
+    }
+
+    /// Style the selection. The formatting is not undoable.
+    pub fn format(&mut self, style: Style) -> Result<(), Error> {
+        let range = self.selection.range();
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        let before = self.doc.version();
+        self.doc.try_annotate(range, style)?;
+        self.persist(&before)?;
+        self.drain_events();
+        Ok(())
+    }
+
+    /// Revert the last local action. Return false if there is nothing to undo.
+    pub fn undo(&mut self) -> Result<bool, Error> {
+        let Some(item) = self.undo.pop_undo() else {
+            return Ok(false);
+        };
+
+        let inverse = self.apply(&item.edits)?;
+        self.undo.push_redo(UndoItem { edits: inverse });
+        Ok(true)
+    }
+
+    /// Apply the last undone action again. Return false if there is nothing
+    /// to redo.
+    pub fn redo(&mut self) -> Result<bool, Error> {
+        let Some(item) = self.undo.pop_redo() else {
+            return Ok(false);
+        };
+
+        let inverse = self.apply(&item.edits)?;
+        self.undo.push_undo(UndoItem { edits: inverse });
+        Ok(true)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.undo.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.undo.can_redo()
+    }
+
+    pub fn connect(&mut self, relay: &mut Relay) {
+        self.connection = Some(relay.connect());
+        self.sent = 0;
+    }
+
+    /// Send the own ops that are not sent yet and apply the received
+    /// updates. It doesn't block, so it can be called on every tick of the
+    /// event loop.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        let Some(connection) = self.connection.as_ref() else {
+            return Ok(());
+        };
+
+        let client = self.doc.id();
+        let end = self.doc.version().vv.get(&client).copied().unwrap_or(0);
+        if end > self.sent {
+            // only the own ops, the others are from the relay
+            let mut vv = self.doc.version();
+            vv.vv.insert(client, self.sent);
+            connection.send(self.doc.try_export(&vv)?);
+            self.sent = end;
+        }
+
+        let updates = connection.try_recv();
+        for update in updates {
+            self.receive(&update)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply an update from other peers
+    pub fn receive(&mut self, update: &[u8]) -> Result<(), Error> {
+        let before = self.doc.version();
+        self.doc.import(update)?;
+        if self.doc.version() != before {
+            self.wal.append(update)?;
+        }
+
+        self.drain_events();
+        Ok(())
+    }
+
+    /// Compact the WAL into a single record of the whole document
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        self.wal.checkpoint(&self.doc)
+    }
+
+    /// Apply the edits and return the edits that revert them
+    fn apply(&mut self, edits: &[Edit]) -> Result<Vec<Edit>, Error> {
+        let before = self.doc.version();
+        let mut inverse = Vec::new();
+        for edit in edits {
+            let len = self.doc.len();
+            match edit {
+                Edit::Insert { index, text } => {
+                    if text.is_empty() {
+                        continue;
+                    }
+
+                    let index = (*index).min(len);
+                    self.doc.try_insert(index, text)?;
+                    inverse.push(Edit::Delete {
+                        range: index..index + text.len(),
+                    });
+                    self.selection = Selection::caret(index + text.len());
+                }
+                Edit::Delete { range } => {
+                    let range = range.start.min(len)..range.end.min(len);
+                    self.selection = Selection::caret(range.start);
+                    if range.is_empty() {
+                        continue;
+                    }
+
+                    let text = self.doc.slice_str(range.clone(), IndexType::Utf8);
+                    self.doc.try_delete(range.clone())?;
+                    inverse.push(Edit::Insert {
+                        index: range.start,
+                        text,
+                    });
+                }
+            }
+        }
+
+        inverse.reverse();
+        self.persist(&before)?;
+        self.drain_events();
+        Ok(inverse)
+    }
+
+    /// Append the local changes since `before` to the WAL
+    fn persist(&mut self, before: &VersionVector) -> Result<(), Error> {
+        if self.doc.version() != *before {
+            self.wal.append(&self.doc.try_export(before)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Map the selection and the undo history through the remote changes.
+    /// The local changes already placed them.
+    fn drain_events(&mut self) {
+        let events = std::mem::take(&mut *self.events.borrow_mut());
+        for event in events.iter().filter(|x| !x.is_local) {
+            debug_assert_eq!(event.index_type, IndexType::Utf8);
+            self.selection = Selection {
+                anchor: transform_index(&event.ops, self.selection.anchor, false),
+                head: transform_index(&event.ops, self.selection.head, false),
+            };
+            self.undo.transform(&event.ops);
+        }
+    }
+}
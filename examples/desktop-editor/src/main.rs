@@ -0,0 +1,66 @@
+//! Run two editors against a relay and print what each of them sees.
+//!
+//! The WAL files are written to the temp directory, so running it again
+//! continues the same documents.
+
+use std::{error::Error, time::Duration};
+
+use crdt_richtext::Style;
+use crdt_richtext_desktop_editor::{Editor, Relay, Selection};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir();
+    let mut relay = Relay::spawn();
+    let mut alice = Editor::open(dir.join("desktop-editor-alice.wal"), 1)?;
+    let mut bob = Editor::open(dir.join("desktop-editor-bob.wal"), 2)?;
+    alice.connect(&mut relay);
+    bob.connect(&mut relay);
+    tick(&mut [&mut alice, &mut bob])?;
+
+    alice.set_selection(Selection::caret(alice.doc().len()));
+    alice.type_text("Hello")?;
+    tick(&mut [&mut alice, &mut bob])?;
+
+    bob.set_selection(Selection::caret(bob.doc().len()));
+    bob.type_text(" world")?;
+    bob.set_selection(Selection {
+        anchor: bob.doc().len() - 5,
+        head: bob.doc().len(),
+    });
+    bob.format(Style::new_bold_like("bold".into(), true.into()))?;
+    alice.type_text("!")?;
+    tick(&mut [&mut alice, &mut bob])?;
+    print("after editing", &alice, &bob);
+
+    alice.undo()?;
+    tick(&mut [&mut alice, &mut bob])?;
+    print("after alice's undo", &alice, &bob);
+
+    alice.checkpoint()?;
+    bob.checkpoint()?;
+    Ok(())
+}
+
+/// One tick of the event loops of the editors. The relay forwards the
+/// updates in the background.
+fn tick(editors: &mut [&mut Editor]) -> Result<(), Box<dyn Error>> {
+    for _ in 0..2 {
+        for editor in editors.iter_mut() {
+            editor.sync()?;
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(())
+}
+
+fn print(title: &str, alice: &Editor, bob: &Editor) {
+    println!("{}:", title);
+    for (name, editor) in [("alice", alice), ("bob", bob)] {
+        println!("  {:<5} {:?} {:?}", name, editor.text(), editor.selection());
+        for span in editor.doc().get_spans() {
+            println!("        {}", serde_json::to_string(&span).unwrap());
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! A relay on a background thread that forwards the updates between the
+//! editors, standing in for a sync server.
+//!
+//! The relay keeps its own replica of the document, so an editor that
+//! connects later gets the whole document first. The updates are imported
+//! idempotently, and only the ones that bring new ops are forwarded.
+
+use std::{
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crdt_richtext::RichText;
+
+enum Message {
+    Join { peer: u64, outbox: Sender<Vec<u8>> },
+    Update { peer: u64, update: Vec<u8> },
+    Shutdown,
+}
+
+pub struct Relay {
+    sender: Sender<Message>,
+    handle: Option<JoinHandle<()>>,
+    next_peer: u64,
+}
+
+impl Relay {
+    pub fn spawn() -> Self {
+        let (sender, receiver) = channel();
+        let handle = std::thread::spawn(move || run(receiver));
+        Self {
+            sender,
+            handle: Some(handle),
+            next_peer: 0,
+        }
+    }
+
+    pub fn connect(&mut self) -> Connection {
+        let peer = self.next_peer;
+        self.next_peer += 1;
+        let (outbox, inbox) = channel();
+        self.sender.send(Message::Join { peer, outbox }).unwrap();
+        Connection {
+            peer,
+            relay: self.sender.clone(),
+            inbox,
+        }
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Message::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// The connection of an editor to the [`Relay`]
+pub struct Connection {
+    peer: u64,
+    relay: Sender<Message>,
+    inbox: Receiver<Vec<u8>>,
+}
+
+impl Connection {
+    pub fn send(&self, update: Vec<u8>) {
+        // the updates are kept in the WAL, so they are sent again after the
+        // relay is back
+        let _ = self.relay.send(Message::Update {
+            peer: self.peer,
+            update,
+        });
+    }
+
+    /// Take the received updates without blocking
+    pub fn try_recv(&self) -> Vec<Vec<u8>> {
+        self.inbox.try_iter().collect()
+    }
+
+    /// Wait for an update for at most `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        match self.inbox.recv_timeout(timeout) {
+            Ok(update) => Some(update),
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+fn run(receiver: Receiver<Message>) {
+    // the relay never edits, so its client id is never used by the ops
+    let mut doc = RichText::new(u64::MAX);
+    let mut peers: Vec<(u64, Sender<Vec<u8>>)> = Vec::new();
+    for message in receiver {
+        match message {
+            Message::Join { peer, outbox } => {
+                if !doc.version().vv.is_empty() {
+                    let _ = outbox.send(doc.export(&Default::default()));
+                }
+
+                peers.push((peer, outbox));
+            }
+            Message::Update { peer, update } => {
+                let version = doc.version();
+                if let Err(e) = doc.import(&update) {
+                    eprintln!("relay: dropped an update from {}: {}", peer, e);
+                    continue;
+                }
+
+                if doc.version() == version {
+                    continue;
+                }
+
+                peers.retain(|(id, outbox)| *id == peer || outbox.send(update.clone()).is_ok());
+            }
+            Message::Shutdown => break,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! A local undo stack on top of the text edits.
+//!
+//! The CRDT has no undo of its own. The editor records the inverse of every
+//! local edit as index-based [`Edit`]s, and maps them through the remote
+//! changes with [`transform_index`], so undo only reverts the local edits.
+//!
+//! Only the text is restored. The styles of the deleted text and the
+//! formatting are not part of the undo history.
+
+use std::ops::Range;
+
+use crdt_richtext::rich_text::DeltaItem;
+
+/// A text edit in utf8 indexes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    Insert { index: usize, text: String },
+    Delete { range: Range<usize> },
+}
+
+impl Edit {
+    fn transform(&mut self, delta: &[DeltaItem]) {
+        match self {
+            Edit::Insert { index, .. } => *index = transform_index(delta, *index, false),
+            Edit::Delete { range } => {
+                // the text inserted at the edges is not deleted
+                let start = transform_index(delta, range.start, true);
+                let end = transform_index(delta, range.end, false);
+                *range = start..end.max(start);
+            }
+        }
+    }
+}
+
+/// The edits that undo or redo a single user action, in the order to apply
+#[derive(Debug, Clone, Default)]
+pub struct UndoItem {
+    pub edits: Vec<Edit>,
+}
+
+#[derive(Debug, Default)]
+pub struct UndoStack {
+    undo: Vec<UndoItem>,
+    redo: Vec<UndoItem>,
+}
+
+impl UndoStack {
+    /// Record a new local action. It clears the redo stack.
+    pub fn record(&mut self, item: UndoItem) {
+        if !item.edits.is_empty() {
+            self.undo.push(item);
+            self.redo.clear();
+        }
+    }
+
+    pub fn pop_undo(&mut self) -> Option<UndoItem> {
+        self.undo.pop()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<UndoItem> {
+        self.redo.pop()
+    }
+
+    pub fn push_undo(&mut self, item: UndoItem) {
+        self.undo.push(item);
+    }
+
+    pub fn push_redo(&mut self, item: UndoItem) {
+        self.redo.push(item);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Map the recorded edits through a remote change
+    pub fn transform(&mut self, delta: &[DeltaItem]) {
+        for item in self.undo.iter_mut().chain(self.redo.iter_mut()) {
+            for edit in item.edits.iter_mut() {
+                edit.transform(delta);
+            }
+        }
+    }
+}
+
+/// Map the index through the change described by `delta`.
+///
+/// If text is inserted at the index, the index moves after it when
+/// `stick_right` is true and stays before it otherwise.
+pub fn transform_index(delta: &[DeltaItem], index: usize, stick_right: bool) -> usize {
+    let mut old = 0;
+    let mut ans = index;
+    for op in delta {
+        if old > index {
+            break;
+        }
+
+        match op {
+            DeltaItem::Retain { retain, .. } => old += retain,
+            DeltaItem::Insert { .. } => {
+                if old < index || (old == index && stick_right) {
+                    ans += op.length();
+                }
+            }
+            DeltaItem::Delete { delete, .. } => {
+                ans -= (*delete).min(index.saturating_sub(old));
+                old += delete;
+            }
+        }
+    }
+
+    ans
+}
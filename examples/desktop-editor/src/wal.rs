@@ -0,0 +1,84 @@
+//! A write-ahead log of the updates of a document.
+//!
+//! Every record is an update exported by [`RichText::export`], prefixed by its
+//! length as a little-endian `u32`. A record that is cut off by a crash is
+//! dropped when the log is opened again.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crdt_richtext::{rich_text::Error, RichText};
+
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    records: usize,
+}
+
+impl Wal {
+    /// Open the log and read its records
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<Vec<u8>>), Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut data = Vec::new();
+        if path.exists() {
+            File::open(&path)?.read_to_end(&mut data)?;
+        }
+
+        let mut records = Vec::new();
+        let mut rest = &data[..];
+        while rest.len() >= 4 {
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            if rest.len() < 4 + len {
+                break;
+            }
+
+            records.push(rest[4..4 + len].to_vec());
+            rest = &rest[4 + len..];
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // drop the torn record at the end
+        file.set_len((data.len() - rest.len()) as u64)?;
+        let wal = Self {
+            path,
+            file,
+            records: records.len(),
+        };
+        Ok((wal, records))
+    }
+
+    /// Append the update and sync it to the disk
+    pub fn append(&mut self, update: &[u8]) -> Result<(), Error> {
+        let mut record = Vec::with_capacity(update.len() + 4);
+        record.extend_from_slice(&(update.len() as u32).to_le_bytes());
+        record.extend_from_slice(update);
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// The number of the records in the log
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// Replace the records with a single update of the whole document. The
+    /// new log is written next to the old one and renamed over it, so a
+    /// crash leaves either of them intact.
+    pub fn checkpoint(&mut self, doc: &RichText) -> Result<(), Error> {
+        let update = doc.try_export(&Default::default())?;
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&(update.len() as u32).to_le_bytes())?;
+        file.write_all(&update)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crdt_richtext::Style;
+use crdt_richtext_desktop_editor::{Editor, Relay, Selection};
+
+fn wal_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "crdt-richtext-editor-{}-{}.wal",
+        std::process::id(),
+        name
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Sync the editors until `done` returns true
+fn sync_until(editors: &mut [&mut Editor], done: impl Fn(&[&mut Editor]) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        for editor in editors.iter_mut() {
+            editor.sync().unwrap();
+        }
+
+        if done(editors) {
+            return;
+        }
+
+        assert!(Instant::now() < deadline, "the editors did not converge");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn reopen_from_wal() {
+    let path = wal_path("reopen");
+    let mut editor = Editor::open(&path, 1).unwrap();
+    editor.type_text("hello").unwrap();
+    editor.set_selection(Selection { anchor: 0, head: 5 });
+    editor
+        .format(Style::new_bold_like("bold".into(), true.into()))
+        .unwrap();
+    editor.set_selection(Selection::caret(5));
+    editor.type_text(" world").unwrap();
+    let spans = editor.doc().get_spans();
+    drop(editor);
+
+    // a record torn by a crash is dropped
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[100, 0, 0, 0, 1, 2]).unwrap();
+    drop(file);
+    let mut editor = Editor::open(&path, 1).unwrap();
+    assert_eq!(editor.doc().get_spans(), spans);
+    assert_eq!(editor.wal().records(), 3);
+
+    editor.checkpoint().unwrap();
+    editor.set_selection(Selection::caret(0));
+    editor.type_text("> ").unwrap();
+    drop(editor);
+    let editor = Editor::open(&path, 1).unwrap();
+    assert_eq!(editor.text(), "> hello world");
+    assert_eq!(editor.wal().records(), 2);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn undo_with_remote_changes() {
+    let mut relay = Relay::spawn();
+    let mut a = Editor::open(wal_path("undo-a"), 1).unwrap();
+    let mut b = Editor::open(wal_path("undo-b"), 2).unwrap();
+    a.connect(&mut relay);
+    b.connect(&mut relay);
+
+    a.type_text("hello").unwrap();
+    sync_until(&mut [&mut a, &mut b], |x| x[1].text() == "hello");
+    b.set_selection(Selection::caret(0));
+    b.type_text(">> ").unwrap();
+    b.set_selection(Selection::caret(8));
+    b.type_text(" world").unwrap();
+    sync_until(&mut [&mut a, &mut b], |x| x[0].text() == ">> hello world");
+    // the caret moves with the remote insertion before it
+    assert_eq!(a.selection(), Selection::caret(8));
+
+    assert!(a.undo().unwrap());
+    assert_eq!(a.text(), ">>  world");
+    assert!(!a.undo().unwrap());
+    assert!(a.redo().unwrap());
+    assert_eq!(a.text(), ">> hello world");
+    a.delete_backward().unwrap();
+    assert_eq!(a.text(), ">> hell world");
+    assert!(!a.can_redo());
+    sync_until(&mut [&mut a, &mut b], |x| x[0].text() == x[1].text());
+    assert_eq!(b.text(), ">> hell world");
+}
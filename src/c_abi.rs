@@ -0,0 +1,282 @@
+//! A thin `extern "C"` facade of the core operations, enabled by the `c-abi`
+//! feature, for the hosts that load a plain wasm module without the
+//! wasm-bindgen glue, e.g. Cloudflare Workers or AssemblyScript:
+//!
+//! ```sh
+//! cargo rustc --release --features c-abi --target wasm32-unknown-unknown --crate-type cdylib
+//! ```
+//!
+//! The host copies its input into the memory of the module with
+//! [`crdt_alloc`], and passes the pointer and the length. The indexes are in
+//! utf8 bytes.
+//!
+//! The functions that return data put it in a buffer owned by the module and
+//! return its length, then the host reads it from [`crdt_result_ptr`] before
+//! the next call. They return -1 on failure, and the buffer holds the error
+//! message instead.
+
+#![allow(unsafe_code)]
+
+use std::cell::RefCell;
+
+use crate::{rich_text::Error, Expand, RichText, Style, VersionVector};
+
+thread_local! {
+    static RESULT: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+fn set_result(data: Vec<u8>) -> i32 {
+    let len = data.len() as i32;
+    RESULT.with(|x| *x.borrow_mut() = data);
+    len
+}
+
+fn to_code(result: Result<Vec<u8>, Error>) -> i32 {
+    match result {
+        Ok(data) => set_result(data),
+        Err(err) => {
+            set_result(err.to_string().into_bytes());
+            -1
+        }
+    }
+}
+
+/// # Safety
+///
+/// `ptr` and `len` should be a valid byte slice, e.g. from [`crdt_alloc`]
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+
+    std::slice::from_raw_parts(ptr, len)
+}
+
+/// # Safety
+///
+/// The same as [`bytes`]
+unsafe fn str<'a>(ptr: *const u8, len: usize) -> Result<&'a str, Error> {
+    std::str::from_utf8(bytes(ptr, len)).map_err(|err| Error::DecodeError(err.to_string()))
+}
+
+/// The panics abort the module, so the out of bound indexes are rejected
+/// before calling into the document
+fn check_bound(doc: &RichText, end: usize) -> Result<(), Error> {
+    if end > doc.len() {
+        return Err(Error::InvalidOp(format!(
+            "index {} is out of bound {}",
+            end,
+            doc.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Allocate `len` bytes for the input of the next call
+#[no_mangle]
+pub extern "C" fn crdt_alloc(len: usize) -> *mut u8 {
+    let mut buf = Vec::<u8>::with_capacity(len);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+    ptr
+}
+
+/// # Safety
+///
+/// `ptr` should be from [`crdt_alloc`] with the same `len`
+#[no_mangle]
+pub unsafe extern "C" fn crdt_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+/// The output of the last call
+#[no_mangle]
+pub extern "C" fn crdt_result_ptr() -> *const u8 {
+    RESULT.with(|x| x.borrow().as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn crdt_new(client_id: u64) -> *mut RichText {
+    Box::into_raw(Box::new(RichText::new(client_id)))
+}
+
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`], and it can't be used after this call
+#[no_mangle]
+pub unsafe extern "C" fn crdt_drop(doc: *mut RichText) {
+    drop(Box::from_raw(doc));
+}
+
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`]
+#[no_mangle]
+pub unsafe extern "C" fn crdt_len(doc: *const RichText) -> usize {
+    (*doc).len()
+}
+
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`], and `ptr` should be a byte slice of
+/// `len`
+#[no_mangle]
+pub unsafe extern "C" fn crdt_insert(
+    doc: *mut RichText,
+    index: usize,
+    ptr: *const u8,
+    len: usize,
+) -> i32 {
+    let doc = &mut *doc;
+    let result = (|| {
+        let text = str(ptr, len)?;
+        check_bound(doc, index)?;
+        doc.try_insert(index, text)?;
+        Ok(Vec::new())
+    })();
+    to_code(result)
+}
+
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`]
+#[no_mangle]
+pub unsafe extern "C" fn crdt_delete(doc: *mut RichText, index: usize, len: usize) -> i32 {
+    let doc = &mut *doc;
+    let result = check_bound(doc, index.saturating_add(len))
+        .and_then(|_| doc.try_delete(index..index + len))
+        .map(|_| Vec::new());
+    to_code(result)
+}
+
+/// Style the range with the JSON `value` of the type. The expand is inferred
+/// from the type like [`Expand::infer_insert_expand`], and a `null` value
+/// removes the style.
+///
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`], and the pointers should be byte slices
+/// of their lengths
+#[no_mangle]
+pub unsafe extern "C" fn crdt_annotate(
+    doc: *mut RichText,
+    start: usize,
+    end: usize,
+    type_ptr: *const u8,
+    type_len: usize,
+    value_ptr: *const u8,
+    value_len: usize,
+) -> i32 {
+    let doc = &mut *doc;
+    let result = (|| {
+        check_bound(doc, end)?;
+        let type_ = str(type_ptr, type_len)?;
+        let value: serde_json::Value = serde_json::from_slice(bytes(value_ptr, value_len))
+            .map_err(|_| Error::InvalidValue(type_.to_string()))?;
+        let style = match (value.is_null(), Expand::infer_insert_expand(type_)) {
+            (true, Expand::None) => Style::new_erase_link_like(type_.into()),
+            (true, _) => Style::new_erase_bold_like(type_.into()),
+            (false, Expand::None) => Style::new_link_like(type_.into(), value),
+            (false, _) => Style::new_bold_like(type_.into(), value),
+        };
+        doc.try_annotate(start..end, style)?;
+        Ok(Vec::new())
+    })();
+    to_code(result)
+}
+
+/// Put the text in the result buffer
+///
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`]
+#[no_mangle]
+pub unsafe extern "C" fn crdt_to_string(doc: *const RichText) -> i32 {
+    set_result((*doc).to_string().into_bytes())
+}
+
+/// Put the spans in JSON in the result buffer
+///
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`]
+#[no_mangle]
+pub unsafe extern "C" fn crdt_spans_json(doc: *const RichText) -> i32 {
+    set_result(serde_json::to_vec(&(*doc).get_spans()).unwrap())
+}
+
+/// Put the encoded version in the result buffer
+///
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`]
+#[no_mangle]
+pub unsafe extern "C" fn crdt_version(doc: *const RichText) -> i32 {
+    set_result((*doc).version().encode())
+}
+
+/// Put the updates after the encoded version in the result buffer. An empty
+/// version exports the whole document.
+///
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`], and `ptr` should be a byte slice of
+/// `len`
+#[no_mangle]
+pub unsafe extern "C" fn crdt_export(doc: *const RichText, ptr: *const u8, len: usize) -> i32 {
+    let result = match len {
+        0 => Ok(VersionVector::default()),
+        _ => VersionVector::decode(bytes(ptr, len)),
+    }
+    .and_then(|vv| (*doc).try_export(&vv));
+    to_code(result)
+}
+
+/// # Safety
+///
+/// `doc` should be from [`crdt_new`], and `ptr` should be a byte slice of
+/// `len`
+#[no_mangle]
+pub unsafe extern "C" fn crdt_import(doc: *mut RichText, ptr: *const u8, len: usize) -> i32 {
+    to_code((*doc).import(bytes(ptr, len)).map(|_| Vec::new()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn result() -> Vec<u8> {
+        RESULT.with(|x| x.borrow().clone())
+    }
+
+    #[test]
+    fn round_trip() {
+        unsafe {
+            let a = crdt_new(1);
+            let b = crdt_new(2);
+            assert_eq!(crdt_insert(a, 0, "hello".as_ptr(), 5), 0);
+            assert_eq!(
+                crdt_annotate(a, 0, 5, "bold".as_ptr(), 4, "true".as_ptr(), 4),
+                0
+            );
+            let len = crdt_export(a, std::ptr::null(), 0);
+            assert!(len > 0);
+            let update = result();
+            assert_eq!(crdt_import(b, update.as_ptr(), update.len()), 0);
+            assert_eq!(crdt_to_string(b), 5);
+            assert_eq!(result(), b"hello");
+            crdt_spans_json(b);
+            assert_eq!(
+                String::from_utf8(result()).unwrap(),
+                r#"[{"insert":"hello","attributes":{"bold":true}}]"#
+            );
+
+            assert_eq!(crdt_delete(b, 4, 10), -1);
+            assert!(!result().is_empty());
+            assert_eq!(crdt_len(b), 5);
+            crdt_drop(a);
+            crdt_drop(b);
+        }
+    }
+}
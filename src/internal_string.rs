@@ -0,0 +1,134 @@
+//! A light replacement of `string_cache::DefaultAtom` when the `string_cache`
+//! feature is disabled.
+//!
+//! The annotation types are not interned. Every string is a reference-counted
+//! `str`, so cloning is still cheap but the comparison is by content.
+//!
+//! The recently used strings are cached, so the common types like "bold" share
+//! their allocations. The cache is bounded, so a string is freed once it's
+//! evicted and no longer used, unlike the interned strings that are never
+//! freed. It's the better choice when the types are dynamic, e.g. a highlight
+//! type per user.
+
+use std::{cell::RefCell, fmt::Display, ops::Deref, sync::Arc};
+
+use fxhash::FxHashMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InternalString(Arc<str>);
+
+/// The number of the strings in a generation of the cache
+const CACHE_SIZE: usize = 1024;
+
+/// An approximate LRU cache with two generations. The strings used in the
+/// current generation are kept in the next one, and the others are evicted
+/// when the current generation is full.
+#[derive(Default)]
+struct Cache {
+    hot: FxHashMap<Arc<str>, ()>,
+    cold: FxHashMap<Arc<str>, ()>,
+}
+
+impl Cache {
+    fn get(&mut self, value: &str) -> Arc<str> {
+        if let Some((s, _)) = self.hot.get_key_value(value) {
+            return s.clone();
+        }
+
+        let s = match self.cold.remove_entry(value) {
+            Some((s, _)) => s,
+            None => Arc::from(value),
+        };
+        if self.hot.len() >= CACHE_SIZE {
+            self.cold = std::mem::take(&mut self.hot);
+        }
+
+        self.hot.insert(s.clone(), ());
+        s
+    }
+}
+
+thread_local! {
+    static CACHE: RefCell<Cache> = RefCell::new(Cache::default());
+}
+
+impl Default for InternalString {
+    fn default() -> Self {
+        Self(Arc::from(""))
+    }
+}
+
+impl Deref for InternalString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for InternalString {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for InternalString {
+    fn from(value: &str) -> Self {
+        Self(CACHE.with(|cache| cache.borrow_mut().get(value)))
+    }
+}
+
+impl From<String> for InternalString {
+    fn from(value: String) -> Self {
+        Self::from(value.as_str())
+    }
+}
+
+impl std::fmt::Debug for InternalString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl Display for InternalString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for InternalString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for InternalString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{Cache, InternalString, CACHE_SIZE};
+
+    #[test]
+    fn share_and_evict() {
+        let a = InternalString::from("bold");
+        let b = InternalString::from(String::from("bold"));
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+
+        let mut cache = Cache::default();
+        let bold = cache.get("bold");
+        for i in 0..CACHE_SIZE * 2 {
+            cache.get(&format!("highlight-{}", i));
+        }
+        assert!(cache.hot.len() + cache.cold.len() <= CACHE_SIZE * 2);
+        assert!(!cache.hot.contains_key("bold") && !cache.cold.contains_key("bold"));
+        assert_eq!(Arc::strong_count(&bold), 1);
+    }
+}
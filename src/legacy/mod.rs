@@ -4,18 +4,71 @@ pub use range_map::RangeMap;
 mod range_map;
 #[cfg(feature = "test")]
 pub mod test_utils;
-use range_map::{AnnPosRelativeToInsert, Span};
+#[cfg(feature = "debug-trace")]
+pub use range_map::AnnPosRelativeToInsert;
+#[cfg(not(feature = "debug-trace"))]
+use range_map::AnnPosRelativeToInsert;
+use range_map::Span;
 
 #[derive(Debug)]
 pub struct CrdtRange<R> {
     pub(crate) range_map: R,
+    listeners: RangeListeners,
+    #[cfg(feature = "merge-log")]
+    merge_log: Vec<crate::merge_log::MergeRecord>,
+    #[cfg(feature = "debug-trace")]
+    insert_trace: Vec<InsertDecision>,
+}
+
+/// The events of [`CrdtRange`], see [`CrdtRange::observe`]
+#[derive(Debug, Clone)]
+pub enum RangeEvent {
+    /// A local insert next to the tombstones moved the anchors of an
+    /// annotation. The patch is also in the ops returned by
+    /// [`CrdtRange::insert_text`], so it needs to be sent to the other peers.
+    PatchEmitted(Patch),
+}
+
+pub type RangeListener = Box<dyn FnMut(&RangeEvent)>;
+
+#[derive(Default)]
+struct RangeListeners(Vec<RangeListener>);
+
+impl Debug for RangeListeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RangeListeners({})", self.0.len())
+    }
+}
+
+/// How an annotation ending at the insert position is placed relative to the
+/// new text, recorded by [`CrdtRange::insert_text`].
+///
+/// It explains why the new text inherits or doesn't inherit a style when it's
+/// inserted next to the tombstones that the annotation is anchored to.
+#[cfg(feature = "debug-trace")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertDecision {
+    pub annotation: OpID,
+    pub type_: InternalString,
+    /// Whether the start anchor of the annotation is before the new text
+    pub start_before_insert: bool,
+    /// Whether the end anchor of the annotation is after the new text
+    pub end_after_insert: bool,
+    pub position: AnnPosRelativeToInsert,
 }
 
 impl<R: RangeMap + Debug> CrdtRange<R> {
     pub fn new() -> Self {
         let mut r = R::init();
         r.insert_directly(0, 2);
-        CrdtRange { range_map: r }
+        CrdtRange {
+            range_map: r,
+            listeners: Default::default(),
+            #[cfg(feature = "merge-log")]
+            merge_log: Vec::new(),
+            #[cfg(feature = "debug-trace")]
+            insert_trace: Vec::new(),
+        }
     }
 
     /// Insert a new span of text into the range. It's used to sync
@@ -39,13 +92,15 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
         next_lamport: Lamport,
         next_op_id: OpID,
         mut cmp: Cmp,
-    ) -> Vec<RangeOp>
+    ) -> Result<Vec<RangeOp>, Error>
     where
         Cmp: FnMut(OpID) -> Ordering,
     {
         let mut ans = vec![];
+        let tri_pos = tri_index(pos, 1)?;
+        let tri_len = tri_index(len, 0)?;
         // Maybe add the zero-len filter rule as a requirement for the range_map?
-        let spans = self.get_trimmed_spans_around(pos);
+        let spans = self.get_trimmed_spans_around(tri_pos - 1);
         assert!(spans.len() <= 3, "{}", spans.len());
         assert!(spans.iter().map(|x| x.len).sum::<usize>() == 2);
         let non_empty_span_count = spans.iter().filter(|x| x.len != 0).count();
@@ -58,10 +113,13 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
                 next_lamport,
                 next_op_id,
                 &mut ans,
-            );
+            )?;
+            self.emit_patches(&ans);
         }
 
-        self.range_map.insert(pos * 3 + 1, len * 3, |ann| {
+        #[cfg(feature = "debug-trace")]
+        let mut trace = Vec::new();
+        self.range_map.insert(tri_pos, tri_len, |ann| {
             // dbg!(&tombstones, first_new_op_id, ann, relative);
             let start_before_insert = match ann.range.start.id {
                 Some(id) => cmp(id) == Ordering::Less,
@@ -71,21 +129,93 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
                 Some(id) => cmp(id) == Ordering::Greater,
                 None => true,
             };
-            match (start_before_insert, end_after_insert) {
+            let position = match (start_before_insert, end_after_insert) {
                 (true, true) => AnnPosRelativeToInsert::IncludeInsert,
                 (true, false) => AnnPosRelativeToInsert::Before,
                 (false, true) => AnnPosRelativeToInsert::After,
                 (false, false) => unreachable!(),
-            }
+            };
+            #[cfg(feature = "debug-trace")]
+            trace.push(InsertDecision {
+                annotation: ann.id,
+                type_: ann.type_.clone(),
+                start_before_insert,
+                end_after_insert,
+                position,
+            });
+            position
         });
+        #[cfg(feature = "debug-trace")]
+        {
+            self.insert_trace = trace;
+        }
+
+        Ok(ans)
+    }
+
+    /// Listen to the ops generated implicitly, like the patches of the local
+    /// inserts next to the tombstones
+    pub fn observe(&mut self, listener: RangeListener) {
+        self.listeners.0.push(listener);
+    }
+
+    /// The patches in the ops returned by [`CrdtRange::insert_text`]
+    pub fn patches(ops: &[RangeOp]) -> impl Iterator<Item = &Patch> {
+        ops.iter().filter_map(|op| match op {
+            RangeOp::Patch(patch) => Some(patch),
+            RangeOp::Annotate(_) => None,
+        })
+    }
+
+    /// The merge decisions recorded since the last call, see
+    /// [`MergeRecord`](crate::merge_log::MergeRecord)
+    #[cfg(feature = "merge-log")]
+    pub fn take_merge_log(&mut self) -> Vec<crate::merge_log::MergeRecord> {
+        std::mem::take(&mut self.merge_log)
+    }
+
+    #[cfg(feature = "merge-log")]
+    fn log_patch(&mut self, patch: &Patch, is_local: bool, applied: bool) {
+        self.merge_log
+            .push(crate::merge_log::MergeRecord::PatchApplied {
+                patch: patch.id,
+                lamport: patch.lamport,
+                target: patch.target_range_id,
+                move_start_to: patch.move_start_to,
+                move_end_to: patch.move_end_to,
+                is_local,
+                applied,
+            });
+    }
+
+    fn emit_patches(&mut self, ops: &[RangeOp]) {
+        #[cfg(feature = "merge-log")]
+        for patch in Self::patches(ops) {
+            self.log_patch(patch, true, true);
+        }
+
+        if self.listeners.0.is_empty() {
+            return;
+        }
 
-        ans
+        for patch in Self::patches(ops) {
+            let event = RangeEvent::PatchEmitted(*patch);
+            for listener in self.listeners.0.iter_mut() {
+                listener(&event);
+            }
+        }
     }
 
-    fn get_trimmed_spans_around(&mut self, pos: usize) -> Vec<Span> {
+    /// The decisions made for the annotations around the last inserted text
+    #[cfg(feature = "debug-trace")]
+    pub fn last_insert_trace(&self) -> &[InsertDecision] {
+        &self.insert_trace
+    }
+
+    fn get_trimmed_spans_around(&mut self, tri_pos: usize) -> Vec<Span> {
         let mut spans: Vec<Span> = self
             .range_map
-            .get_annotations(pos * 3, 2)
+            .get_annotations(tri_pos, 2)
             .into_iter()
             .skip_while(|x| x.len == 0)
             .collect();
@@ -108,7 +238,7 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
         mut next_lamport: Lamport,
         mut next_op_id: OpID,
         ans: &mut Vec<RangeOp>,
-    ) {
+    ) -> Result<(), Error> {
         assert!(non_empty_span_count <= 2);
         let mut visited_left = false;
         let mut pure_left = BTreeSet::new();
@@ -159,7 +289,7 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
                         next_op_id,
                         None,
                         Some((1, right_id)),
-                    );
+                    )?;
                     next_op_id.counter += 1;
                     next_lamport += 1;
                 } else if !pure_middle.contains(&annotation) {
@@ -176,7 +306,7 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
                         next_op_id,
                         None,
                         Some((-1, left_id)),
-                    );
+                    )?;
                     next_op_id.counter += 1;
                     next_lamport += 1;
                 }
@@ -202,7 +332,7 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
                                 next_op_id,
                                 Some((1, right_id)),
                                 None,
-                            );
+                            )?;
                             next_op_id.counter += 1;
                             next_lamport += 1;
                         }
@@ -221,66 +351,80 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
                             next_op_id,
                             Some((-1, left_id)),
                             None,
-                        );
+                        )?;
                         next_op_id.counter += 1;
                         next_lamport += 1;
                     }
                 }
             }
         }
+
+        Ok(())
     }
 
     /// NOTE: This is error-prone, need more attention
-    fn apply_remote_patch<Index>(&mut self, patch: Patch, index: &Index)
+    fn apply_remote_patch<Index>(&mut self, patch: Patch, index: &Index) -> Result<(), Error>
     where
         Index: Fn(OpID) -> Result<usize, usize>,
     {
-        let Some((ann, pos)) = self.range_map.get_annotation_pos(patch.target_range_id) else { return };
+        let Some((ann, pos)) = self.range_map.get_annotation_pos(patch.target_range_id) else { return Ok(()) };
         let new_start = index_start(
             Anchor {
                 id: patch.move_start_to,
                 type_: ann.range.start.type_,
             },
             index,
-        );
+        )?;
         let new_end = index_end(
             Anchor {
                 id: patch.move_end_to,
                 type_: ann.range.end.type_,
             },
             index,
-        )
+        )?
         .unwrap_or(self.range_map.len());
 
+        #[cfg(feature = "merge-log")]
+        self.log_patch(
+            &patch,
+            false,
+            ann.range_lamport <= (patch.lamport, patch.id),
+        );
         self.range_map.adjust_annotation(
             patch.target_range_id,
             patch.lamport,
             patch.id,
-            Some((new_start as isize - pos.start as isize, patch.move_start_to)),
-            Some((new_end as isize - pos.end as isize, patch.move_end_to)),
-        );
+            Some((shift(pos.start, new_start)?, patch.move_start_to)),
+            Some((shift(pos.end, new_end)?, patch.move_end_to)),
+        )
     }
 
-    pub fn delete_text(&mut self, pos: usize, len: usize) {
-        self.range_map.delete(pos * 3 + 1, len * 3);
+    pub fn delete_text(&mut self, pos: usize, len: usize) -> Result<(), Error> {
+        self.range_map
+            .delete(tri_index(pos, 1)?, tri_index(len, 0)?);
+        Ok(())
     }
 
-    pub fn annotate(&mut self, annotation: Annotation, range: impl RangeBounds<usize>) -> RangeOp {
+    pub fn annotate(
+        &mut self,
+        annotation: Annotation,
+        range: impl RangeBounds<usize>,
+    ) -> Result<RangeOp, Error> {
         let start = match range.start_bound() {
-            Bound::Included(x) => *x * 3 + 2,
-            Bound::Excluded(x) => *x * 3 + 3,
+            Bound::Included(x) => tri_index(*x, 2)?,
+            Bound::Excluded(x) => tri_index(*x, 3)?,
             Bound::Unbounded => 0,
         };
         assert!(annotation.range.start.type_ != AnchorType::After);
         assert!(annotation.range.start.id.is_some());
         let end = match range.end_bound() {
-            Bound::Included(x) => *x * 3 + 3,
-            Bound::Excluded(x) => *x * 3 + 2,
+            Bound::Included(x) => tri_index(*x, 3)?,
+            Bound::Excluded(x) => tri_index(*x, 2)?,
             Bound::Unbounded => self.range_map.len(),
         };
         self.range_map
             .annotate(start, end - start, annotation.clone());
-        RangeOp::Annotate(annotation)
+        Ok(RangeOp::Annotate(annotation))
     }
 
     pub fn delete_annotation(&mut self, lamport: Lamport, op_id: OpID, target_id: OpID) -> RangeOp {
@@ -294,20 +438,22 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
         })
     }
 
-    pub fn apply_remote_op<Index>(&mut self, op: RangeOp, index: &Index)
+    pub fn apply_remote_op<Index>(&mut self, op: RangeOp, index: &Index) -> Result<(), Error>
     where
         Index: Fn(OpID) -> Result<usize, usize>,
     {
         match op {
             RangeOp::Patch(patch) => {
-                self.apply_remote_patch(patch, index);
+                self.apply_remote_patch(patch, index)?;
             }
             RangeOp::Annotate(a) => {
-                let start = index_start(a.range.start, index);
-                let end = index_end(a.range.end, index).unwrap_or(self.range_map.len());
+                let start = index_start(a.range.start, index)?;
+                let end = index_end(a.range.end, index)?.unwrap_or(self.range_map.len());
                 self.range_map.annotate(start, end - start, a)
             }
         }
+
+        Ok(())
     }
 
     pub fn get_annotation_range(&mut self, id: OpID) -> Option<Range<usize>> {
@@ -315,15 +461,15 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
         Some((range.start / 3)..(range.end / 3))
     }
 
-    pub fn get_annotations(&mut self, range: impl RangeBounds<usize>) -> Vec<Span> {
+    pub fn get_annotations(&mut self, range: impl RangeBounds<usize>) -> Result<Vec<Span>, Error> {
         let start = match range.start_bound() {
-            std::ops::Bound::Included(x) => x * 3 + 2,
+            std::ops::Bound::Included(x) => tri_index(*x, 2)?,
             std::ops::Bound::Excluded(_) => unreachable!(),
             std::ops::Bound::Unbounded => 2,
         };
         let end = match range.end_bound() {
-            std::ops::Bound::Included(x) => x * 3 + 3,
-            std::ops::Bound::Excluded(x) => x * 3,
+            std::ops::Bound::Included(x) => tri_index(*x, 3)?,
+            std::ops::Bound::Excluded(x) => tri_index(*x, 0)?,
             std::ops::Bound::Unbounded => self.range_map.len(),
         };
 
@@ -357,7 +503,7 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
             last_index = next_index;
         }
 
-        ans
+        Ok(ans)
     }
 
     pub fn len(&self) -> usize {
@@ -369,40 +515,63 @@ impl<R: RangeMap + Debug> CrdtRange<R> {
     }
 }
 
-fn index_start<Index>(start: Anchor, index: &Index) -> usize
+/// Convert the text index to the index in the range map, which has three
+/// positions for every character.
+///
+/// The annotations are shifted by `isize` in the range map, so the result
+/// should also fit in `isize`. It matters on 32-bit targets like wasm32.
+pub(crate) fn tri_index(index: usize, offset: usize) -> Result<usize, Error> {
+    index
+        .checked_mul(3)
+        .and_then(|x| x.checked_add(offset))
+        .filter(|&x| x <= isize::MAX as usize)
+        .ok_or(Error::IndexOverflow)
+}
+
+/// The shift from `from` to `to` in the range map
+fn shift(from: usize, to: usize) -> Result<isize, Error> {
+    // both of them are not greater than isize::MAX, so the subtraction cannot overflow
+    let from = isize::try_from(from).map_err(|_| Error::IndexOverflow)?;
+    let to = isize::try_from(to).map_err(|_| Error::IndexOverflow)?;
+    Ok(to - from)
+}
+
+fn index_start<Index>(start: Anchor, index: &Index) -> Result<usize, Error>
 where
     Index: Fn(OpID) -> Result<usize, usize>,
 {
-    start
-        .id
-        .map(|x| match index(x) {
+    match start.id {
+        Some(x) => match index(x) {
             Ok(x) => {
                 if start.type_ == AnchorType::Before {
-                    x * 3 + 2
+                    tri_index(x, 2)
                 } else {
-                    x * 3 + 3
+                    tri_index(x, 3)
                 }
             }
-            Err(x) => x * 3 + 1,
-        })
-        .unwrap_or(0)
+            Err(x) => tri_index(x, 1),
+        },
+        None => Ok(0),
+    }
 }
 
-fn index_end<Index>(end: Anchor, index: &Index) -> Option<usize>
+fn index_end<Index>(end: Anchor, index: &Index) -> Result<Option<usize>, Error>
 where
     Index: Fn(OpID) -> Result<usize, usize>,
 {
-    end.id.map(|x| match index(x) {
-        Ok(x) => {
-            if end.type_ == AnchorType::Before {
-                x * 3 + 2
-            } else {
-                x * 3 + 3
+    end.id
+        .map(|x| match index(x) {
+            Ok(x) => {
+                if end.type_ == AnchorType::Before {
+                    tri_index(x, 2)
+                } else {
+                    tri_index(x, 3)
+                }
             }
-        }
 
-        Err(x) => x * 3 + 1,
-    })
+            Err(x) => tri_index(x, 1),
+        })
+        .transpose()
 }
 
 impl<R: RangeMap + Debug> Default for CrdtRange<R> {
@@ -1,7 +1,7 @@
 use std::{collections::BTreeSet, ops::Range, sync::Arc};
 pub mod tree_impl;
 
-use crate::{Annotation, Lamport, OpID};
+use crate::{Annotation, Error, Lamport, OpID};
 
 pub trait RangeMap {
     fn init() -> Self;
@@ -15,6 +15,9 @@ pub trait RangeMap {
     fn delete(&mut self, pos: usize, len: usize);
     fn annotate(&mut self, pos: usize, len: usize, annotation: Annotation);
     /// should keep the shrink annotations around even if they are deleted completely
+    ///
+    /// Returns [Error::IndexOverflow] without changing the annotation if the
+    /// shifted position does not fit in `usize`
     fn adjust_annotation(
         &mut self,
         target_id: OpID,
@@ -22,7 +25,7 @@ pub trait RangeMap {
         patch_id: OpID,
         start_shift: Option<(isize, Option<OpID>)>,
         end_shift: Option<(isize, Option<OpID>)>,
-    );
+    ) -> Result<(), Error>;
     fn delete_annotation(&mut self, id: OpID);
     /// TODO: need to clarify the rules when encounter an empty span on the edges
     fn get_annotations(&mut self, pos: usize, len: usize) -> Vec<Span>;
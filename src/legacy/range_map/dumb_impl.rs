@@ -523,7 +523,7 @@ impl RangeMap for DumbRangeMap {
         patch_id: OpID,
         start: Option<(isize, Option<OpID>)>,
         end: Option<(isize, Option<OpID>)>,
-    ) {
+    ) -> Result<(), Error> {
         self.check();
         let (ann, pos) = self.get_annotation_pos(id).unwrap();
         let mut end_pos = pos.end as isize;
@@ -555,6 +555,7 @@ impl RangeMap for DumbRangeMap {
         }
 
         self.check();
+        Ok(())
     }
 }
 
@@ -668,7 +669,9 @@ mod test {
         let mut range_map = DumbRangeMap::init();
         range_map.insert_directly(0, 10);
         range_map.annotate(2, 2, a(1));
-        range_map.adjust_annotation(id(1), 2, id(1), None, Some((2, None)));
+        range_map
+            .adjust_annotation(id(1), 2, id(1), None, Some((2, None)))
+            .unwrap();
         let spans = range_map.get_annotations(0, 10);
         assert_eq!(
             from_spans(&spans),
@@ -683,7 +686,9 @@ mod test {
             (vec![(vec![], 2), (vec![1, 2], 4), (vec![2], 1), (vec![], 3)])
         );
 
-        range_map.adjust_annotation(id(1), 3, id(1), None, Some((2, None)));
+        range_map
+            .adjust_annotation(id(1), 3, id(1), None, Some((2, None)))
+            .unwrap();
         let spans = range_map.get_annotations(0, 10);
         assert_eq!(
             from_spans(&spans),
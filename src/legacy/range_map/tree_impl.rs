@@ -11,7 +11,7 @@ use std::{
 };
 
 use crate::{
-    legacy::range_map::AnnPosRelativeToInsert, small_set::SmallSetI32, Annotation, Counter,
+    legacy::range_map::AnnPosRelativeToInsert, small_set::SmallSetI32, Annotation, Counter, Error,
     InternalString, OpID,
 };
 use fxhash::{FxHashMap, FxHashSet};
@@ -202,6 +202,8 @@ impl Elem {
 
     fn apply_diff(&mut self, diff: &CacheDiff) {
         self.anchor_set.apply_diff(diff);
+        // The lengths are bounded by the tri-index checks in `CrdtRange`,
+        // so the diff always fits
         self.len = (self.len as isize + diff.len_diff) as usize;
     }
 }
@@ -997,34 +999,38 @@ impl RangeMap for TreeRangeMap {
         patch_id: OpID,
         start_shift: Option<(isize, Option<OpID>)>,
         end_shift: Option<(isize, Option<OpID>)>,
-    ) {
+    ) -> Result<(), Error> {
         self.check();
         debug_log::group!("AdjustAnnotation {:?}", target_id);
         if let Some(ann) = self.id_to_ann(target_id) {
             // skip update if the current lamport is larger
             if ann.range_lamport > (lamport, patch_id) {
-                return;
+                return Ok(());
             }
             ann
         } else {
-            return;
+            return Ok(());
         };
         let idx = self.get_ann_idx(target_id).unwrap();
-        let Some(( range, index_range )) = self.get_annotation_range(target_id) else { return };
-        let (start, end) = range.into_inner();
-        self.insert_or_delete_ann(&start..&end, idx, false);
-
-        let new_start = if let Some((index_shift, _)) = start_shift {
-            (index_range.start as isize + index_shift) as usize
-        } else {
-            index_range.start
+        let Some(( range, index_range )) = self.get_annotation_range(target_id) else { return Ok(()) };
+        let new_start = match start_shift {
+            Some((index_shift, _)) => index_range
+                .start
+                .checked_add_signed(index_shift)
+                .ok_or(Error::IndexOverflow)?,
+            None => index_range.start,
         };
-        let new_end = if let Some((index_shift, _)) = end_shift {
-            (index_range.end as isize + index_shift) as usize
-        } else {
-            index_range.end
+        let new_end = match end_shift {
+            Some((index_shift, _)) => index_range
+                .end
+                .checked_add_signed(index_shift)
+                .ok_or(Error::IndexOverflow)?,
+            None => index_range.end,
         };
 
+        let (start, end) = range.into_inner();
+        self.insert_or_delete_ann(&start..&end, idx, false);
+
         self.log_inner();
         assert!(self.get_annotation_range(target_id).is_none());
         debug_log::debug_log!("Insert new range");
@@ -1047,6 +1053,7 @@ impl RangeMap for TreeRangeMap {
         *ann = Arc::new(new_ann);
         self.check();
         debug_log::group_end!();
+        Ok(())
     }
 
     fn delete_annotation(&mut self, id: OpID) {
@@ -1892,7 +1899,8 @@ mod tree_impl_tests {
             tree.insert(0, 100, |_| AnnPosRelativeToInsert::After);
             tree.annotate(1, 9, a(0));
             // expand end
-            tree.adjust_annotation(id(0), 1, id(1), None, Some((1, Some(id(0)))));
+            tree.adjust_annotation(id(0), 1, id(1), None, Some((1, Some(id(0)))))
+                .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(
                 ans,
@@ -1900,7 +1908,8 @@ mod tree_impl_tests {
             );
 
             // expand start
-            tree.adjust_annotation(id(0), 1, id(1), Some((-1, Some(id(0)))), None);
+            tree.adjust_annotation(id(0), 1, id(1), Some((-1, Some(id(0)))), None)
+                .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(ans, make_spans(vec![(vec![0], 11), (vec![], 89)]));
         }
@@ -1910,7 +1919,8 @@ mod tree_impl_tests {
             let mut tree = TreeRangeMap::new();
             tree.insert(0, 100, |_| AnnPosRelativeToInsert::After);
             tree.annotate(0, 10, a(0));
-            tree.adjust_annotation(id(0), 1, id(1), None, Some((1, Some(id(4)))));
+            tree.adjust_annotation(id(0), 1, id(1), None, Some((1, Some(id(4)))))
+                .unwrap();
             let span = tree.get_annotations(2, 1)[0].clone();
             let ann = span.annotations.into_iter().next().unwrap();
             assert_eq!(ann.range.end.id, Some(id(4)));
@@ -1922,12 +1932,14 @@ mod tree_impl_tests {
             tree.insert(0, 100, |_| AnnPosRelativeToInsert::After);
             tree.annotate(0, 10, a(0));
             // shrink end
-            tree.adjust_annotation(id(0), 1, id(1), None, Some((-1, Some(id(0)))));
+            tree.adjust_annotation(id(0), 1, id(1), None, Some((-1, Some(id(0)))))
+                .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(ans, make_spans(vec![(vec![0], 9), (vec![], 91)]));
 
             // shrink start
-            tree.adjust_annotation(id(0), 1, id(1), Some((1, Some(id(0)))), None);
+            tree.adjust_annotation(id(0), 1, id(1), Some((1, Some(id(0)))), None)
+                .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(
                 ans,
@@ -1942,7 +1954,8 @@ mod tree_impl_tests {
             tree.annotate(10, 10, a(0));
             tree.delete(10, 10);
             tree.annotate(9, 1, a(1));
-            tree.adjust_annotation(id(1), 1, id(2), None, Some((2, Some(id(2)))));
+            tree.adjust_annotation(id(1), 1, id(2), None, Some((2, Some(id(2)))))
+                .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(
                 ans,
@@ -1967,7 +1980,8 @@ mod tree_impl_tests {
                 ans,
                 make_spans(vec![(vec![], 10), (vec![0], 0), (vec![], 80)]),
             );
-            tree.adjust_annotation(id(0), 1, id(3), None, Some((2, Some(id(3)))));
+            tree.adjust_annotation(id(0), 1, id(3), None, Some((2, Some(id(3)))))
+                .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(
                 ans,
@@ -1975,6 +1989,22 @@ mod tree_impl_tests {
             );
         }
 
+        #[test]
+        fn overflowing_shift_keeps_the_range() {
+            let mut tree = TreeRangeMap::new();
+            tree.insert(0, 100, |_| AnnPosRelativeToInsert::After);
+            tree.annotate(10, 10, a(0));
+            assert!(matches!(
+                tree.adjust_annotation(id(0), 1, id(1), Some((isize::MIN, Some(id(0)))), None),
+                Err(Error::IndexOverflow)
+            ));
+            let ans = tree.get_annotations(0, 100);
+            assert_span_eq(
+                ans,
+                make_spans(vec![(vec![], 10), (vec![0], 10), (vec![], 80)]),
+            );
+        }
+
         #[test]
         fn should_ignore_adjustment_if_lamport_is_too_small() {
             let mut tree = TreeRangeMap::new();
@@ -1987,7 +2017,8 @@ mod tree_impl_tests {
                 id(3),
                 Some((0, Some(id(1)))),
                 Some((0, Some(id(3)))),
-            );
+            )
+            .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(
                 ans,
@@ -2001,7 +2032,8 @@ mod tree_impl_tests {
                 id(3),
                 Some((-2, Some(id(1)))),
                 Some((10, Some(id(3)))),
-            );
+            )
+            .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(
                 ans,
@@ -2015,7 +2047,8 @@ mod tree_impl_tests {
                 id(3),
                 Some((-2, Some(id(1)))),
                 Some((10, Some(id(3)))),
-            );
+            )
+            .unwrap();
             let ans = tree.get_annotations(0, 100);
             assert_span_eq(
                 ans,
@@ -1,5 +1,5 @@
 #![allow(unsafe_code)]
-use std::time::Instant;
+use std::{cell::RefCell, rc::Rc, time::Instant};
 
 use crate::{legacy::test_utils::Actor, test_utils::make_spans};
 
@@ -276,6 +276,59 @@ fn weird_link() {
     assert_eq!(a.get_annotations(..), b.get_annotations(..));
 }
 
+#[test]
+fn patch_emitted_near_tombstones() {
+    let mut a = Actor::new(0);
+    let patches: Rc<RefCell<Vec<Patch>>> = Default::default();
+    let events = patches.clone();
+    a.observe_range(Box::new(move |event| match event {
+        RangeEvent::PatchEmitted(patch) => events.borrow_mut().push(*patch),
+    }));
+    a.insert(0, 10);
+    a.annotate(3..=6, "link");
+    assert!(patches.borrow().is_empty());
+    a.delete(3, 3);
+    // 012x<6>789
+    a.insert(3, 1);
+    assert!(!patches.borrow().is_empty());
+    let mut b = Actor::new(1);
+    b.merge(&a);
+    assert_eq!(a.get_annotations(..), b.get_annotations(..));
+}
+
+#[cfg(feature = "debug-trace")]
+#[test]
+fn insert_trace_near_tombstones() {
+    let mut a = Actor::new(0);
+    a.insert(0, 10);
+    a.annotate(3..=6, "link");
+    a.delete(3, 3);
+    // 012x<6>789
+    a.insert(3, 1);
+    let trace = a.last_insert_trace();
+    assert!(trace.iter().any(|x| x.type_ == "link".into()
+        && !x.start_before_insert
+        && x.position == AnnPosRelativeToInsert::After));
+}
+
+#[test]
+fn tri_index_near_boundary() {
+    let max = isize::MAX as usize;
+    assert_eq!(tri_index(max / 3 - 1, 3).unwrap(), (max / 3 - 1) * 3 + 3);
+    assert!(tri_index(max / 3, 3).is_err());
+    assert!(tri_index(max / 3 + 1, 0).is_err());
+    assert!(tri_index(usize::MAX / 3 + 1, 0).is_err());
+    assert!(tri_index(usize::MAX, 1).is_err());
+
+    let mut range: CrdtRange<TreeRangeMap> = CrdtRange::new();
+    assert!(matches!(
+        range.delete_text(max / 3, 1),
+        Err(Error::IndexOverflow)
+    ));
+    assert!(range.get_annotations(..=usize::MAX / 2).is_err());
+    assert!(range.get_annotations(..).unwrap().len() <= 1);
+}
+
 #[cfg(test)]
 mod failed_tests {
     use crate::{
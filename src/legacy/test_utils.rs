@@ -280,6 +280,15 @@ impl Actor {
         debug_log::group_end!();
     }
 
+    pub fn observe_range(&mut self, listener: RangeListener) {
+        self.range.observe(listener);
+    }
+
+    #[cfg(feature = "debug-trace")]
+    pub fn last_insert_trace(&self) -> &[InsertDecision] {
+        self.range.last_insert_trace()
+    }
+
     /// this should happen after the op is integrated to the list crdt
     fn _range_insert(&mut self, len: usize, first_op: &Op, arr_pos: usize, is_local: bool) {
         let right = {
@@ -345,23 +354,26 @@ impl Actor {
         }
 
         assert_eq!(i, arr_pos);
-        let range_ops = self.range.insert_text(
-            text_pos,
-            len,
-            is_local,
-            left,
-            right,
-            self.next_lamport,
-            self.next_id(),
-            |a| {
-                // this can be O(lgN) when using a proper data structure
-                if left_set.contains(&a) {
-                    Ordering::Less
-                } else {
-                    Ordering::Greater
-                }
-            },
-        );
+        let range_ops = self
+            .range
+            .insert_text(
+                text_pos,
+                len,
+                is_local,
+                left,
+                right,
+                self.next_lamport,
+                self.next_id(),
+                |a| {
+                    // this can be O(lgN) when using a proper data structure
+                    if left_set.contains(&a) {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    }
+                },
+            )
+            .unwrap();
 
         if !range_ops.is_empty() {
             debug_log::debug_log!("range_ops: {:#?}", range_ops);
@@ -381,7 +393,7 @@ impl Actor {
         YataImpl::integrate_delete_op(&mut self.list, op.clone());
         self.deleted.extend(op.into_iter());
         self.next_lamport += len as Lamport;
-        self.range.delete_text(pos, len);
+        self.range.delete_text(pos, len).unwrap();
     }
 
     #[inline(always)]
@@ -430,7 +442,8 @@ impl Actor {
             value: Value::Null,
         };
         debug_log::debug_dbg!(&ann);
-        self.range_ops.push(self.range.annotate(ann, range));
+        self.range_ops
+            .push(self.range.annotate(ann, range).unwrap());
     }
 
     pub(crate) fn get_annotations(&mut self, range: impl RangeBounds<usize>) -> Vec<SimpleSpan> {
@@ -438,6 +451,7 @@ impl Actor {
         for span in self
             .range
             .get_annotations(range)
+            .unwrap()
             .iter()
             .map(|x| -> SimpleSpan { x.into() })
         {
@@ -566,7 +580,7 @@ impl Actor {
                 }
             }
             for (index, len) in deleted_text.iter().rev() {
-                self.range.delete_text(*index, *len);
+                self.range.delete_text(*index, *len).unwrap();
             }
         }
 
@@ -584,7 +598,8 @@ impl Actor {
             if !self.visited.contains(&op.id()) {
                 debug_log::group!("apply {:?}", &op);
                 self.range
-                    .apply_remote_op(op.clone(), &|x| index(&self.list, x).0);
+                    .apply_remote_op(op.clone(), &|x| index(&self.list, x).0)
+                    .unwrap();
                 self.range_ops.push(op.clone());
                 self.visited.insert(op.id());
                 debug_log::group_end!();
@@ -687,8 +702,8 @@ impl Actor {
         assert_eq!(self.len(), other.len());
         assert_eq!(self.list.content, other.list.content);
         assert_eq!(
-            self.range.get_annotations(..),
-            other.range.get_annotations(..)
+            self.range.get_annotations(..).unwrap(),
+            other.range.get_annotations(..).unwrap()
         );
         assert_eq!(self.deleted, other.deleted);
     }
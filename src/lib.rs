@@ -16,15 +16,24 @@ use std::{
 use rich_text::Error;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use string_cache::DefaultAtom;
 
+#[cfg(feature = "c-abi")]
+pub mod c_abi;
+#[cfg(not(feature = "string_cache"))]
+mod internal_string;
 pub mod legacy;
+#[cfg(feature = "merge-log")]
+pub mod merge_log;
 pub mod rich_text;
 pub use rich_text::{vv::VersionVector, RichText};
 mod small_set;
+pub mod styles;
 #[cfg(feature = "test")]
 mod test_utils;
-pub(crate) type InternalString = DefaultAtom;
+#[cfg(feature = "string_cache")]
+pub(crate) type InternalString = string_cache::DefaultAtom;
+#[cfg(not(feature = "string_cache"))]
+pub(crate) use internal_string::InternalString;
 type Lamport = u32;
 type ClientID = u64;
 type Counter = u32;
@@ -132,6 +141,14 @@ pub struct Annotation {
     pub value: Value,
 }
 
+impl Annotation {
+    /// Whether the text inserted at the edges of the range joins the
+    /// annotation, decided by the anchors of the range
+    pub fn expand(&self) -> Expand {
+        Expand::from_anchor_types(self.range.start.type_, self.range.end.type_)
+    }
+}
+
 impl PartialOrd for Annotation {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         match self.id.partial_cmp(&other.id) {
@@ -154,7 +171,7 @@ impl Ord for Annotation {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Expand {
     None,
     Before,
@@ -218,6 +235,16 @@ impl Expand {
         Self::infer_insert_expand(type_).toggle()
     }
 
+    /// The inverse of [`Expand::start_type`] and [`Expand::end_type`]
+    pub fn from_anchor_types(start: AnchorType, end: AnchorType) -> Self {
+        match (start, end) {
+            (AnchorType::Before, AnchorType::After) => Self::None,
+            (AnchorType::After, AnchorType::After) => Self::Before,
+            (AnchorType::Before, AnchorType::Before) => Self::After,
+            (AnchorType::After, AnchorType::Before) => Self::Both,
+        }
+    }
+
     /// For a target format, the Expand type of insertion is different
     /// from the Expand type of deletion. This method will convert one
     // to another.
@@ -0,0 +1,62 @@
+//! Machine-readable records of the merge decisions, enabled by the
+//! `merge-log` feature.
+//!
+//! They answer questions like "why did my bold disappear" after the fact: the
+//! records describe the inputs of a decision and its outcome, and they can be
+//! serialized to JSON and attached to an issue. See
+//! [`RichText::take_merge_log`](crate::RichText::take_merge_log) and
+//! [`CrdtRange::take_merge_log`](crate::legacy::CrdtRange::take_merge_log).
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Annotation, Behavior, Lamport, OpID};
+
+/// A merge decision
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MergeRecord {
+    /// Two concurrent annotations of the same type overlap, and the one with
+    /// the higher lamport wins in the overlapped range
+    AnnotationConflict {
+        type_: String,
+        discarded: AnnotationSummary,
+        winner: AnnotationSummary,
+        /// The overlapped range in utf8 when the conflict is detected
+        overlap: (usize, usize),
+    },
+    /// A patch moved the anchors of an annotation in the legacy range CRDT
+    PatchApplied {
+        patch: OpID,
+        lamport: Lamport,
+        target: OpID,
+        move_start_to: Option<OpID>,
+        move_end_to: Option<OpID>,
+        /// Whether the patch was generated by a local insert next to the
+        /// tombstones
+        is_local: bool,
+        /// False if the target has already been moved by a patch with a
+        /// higher lamport, so this patch is ignored
+        applied: bool,
+    },
+}
+
+/// The fields of an annotation that decide the merges
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationSummary {
+    pub id: OpID,
+    pub lamport: Lamport,
+    pub behavior: Behavior,
+    pub value: Value,
+}
+
+impl From<&Annotation> for AnnotationSummary {
+    fn from(ann: &Annotation) -> Self {
+        Self {
+            id: ann.id,
+            lamport: ann.range_lamport.0,
+            behavior: ann.behavior,
+            value: ann.value.clone(),
+        }
+    }
+}
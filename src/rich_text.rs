@@ -1,16 +1,20 @@
 use std::{
+    borrow::Cow,
     cmp::Ordering,
+    collections::VecDeque,
     fmt::Display,
-    ops::{Bound, RangeBounds},
+    hash::Hasher,
+    ops::{Bound, Range, RangeBounds},
     sync::Arc,
+    time::Duration,
 };
 
 use append_only_bytes::AppendOnlyBytes;
 
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
 use generic_btree::{
     rle::{HasLength, Mergeable, Sliceable},
-    BTree, MoveEvent, QueryResult,
+    BTree, BTreeTrait, MoveEvent, QueryResult,
 };
 use serde_json::Value;
 use smallvec::SmallVec;
@@ -21,51 +25,145 @@ use crate::{
         op::OpContent,
         rich_tree::utf16::{bytes_to_str, get_utf16_len_and_line_breaks, Utf16LenAndLineBreaks},
     },
+    styles::type_matches,
     Anchor, AnchorType, Annotation, Behavior, ClientID, Counter, Expand, IdSpan, InternalString,
-    OpID, Style,
+    Lamport, OpID, Style,
 };
 
 use self::{
     ann::{insert_anchor_to_char, AnchorSetDiff, AnnIdx, AnnManager, StyleCalculator},
+    cold_text::ColdText,
+    crypto::Cipher,
     cursor::CursorMap,
-    delta::compose,
-    encoding::{decode, encode},
+    delta::{compose, intern_attributes, unwrap_attributes},
+    derived::apply_derived,
+    encoding::{decode_updates, encode_updates, encode_updates_as},
     op::{Op, OpStore},
     rich_tree::{
         query::{IndexFinder, IndexFinderWithStyles, LineStartFinder},
         rich_tree_btree_impl::RichTreeTrait,
-        utf16::{get_utf16_len, utf16_to_utf8},
-        CacheDiff, Elem,
+        utf16::get_utf16_len,
+        CacheDiff, Elem, WordStats,
     },
     vv::VersionVector,
 };
 
-pub use ann::Span;
-pub use delta::DeltaItem;
-pub use error::Error;
+pub use ann::{Mark, MarkedSpan, Span, ValueMode};
+pub use delta::{diff_spans, Attributes, DeltaItem};
+pub use derived::Deriver;
+pub use diagnostics::{Diagnostic, Diagnostics};
+pub use encoding::FormatVersion;
+pub use error::{Error, LimitKind};
 pub use event::Event;
+pub use json::{DocumentJson, DocumentMeta, Run};
+pub use list_numbering::ListNumbering;
+pub use outline::{Heading, Section, HEADER_KEY};
 pub use rich_tree::query::IndexType;
+pub use serialized_spans::{SerializedSpans, SPANS_SCHEMA_VERSION};
+pub use snapshot::Snapshot;
+pub use source_map::{SourceMap, SourceSegment};
+pub use transaction::{TransactOptions, Transaction};
 
+pub mod admission;
+pub mod anchor;
 mod ann;
+pub mod chunk_store;
+mod cold_text;
+#[cfg(feature = "async")]
+mod cooperative;
+pub mod crypto;
 mod cursor;
 mod delta;
+mod derived;
+mod diagnostics;
+mod diff;
+pub mod divergence;
 mod encoding;
 mod error;
 mod event;
+mod hash;
 mod id_map;
 mod iter;
+mod json;
+mod list_numbering;
 mod op;
+pub mod ops;
+mod outline;
 mod rich_tree;
+mod serialized_spans;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+mod snapshot;
+mod source_map;
+pub mod storage;
 #[cfg(all(test, feature = "test"))]
 mod test;
 #[cfg(feature = "test")]
 pub mod test_utils;
+mod transaction;
 pub mod vv;
 
 type Listener = Box<dyn FnMut(&Event)>;
+/// Check whether the value is valid for the annotation type
+pub type Validator = Box<dyn Fn(&Value) -> bool>;
+/// Rewrite the text inserted locally, e.g. for smart quotes or emoji
+/// shortcodes. It gets the char before the insertion and the inserted text,
+/// and returns `None` to keep the text.
+pub type TextTransform = Box<dyn Fn(Option<char>, &str) -> Option<String>>;
+/// Merge the custom ops of a kind received from the peers, see
+/// [`RichText::register_op_kind`]
+pub type OpKindHandler = Box<dyn FnMut(&CustomOp)>;
+type ConflictListener = Box<dyn FnMut(&AnnotationConflict)>;
+
+/// The char that stands for an embed in the text, e.g. an image or a mention,
+/// see [`RichText::insert_embed`]
+pub const EMBED_CHAR: char = '\u{FFFC}';
+/// The annotation type of the values of the embeds
+pub const EMBED_TYPE: &str = "embed";
+/// The kind of the custom ops that prune the orphaned annotations, see
+/// [`RichText::prune_orphaned_annotations`]
+pub const PRUNE_ANNOTATIONS_KIND: &str = "crdt-richtext/prune-annotations";
+
+/// An op of a kind defined by an extension, e.g. a reaction attached to a
+/// range, see [`RichText::insert_custom_op`].
+///
+/// The payload is opaque to the document. The ops take an op id and a lamport
+/// like the text ops, so they are synced, ordered and covered by the version
+/// vectors the same way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomOp {
+    pub id: OpID,
+    pub lamport: Lamport,
+    pub kind: InternalString,
+    pub payload: Arc<[u8]>,
+}
+
+/// A listener that only gets the events touching its range, see
+/// [`RichText::observe_range`]
+struct RangeListener {
+    /// The last byte of the char before the range, `None` for the document start
+    start: Option<OpID>,
+    /// The first byte of the char after the range, `None` for the document end
+    end: Option<OpID>,
+    listener: Listener,
+}
+
+#[derive(Default)]
+struct Listeners {
+    all: Vec<Listener>,
+    ranged: Vec<RangeListener>,
+}
+
+impl Listeners {
+    fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.ranged.is_empty()
+    }
+}
 
 pub struct RichText {
     bytes: AppendOnlyBytes,
+    /// The compressed text of the tombstones
+    cold: ColdText,
     content: BTree<RichTreeTrait>,
     cursor_map: CursorMap,
     store: OpStore,
@@ -74,8 +172,331 @@ pub struct RichText {
     /// this is the styles starting from the very beginning,
     /// which have start anchor of None
     init_styles: StyleCalculator,
-    listeners: Vec<Listener>,
+    listeners: Listeners,
     event_index_type: IndexType,
+    event_ann_ids: bool,
+    compaction: Option<Compaction>,
+    defragment: Defragment,
+    readonly: bool,
+    validators: FxHashMap<InternalString, Validator>,
+    value_modes: FxHashMap<InternalString, ValueMode>,
+    /// The remote annotations that failed the validation
+    invalid_annotations: Vec<OpID>,
+    /// The remote annotations whose anchors refer to the text that has not arrived
+    unresolved_anns: Vec<Arc<Annotation>>,
+    /// The annotations dropped by [`RichText::prune_orphaned_annotations`]
+    pruned_anns: FxHashSet<OpID>,
+    doc_id: Option<u128>,
+    doc_id_check: DocIdCheck,
+    cipher: Option<Box<dyn Cipher>>,
+    conflict_listener: Option<ConflictListener>,
+    conflict_count: usize,
+    #[cfg(feature = "merge-log")]
+    merge_log: Vec<crate::merge_log::MergeRecord>,
+    derived: Vec<(InternalString, Deriver)>,
+    transforms: Vec<(InternalString, TextTransform)>,
+    op_kinds: FxHashMap<InternalString, OpKindHandler>,
+    limits: Limits,
+    /// The version of the parent when the document was branched from it
+    branch_base: Option<VersionVector>,
+    /// The events of the ongoing transaction, which are emitted when it commits
+    staged_events: Option<Vec<Event>>,
+}
+
+/// A remote annotation is overridden by an annotation of the same type with a
+/// higher lamport where their ranges overlap.
+///
+/// The remote annotation arrives after the winner but has a lower lamport, so
+/// its author had not seen the winner, i.e. they are concurrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnotationConflict {
+    pub discarded: Arc<Annotation>,
+    pub winner: Arc<Annotation>,
+}
+
+/// How [`RichText::import`] checks the document id embedded in the updates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DocIdCheck {
+    /// Reject the updates whose id is different from the id of the document.
+    /// The updates without an id are accepted, and so are all the updates if
+    /// the document has no id.
+    #[default]
+    Lenient,
+    /// Reject the updates whose id is not the same as the id of the document,
+    /// including the updates without an id when the document has one and the
+    /// other way around.
+    Strict,
+}
+
+/// The hard limits of a document, see [`RichText::set_limits`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The max length of the text in utf8 bytes
+    pub max_len: Option<usize>,
+    /// The max number of the annotations, including the ones that erase styles
+    pub max_annotations: Option<usize>,
+    pub policy: LimitPolicy,
+}
+
+/// What the local edits do when they exceed the [`Limits`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Ignore the edit
+    #[default]
+    Reject,
+    /// Insert the prefix of the text that fits into the max length. The
+    /// annotations are still ignored.
+    Clamp,
+}
+
+/// The format of [`RichText::write_to`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// The plain text
+    Text,
+    /// The spans in JSON, the same as serializing [`RichText::get_spans`]
+    Spans,
+    /// The updates of all the ops, the same as [`RichText::export`] from
+    /// the empty version
+    Snapshot,
+}
+
+/// The state of a style in a range, see [`RichText::style_state_at`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum StyleState {
+    /// No text in the range has the style
+    Unset,
+    /// The whole range has the style set by the same annotation
+    Set { id: OpID, value: Value },
+    /// Only a part of the range has the style, or the parts have it set by
+    /// different annotations
+    Mixed,
+}
+
+/// How much of the selection has a style, see [`RichText::selection_styles`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StyleCoverage {
+    /// Every char of the selection has the style
+    All,
+    /// Only a part of the selection has the style
+    Partial,
+}
+
+/// The summary of a style in the selection, see [`RichText::selection_styles`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StyleAggregate {
+    pub coverage: StyleCoverage,
+    /// The value that covers the most chars of the selection
+    pub value: Value,
+    /// The length of the text that has the style, in the index type of the query
+    pub len: usize,
+}
+
+/// Where an op is in the current document, see [`RichText::locate_op`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpLocation {
+    /// The char inserted by the op is at the index
+    Visible(usize),
+    /// The char inserted by the op is deleted. The index is where it was.
+    Deleted(usize),
+    /// The op is an annotation on the range
+    Annotation(Range<usize>),
+}
+
+/// A change of an annotation, see [`RichText::annotation_history`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchRecord {
+    /// The op that made the change
+    pub id: OpID,
+    pub lamport: u32,
+    pub kind: PatchKind,
+}
+
+impl PatchRecord {
+    /// The client that made the change
+    pub fn author(&self) -> ClientID {
+        self.id.client
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchKind {
+    /// The annotation is created
+    Created,
+    /// A part of the annotation is erased by a later annotation that erases
+    /// the style
+    Erased,
+    /// A part of the annotation is overridden by a later annotation of the
+    /// same type, e.g. the url of a link is changed
+    Overridden,
+}
+
+/// The options of [`RichText::apply_delta_with_options`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApplyDeltaOptions {
+    /// Whether the inserted text keeps the styles it inherits from the text
+    /// around it by the expand rules, e.g. when it's pasted into the middle of
+    /// a bold run.
+    ///
+    /// By default, the inserted text only has the attributes of the delta, like
+    /// in Quill, and the inherited styles are erased by the annotations that
+    /// cover only the inserted text. With it, only the attributes of the delta
+    /// that differ from the inherited styles create annotations.
+    pub inherit_surrounding_styles: bool,
+}
+
+/// The options of [`RichText::import_with_options`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// Whether the listeners get the event of the import. Turn it off to load
+    /// a large history without flooding the listeners.
+    pub emit_events: bool,
+    /// Attached to the event of the import, e.g. to tell the updates of a
+    /// provider from the others
+    pub origin: Option<String>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            emit_events: true,
+            origin: None,
+        }
+    }
+}
+
+/// An import split into steps, see [`RichText::begin_import`]
+struct ImportTask {
+    /// The ops to apply in order, the deletions are after the others
+    ops: std::vec::IntoIter<Op>,
+    /// The ops not added to the op log yet, in the counter order of each
+    /// client. They are added when the ops are applied, so the op log never
+    /// includes the ops that are not applied.
+    unstored: FxHashMap<ClientID, VecDeque<Op>>,
+    emit: bool,
+    origin: Option<String>,
+    /// The composed delta of the applied ops
+    delta: Vec<DeltaItem>,
+}
+
+impl ImportTask {
+    /// Add the ops of the client up to `id` to the op log
+    fn store_until(&mut self, store: &mut OpStore, id: OpID) {
+        if let Some(queue) = self.unstored.get_mut(&id.client) {
+            while queue
+                .front()
+                .map_or(false, |op| op.id.counter <= id.counter)
+            {
+                store.insert(queue.pop_front().unwrap());
+            }
+        }
+    }
+}
+
+/// What an import would change, see [`RichText::import_preview`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The number of the new ops, counting every inserted or deleted char
+    pub new_ops: usize,
+    /// The change of the length of the text in utf8 bytes. It's negative if
+    /// the updates delete more than they insert.
+    pub text_growth: isize,
+    /// The number of the new annotations, including the ones that remove
+    /// the styles
+    pub annotation_changes: usize,
+}
+
+/// The thresholds that trigger the compaction in [`RichText::maybe_compact`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionPolicy {
+    /// Compact only when the deleted text takes more than this ratio of all the
+    /// text that has ever been inserted.
+    pub max_tombstone_ratio: f64,
+    /// Compact only when there are at least this number of new op atoms
+    /// since the last compaction.
+    pub min_ops: usize,
+    /// Also compress the deleted text when compacting if the history has been
+    /// offloaded, see [`RichText::compress_tombstones`].
+    pub compress_tombstones: bool,
+}
+
+/// The approximate memory usage of a document in bytes.
+///
+/// See [`RichText::memory_breakdown`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryBreakdown {
+    /// The visible text
+    pub text: usize,
+    /// The deleted text and the elements that hold it
+    pub tombstones: usize,
+    pub annotations: usize,
+    pub op_log: usize,
+    /// The content tree and the map from op ids to the tree leaves
+    pub indexes: usize,
+}
+
+impl MemoryBreakdown {
+    pub fn total(&self) -> usize {
+        self.text + self.tombstones + self.annotations + self.op_log + self.indexes
+    }
+}
+
+/// The size of the op log, see [`RichText::op_log_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpLogStats {
+    /// The number of the ops in memory. The adjacent inserts and deletions of
+    /// the same client are merged into one op, so typing continuously only
+    /// adds a few ops.
+    pub ops: usize,
+    /// The number of the op ids of all the ops, i.e. the inserted bytes, the
+    /// deleted bytes and the annotations
+    pub atoms: usize,
+}
+
+/// The ops of a client, see [`RichText::client_activity`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientSummary {
+    pub client: ClientID,
+    /// The number of the ops. The adjacent inserts and deletions are merged
+    /// like [`OpLogStats::ops`].
+    pub ops: usize,
+    pub chars_inserted: usize,
+    /// The chars deleted by the client, no matter who inserted them. A char
+    /// deleted by several clients concurrently is counted for each of them.
+    pub chars_deleted: usize,
+    pub annotations: usize,
+}
+
+/// The order of the annotations returned by [`RichText::annotations_page`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationOrder {
+    /// By the start of the range, the ties are broken by the lamport
+    Position,
+    /// By the lamport of the annotation, i.e. the order of creation
+    Lamport,
+}
+
+/// An annotation and its current range in the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnotationRange {
+    pub annotation: Arc<Annotation>,
+    pub range: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Compaction {
+    policy: CompactionPolicy,
+    last_op_atoms: usize,
+}
+
+/// The progress of [`RichText::defragment`] between the calls
+#[derive(Debug, Clone, Copy, Default)]
+struct Defragment {
+    /// The first element of the next leaf to visit
+    next: Option<OpID>,
+    /// The elements and the leaves visited in the current pass
+    elements: usize,
+    leaves: usize,
 }
 
 impl RichText {
@@ -86,14 +507,37 @@ impl RichText {
         content.set_listener(Some(update_fn));
         RichText {
             bytes: AppendOnlyBytes::new(),
+            cold: ColdText::default(),
             content,
             cursor_map,
             store: OpStore::new(client_id),
             pending_ops: Default::default(),
             ann: AnnManager::new(),
             init_styles: StyleCalculator::default(),
-            listeners: Vec::new(),
+            listeners: Listeners::default(),
             event_index_type: IndexType::Utf8,
+            event_ann_ids: false,
+            compaction: None,
+            defragment: Defragment::default(),
+            readonly: false,
+            validators: Default::default(),
+            value_modes: Default::default(),
+            invalid_annotations: Vec::new(),
+            unresolved_anns: Vec::new(),
+            pruned_anns: Default::default(),
+            doc_id: None,
+            doc_id_check: DocIdCheck::default(),
+            cipher: None,
+            conflict_listener: None,
+            conflict_count: 0,
+            #[cfg(feature = "merge-log")]
+            merge_log: Vec::new(),
+            derived: Vec::new(),
+            transforms: Vec::new(),
+            op_kinds: Default::default(),
+            limits: Limits::default(),
+            branch_base: None,
+            staged_events: None,
         }
     }
 
@@ -101,12 +545,446 @@ impl RichText {
         self.store.client
     }
 
+    /// In readonly mode, the `try_` variants of the editing methods return
+    /// [`Error::Readonly`]. The other editing methods, e.g. `insert`, `delete`
+    /// and `annotate`, do nothing and log the ignored edit at the debug level
+    /// with `debug_log`, so use the `try_` variants to find out. The remote
+    /// updates can still be imported.
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Bind the document to an id, e.g. a UUID. The id is embedded in every
+    /// export and checked on import, so the updates of another document are
+    /// rejected instead of being merged into this one.
+    pub fn set_doc_id(&mut self, doc_id: Option<u128>) {
+        self.doc_id = doc_id;
+    }
+
+    pub fn doc_id(&self) -> Option<u128> {
+        self.doc_id
+    }
+
+    pub fn set_doc_id_check(&mut self, check: DocIdCheck) {
+        self.doc_id_check = check;
+    }
+
+    /// Encrypt the text and the annotation values of the exported updates, and
+    /// decrypt them on import. See [`crypto`] for more details.
+    ///
+    /// The updates that are not encrypted can still be imported.
+    pub fn set_cipher(&mut self, cipher: Option<Box<dyn Cipher>>) {
+        self.cipher = cipher;
+    }
+
+    fn check_doc_id(&self, doc_id: Option<u128>) -> Result<(), Error> {
+        let ok = match (self.doc_id_check, self.doc_id, doc_id) {
+            (DocIdCheck::Lenient, Some(a), Some(b)) => a == b,
+            (DocIdCheck::Lenient, _, _) => true,
+            (DocIdCheck::Strict, a, b) => a == b,
+        };
+
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::DocIdMismatch)
+        }
+    }
+
+    /// Whether the local edit should be ignored because the document is
+    /// readonly, see [`RichText::set_readonly`]
+    fn ignore_readonly(&self, edit: &str) -> bool {
+        if self.readonly {
+            debug_log::debug_log!("ignored {} on a readonly document", edit);
+        }
+
+        self.readonly
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.readonly {
+            Err(Error::Readonly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set the hard limits to protect the document from abuse, e.g. a client
+    /// inserting gigabytes of text or creating millions of annotations.
+    ///
+    /// The local edits that exceed the limits follow the [`LimitPolicy`], and
+    /// the `try_` variants of the editing methods return
+    /// [`Error::LimitExceeded`] instead. The imports that may exceed the limits
+    /// are rejected as a whole, because the remote ops cannot be clamped
+    /// without diverging from the other peers. The inserted text of an import
+    /// is counted before its deletions are applied.
+    ///
+    /// [`RichText::merge`] and the other methods that take the ops from a
+    /// replica in the same process, like [`RichText::merge_all`] and
+    /// [`RichText::fork`], trust the replica and don't check the limits. Only
+    /// the local edits and the imported updates are limited.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    fn check_len_limit(&self, added: usize) -> Result<(), Error> {
+        match self.limits.max_len {
+            Some(max) if self.len() + added > max => Err(Error::LimitExceeded {
+                kind: LimitKind::Length,
+                limit: max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_annotation_limit(&self, added: usize) -> Result<(), Error> {
+        match self.limits.max_annotations {
+            Some(max) if self.ann.count() + added > max => Err(Error::LimitExceeded {
+                kind: LimitKind::Annotations,
+                limit: max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The part of the local insert that is allowed by the limits
+    fn clamp_insert<'a>(&self, string: &'a str) -> &'a str {
+        let max = match self.limits.max_len {
+            Some(max) if self.len() + string.len() > max => max,
+            _ => return string,
+        };
+
+        match self.limits.policy {
+            LimitPolicy::Reject => "",
+            LimitPolicy::Clamp => {
+                let mut end = max.saturating_sub(self.len());
+                while !string.is_char_boundary(end) {
+                    end -= 1;
+                }
+                &string[..end]
+            }
+        }
+    }
+
+    /// Register the validator of the values of the annotation type.
+    ///
+    /// The local annotations with invalid values are ignored, and the `try_`
+    /// variants of the editing methods return [`Error::InvalidValue`]. The
+    /// remote annotations with invalid values are still applied so the peers
+    /// converge, but they are recorded in [`RichText::invalid_annotations`].
+    ///
+    /// The annotations that erase the style are not validated.
+    pub fn set_validator(&mut self, type_: &str, validator: Validator) {
+        self.validators.insert(type_.into(), validator);
+    }
+
+    /// The ids of the remote annotations whose values failed the validation
+    pub fn invalid_annotations(&self) -> &[OpID] {
+        &self.invalid_annotations
+    }
+
+    /// The remote annotations whose anchors refer to the text that has not
+    /// arrived yet, e.g. because it was excluded from the exported updates.
+    ///
+    /// They take effect once the text is imported.
+    pub fn unresolved_annotations(&self) -> &[Arc<Annotation>] {
+        &self.unresolved_anns
+    }
+
+    /// The unresolved annotations whose anchors all point to the ops that this
+    /// document has never seen, e.g. after importing the updates of a peer
+    /// that compacted its history or excluded the text, sorted by their ids.
+    ///
+    /// The unresolved annotations with an anchor on the known text are not
+    /// orphaned, they are still waiting for the rest of their text.
+    pub fn orphaned_annotations(&self) -> Vec<Arc<Annotation>> {
+        let mut ans: Vec<_> = self
+            .unresolved_anns
+            .iter()
+            .filter(|ann| {
+                [ann.range.start.id, ann.range.end.id]
+                    .into_iter()
+                    .flatten()
+                    .all(|id| !self.store.includes(id))
+            })
+            .cloned()
+            .collect();
+        ans.sort_by_key(|x| x.id);
+        ans
+    }
+
+    /// Drop the [orphaned annotations](RichText::orphaned_annotations), and
+    /// record their ids in a custom op of [`PRUNE_ANNOTATIONS_KIND`], so the
+    /// peers importing it drop them too. Return the ids of the dropped
+    /// annotations.
+    ///
+    /// The pruned annotations are never applied, even if their text arrives
+    /// later, and the peers that have already applied them remove them, so
+    /// the peers converge whatever the order of the ops.
+    pub fn prune_orphaned_annotations(&mut self) -> Result<Vec<OpID>, Error> {
+        self.check_writable()?;
+        let ids: Vec<OpID> = self.orphaned_annotations().iter().map(|x| x.id).collect();
+        if ids.is_empty() {
+            return Ok(ids);
+        }
+
+        let payload: Vec<u8> = ids
+            .iter()
+            .flat_map(|id| {
+                id.client
+                    .to_le_bytes()
+                    .into_iter()
+                    .chain(id.counter.to_le_bytes())
+            })
+            .collect();
+        self.insert_custom_op(PRUNE_ANNOTATIONS_KIND, &payload)?;
+        let ops = self.prune_annotations(ids.iter().copied());
+        if !ops.is_empty() {
+            self.emit(Event {
+                ops,
+                is_local: true,
+                index_type: self.event_index_type,
+                origin: None,
+            });
+        }
+
+        Ok(ids)
+    }
+
+    /// Drop the annotations whether they are applied, waiting for their text
+    /// or not received yet. Return the delta of the removed styles.
+    fn prune_annotations(&mut self, ids: impl IntoIterator<Item = OpID>) -> Vec<DeltaItem> {
+        let mut anns: FxHashSet<AnnIdx> = Default::default();
+        for id in ids {
+            anns.extend(self.ann.get_idx_by_id(id));
+            self.ann.remove(id);
+            self.pruned_anns.insert(id);
+        }
+
+        self.unresolved_anns
+            .retain(|ann| !self.pruned_anns.contains(&ann.id));
+        if anns.is_empty() {
+            return Vec::new();
+        }
+
+        let old = self.has_listener().then(|| self.get_spans());
+        let elements = self
+            .content
+            .iter()
+            .map(|elem| {
+                let mut elem = elem.clone();
+                elem.anchor_set.retain(|idx| !anns.contains(&idx));
+                elem
+            })
+            .collect();
+        self.rebuild_content(elements);
+        self.init_styles.retain(|idx| !anns.contains(&idx));
+        match old {
+            Some(old) => diff_spans(&old, &self.get_spans(), self.event_index_type),
+            None => Vec::new(),
+        }
+    }
+
+    fn is_resolved(&self, ann: &Annotation) -> bool {
+        [ann.range.start.id, ann.range.end.id]
+            .into_iter()
+            .flatten()
+            .all(|id| self.store.includes(id))
+    }
+
+    fn is_valid_value(&self, type_: &InternalString, value: &Value, behavior: Behavior) -> bool {
+        behavior == Behavior::Delete
+            || self
+                .validators
+                .get(type_)
+                .map_or(true, |validator| validator(value))
+    }
+
+    fn check_value(
+        &self,
+        type_: &InternalString,
+        value: &Value,
+        behavior: Behavior,
+    ) -> Result<(), Error> {
+        if self.is_valid_value(type_, value, behavior) {
+            Ok(())
+        } else {
+            Err(Error::InvalidValue(type_.to_string()))
+        }
+    }
+
+    /// Set how the values of the overlapping annotations of the type are
+    /// resolved in the spans and the events, e.g. [`ValueMode::SetUnion`] for
+    /// the labels whose concurrent additions should all be kept.
+    ///
+    /// It only changes how the document is read, so every peer should set the
+    /// same modes.
+    pub fn set_value_mode(&mut self, type_: &str, mode: ValueMode) {
+        match mode {
+            ValueMode::Lww => self.value_modes.remove(&InternalString::from(type_)),
+            mode => self.value_modes.insert(type_.into(), mode),
+        };
+    }
+
     pub fn set_event_index_type(&mut self, index_type: IndexType) {
         self.event_index_type = index_type;
     }
 
+    /// Include the ids of the annotations in the retain items of the events,
+    /// so the attribute changes can be mapped back to the annotations that
+    /// caused them. It's disabled by default.
+    pub fn set_event_annotation_ids(&mut self, enable: bool) {
+        self.event_ann_ids = enable;
+    }
+
+    fn retain_with_annotation(
+        &self,
+        retain: usize,
+        attributes: FxHashMap<String, Value>,
+        id: OpID,
+    ) -> DeltaItem {
+        if self.event_ann_ids {
+            DeltaItem::retain_with_annotation(retain, attributes, id)
+        } else {
+            DeltaItem::retain_with_attributes(retain, attributes)
+        }
+    }
+
     pub fn observe(&mut self, listener: Listener) {
-        self.listeners.push(listener);
+        self.listeners.all.push(listener);
+    }
+
+    /// Observe the changes in the utf8 `range` only, e.g. for an editor that
+    /// only renders a window of a large document.
+    ///
+    /// The range is anchored to the chars around it, so it moves with the
+    /// edits, and the text inserted at its edges is inside it. The listener
+    /// gets the whole event if any change in the event touches the range.
+    pub fn observe_range(&mut self, range: impl RangeBounds<usize>, listener: Listener) {
+        let range = self.resolve_range(range, IndexType::Utf8);
+        let id_at = |index: usize| {
+            self.get_id_at_pos(self.content.query::<IndexFinder>(&(index, IndexType::Utf8)))
+        };
+        let start = range.start.checked_sub(1).map(id_at);
+        let end = Some(range.end).filter(|&end| end < self.len()).map(id_at);
+        self.listeners.ranged.push(RangeListener {
+            start,
+            end,
+            listener,
+        });
+    }
+
+    /// The current range of a [`RangeListener`]
+    fn observed_range(
+        &self,
+        start: Option<OpID>,
+        end: Option<OpID>,
+        index_type: IndexType,
+    ) -> Range<usize> {
+        let start = start.and_then(|id| self.id_position(id, true)).unwrap_or(0);
+        let end = end
+            .and_then(|id| self.id_position(id, false))
+            .unwrap_or(self.len())
+            .max(start);
+        self.convert_index(start, IndexType::Utf8, index_type)
+            ..self.convert_index(end, IndexType::Utf8, index_type)
+    }
+
+    /// The utf8 index before or after the byte with the id. The index of a
+    /// deleted byte is where it was.
+    fn id_position(&self, id: OpID, after: bool) -> Option<usize> {
+        self.cursor_map.get_insert(id)?;
+        let path = self.find_cursor(id);
+        let alive = !self.content.get_node(path.leaf).elements()[path.elem_index].is_dead();
+        Some(self.get_index_from_path(path, IndexType::Utf8) + (after && alive) as usize)
+    }
+
+    /// Track the conflicts of the concurrent annotations of the same type.
+    ///
+    /// The listener is called when a remote annotation is overridden by an
+    /// annotation with a higher lamport. A conflict is only detected by the
+    /// peers that receive the losing annotation after the winner, so every
+    /// conflict is reported by at least one of the peers.
+    ///
+    /// The annotations with [`Behavior::AllowMultiple`] never conflict.
+    pub fn observe_annotation_conflicts(&mut self, listener: ConflictListener) {
+        self.conflict_listener = Some(listener);
+    }
+
+    /// The number of the annotation conflicts detected since
+    /// [`RichText::observe_annotation_conflicts`] is called
+    pub fn annotation_conflict_count(&self) -> usize {
+        self.conflict_count
+    }
+
+    /// The merge decisions recorded since the last call, see
+    /// [`MergeRecord`](crate::merge_log::MergeRecord)
+    #[cfg(feature = "merge-log")]
+    pub fn take_merge_log(&mut self) -> Vec<crate::merge_log::MergeRecord> {
+        std::mem::take(&mut self.merge_log)
+    }
+
+    fn detect_annotation_conflicts(&mut self, ann: &Arc<Annotation>) {
+        let observed = self.conflict_listener.is_some() || cfg!(feature = "merge-log");
+        if !observed || ann.behavior == Behavior::AllowMultiple {
+            return;
+        }
+
+        let winners: Vec<&Arc<Annotation>> = self
+            .ann
+            .iter()
+            .filter(|x| {
+                x.type_ == ann.type_
+                    && x.behavior != Behavior::AllowMultiple
+                    && x.range_lamport > ann.range_lamport
+            })
+            .collect();
+        if winners.is_empty() {
+            return;
+        }
+
+        let mut anns = winners.clone();
+        anns.push(ann);
+        let ranges = self.annotation_ranges(&anns, IndexType::Utf8);
+        let range = &ranges[&ann.id];
+        let conflicts: Vec<AnnotationConflict> = winners
+            .into_iter()
+            .filter(|winner| {
+                let r = &ranges[&winner.id];
+                r.start.max(range.start) < r.end.min(range.end)
+            })
+            .map(|winner| AnnotationConflict {
+                discarded: ann.clone(),
+                winner: winner.clone(),
+            })
+            .collect();
+
+        #[cfg(feature = "merge-log")]
+        for conflict in conflicts.iter() {
+            let winner = &ranges[&conflict.winner.id];
+            self.merge_log
+                .push(crate::merge_log::MergeRecord::AnnotationConflict {
+                    type_: ann.type_.to_string(),
+                    discarded: (&**ann).into(),
+                    winner: (&*conflict.winner).into(),
+                    overlap: (range.start.max(winner.start), range.end.min(winner.end)),
+                });
+        }
+
+        if let Some(listener) = self.conflict_listener.as_mut() {
+            self.conflict_count += conflicts.len();
+            for conflict in conflicts.iter() {
+                listener(conflict);
+            }
+        }
     }
 
     #[inline(always)]
@@ -116,9 +994,31 @@ impl RichText {
 
     fn emit(&mut self, mut event: Event) {
         event.ops.retain(|x| !x.should_remove());
-        for listener in &mut self.listeners {
+        intern_attributes(&mut event.ops);
+        if let Some(staged) = self.staged_events.as_mut() {
+            staged.push(event);
+            return;
+        }
+
+        for listener in &mut self.listeners.all {
             listener(&event);
         }
+
+        if self.listeners.ranged.is_empty() {
+            return;
+        }
+
+        let ranges: Vec<Range<usize>> = self
+            .listeners
+            .ranged
+            .iter()
+            .map(|x| self.observed_range(x.start, x.end, event.index_type))
+            .collect();
+        for (listener, range) in self.listeners.ranged.iter_mut().zip(ranges) {
+            if event::delta_touches(&event.ops, &range) {
+                (listener.listener)(&event);
+            }
+        }
     }
 
     #[inline]
@@ -129,34 +1029,311 @@ impl RichText {
     #[inline]
     pub fn insert_utf16(&mut self, index: usize, string: &str) {
         assert!(index <= self.utf16_len());
-        self.insert_inner(index, string, IndexType::Utf16);
+        let string = self.transform_insert(index, string, IndexType::Utf16);
+        self.insert_inner(index, &string, IndexType::Utf16);
     }
 
     #[inline]
     pub fn insert(&mut self, index: usize, string: &str) {
         assert!(index <= self.len());
-        self.insert_inner(index, string, IndexType::Utf8);
+        let string = self.transform_insert(index, string, IndexType::Utf8);
+        self.insert_inner(index, &string, IndexType::Utf8);
     }
 
-    fn insert_inner(&mut self, index: usize, string: &str, index_type: IndexType) {
+    /// Register a transform of the text inserted locally by the `insert`, the
+    /// `insert_with_styles`, the `apply_delta`, [`RichText::prepend`] and
+    /// [`RichText::append`] methods. The transforms run in the order of the registration, and the
+    /// one with the same name is replaced.
+    ///
+    /// They run before the ops are created, so the peers only receive the
+    /// transformed text and never diverge, even if they don't register the
+    /// same transforms. The imported text is never transformed.
+    pub fn register_text_transform(&mut self, name: &str, transform: TextTransform) {
+        let name: InternalString = name.into();
+        match self.transforms.iter_mut().find(|(x, _)| *x == name) {
+            Some((_, x)) => *x = transform,
+            None => self.transforms.push((name, transform)),
+        }
+    }
+
+    pub fn unregister_text_transform(&mut self, name: &str) {
+        let name: InternalString = name.into();
+        self.transforms.retain(|(x, _)| *x != name);
+    }
+
+    fn transform_insert<'a>(
+        &self,
+        index: usize,
+        string: &'a str,
+        index_type: IndexType,
+    ) -> Cow<'a, str> {
+        let mut ans = Cow::Borrowed(string);
+        if self.transforms.is_empty() {
+            return ans;
+        }
+
+        // a char takes at most 2 utf16 units
+        let utf16 = self.convert_index(index, index_type, IndexType::Utf16);
+        let prev = self
+            .slice_str(utf16.saturating_sub(2)..utf16, IndexType::Utf16)
+            .chars()
+            .last();
+        for (_, transform) in self.transforms.iter() {
+            if let Some(x) = transform(prev, &ans) {
+                ans = Cow::Owned(x);
+            }
+        }
+
+        ans
+    }
+
+    /// Insert the text like [`RichText::insert_utf16`]. The limits are checked
+    /// against the transformed text.
+    pub fn try_insert_utf16(&mut self, index: usize, string: &str) -> Result<(), Error> {
+        assert!(index <= self.utf16_len());
+        let string = self.transform_insert(index, string, IndexType::Utf16);
+        self.try_insert_transformed(index, &string, IndexType::Utf16)
+    }
+
+    /// Insert the text like [`RichText::insert`]. The limits are checked
+    /// against the transformed text.
+    pub fn try_insert(&mut self, index: usize, string: &str) -> Result<(), Error> {
+        assert!(index <= self.len());
+        let string = self.transform_insert(index, string, IndexType::Utf8);
+        self.try_insert_transformed(index, &string, IndexType::Utf8)
+    }
+
+    /// Insert the text that has been transformed by [`RichText::transform_insert`]
+    fn try_insert_transformed(
+        &mut self,
+        index: usize,
+        string: &str,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_len_limit(string.len())?;
+        self.insert_inner(index, string, index_type);
+        Ok(())
+    }
+
+    /// Insert the text with exactly the given styles, like an insert with
+    /// attributes in Quill's delta.
+    ///
+    /// The styles inherited from the neighbors are erased unless they are given,
+    /// so an empty `styles` inserts plain text. Only the given styles that
+    /// differ from the inherited ones create annotations. The listeners get a
+    /// single event of the insertion with its final attributes.
+    ///
+    /// The styles are validated before the insertion: nothing is inserted if
+    /// any value fails its validator or the styles exceed the annotation limit.
+    pub fn insert_with_styles(&mut self, index: usize, string: &str, styles: &[Style]) {
+        assert!(index <= self.len());
+        self.insert_with_styles_inner(index, string, styles, IndexType::Utf8);
+    }
+
+    pub fn insert_with_styles_utf16(&mut self, index: usize, string: &str, styles: &[Style]) {
+        assert!(index <= self.utf16_len());
+        self.insert_with_styles_inner(index, string, styles, IndexType::Utf16);
+    }
+
+    fn insert_with_styles_inner(
+        &mut self,
+        index: usize,
+        string: &str,
+        styles: &[Style],
+        index_type: IndexType,
+    ) {
+        if self.ignore_readonly("insert") {
+            return;
+        }
+
+        // the types the inserted text can inherit, whose erases are counted
+        // against the annotation limit too
+        let mut inheritable: FxHashSet<InternalString> = self
+            .get_style_at_position(self.align_index(index, index_type, true), index_type)
+            .map(|(type_, _)| type_)
+            .collect();
+        inheritable.extend(
+            self.init_styles
+                .calc_styles(&self.ann)
+                .map(|x| x.type_.clone()),
+        );
+        if let Err(err) = self.check_styles(styles, &inheritable) {
+            debug_log::debug_log!("ignored the styled insert with {}", err);
+            return;
+        }
+
+        let string = self.transform_insert(index, string, index_type);
+        let string = self.clamp_insert(&string);
         if string.is_empty() {
             return;
         }
 
-        fn can_merge_new_slice(
-            elem: &Elem,
-            id: OpID,
-            right: Option<OpID>,
-            slice: &append_only_bytes::BytesSlice,
-        ) -> bool {
-            elem.id.client == id.client
-                && elem.id.counter + elem.atom_len() as Counter == id.counter
-                && elem.right == right
-                && !elem.is_dead()
-                && elem.string.can_merge(slice)
-                && !elem.has_after_anchor()
+        let index = self.align_index(index, index_type, true);
+        let end = match index_type {
+            IndexType::Utf8 => index + string.len(),
+            IndexType::Utf16 => index + get_utf16_len(string),
+        };
+        // the events of the inner edits are composed into one event below
+        let listeners = std::mem::take(&mut self.listeners);
+        self.insert_inner(index, string, index_type);
+        let inherited = self
+            .slice(index..end, index_type)
+            .into_iter()
+            .next()
+            .map(|span| span.attributes)
+            .unwrap_or_default();
+        for type_ in inherited.keys() {
+            if !styles.iter().any(|style| &style.type_ == type_) {
+                self.annotate_inner(
+                    index..end,
+                    Style {
+                        expand: Expand::infer_delete_expand(type_),
+                        behavior: Behavior::Delete,
+                        type_: type_.clone(),
+                        value: Value::Null,
+                    },
+                    index_type,
+                );
+            }
+        }
+
+        for style in styles {
+            if inherited.get(&style.type_) != Some(&style.value) {
+                self.annotate_inner(index..end, style.clone(), index_type);
+            }
+        }
+
+        self.listeners = listeners;
+        if self.has_listener() {
+            let retain = self.convert_index(index, index_type, self.event_index_type);
+            let mut ops = vec![DeltaItem::retain(retain)];
+            let spans = self.slice(index..end, index_type);
+            ops.extend(diff_spans(&[], &spans, self.event_index_type));
+
+            self.emit(Event {
+                ops,
+                is_local: true,
+                index_type: self.event_index_type,
+                origin: None,
+            });
+        }
+    }
+
+    /// Check the values of the styles of [`RichText::insert_with_styles`],
+    /// and the annotation limit with all the annotations it may create: the
+    /// given styles and the erases of the `inheritable` types that are not
+    /// given
+    fn check_styles(
+        &self,
+        styles: &[Style],
+        inheritable: &FxHashSet<InternalString>,
+    ) -> Result<(), Error> {
+        for style in styles {
+            self.check_value(&style.type_, &style.value, style.behavior)?;
+        }
+
+        let erases = inheritable
+            .iter()
+            .filter(|type_| !styles.iter().any(|style| &style.type_ == *type_))
+            .count();
+        self.check_annotation_limit(styles.len() + erases)
+    }
+
+    /// Insert the text at the start of the document.
+    ///
+    /// It's the same as [`RichText::insert`] at 0. Unlike [`RichText::append`],
+    /// there is no fast path, because the new text still has to be ordered
+    /// among the tombstones at the start of the document.
+    #[inline]
+    pub fn prepend(&mut self, string: &str) {
+        let string = self.transform_insert(0, string, IndexType::Utf8);
+        self.insert_inner(0, &string, IndexType::Utf8);
+    }
+
+    /// Insert the text at the end of the document.
+    ///
+    /// It skips the index resolution of [`RichText::insert`] when the last
+    /// element of the document is not deleted, and extends the last op if it's
+    /// also appended by this client, so appending many times only creates a
+    /// few ops.
+    pub fn append(&mut self, string: &str) {
+        let string = self.transform_insert(self.len(), string, IndexType::Utf8);
+        let string = self.clamp_insert(&string);
+        if string.is_empty() || self.ignore_readonly("insert") {
+            return;
+        }
+
+        let leaf = self.content.last_leaf();
+        let left = match self.content.get_node(leaf).elements().last() {
+            Some(last) if !last.is_dead() => last.id_last(),
+            _ => {
+                // the ideal position may be among the tombstones at the end
+                self.insert_inner(self.len(), string, IndexType::Utf8);
+                return;
+            }
+        };
+
+        let index = self.len();
+        let start = self.bytes.len();
+        self.bytes.push_str(string);
+        let slice = self.bytes.slice(start..);
+        let Utf16LenAndLineBreaks { utf16, line_breaks } = get_utf16_len_and_line_breaks(&slice);
+        let cache_diff = Some(CacheDiff::new_len_diff(
+            string.len() as isize,
+            utf16 as isize,
+            line_breaks as isize,
+        ));
+        let id = self.next_id();
+        let op_slice = slice.clone();
+        self.content.update_leaf(leaf, |elements| {
+            let last = elements.len() - 1;
+            if can_merge_new_slice(&elements[last], id, None, &slice) {
+                elements[last].merge_slice(&slice);
+            } else {
+                elements.push(Elem::new(id, Some(left), None, slice));
+            }
+
+            self.cursor_map
+                .update(MoveEvent::new_move(leaf, elements.last().unwrap()));
+            (true, cache_diff)
+        });
+        self.store
+            .insert_local(OpContent::new_insert(Some(left), None, op_slice));
+
+        if self.has_listener() {
+            let retain = self.convert_index(index, IndexType::Utf8, self.event_index_type);
+            let annotations = self
+                .get_style_at_position(index, IndexType::Utf8)
+                .map(|(k, v)| (k.to_string(), v))
+                .collect();
+            self.emit(Event {
+                ops: vec![
+                    DeltaItem::retain(retain),
+                    DeltaItem::insert_with_attributes(
+                        string.to_owned(),
+                        self.event_index_type,
+                        annotations,
+                    ),
+                ],
+                is_local: true,
+                index_type: self.event_index_type,
+                origin: None,
+            })
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, string), fields(len = string.len()))
+    )]
+    fn insert_inner(&mut self, index: usize, string: &str, index_type: IndexType) {
+        let string = self.clamp_insert(string);
+        if string.is_empty() || self.ignore_readonly("insert") {
+            return;
         }
 
+        let index = self.align_index(index, index_type, true);
         let start = self.bytes.len();
         self.bytes.push_str(string);
         let slice = self.bytes.slice(start..);
@@ -275,6 +1452,7 @@ impl RichText {
                 ],
                 is_local: true,
                 index_type: self.event_index_type,
+                origin: None,
             })
         }
     }
@@ -432,24 +1610,63 @@ impl RichText {
         self.delete_inner(range, IndexType::Utf8);
     }
 
+    pub fn try_delete_utf16(&mut self, range: impl RangeBounds<usize>) -> Result<(), Error> {
+        self.check_writable()?;
+        self.delete_utf16(range);
+        Ok(())
+    }
+
+    pub fn try_delete(&mut self, range: impl RangeBounds<usize>) -> Result<(), Error> {
+        self.check_writable()?;
+        self.delete(range);
+        Ok(())
+    }
+
+    /// Delete the text after the first `max_len` utf8 bytes, e.g. to keep a
+    /// log-like document bounded. The cut is moved back to the start of the
+    /// char if it's inside a char. Return the length of the deleted text.
+    ///
+    /// The tail is deleted with normal delete ops, so the peers that truncate
+    /// concurrently at different lengths converge: the deletions are merged,
+    /// and the text known by both of them is cut at the shorter length. The
+    /// text inserted concurrently into the tail is kept, so the merged
+    /// document can still be longer than `max_len`. Truncate it again after
+    /// merging to enforce the bound.
+    pub fn truncate_to(&mut self, max_len: usize) -> usize {
+        let len = self.len();
+        if max_len >= len {
+            return 0;
+        }
+
+        let path = self
+            .content
+            .query::<IndexFinder>(&(max_len, IndexType::Utf8));
+        let elements = self.content.get_node(path.leaf).elements();
+        let mut start = max_len;
+        if let Some(elem) = elements.get(path.elem_index) {
+            let s = bytes_to_str(&elem.string);
+            let mut offset = path.offset;
+            while !s.is_char_boundary(offset) {
+                offset -= 1;
+                start -= 1;
+            }
+        }
+
+        self.delete(start..);
+        len - self.len()
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
     fn delete_inner(&mut self, range: impl RangeBounds<usize>, index_type: IndexType) {
-        let start = match range.start_bound() {
-            Bound::Included(start) => *start,
-            Bound::Excluded(start) => *start + 1,
-            Bound::Unbounded => 0,
-        };
-        let end = match range.end_bound() {
-            Bound::Included(end) => *end + 1,
-            Bound::Excluded(end) => *end,
-            Bound::Unbounded => self.len_with(index_type),
-        };
+        if self.ignore_readonly("delete") {
+            return;
+        }
 
+        let Range { start, end } = self.resolve_range(range, index_type);
         if start == end {
             return;
         }
 
-        assert!(end <= self.len_with(index_type));
-
         let event = if self.has_listener() {
             let retain = self.convert_index(start, index_type, self.event_index_type);
             let end = self.convert_index(end, index_type, self.event_index_type);
@@ -457,6 +1674,7 @@ impl RichText {
                 ops: vec![DeltaItem::retain(retain), DeltaItem::delete(end - retain)],
                 is_local: true,
                 index_type: self.event_index_type,
+                origin: None,
             })
         } else {
             None
@@ -616,38 +1834,274 @@ impl RichText {
         self.annotate_inner(range, style, IndexType::Utf16)
     }
 
-    /// Annotate the given range with style.
-    ///
-    /// Under the hood, it will assign anchors to the characters at the given start pos and end pos.
-    /// The range start OpID and end OpID are the OpID of those characters;
-    ///
-    /// Although the arg is a range bound, a `..` range doesn't necessary means the start anchor
-    /// and the end anchor is None. Because the range is also depends on the anchor type.
-    pub fn annotate(&mut self, range: impl RangeBounds<usize>, style: Style) {
-        self.annotate_inner(range, style, IndexType::Utf8)
+    /// Annotate the given range with style.
+    ///
+    /// Under the hood, it will assign anchors to the characters at the given start pos and end pos.
+    /// The range start OpID and end OpID are the OpID of those characters;
+    ///
+    /// Although the arg is a range bound, a `..` range doesn't necessary means the start anchor
+    /// and the end anchor is None. Because the range is also depends on the anchor type.
+    pub fn annotate(&mut self, range: impl RangeBounds<usize>, style: Style) {
+        self.annotate_inner(range, style, IndexType::Utf8)
+    }
+
+    pub fn try_annotate_utf16(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        style: Style,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_value(&style.type_, &style.value, style.behavior)?;
+        self.check_annotation_limit(1)?;
+        self.annotate_utf16(range, style);
+        Ok(())
+    }
+
+    pub fn try_annotate(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        style: Style,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_value(&style.type_, &style.value, style.behavior)?;
+        self.check_annotation_limit(1)?;
+        self.annotate(range, style);
+        Ok(())
+    }
+
+    /// Insert an embed at the utf8 `index`, e.g. `{"image": "a.png"}`.
+    ///
+    /// The embed is an [`EMBED_CHAR`] with the [`EMBED_TYPE`] annotation of
+    /// the value, which is applied by [`RichText::annotate_element`]. The text
+    /// transforms are skipped.
+    ///
+    /// Nothing is inserted if the document is readonly, the value fails its
+    /// validator, or the embed exceeds the limits.
+    pub fn insert_embed(&mut self, index: usize, value: Value) -> Result<(), Error> {
+        assert!(index <= self.len());
+        let style = Style::new_link_like(EMBED_TYPE.into(), value);
+        let mut buf = [0; 4];
+        let embed = EMBED_CHAR.encode_utf8(&mut buf);
+        self.check_writable()?;
+        self.check_value(&style.type_, &style.value, style.behavior)?;
+        self.check_len_limit(embed.len())?;
+        self.check_annotation_limit(1)?;
+        self.insert_inner(index, embed, IndexType::Utf8);
+        self.annotate_element(index, style);
+        Ok(())
+    }
+
+    /// The value of the embed at the utf8 `index`
+    pub fn embed_at(&self, index: usize) -> Option<Value> {
+        if self.char_at(index)? != EMBED_CHAR {
+            return None;
+        }
+
+        let span = self
+            .slice(index..index + EMBED_CHAR.len_utf8(), IndexType::Utf8)
+            .pop()?;
+        span.attributes
+            .get(&InternalString::from(EMBED_TYPE))
+            .cloned()
+    }
+
+    /// Annotate the char at the utf8 `index` as an atomic element, e.g. to
+    /// resize an embedded image.
+    ///
+    /// The style never expands, whatever its [`Expand`] is, so the text
+    /// inserted next to the element, locally or concurrently, never takes the
+    /// style. The element is either covered as a whole or not at all.
+    pub fn annotate_element(&mut self, index: usize, mut style: Style) {
+        let len = match self.char_at(index) {
+            Some(c) => c.len_utf8(),
+            None => return,
+        };
+        style.expand = match style.behavior {
+            // the erased range shouldn't grow either
+            Behavior::Delete => Expand::None.toggle(),
+            _ => Expand::None,
+        };
+        self.annotate_inner(index..index + len, style, IndexType::Utf8);
+    }
+
+    fn char_at(&self, index: usize) -> Option<char> {
+        if index >= self.len() {
+            return None;
+        }
+
+        let utf16 = self.convert_index(index, IndexType::Utf8, IndexType::Utf16);
+        self.slice_str(utf16..utf16 + 1, IndexType::Utf16)
+            .chars()
+            .next()
+    }
+
+    /// Annotate all the occurrences of `pattern` with the style, e.g. to
+    /// highlight the search results.
+    ///
+    /// The overlapping occurrences are skipped, like [`str::match_indices`].
+    /// The listeners get a single event for all the annotations. Return the
+    /// number of the annotated occurrences, which is 0 if annotating all of
+    /// them would exceed the annotation limit.
+    pub fn annotate_matches(&mut self, pattern: &str, style: Style) -> usize {
+        if pattern.is_empty()
+            || self.ignore_readonly("annotate")
+            || !self.is_valid_value(&style.type_, &style.value, style.behavior)
+        {
+            return 0;
+        }
+
+        let text = self.to_string();
+        let ranges: Vec<_> = text
+            .match_indices(pattern)
+            .map(|(start, s)| start..start + s.len())
+            .collect();
+        if let Err(err) = self.check_annotation_limit(ranges.len()) {
+            debug_log::debug_log!("ignored annotate_matches with {}", err);
+            return 0;
+        }
+
+        let listeners = std::mem::take(&mut self.listeners);
+        let mut ops = Vec::with_capacity(ranges.len() * 2);
+        let mut last_end = 0;
+        for range in ranges.iter() {
+            if !listeners.is_empty() {
+                let start = self.convert_index(range.start, IndexType::Utf8, self.event_index_type);
+                let end = self.convert_index(range.end, IndexType::Utf8, self.event_index_type);
+                let mut attributes: FxHashMap<_, _> = Default::default();
+                attributes.insert(style.type_.to_string(), style.value.clone());
+                ops.push(DeltaItem::retain(start - last_end));
+                ops.push(self.retain_with_annotation(end - start, attributes, self.next_id()));
+                last_end = end;
+            }
+
+            self.annotate_inner(range.clone(), style.clone(), IndexType::Utf8);
+        }
+
+        self.listeners = listeners;
+        if !ops.is_empty() {
+            self.emit(Event {
+                ops,
+                is_local: true,
+                index_type: self.event_index_type,
+                origin: None,
+            });
+        }
+
+        ranges.len()
+    }
+
+    /// Split the annotation at the utf8 `index` into two annotations with the
+    /// same type and value but separate identities, e.g. to edit the URL of
+    /// only a half of a link. Return the ids of the two halves.
+    ///
+    /// The range of an annotation never changes, so the halves are new
+    /// annotations that override the original one. The styles of the text are
+    /// not changed.
+    ///
+    /// It fails if the annotation is unknown, has the [`Behavior::AllowMultiple`]
+    /// behavior, is partially overridden by another annotation of the same
+    /// type, or `index` is not inside its range.
+    pub fn split_annotation(&mut self, id: OpID, index: usize) -> Result<(OpID, OpID), Error> {
+        self.check_writable()?;
+        let ann = self
+            .ann
+            .get_ann_by_id(id)
+            .cloned()
+            .ok_or_else(|| Error::InvalidOp(format!("unknown annotation {:?}", id)))?;
+        if ann.behavior == Behavior::AllowMultiple {
+            return Err(Error::InvalidOp(format!(
+                "cannot split the annotation {:?} that allows multiple",
+                id
+            )));
+        }
+
+        let overriding: Vec<&Arc<Annotation>> = self
+            .ann
+            .iter()
+            .filter(|x| {
+                x.type_ == ann.type_
+                    && x.behavior != Behavior::AllowMultiple
+                    && x.range_lamport > ann.range_lamport
+            })
+            .collect();
+        let mut anns = overriding.clone();
+        anns.push(&ann);
+        let ranges = self.annotation_ranges(&anns, IndexType::Utf8);
+        let range = ranges[&id].clone();
+        if overriding.iter().any(|x| {
+            let r = &ranges[&x.id];
+            r.start.max(range.start) < r.end.min(range.end)
+        }) {
+            return Err(Error::InvalidOp(format!(
+                "the annotation {:?} is overridden by another annotation",
+                id
+            )));
+        }
+        if index <= range.start || index >= range.end {
+            return Err(Error::InvalidOp(format!(
+                "{} is not inside the annotation {:?}",
+                index, id
+            )));
+        }
+
+        let style = Style {
+            expand: Expand::from_anchor_types(ann.range.start.type_, ann.range.end.type_),
+            behavior: ann.behavior,
+            type_: ann.type_.clone(),
+            value: ann.value.clone(),
+        };
+        let listeners = std::mem::take(&mut self.listeners);
+        let first = self.next_id();
+        self.annotate_inner(range.start..index, style.clone(), IndexType::Utf8);
+        let second = self.next_id();
+        self.annotate_inner(index..range.end, style, IndexType::Utf8);
+        self.listeners = listeners;
+        if self.has_listener() {
+            let start = self.convert_index(range.start, IndexType::Utf8, self.event_index_type);
+            let mid = self.convert_index(index, IndexType::Utf8, self.event_index_type);
+            let end = self.convert_index(range.end, IndexType::Utf8, self.event_index_type);
+            let mut attributes: FxHashMap<_, _> = Default::default();
+            attributes.insert(ann.type_.to_string(), ann.value.clone());
+            let ops = vec![
+                DeltaItem::retain(start),
+                self.retain_with_annotation(mid - start, attributes.clone(), first),
+                self.retain_with_annotation(end - mid, attributes, second),
+            ];
+            self.emit(Event {
+                ops,
+                is_local: true,
+                index_type: self.event_index_type,
+                origin: None,
+            });
+        }
+
+        Ok((first, second))
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(type_ = %style.type_))
+    )]
     fn annotate_inner(
         &mut self,
         range: impl RangeBounds<usize>,
         style: Style,
         index_type: IndexType,
     ) {
-        let start = match range.start_bound() {
-            Bound::Included(start) => *start,
-            Bound::Excluded(start) => *start + 1,
-            Bound::Unbounded => 0,
-        };
-        let mut inclusive_end = match range.end_bound() {
-            Bound::Included(end) => *end,
-            Bound::Excluded(end) => *end - 1,
-            Bound::Unbounded => self.len_with(index_type) - 1,
-        };
+        if self.ignore_readonly("annotate")
+            || !self.is_valid_value(&style.type_, &style.value, style.behavior)
+            || self.check_annotation_limit(1).is_err()
+        {
+            return;
+        }
 
-        if inclusive_end < start {
+        let range = self.resolve_range(range, index_type);
+        if range.is_empty() {
             return;
         }
 
+        let start = range.start;
+        let inclusive_end = range.end - 1;
         let event = if self.has_listener() {
             let retain = self.convert_index(start, index_type, self.event_index_type);
             let end = self.convert_index(inclusive_end + 1, index_type, self.event_index_type);
@@ -656,41 +2110,16 @@ impl RichText {
             Some(Event {
                 ops: vec![
                     DeltaItem::retain(retain),
-                    DeltaItem::retain_with_attributes(end - retain, attributes),
+                    self.retain_with_annotation(end - retain, attributes, self.next_id()),
                 ],
                 is_local: true,
                 index_type: self.event_index_type,
+                origin: None,
             })
         } else {
             None
         };
-        inclusive_end = inclusive_end.min(self.len_with(index_type) - 1);
-        let start = if style.expand.start_type() == AnchorType::Before {
-            Some(self.content.query::<IndexFinder>(&(start, index_type)))
-        } else if start == 0 {
-            None
-        } else {
-            Some(
-                self.content
-                    .query::<IndexFinder>(&(start.saturating_sub(1), index_type)),
-            )
-        };
-        let inclusive_end = if style.expand.end_type() == AnchorType::Before {
-            if inclusive_end + 1 >= self.len_with(index_type) {
-                None
-            } else {
-                Some(
-                    self.content
-                        .query::<IndexFinder>(&(inclusive_end + 1, index_type)),
-                )
-            }
-        } else {
-            Some(
-                self.content
-                    .query::<IndexFinder>(&(inclusive_end, index_type)),
-            )
-        };
-
+        let (start, inclusive_end) = self.anchor_cursors(range, style.expand, index_type);
         let start_id = start.map(|start| self.get_id_at_pos(start));
         let end_id = inclusive_end.map(|end| self.get_id_at_pos(end));
         let id = self.next_id();
@@ -758,11 +2187,40 @@ impl RichText {
 
         // register op to store
         self.store.insert_local(OpContent::new_ann(ann));
-        if let Some(event) = event {
+        if let Some(mut event) = event {
+            if self.value_modes.contains_key(&style.type_) {
+                let start = event.ops[0].length();
+                let end = start + event.ops[1].length();
+                event.ops = self.merged_value_delta(start..end, &style.type_, id);
+            }
+
             self.emit(event)
         }
     }
 
+    /// The delta that sets the merged values of the type in the range, which
+    /// can differ from the value of the new annotation, see [`ValueMode`]
+    fn merged_value_delta(
+        &self,
+        range: Range<usize>,
+        type_: &InternalString,
+        id: OpID,
+    ) -> Vec<DeltaItem> {
+        let mut ops = vec![DeltaItem::retain(range.start)];
+        for span in self.slice(range, self.event_index_type) {
+            let len = match self.event_index_type {
+                IndexType::Utf8 => span.insert.len(),
+                IndexType::Utf16 => get_utf16_len(&span.insert),
+            };
+            let mut attributes: FxHashMap<_, _> = Default::default();
+            let value = span.attributes.get(type_).cloned().unwrap_or(Value::Null);
+            attributes.insert(type_.to_string(), value);
+            ops.push(self.retain_with_annotation(len, attributes, id));
+        }
+
+        ops
+    }
+
     fn annotate_given_range(
         &mut self,
         start: QueryResult,
@@ -811,92 +2269,681 @@ impl RichText {
                                 style.end_type(),
                             );
 
-                            elements.splice(start.elem_index + 1..start.elem_index + 1, new);
-                            return true;
-                        }
+                            elements.splice(start.elem_index + 1..start.elem_index + 1, new);
+                            return true;
+                        }
+
+                        assert!(end.elem_index > start.elem_index);
+                        ann::insert_anchor_to_char(
+                            elements,
+                            end.elem_index,
+                            end.offset,
+                            ann_idx,
+                            style.end_type(),
+                            false,
+                        );
+                        ann::insert_anchor_to_char(
+                            elements,
+                            start.elem_index,
+                            start.offset,
+                            ann_idx,
+                            style.start_type(),
+                            true,
+                        );
+
+                        true
+                    }
+                }
+            })
+    }
+
+    fn get_id_at_pos(&self, pos: QueryResult) -> OpID {
+        let node = self.content.get_node(pos.leaf);
+        // elem_index may be > elements.len()?
+        let elem = &node.elements()[pos.elem_index];
+        assert!(pos.offset < elem.rle_len());
+        elem.id.inc(pos.offset as u32)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Span> + '_ {
+        iter::Iter::new(self)
+    }
+
+    /// Iterate the spans with only the annotations that `filter` returns true.
+    ///
+    /// It's useful to exclude the annotations that are only used internally.
+    pub fn iter_with_filter<'a>(
+        &'a self,
+        filter: impl Fn(&Annotation) -> bool + 'a,
+    ) -> impl Iterator<Item = Span> + 'a {
+        iter::Iter::new(self).with_filter(filter)
+    }
+
+    pub fn get_spans(&self) -> Vec<Span> {
+        self.iter().collect()
+    }
+
+    /// Get the spans with the annotations that style them, including their
+    /// behaviors and expands, e.g. for the clients to decide whether the text
+    /// typed at the edge of a span joins its styles.
+    ///
+    /// The spans are split where the annotations change, even if the
+    /// attributes stay the same.
+    pub fn get_spans_with_marks(&self) -> Vec<MarkedSpan> {
+        let mut style = self.init_styles.clone();
+        let mut ans: Vec<MarkedSpan> = Vec::new();
+        for elem in self.content.iter() {
+            style.apply_start(&elem.anchor_set);
+            if !elem.is_dead() {
+                let marks = style.calc_marks(&self.ann);
+                let insert = bytes_to_str(&elem.string);
+                match ans.last_mut() {
+                    Some(last) if last.marks == marks => last.insert.push_str(insert),
+                    _ => ans.push(MarkedSpan {
+                        insert: insert.to_string(),
+                        marks,
+                    }),
+                }
+            }
+            style.apply_end(&elem.anchor_set);
+        }
+
+        ans
+    }
+
+    /// Register a derived annotation of `type_` computed by `deriver`, e.g. to
+    /// autodetect the urls. It replaces the deriver registered for the type.
+    ///
+    /// The derived annotations are never stored or synced. They only appear in
+    /// [`RichText::get_spans_with_derived`].
+    pub fn register_derived_annotation(&mut self, type_: &str, deriver: Deriver) {
+        let type_: InternalString = type_.into();
+        self.derived.retain(|(x, _)| *x != type_);
+        self.derived.push((type_, deriver));
+    }
+
+    pub fn unregister_derived_annotation(&mut self, type_: &str) {
+        let type_: InternalString = type_.into();
+        self.derived.retain(|(x, _)| *x != type_);
+    }
+
+    /// Get the spans with the derived annotations. The stored annotations win
+    /// over the derived annotations of the same type.
+    pub fn get_spans_with_derived(&self) -> Vec<Span> {
+        apply_derived(self.get_spans(), &self.derived)
+    }
+
+    /// Export the text and its styles as plain JSON, see [`DocumentJson`]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&DocumentJson::from_doc(self)).unwrap()
+    }
+
+    /// Create a new document from the output of [`RichText::to_json`]. The
+    /// history is not restored.
+    pub fn from_json(client_id: u64, json: &str) -> Result<Self, Error> {
+        let doc: DocumentJson =
+            serde_json::from_str(json).map_err(|e| Error::DecodeError(e.to_string()))?;
+        doc.to_doc(client_id)
+    }
+
+    /// Write the document to `w` in the format.
+    ///
+    /// The text and the spans are written piece by piece without building the
+    /// whole output in memory. The snapshot is still encoded in memory first,
+    /// because the columnar encoding needs all the ops.
+    pub fn write_to(&self, mut w: impl std::io::Write, format: ExportFormat) -> Result<(), Error> {
+        match format {
+            ExportFormat::Text => {
+                for elem in self.content.iter() {
+                    if !elem.is_dead() {
+                        w.write_all(&elem.string)?;
+                    }
+                }
+            }
+            ExportFormat::Spans => {
+                w.write_all(b"[")?;
+                for (i, span) in self.iter().enumerate() {
+                    if i > 0 {
+                        w.write_all(b",")?;
+                    }
+                    serde_json::to_writer(&mut w, &span).map_err(std::io::Error::from)?;
+                }
+                w.write_all(b"]")?;
+            }
+            ExportFormat::Snapshot => {
+                w.write_all(&self.try_export(&Default::default())?)?;
+            }
+        }
+
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Get the visible text and the map from its byte offsets to the ids of the
+    /// chars, so the positions can be resolved later by [`RichText::index_of_id`].
+    pub fn to_string_with_source_map(&self) -> (String, SourceMap) {
+        let mut text = String::with_capacity(self.len());
+        let mut map = SourceMap::default();
+        for elem in self.content.iter() {
+            if elem.is_dead() {
+                continue;
+            }
+
+            map.push(text.len(), elem.id, elem.string.len());
+            text.push_str(bytes_to_str(&elem.string));
+        }
+
+        (text, map)
+    }
+
+    /// Get the current index of the char with the id. Return `None` if the char
+    /// is deleted, unknown, or the id is not at the start of a char.
+    pub fn index_of_id(&self, id: OpID, index_type: IndexType) -> Option<usize> {
+        self.cursor_map.get_insert(id)?;
+        let path = self.find_cursor(id);
+        let elem = &self.content.get_node(path.leaf).elements()[path.elem_index];
+        if elem.is_dead() || !bytes_to_str(&elem.string).is_char_boundary(path.offset) {
+            return None;
+        }
+
+        Some(self.get_index_from_path(path, index_type))
+    }
+
+    /// Find where the op is in the current document, e.g. to jump to the text
+    /// that a comment refers to, even if the text is deleted.
+    ///
+    /// The id of a text insertion can be the id of any char of it. Return
+    /// `None` for the deletions and the unknown ids.
+    pub fn locate_op(&self, id: OpID, index_type: IndexType) -> Option<OpLocation> {
+        if let Some(ann) = self.ann.get_ann_by_id(id) {
+            let range = self.annotation_ranges(&[ann], index_type).remove(&id)?;
+            return Some(OpLocation::Annotation(range));
+        }
+
+        self.cursor_map.get_insert(id)?;
+        let mut path = self.find_cursor(id);
+        let elem = &self.content.get_node(path.leaf).elements()[path.elem_index];
+        let s = bytes_to_str(&elem.string);
+        while !s.is_char_boundary(path.offset) {
+            path.offset -= 1;
+        }
+
+        let dead = elem.is_dead();
+        let index = self.get_index_from_path(path, index_type);
+        Some(if dead {
+            OpLocation::Deleted(index)
+        } else {
+            OpLocation::Visible(index)
+        })
+    }
+
+    /// Serialize the spans to a compact JSON for the server-side rendering.
+    /// See [`SerializedSpans`] for the schema.
+    pub fn get_spans_serialized(&self) -> String {
+        serde_json::to_string(&SerializedSpans::from_spans(&self.get_spans())).unwrap()
+    }
+
+    /// Take an immutable snapshot of the visible content, which can be read
+    /// by other threads while this document keeps being edited.
+    ///
+    /// The snapshot doesn't share the structures of the document: it copies
+    /// all the visible spans, so it costs O(n) in time and memory like
+    /// [RichText::get_spans]. Clone the returned [Snapshot] to share it, which
+    /// is cheap.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.get_spans(), self.version())
+    }
+
+    /// A hash of the visible text and its resolved annotations.
+    ///
+    /// It doesn't depend on the history or the platform, so peers can compare
+    /// it to check whether they have converged.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = hash::StableHasher::default();
+        let mut last: Option<Span> = None;
+        for span in self.iter() {
+            if let Some(last) = last.as_mut() {
+                if last.attributes == span.attributes {
+                    last.insert.push_str(&span.insert);
+                    continue;
+                }
+            }
+
+            if let Some(last) = last.replace(span) {
+                hasher.write_span(&last);
+            }
+        }
+
+        if let Some(last) = last {
+            hasher.write_span(&last);
+        }
+
+        hasher.finish()
+    }
+
+    pub fn iter_range(&self, _range: impl RangeBounds<usize>) {
+        todo!()
+    }
+
+    pub fn len(&self) -> usize {
+        self.content.root_cache().len as usize
+    }
+
+    pub fn len_utf16(&self) -> usize {
+        self.content.root_cache().utf16_len as usize
+    }
+
+    /// Move a utf16 index that falls between the two code units of a surrogate
+    /// pair to the start (or the end if `round_up`) of the char, so the edits,
+    /// the slices and the events never split a char.
+    fn align_index(&self, index: usize, index_type: IndexType, round_up: bool) -> usize {
+        if index_type == IndexType::Utf8 || index == 0 || index >= self.len_with(index_type) {
+            return index;
+        }
+
+        // utf16 indexes inside a char are rounded up to the end of the char
+        let utf8 = self.convert_index(index, IndexType::Utf16, IndexType::Utf8);
+        let aligned = self.convert_index(utf8, IndexType::Utf8, IndexType::Utf16);
+        if aligned == index || round_up {
+            aligned
+        } else {
+            aligned - 2
+        }
+    }
+
+    fn len_with(&self, index_type: IndexType) -> usize {
+        match index_type {
+            IndexType::Utf8 => self.content.root_cache().len as usize,
+            IndexType::Utf16 => self.content.root_cache().utf16_len as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn utf16_len(&self) -> usize {
+        self.content.root_cache().utf16_len as usize
+    }
+
+    /// Export the updates that are not included in `vv`.
+    ///
+    /// The output only depends on the ops and the document id, so the
+    /// documents with the same ops export the same bytes no matter how they
+    /// received the ops, e.g. for the backups to dedupe identical blobs. The
+    /// encrypted updates depend on the [`Cipher`] too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offloaded history cannot be loaded from the op log storage.
+    /// Use [`RichText::try_export`] to handle the error.
+    pub fn export(&self, vv: &VersionVector) -> Vec<u8> {
+        self.try_export(vv)
+            .expect("failed to load the history from the op log storage")
+    }
+
+    pub fn try_export(&self, vv: &VersionVector) -> Result<Vec<u8>, Error> {
+        Ok(encode_updates(
+            self.store.export(vv)?,
+            self.doc_id,
+            self.cipher.as_deref(),
+        ))
+    }
+
+    /// Export the updates like [`RichText::try_export`] in an older
+    /// [`FormatVersion`], so the peers on the previous releases can still
+    /// import them during a rolling upgrade.
+    pub fn export_as(&self, vv: &VersionVector, version: FormatVersion) -> Result<Vec<u8>, Error> {
+        Ok(encode_updates_as(
+            self.store.export(vv)?,
+            self.doc_id,
+            self.cipher.as_deref(),
+            version,
+        ))
+    }
+
+    /// The updates that the peer at `peer_vv` is missing, or `None` if it's up
+    /// to date, e.g. for a server rebroadcasting a hot document to many peers
+    /// with different versions.
+    ///
+    /// The ops of every client are sorted by their counters, and the
+    /// offloaded chunks by their counter ranges, so the missing ops are found
+    /// by binary search instead of scanning the history. The up-to-date peers
+    /// are answered without encoding anything.
+    pub fn updates_for(&self, peer_vv: &VersionVector) -> Result<Option<Vec<u8>>, Error> {
+        if !self.store.has_ops_after(peer_vv) {
+            return Ok(None);
+        }
+
+        self.try_export(peer_vv).map(Some)
+    }
+
+    /// Export the updates like [`RichText::export`], but the annotations that
+    /// don't pass the `filter` are replaced by placeholders.
+    ///
+    /// It's useful to share the text and formatting without leaking private
+    /// annotations, e.g. `|ann| &*ann.type_ != "comment"`.
+    ///
+    /// The placeholders keep the op ids of the excluded annotations, so the
+    /// receiver can still apply the following ops. But the receiver treats the
+    /// excluded annotations as seen. It will never get them even if it imports
+    /// the unfiltered updates later.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offloaded history cannot be loaded from the op log storage.
+    /// Use [`RichText::try_export_with_filter`] to handle the error.
+    pub fn export_with_filter(
+        &self,
+        vv: &VersionVector,
+        filter: impl Fn(&Annotation) -> bool,
+    ) -> Vec<u8> {
+        self.try_export_with_filter(vv, filter)
+            .expect("failed to load the history from the op log storage")
+    }
+
+    pub fn try_export_with_filter(
+        &self,
+        vv: &VersionVector,
+        filter: impl Fn(&Annotation) -> bool,
+    ) -> Result<Vec<u8>, Error> {
+        let mut exported = self.store.export(vv)?;
+        for ops in exported.values_mut() {
+            for op in ops.iter_mut() {
+                if let OpContent::Ann(ann) = &op.content {
+                    if !filter(ann) {
+                        op.content = OpContent::Placeholder;
+                    }
+                }
+            }
+        }
+
+        Ok(encode_updates(
+            exported,
+            self.doc_id,
+            self.cipher.as_deref(),
+        ))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(len = data.len()))
+    )]
+    pub fn import(&mut self, data: &[u8]) -> Result<(), Error> {
+        let (doc_id, mut updates) = decode_updates(data, self.cipher.as_deref())?;
+        self.check_doc_id(doc_id)?;
+        self.validate_updates(&mut updates)?;
+        self.import_inner(updates, &ImportOptions::default());
+        Ok(())
+    }
+
+    /// Import the updates like [`RichText::import`] with the options.
+    ///
+    /// The updates are applied in one transaction, so there is at most one
+    /// event for them.
+    pub fn import_with_options(
+        &mut self,
+        data: &[u8],
+        options: &ImportOptions,
+    ) -> Result<(), Error> {
+        let (doc_id, mut updates) = decode_updates(data, self.cipher.as_deref())?;
+        self.check_doc_id(doc_id)?;
+        self.validate_updates(&mut updates)?;
+        self.import_inner(updates, options);
+        Ok(())
+    }
+
+    /// Import many updates at once, e.g. a backlog of small updates from a
+    /// relay. They are decoded one by one and applied in a single pass, so the
+    /// listeners get at most one event for all of them.
+    ///
+    /// `on_progress` is called with the number of the decoded updates and the
+    /// total after every update is decoded. If an update cannot be decoded or
+    /// applied, none of the updates are applied.
+    pub fn import_many<T: AsRef<[u8]>>(
+        &mut self,
+        updates: impl IntoIterator<Item = T>,
+        mut on_progress: impl FnMut(usize, usize),
+    ) -> Result<(), Error> {
+        let updates: Vec<T> = updates.into_iter().collect();
+        let mut merged: FxHashMap<ClientID, Vec<Op>> = Default::default();
+        for (i, data) in updates.iter().enumerate() {
+            let (doc_id, decoded) = decode_updates(data.as_ref(), self.cipher.as_deref())?;
+            self.check_doc_id(doc_id)?;
+            for (client, ops) in decoded {
+                merged.entry(client).or_default().extend(ops);
+            }
+
+            on_progress(i + 1, updates.len());
+        }
+
+        // the updates can overlap, so only the ops that are not covered by the
+        // version or by the other updates are kept
+        let vv = self.store.vv();
+        for (client, ops) in merged.iter_mut() {
+            ops.sort_by_key(|op| op.id.counter);
+            let mut end = vv.vv.get(client).copied().unwrap_or(0);
+            let mut new_ops = Vec::with_capacity(ops.len());
+            for op in ops.drain(..) {
+                let op_end = op.id.counter + op.rle_len() as Counter;
+                if op_end <= end {
+                    continue;
+                }
+
+                if op.id.counter < end {
+                    new_ops.push(op.slice((end - op.id.counter) as usize..));
+                } else {
+                    new_ops.push(op);
+                }
+                end = op_end;
+            }
+
+            *ops = new_ops;
+        }
+
+        merged.retain(|_, ops| !ops.is_empty());
+        self.validate_updates(&mut merged)?;
+        self.import_inner(merged, &ImportOptions::default());
+        Ok(())
+    }
+
+    /// Report what [`RichText::import`] would change without changing the
+    /// document, e.g. to enforce a policy before the updates are applied.
+    ///
+    /// The report is computed from the decoded ops and the current version,
+    /// so it only reads the deleted ranges of the document. It fails in the
+    /// same cases as the import.
+    pub fn import_preview(&self, data: &[u8]) -> Result<ImportReport, Error> {
+        let (doc_id, mut updates) = decode_updates(data, self.cipher.as_deref())?;
+        self.check_doc_id(doc_id)?;
+        self.validate_updates(&mut updates)?;
+        let vv = self.store.vv();
+        let mut report = ImportReport::default();
+        let mut inserted: FxHashMap<ClientID, Vec<Range<usize>>> = Default::default();
+        let mut deleted: FxHashMap<ClientID, Vec<Range<usize>>> = Default::default();
+        for (&client, ops) in updates.iter() {
+            // the ops after a gap are pending, and the known ones are skipped
+            let mut end = vv.vv.get(&client).copied().unwrap_or(0);
+            for op in ops.iter() {
+                let op_end = op.id.counter + op.rle_len() as Counter;
+                if op.id.counter > end {
+                    break;
+                }
+
+                if op_end <= end {
+                    continue;
+                }
+
+                let op = op.slice((end - op.id.counter) as usize..);
+                report.new_ops += op.rle_len();
+                match &op.content {
+                    OpContent::Text(insert) => {
+                        report.text_growth += insert.text.len() as isize;
+                        inserted
+                            .entry(client)
+                            .or_default()
+                            .push(end as usize..op_end as usize);
+                    }
+                    OpContent::Del(del) => {
+                        let del = del.positive();
+                        let start = del.start.counter as usize;
+                        deleted
+                            .entry(del.start.client)
+                            .or_default()
+                            .push(start..start + del.len as usize);
+                    }
+                    OpContent::Ann(_) => report.annotation_changes += 1,
+                    OpContent::Placeholder | OpContent::Custom(_) => {}
+                }
+                end = op_end;
+            }
+        }
+
+        for (client, mut ranges) in deleted {
+            ranges.sort_by_key(|x| x.start);
+            let new_text = inserted.get(&client).map_or(&[][..], |x| &x[..]);
+            // the text deleted by several ops is counted once
+            let mut last_end = 0;
+            for range in ranges {
+                let range = range.start.max(last_end)..range.end;
+                if range.is_empty() {
+                    continue;
+                }
 
-                        assert!(end.elem_index > start.elem_index);
-                        ann::insert_anchor_to_char(
-                            elements,
-                            end.elem_index,
-                            end.offset,
-                            ann_idx,
-                            style.end_type(),
-                            false,
-                        );
-                        ann::insert_anchor_to_char(
-                            elements,
-                            start.elem_index,
-                            start.offset,
-                            ann_idx,
-                            style.start_type(),
-                            true,
-                        );
+                last_end = range.end;
+                for x in new_text.iter() {
+                    let overlap = x.start.max(range.start)..x.end.min(range.end);
+                    report.text_growth -= overlap.len() as isize;
+                }
 
-                        true
-                    }
+                for known in subtract_ranges(range, new_text) {
+                    let id = OpID::new(client, known.start as Counter);
+                    report.text_growth -= self.alive_len_in_id_range(id, known.len()) as isize;
                 }
-            })
-    }
+            }
+        }
 
-    fn get_id_at_pos(&self, pos: QueryResult) -> OpID {
-        let node = self.content.get_node(pos.leaf);
-        // elem_index may be > elements.len()?
-        let elem = &node.elements()[pos.elem_index];
-        assert!(pos.offset < elem.rle_len());
-        elem.id.inc(pos.offset as u32)
+        Ok(report)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = Span> + '_ {
-        iter::Iter::new(self)
-    }
+    /// The length of the alive text with the ids in the range in utf8 bytes
+    fn alive_len_in_id_range(&self, mut id: OpID, mut len: usize) -> usize {
+        let mut ans = 0;
+        while len > 0 {
+            let (leaf, leaf_len) = self.cursor_map.get_insert(id).unwrap();
+            let leaf_len = leaf_len.min(len);
+            for elem in self.content.get_node(leaf).elements() {
+                if !elem.overlap(id, leaf_len) || elem.is_dead() {
+                    continue;
+                }
 
-    pub fn get_spans(&self) -> Vec<Span> {
-        self.iter().collect()
-    }
+                let start = id.counter.max(elem.id.counter);
+                let end = (id.counter + leaf_len as Counter)
+                    .min(elem.id.counter + elem.rle_len() as Counter);
+                ans += (end - start) as usize;
+            }
 
-    pub fn iter_range(&self, _range: impl RangeBounds<usize>) {
-        todo!()
-    }
+            id = id.inc(leaf_len as Counter);
+            len -= leaf_len;
+        }
 
-    pub fn len(&self) -> usize {
-        self.content.root_cache().len as usize
+        ans
     }
 
-    pub fn len_utf16(&self) -> usize {
-        self.content.root_cache().utf16_len as usize
-    }
+    /// Check that every text op that will be applied only refers to the known
+    /// ops, so the import either fails without changing the document or succeeds.
+    ///
+    /// The ops of every client are sorted by their counters.
+    fn validate_updates(&self, updates: &mut FxHashMap<ClientID, Vec<Op>>) -> Result<(), Error> {
+        let mut known = self.store.vv();
+        for (client, ops) in updates.iter_mut() {
+            ops.sort_by_key(|op| op.id.counter);
+            let end = known.vv.entry(*client).or_default();
+            for op in ops.iter() {
+                if op.id.counter <= *end {
+                    *end = (*end).max(op.id.counter + op.rle_len() as Counter);
+                }
+            }
+        }
 
-    fn len_with(&self, index_type: IndexType) -> usize {
-        match index_type {
-            IndexType::Utf8 => self.content.root_cache().len as usize,
-            IndexType::Utf16 => self.content.root_cache().utf16_len as usize,
+        self.check_import_limits(updates)?;
+        for op in updates.values().flatten() {
+            if !known.includes(op.id) {
+                // it will be pending
+                continue;
+            }
+
+            match &op.content {
+                OpContent::Text(insert) => {
+                    known.check_dep(insert.left)?;
+                    known.check_dep(insert.right)?;
+                }
+                OpContent::Del(del) => {
+                    let del = del.positive();
+                    known.check_dep(Some(del.start))?;
+                    known.check_dep(Some(del.start.inc(del.len as Counter - 1)))?;
+                }
+                // the annotations with unknown anchors wait for their targets
+                OpContent::Ann(_) | OpContent::Placeholder | OpContent::Custom(_) => {}
+            }
         }
-    }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+        Ok(())
     }
 
-    pub fn utf16_len(&self) -> usize {
-        self.content.root_cache().utf16_len as usize
-    }
+    /// Check the upper bound of the text and the annotations after the import
+    fn check_import_limits(&self, updates: &FxHashMap<ClientID, Vec<Op>>) -> Result<(), Error> {
+        if self.limits.max_len.is_none() && self.limits.max_annotations.is_none() {
+            return Ok(());
+        }
 
-    pub fn export(&self, vv: &VersionVector) -> Vec<u8> {
-        encode(self.store.export(vv))
-    }
+        let vv = self.store.vv();
+        let mut text_len = 0;
+        let mut anns = 0;
+        for op in updates.values().flatten() {
+            let known = vv.vv.get(&op.id.client).copied().unwrap_or(0);
+            let end = op.id.counter + op.rle_len() as Counter;
+            if end <= known {
+                continue;
+            }
+
+            match &op.content {
+                OpContent::Text(insert) => {
+                    let skip = known.saturating_sub(op.id.counter) as usize;
+                    text_len += insert.text.len().saturating_sub(skip);
+                }
+                OpContent::Ann(_) => anns += 1,
+                OpContent::Del(_) | OpContent::Placeholder | OpContent::Custom(_) => {}
+            }
+        }
 
-    pub fn import(&mut self, data: &[u8]) {
-        self.import_inner(decode(data));
+        self.check_len_limit(text_len)?;
+        self.check_annotation_limit(anns)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = ?op.id))
+    )]
     fn apply(&mut self, op: Op) -> Vec<DeltaItem> {
-        debug_log::group!("apply op");
         let mut ans = Vec::new();
         let has_listener = self.has_listener();
         'apply: {
             match &op.content {
                 OpContent::Ann(ann) => {
+                    if self.pruned_anns.contains(&ann.id) {
+                        break 'apply;
+                    }
+
+                    if !self.is_resolved(ann) {
+                        self.unresolved_anns.push(ann.clone());
+                        break 'apply;
+                    }
+
+                    if !self.is_valid_value(&ann.type_, &ann.value, ann.behavior) {
+                        self.invalid_annotations.push(ann.id);
+                    }
+
                     let ann_idx = self.ann.register(ann.clone());
                     let mut start = 0;
                     match ann.range.start.id {
@@ -950,10 +2997,15 @@ impl RichText {
                         });
                     }
                     if has_listener {
-                        let mut attributes: FxHashMap<_, _> = Default::default();
-                        attributes.insert(ann.type_.to_string(), ann.value.clone());
-                        ans.push(DeltaItem::retain_with_attributes(end - start, attributes));
+                        if self.value_modes.contains_key(&ann.type_) {
+                            ans = self.merged_value_delta(start..end, &ann.type_, ann.id);
+                        } else {
+                            let mut attributes: FxHashMap<_, _> = Default::default();
+                            attributes.insert(ann.type_.to_string(), ann.value.clone());
+                            ans.push(self.retain_with_annotation(end - start, attributes, ann.id));
+                        }
                     }
+                    self.detect_annotation_conflicts(ann);
                 }
                 OpContent::Text(text) => {
                     let right = match self.find_right(text, &op) {
@@ -1021,10 +3073,24 @@ impl RichText {
                     let del = del.positive();
                     self.delete_in_id_range(del.start, del.len as usize, &mut ans)
                 }
+                OpContent::Custom(custom) => {
+                    if &*custom.kind == PRUNE_ANNOTATIONS_KIND {
+                        ans = self.prune_annotations(custom.payload.chunks_exact(12).map(|x| {
+                            OpID::new(
+                                u64::from_le_bytes(x[..8].try_into().unwrap()),
+                                u32::from_le_bytes(x[8..].try_into().unwrap()),
+                            )
+                        }));
+                    }
+
+                    if let Some(handler) = self.op_kinds.get_mut(&custom.kind) {
+                        handler(custom);
+                    }
+                }
+                OpContent::Placeholder => {}
             }
         }
 
-        debug_log::group_end!();
         ans
     }
 
@@ -1108,116 +3174,502 @@ impl RichText {
                         }));
                     }
 
-                    match self.cmp_right_parent_pos(o_right_parent, elt_right_parent.unwrap()) {
-                        Ordering::Less => {
-                            scanning = true;
-                        }
-                        Ordering::Equal if o_slice.elem.id.client > op.id.client => {
-                            break;
-                        }
-                        _ => {
-                            scanning = false;
-                        }
-                    }
+                    match self.cmp_right_parent_pos(o_right_parent, elt_right_parent.unwrap()) {
+                        Ordering::Less => {
+                            scanning = true;
+                        }
+                        Ordering::Equal if o_slice.elem.id.client > op.id.client => {
+                            break;
+                        }
+                        _ => {
+                            scanning = false;
+                        }
+                    }
+                }
+            }
+
+            if !scanning {
+                // set before to the last element
+                let mut path = *o_slice.path();
+                path.offset = end_offset - 1;
+                left = Some(path);
+            }
+        }
+
+        // convert left to right
+        match left {
+            Some(left) => Some(self.content.shift_path_by_one_offset(left)),
+            None => Some(Some(scan_start)),
+        }
+    }
+
+    #[inline]
+    fn cmp_right_parent_pos(&self, a: Option<QueryResult>, b: Option<QueryResult>) -> Ordering {
+        match (a, b) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a), Some(b)) => self.content.compare_pos(a, b),
+        }
+    }
+
+    /// Create a copy of the document with a new client id, e.g. to use the
+    /// document as a template. The listeners and the settings are not copied.
+    ///
+    /// Return an error if the offloaded history cannot be loaded from the op
+    /// log storage.
+    pub fn fork(&self, client_id: ClientID) -> Result<RichText, Error> {
+        let mut ans = RichText::new(client_id);
+        ans.doc_id = self.doc_id;
+        ans.import_inner(
+            self.store.export(&Default::default())?,
+            &ImportOptions::default(),
+        );
+        Ok(ans)
+    }
+
+    /// Merge many replicas into a new document at once, e.g. to reconcile the
+    /// replicas on the server after a long offline period.
+    ///
+    /// Unlike [`RichText::merge`] in a loop, the ops are collected from the
+    /// replicas without being encoded, and they are applied in a single import.
+    ///
+    /// Return an error if the offloaded history of a replica cannot be loaded
+    /// from its op log storage.
+    pub fn merge_all(client_id: ClientID, docs: &[RichText]) -> Result<RichText, Error> {
+        let mut ans = RichText::new(client_id);
+        ans.doc_id = docs.iter().find_map(|doc| doc.doc_id);
+        let mut vv = VersionVector::default();
+        let mut updates: FxHashMap<ClientID, Vec<Op>> = Default::default();
+        for doc in docs {
+            for (client, ops) in doc.store.export(&vv)? {
+                let end = vv.vv.entry(client).or_default();
+                for op in ops {
+                    *end = (*end).max(op.id.counter + op.rle_len() as Counter);
+                    updates.entry(client).or_default().push(op);
+                }
+            }
+        }
+
+        ans.import_inner(updates, &ImportOptions::default());
+        Ok(ans)
+    }
+
+    /// Create a branch of the document, e.g. a draft that is published by
+    /// merging it back later. The branch remembers the version it's created
+    /// at, so the changes made on it can be listed by
+    /// [`RichText::changes_since_branch`].
+    ///
+    /// It's a full copy like [`RichText::fork`], which costs O(n) in time and
+    /// memory for the n ops of the history: the generic-btree is not
+    /// persistent, so the branch cannot share the content tree or the op log
+    /// with the parent. Keep the number of the live branches small for the
+    /// large documents.
+    pub fn branch(&self, client_id: ClientID) -> Result<RichText, Error> {
+        let mut ans = self.fork(client_id)?;
+        ans.branch_base = Some(self.version());
+        Ok(ans)
+    }
+
+    /// The version of the parent when the document was branched from it
+    pub fn branch_base(&self) -> Option<&VersionVector> {
+        self.branch_base.as_ref()
+    }
+
+    /// The ops that the parent didn't have when the branch was created,
+    /// including the ops merged from the other peers since then. A document
+    /// that is not a branch returns all of its ops.
+    pub fn changes_since_branch(&self) -> Result<Vec<ops::Op>, Error> {
+        match &self.branch_base {
+            Some(base) => self.export_ops(base),
+            None => self.export_ops(&Default::default()),
+        }
+    }
+
+    /// Merge data from other data into self
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn merge(&mut self, other: &Self) {
+        let vv = self.store.vv();
+        let exported = other.export(&vv);
+        let (_, exported) = decode_updates(&exported, other.cipher.as_deref()).unwrap();
+        if cfg!(debug_assertions) || cfg!(feature = "test") {
+            let expected = other.store.export(&vv).unwrap();
+            assert_eq!(exported, expected);
+        }
+
+        self.import_inner(exported, &ImportOptions::default());
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    fn import_inner(&mut self, exported: FxHashMap<ClientID, Vec<Op>>, options: &ImportOptions) {
+        let mut task = self.begin_import(exported, options);
+        while !self.import_step(&mut task, usize::MAX) {}
+    }
+
+    /// Return the task that adds the new ops to the op log and applies them
+    /// to the content by [`RichText::import_step`]
+    fn begin_import(
+        &mut self,
+        exported: FxHashMap<ClientID, Vec<Op>>,
+        options: &ImportOptions,
+    ) -> ImportTask {
+        let mut all_ops = Vec::new();
+        let mut unstored = FxHashMap::default();
+        for (client, ops) in exported {
+            let mut end = self.store.end(client);
+            let mut queue = VecDeque::new();
+            for mut op in ops {
+                let mut op = match op::CanApply::after(end, &op) {
+                    op::CanApply::Yes => op,
+                    op::CanApply::Trim(len) => {
+                        op.slice_(len as usize..);
+                        op
+                    }
+                    op::CanApply::Pending => {
+                        self.pending_ops.push(op);
+                        continue;
+                    }
+                    op::CanApply::Seen => {
+                        continue;
+                    }
+                };
+                if let OpContent::Text(insert) = &mut op.content {
+                    // copy the text into the buffer of the document, so the
+                    // inserts received in separate updates can be merged
+                    let start = self.bytes.len();
+                    self.bytes.push_slice(&insert.text);
+                    insert.text = self.bytes.slice(start..);
+                }
+                end = op.id.counter + op.rle_len() as Counter;
+                queue.push_back(op.clone());
+                all_ops.push(op);
+            }
+            if !queue.is_empty() {
+                unstored.insert(client, queue);
+            }
+        }
+        all_ops.sort_by(|a, b| a.lamport.cmp(&b.lamport));
+
+        // Handling delete ops afterwards can guarantee the causal order.
+        // Otherwise, the delete op may be applied before the insert op
+        // because of the merges of delete ops.
+        let (deletions, mut ops): (Vec<_>, Vec<_>) = all_ops
+            .into_iter()
+            .partition(|op| matches!(op.content, OpContent::Del(_)));
+        ops.extend(deletions);
+        ImportTask {
+            ops: ops.into_iter(),
+            unstored,
+            emit: options.emit_events && self.has_listener(),
+            origin: options.origin.clone(),
+            delta: Vec::new(),
+        }
+    }
+
+    /// Apply at most `budget` ops of the import. Return true when the import
+    /// is done and its event is emitted.
+    fn import_step(&mut self, task: &mut ImportTask, budget: usize) -> bool {
+        for op in task.ops.by_ref().take(budget) {
+            task.store_until(&mut self.store, op.id);
+            let new_delta = self.apply(op);
+            if task.emit {
+                task.delta = compose(std::mem::take(&mut task.delta), new_delta);
+            }
+        }
+
+        if !task.ops.as_slice().is_empty() {
+            return false;
+        }
+
+        for (_, queue) in task.unstored.drain() {
+            for op in queue {
+                self.store.insert(op);
+            }
+        }
+
+        // apply the annotations whose anchors are resolved by this import
+        let (resolved, unresolved): (Vec<_>, Vec<_>) = std::mem::take(&mut self.unresolved_anns)
+            .into_iter()
+            .partition(|ann| self.is_resolved(ann));
+        self.unresolved_anns = unresolved;
+        for ann in resolved {
+            let new_delta = self.apply(Op {
+                id: ann.id,
+                lamport: ann.range_lamport.0,
+                content: OpContent::Ann(ann),
+            });
+            if task.emit {
+                task.delta = compose(std::mem::take(&mut task.delta), new_delta);
+            }
+        }
+
+        if task.emit {
+            self.emit(Event {
+                ops: std::mem::take(&mut task.delta),
+                is_local: false,
+                index_type: self.event_index_type,
+                origin: task.origin.take(),
+            })
+        }
+
+        true
+    }
+
+    pub fn version(&self) -> VersionVector {
+        self.store.vv()
+    }
+
+    /// Run the local edits of `f` in a transaction. If `f` returns an error,
+    /// e.g. when the inserted text exceeds [`TransactOptions::max_inserted`],
+    /// or it panics, all the edits of the transaction are rolled back as if
+    /// they never happened.
+    ///
+    /// The listeners get the events of the edits after the transaction
+    /// commits, and nothing if it's rolled back. The document should not be
+    /// exported inside the transaction.
+    pub fn transact(
+        &mut self,
+        options: TransactOptions,
+        f: impl FnOnce(&mut Transaction) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        let end = self.next_id().counter;
+        let next_lamport = self.next_lamport();
+        self.staged_events = Some(Vec::new());
+        let mut guard = transaction::RollbackGuard {
+            doc: self,
+            end,
+            next_lamport,
+            committed: false,
+        };
+        let mut txn = Transaction {
+            doc: &mut *guard.doc,
+            options,
+            inserted: 0,
+        };
+        f(&mut txn)?;
+        guard.committed = true;
+        let events = guard.doc.staged_events.take().unwrap_or_default();
+        for event in events {
+            guard.doc.emit(event);
+        }
+
+        Ok(())
+    }
+
+    /// Remove the local ops from the counter `end`, and undo their edits on
+    /// the content in place: the inserted text is dropped, the deleted text
+    /// is restored, and the annotations are removed.
+    fn rollback_local(&mut self, end: Counter, next_lamport: Lamport) {
+        let removed = self.store.truncate_local(end, next_lamport);
+        if removed.is_empty() {
+            return;
+        }
+
+        let mut deleted = Vec::new();
+        let mut anns: FxHashSet<AnnIdx> = Default::default();
+        for op in removed.iter() {
+            match &op.content {
+                OpContent::Del(del) => deleted.push(del.positive()),
+                OpContent::Ann(ann) => {
+                    anns.extend(self.ann.get_idx_by_id(ann.id));
+                    self.ann.remove(ann.id);
+                }
+                _ => {}
+            }
+        }
+
+        let client = self.id();
+        let mut elements = Vec::new();
+        for elem in self.content.iter() {
+            let mut elem = elem.clone();
+            if elem.id.client == client && elem.id_last().counter >= end {
+                if elem.id.counter >= end {
+                    continue;
+                }
+
+                let _ = elem.split((end - elem.id.counter) as usize);
+            }
+
+            if !anns.is_empty() {
+                elem.anchor_set.retain(|idx| !anns.contains(&idx));
+            }
+
+            restore_deleted(elem, &deleted, &mut elements);
+        }
+
+        self.rebuild_content(elements);
+        self.init_styles.retain(|idx| !anns.contains(&idx));
+    }
+
+    /// Replace the content tree with the elements, and merge the adjacent
+    /// elements that can be merged
+    fn rebuild_content(&mut self, elements: Vec<Elem>) {
+        let cursor_map: CursorMap = Default::default();
+        let mut content: BTree<RichTreeTrait> = BTree::new();
+        content.set_listener(Some(cursor_map.gen_update_fn()));
+        let mut last: Option<Elem> = None;
+        for elem in elements {
+            if let Some(last) = last.as_mut() {
+                if last.can_merge(&elem) {
+                    last.merge_right(&elem);
+                    continue;
                 }
             }
 
-            if !scanning {
-                // set before to the last element
-                let mut path = *o_slice.path();
-                path.offset = end_offset - 1;
-                left = Some(path);
+            if let Some(last) = last.replace(elem) {
+                content.push(last);
             }
         }
 
-        // convert left to right
-        match left {
-            Some(left) => Some(self.content.shift_path_by_one_offset(left)),
-            None => Some(Some(scan_start)),
+        if let Some(last) = last {
+            content.push(last);
         }
+
+        self.content = content;
+        self.cursor_map = cursor_map;
     }
 
-    #[inline]
-    fn cmp_right_parent_pos(&self, a: Option<QueryResult>, b: Option<QueryResult>) -> Ordering {
-        match (a, b) {
-            (None, None) => Ordering::Equal,
-            (None, Some(_)) => Ordering::Greater,
-            (Some(_), None) => Ordering::Less,
-            (Some(a), Some(b)) => self.content.compare_pos(a, b),
-        }
+    /// Create a builder that allocates ids and lamports for new ops.
+    ///
+    /// See [`ops`] for more details.
+    pub fn op_builder(&self) -> ops::OpBuilder {
+        ops::OpBuilder::new(self.next_id(), self.next_lamport())
     }
 
-    /// Merge data from other data into self
-    pub fn merge(&mut self, other: &Self) {
-        let vv = self.store.vv();
-        let exported = other.export(&vv);
-        let exported = decode(&exported);
-        if cfg!(debug_assertions) || cfg!(feature = "test") {
-            let expected = other.store.export(&vv);
-            assert_eq!(exported, expected);
+    /// Export the ops that are not included in `vv` in the stable op model,
+    /// sorted by their lamports. It's the inverse of [`RichText::apply_ops`].
+    ///
+    /// The placeholders of the filtered annotations are skipped.
+    pub fn export_ops(&self, vv: &VersionVector) -> Result<Vec<ops::Op>, Error> {
+        let mut ans = Vec::new();
+        for (_, client_ops) in self.store.export(vv)? {
+            for op in client_ops {
+                let (id, lamport) = (op.id, op.lamport);
+                ans.push(match op.content {
+                    OpContent::Text(insert) => ops::Op::TextInsert(ops::TextInsert {
+                        id,
+                        lamport,
+                        left: insert.left,
+                        right: insert.right,
+                        text: std::str::from_utf8(&insert.text).unwrap().to_owned(),
+                    }),
+                    OpContent::Del(delete) => {
+                        let delete = delete.positive();
+                        ops::Op::TextDelete(ops::TextDelete {
+                            id,
+                            lamport,
+                            start: delete.start,
+                            len: delete.len as usize,
+                        })
+                    }
+                    OpContent::Ann(ann) => ops::Op::Annotate((*ann).clone()),
+                    OpContent::Placeholder | OpContent::Custom(_) => continue,
+                });
+            }
         }
 
-        self.import_inner(exported);
+        ans.sort_by_key(|op| (op.lamport(), op.id()));
+        Ok(ans)
     }
 
-    fn import_inner(&mut self, exported: FxHashMap<ClientID, Vec<Op>>) {
-        let mut all_ops = Vec::new();
-        for (_, ops) in exported {
-            for mut op in ops {
-                let op = match self.store.can_apply(&op) {
-                    op::CanApply::Yes => op,
-                    op::CanApply::Trim(len) => {
-                        op.slice_(len as usize..);
-                        op
-                    }
-                    op::CanApply::Pending => {
-                        self.pending_ops.push(op);
-                        continue;
-                    }
-                    op::CanApply::Seen => {
-                        continue;
-                    }
-                };
-                self.store.insert(op.clone());
-                all_ops.push(op);
+    /// Apply the ops built by [`ops::OpBuilder`] or constructed by other tools.
+    ///
+    /// The ops that have been applied already are skipped. It returns an error
+    /// without changing the document if an op is empty or it refers to unknown ops.
+    pub fn apply_ops(&mut self, ops: impl IntoIterator<Item = ops::Op>) -> Result<(), Error> {
+        self.check_writable()?;
+        let ops: Vec<ops::Op> = ops.into_iter().collect();
+        let mut vv = self.store.vv();
+        for op in ops.iter() {
+            if op.atom_len() == 0 {
+                return Err(Error::InvalidOp(format!("empty op {:?}", op.id())));
             }
+
+            let end = vv.vv.entry(op.id().client).or_default();
+            *end = (*end).max(op.id().counter + op.atom_len() as Counter);
         }
-        all_ops.sort_by(|a, b| a.lamport.cmp(&b.lamport));
 
-        // Handling delete ops afterwards can guarantee the causal order.
-        // Otherwise, the delete op may be applied before the insert op
-        // because of the merges of delete ops.
-        let mut deletions = Vec::new();
-        let mut delta = Vec::new();
-        for op in all_ops.iter() {
-            if let OpContent::Del(_) = &op.content {
-                deletions.push(op.clone());
-            } else {
-                let new_delta = self.apply(op.clone());
-                if self.has_listener() {
-                    delta = compose(delta, new_delta);
+        let check = |id: Option<OpID>| vv.check_dep(id);
+        for op in ops.iter() {
+            match op {
+                ops::Op::TextInsert(insert) => {
+                    check(insert.left)?;
+                    check(insert.right)?;
+                }
+                ops::Op::TextDelete(delete) => {
+                    check(Some(delete.start))?;
+                    check(Some(delete.start.inc(delete.len as Counter - 1)))?;
+                }
+                ops::Op::Annotate(ann) => {
+                    check(ann.range.start.id)?;
+                    check(ann.range.end.id)?;
+                    self.check_value(&ann.type_, &ann.value, ann.behavior)?;
                 }
             }
         }
 
-        for op in deletions {
-            let new_delta = self.apply(op);
-            if self.has_listener() {
-                delta = compose(delta, new_delta);
-            }
+        let mut updates: FxHashMap<ClientID, Vec<Op>> = Default::default();
+        for op in ops {
+            let (id, lamport) = (op.id(), op.lamport());
+            let content = match op {
+                ops::Op::TextInsert(insert) => {
+                    let start = self.bytes.len();
+                    self.bytes.push_str(&insert.text);
+                    OpContent::new_insert(insert.left, insert.right, self.bytes.slice(start..))
+                }
+                ops::Op::TextDelete(delete) => {
+                    OpContent::new_delete(delete.start, delete.len as i32)
+                }
+                ops::Op::Annotate(ann) => OpContent::new_ann(Arc::new(ann)),
+            };
+            updates.entry(id.client).or_default().push(Op {
+                id,
+                lamport,
+                content,
+            });
         }
 
-        if self.has_listener() {
-            self.emit(Event {
-                ops: delta,
-                is_local: false,
-                index_type: self.event_index_type,
-            })
+        for ops in updates.values_mut() {
+            ops.sort_by_key(|op| op.id.counter);
         }
+
+        self.check_import_limits(&updates)?;
+        self.import_inner(updates, &ImportOptions::default());
+        Ok(())
     }
 
-    pub fn version(&self) -> VersionVector {
-        self.store.vv()
+    /// Create a new history where the ops of the client `old` are made by
+    /// `new`, e.g. to attribute the edits made anonymously to the user after
+    /// logging in.
+    ///
+    /// It's a migration rather than an edit: the new history shares no ops
+    /// with the old one, so the documents with the old history must not be
+    /// merged with it. Every peer should either run the same migration, which
+    /// gives the same result, or replace its document with the migrated one.
+    /// The concurrent inserts ordered by their client ids may be reordered.
+    ///
+    /// The returned document uses `new` if this document uses `old`. The
+    /// settings like the limits and the listeners are not copied.
+    pub fn remap_client(&self, old: ClientID, new: ClientID) -> Result<RichText, Error> {
+        let vv = self.store.vv();
+        if old == new || vv.vv.get(&new).copied().unwrap_or(0) > 0 {
+            return Err(Error::InvalidOp(format!(
+                "cannot remap client {} to {}, which already has ops",
+                old, new
+            )));
+        }
+
+        let mut ops = self.export_ops(&Default::default())?;
+        for op in ops.iter_mut() {
+            op.remap_client(old, new);
+        }
+
+        let id = if self.id() == old { new } else { self.id() };
+        let mut ans = RichText::new(id);
+        ans.set_doc_id(self.doc_id());
+        ans.apply_ops(ops)?;
+        Ok(ans)
     }
 
     fn delete_in_id_range(&mut self, mut id: OpID, mut len: usize, ans: &mut Vec<DeltaItem>) {
@@ -1361,6 +3813,359 @@ impl RichText {
         self.store.next_lamport()
     }
 
+    /// Register the handler of the custom ops of `kind` received from the
+    /// peers. The one registered for the same kind is replaced.
+    ///
+    /// The ops of the kinds without a handler are still kept and exported, so
+    /// the peers that don't know the kind relay them unchanged.
+    pub fn register_op_kind(&mut self, kind: &str, handler: OpKindHandler) {
+        self.op_kinds.insert(kind.into(), handler);
+    }
+
+    pub fn unregister_op_kind(&mut self, kind: &str) {
+        self.op_kinds.remove(&InternalString::from(kind));
+    }
+
+    /// Create a custom op with an opaque payload. It's exported with the other
+    /// ops in [`FormatVersion::V3`] and later, and the handler of its kind is
+    /// called on the peers that import it. The local handler isn't called.
+    ///
+    /// The older versions export it as a placeholder, so it's lost there.
+    pub fn insert_custom_op(&mut self, kind: &str, payload: &[u8]) -> Result<OpID, Error> {
+        self.check_writable()?;
+        let id = self.next_id();
+        self.store
+            .insert_local(OpContent::Custom(Arc::new(CustomOp {
+                id,
+                lamport: self.next_lamport(),
+                kind: kind.into(),
+                payload: payload.into(),
+            })));
+        Ok(id)
+    }
+
+    /// The custom ops of `kind` from all the clients, sorted by the lamport.
+    /// The offloaded ops are loaded from the op log storage.
+    pub fn custom_ops(&self, kind: &str) -> Result<Vec<Arc<CustomOp>>, Error> {
+        let mut ans: Vec<Arc<CustomOp>> = self
+            .store
+            .export(&Default::default())?
+            .into_values()
+            .flatten()
+            .filter_map(|op| match op.content {
+                OpContent::Custom(custom) if &*custom.kind == kind => Some(custom),
+                _ => None,
+            })
+            .collect();
+        ans.sort_by_key(|x| (x.lamport, x.id));
+        Ok(ans)
+    }
+
+    /// Count the ops of the op log. The ops offloaded to the op log storage
+    /// are not counted in `ops`.
+    pub fn op_log_stats(&self) -> OpLogStats {
+        OpLogStats {
+            ops: self.store.op_len(),
+            atoms: self.store.vv().vv.values().map(|&x| x as usize).sum(),
+        }
+    }
+
+    /// Summarize the ops of every client for the audit trails and the
+    /// contributor statistics, sorted by the client id.
+    ///
+    /// The chars are counted over the whole history, so the chars that were
+    /// deleted later are still counted in `chars_inserted`. The offloaded ops
+    /// are loaded from the op log storage.
+    pub fn client_activity(&self) -> Result<Vec<ClientSummary>, Error> {
+        let ops = self.store.export(&Default::default())?;
+        let mut ans: Vec<ClientSummary> = ops
+            .iter()
+            .map(|(client, client_ops)| {
+                let mut summary = ClientSummary {
+                    client: *client,
+                    ops: client_ops.len(),
+                    ..Default::default()
+                };
+                for op in client_ops.iter() {
+                    match &op.content {
+                        OpContent::Text(insert) => {
+                            summary.chars_inserted += count_chars(&insert.text)
+                        }
+                        OpContent::Del(del) => {
+                            summary.chars_deleted += count_deleted_chars(&ops, del.positive())
+                        }
+                        OpContent::Ann(_) => summary.annotations += 1,
+                        OpContent::Placeholder | OpContent::Custom(_) => {}
+                    }
+                }
+
+                summary
+            })
+            .collect();
+        ans.sort_by_key(|x| x.client);
+        Ok(ans)
+    }
+
+    /// Report the approximate memory usage of the document.
+    ///
+    /// It walks through the whole content tree, so it should not be called in
+    /// hot paths.
+    pub fn memory_breakdown(&self) -> MemoryBreakdown {
+        let elem_size = std::mem::size_of::<Elem>() + std::mem::size_of::<rich_tree::ElemInner>();
+        let mut dead_elems = 0;
+        let mut dead_bytes = 0;
+        let mut alive_elems = 0;
+        for elem in self.content.iter() {
+            if elem.is_dead() {
+                dead_elems += 1;
+                if !self.cold.contains(elem.id) {
+                    dead_bytes += elem.string.len();
+                }
+            } else {
+                alive_elems += 1;
+            }
+        }
+
+        MemoryBreakdown {
+            text: self.bytes.len().saturating_sub(dead_bytes),
+            tombstones: dead_bytes + dead_elems * elem_size + self.cold.mem_size(),
+            annotations: self.ann.mem_size(),
+            op_log: self.store.mem_size() + self.pending_ops.capacity() * std::mem::size_of::<Op>(),
+            indexes: alive_elems * elem_size
+                + self.content.node_len() * std::mem::size_of::<rich_tree::Cache>()
+                + self.cursor_map.mem_size(),
+        }
+    }
+
+    /// Move the old ops out of memory into the op log storage.
+    ///
+    /// The last op of every client is kept in memory. The offloaded ops are
+    /// loaded again only when they are needed by an export. Return the number
+    /// of the offloaded ops.
+    pub fn offload_history(&mut self) -> Result<usize, Error> {
+        self.store.offload()
+    }
+
+    /// Replace the op log storage, which is [`storage::MemoryStorage`] by default.
+    ///
+    /// The ops that have been offloaded to the old storage are moved to the new one.
+    pub fn set_op_log_storage(
+        &mut self,
+        storage: Box<dyn storage::OpLogStorage>,
+    ) -> Result<(), Error> {
+        self.store.set_storage(storage)
+    }
+
+    /// Set the policy used by [`RichText::maybe_compact`].
+    pub fn set_compaction_policy(&mut self, policy: CompactionPolicy) {
+        self.compaction = Some(Compaction {
+            policy,
+            last_op_atoms: 0,
+        });
+    }
+
+    /// Compact the document if the thresholds of the compaction policy are reached.
+    ///
+    /// It's cheap when the thresholds are not reached, so the host can call it
+    /// periodically. Returns whether the compaction is performed.
+    pub fn maybe_compact(&mut self) -> bool {
+        let Some(compaction) = self.compaction else {
+            return false;
+        };
+
+        let op_atoms: usize = self.store.vv().vv.values().map(|&x| x as usize).sum();
+        if op_atoms < compaction.last_op_atoms + compaction.policy.min_ops {
+            return false;
+        }
+
+        let mut total = 0;
+        let mut dead = 0;
+        for elem in self.content.iter() {
+            total += elem.atom_len();
+            if elem.is_dead() {
+                dead += elem.atom_len();
+            }
+        }
+
+        if total == 0 || (dead as f64) < total as f64 * compaction.policy.max_tombstone_ratio {
+            return false;
+        }
+
+        self.compact();
+        if compaction.policy.compress_tombstones && self.store.is_offloaded() {
+            self.compress_tombstones().unwrap();
+        }
+
+        self.compaction = Some(Compaction {
+            policy: compaction.policy,
+            last_op_atoms: op_atoms,
+        });
+        true
+    }
+
+    /// Rebuild the content tree and merge the adjacent elements that can be merged.
+    ///
+    /// The tombstones cannot be removed, because the ops from other peers may still
+    /// refer to them. But the elements are split by the edits and the deletions,
+    /// and they are not always merged back. Merging them reduces the memory usage
+    /// and speeds up the queries.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    fn compact(&mut self) {
+        let elements = self.content.iter().cloned().collect();
+        self.rebuild_content(elements);
+    }
+
+    /// Merge the adjacent elements in the leaves of the content tree within
+    /// `budget` steps, e.g. when the editor is idle. A step visits an element,
+    /// and at least one leaf is visited in a call.
+    ///
+    /// Returns whether the pass over the whole tree is done. Otherwise the
+    /// next call continues from where this one stopped, and the edits in
+    /// between are fine.
+    ///
+    /// When the pass finds the leaves sparse, i.e. they hold less than a
+    /// quarter of their capacity on average, and the rest of the budget covers all the
+    /// elements, the tree is rebuilt like the compaction so the leaves are
+    /// packed again.
+    pub fn defragment(&mut self, budget: usize) -> bool {
+        let resumed = self
+            .defragment
+            .next
+            .and_then(|id| self.cursor_map.get_insert(id));
+        let mut leaf = match resumed {
+            Some((leaf, _)) => leaf,
+            None => {
+                self.defragment = Defragment::default();
+                self.content.first_leaf()
+            }
+        };
+
+        let mut steps = 0;
+        loop {
+            self.content.update_leaf(leaf, |elements| {
+                steps += elements.len();
+                let len = elements.len();
+                elements.dedup_by(|next, last| {
+                    if last.can_merge(next) {
+                        last.merge_right(next);
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if elements.len() == len {
+                    return (false, None);
+                }
+
+                for elem in elements.iter() {
+                    self.cursor_map.update(MoveEvent::new_move(leaf, elem));
+                }
+                (true, None)
+            });
+
+            self.defragment.elements += self.content.get_node(leaf).elements().len();
+            self.defragment.leaves += 1;
+            match self.content.next_same_level_node(leaf) {
+                Some(next) if steps < budget => leaf = next,
+                Some(next) => {
+                    self.defragment.next =
+                        self.content.get_node(next).elements().first().map(|x| x.id);
+                    return false;
+                }
+                None => break,
+            }
+        }
+
+        let pass = std::mem::take(&mut self.defragment);
+        if pass.leaves > 1
+            && pass.elements * 4 < pass.leaves * RichTreeTrait::MAX_LEN
+            && steps + pass.elements <= budget
+        {
+            self.compact();
+        }
+
+        true
+    }
+
+    /// Compress the deleted text in memory, and drop the buffers of the text
+    /// that is no longer referenced, e.g. for the documents with a lot of
+    /// edits. Return the length of the newly compressed text in utf8 bytes.
+    ///
+    /// The deleted text is rarely read, so it's only decompressed on demand by
+    /// [`RichText::diff_overlay`] and [`RichText::style_state_at`]. The ops in
+    /// memory keep their text for the exports, so only the deleted text of
+    /// the history offloaded by [`RichText::offload_history`] is compressed.
+    /// It returns [`Error::NotOffloaded`] if the history has never been
+    /// offloaded, because there is nothing to compress.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn compress_tombstones(&mut self) -> Result<usize, Error> {
+        if !self.store.is_offloaded() {
+            return Err(Error::NotOffloaded);
+        }
+
+        let mut bytes = AppendOnlyBytes::new();
+        let op_texts = self.store.relocate_text(&mut bytes);
+        for op in self.pending_ops.iter_mut() {
+            if let OpContent::Text(insert) = &mut op.content {
+                insert.text = op::relocate(&insert.text, &mut bytes);
+            }
+        }
+
+        let mut elements = Vec::new();
+        let mut runs = Vec::new();
+        let mut run_len = 0;
+        let mut compressed = 0;
+        for elem in self.content.iter() {
+            let mut elem = elem.clone();
+            let len = elem.string.len();
+            let op_text = op_texts
+                .get(&elem.id.client)
+                .and_then(|x| x.range(..=elem.id.counter).next_back())
+                .and_then(|(&start, text)| {
+                    let offset = (elem.id.counter - start) as usize;
+                    (offset + len <= text.len()).then(|| text.slice_clone(offset..offset + len))
+                });
+            if let Some(text) = op_text {
+                elem.string = text;
+            } else if !elem.is_dead() {
+                elem.string = op::relocate(&elem.string, &mut bytes);
+            } else if !self.cold.contains(elem.id) {
+                let placeholder = self.cold.placeholder(len);
+                runs.push((elem.id, std::mem::replace(&mut elem.string, placeholder)));
+                // keep the metadata consistent with the placeholder, it's
+                // not counted by the caches of the tombstones anyway
+                elem.utf16_len = len as u32;
+                elem.line_breaks = 0;
+                elem.word_stats = WordStats::new(&elem.string);
+                run_len += len;
+                compressed += len;
+                if run_len >= cold_text::BLOCK_SIZE {
+                    self.cold.push_block(&runs);
+                    runs.clear();
+                    run_len = 0;
+                }
+            }
+
+            elements.push(elem);
+        }
+
+        self.cold.push_block(&runs);
+        self.rebuild_content(elements);
+        self.bytes = bytes;
+        Ok(compressed)
+    }
+
+    /// The text of the element, decompressed if it's a compressed tombstone
+    fn elem_text<'a>(&self, elem: &'a Elem) -> Cow<'a, str> {
+        if elem.is_dead() {
+            if let Some(text) = self.cold.get(elem.id, elem.string.len()) {
+                return Cow::Owned(String::from_utf8_lossy(&text).into_owned());
+            }
+        }
+
+        Cow::Borrowed(bytes_to_str(&elem.string))
+    }
+
     #[inline]
     #[allow(unused)]
     pub(crate) fn check(&self) {
@@ -1437,21 +4242,184 @@ impl RichText {
             finder.style_calculator,
         );
 
-        iter.collect()
+        iter.collect()
+    }
+
+    /// Get the range of the line in the document, including its trailing line break.
+    ///
+    /// Return `None` if `line >= self.lines()`.
+    pub fn get_line_range(&self, line: usize, index_type: IndexType) -> Option<Range<usize>> {
+        if line >= self.lines() {
+            return None;
+        }
+
+        let start = self.content.query::<LineStartFinder>(&line);
+        if !start.found {
+            return None;
+        }
+
+        let start = self.get_index_from_path(start, index_type);
+        let end = if line + 1 < self.lines() {
+            let end = self.content.query::<LineStartFinder>(&(line + 1));
+            self.get_index_from_path(end, index_type)
+        } else {
+            self.len_with(index_type)
+        };
+
+        Some(start..end)
+    }
+
+    /// Get the line that the `index` is in, i.e. the number of line breaks before it.
+    pub fn line_at(&self, index: usize, index_type: IndexType) -> usize {
+        let path = self.content.query::<IndexFinder>(&(index, index_type));
+        let mut count: usize = 0;
+        self.content.visit_previous_caches(path, |v| match v {
+            generic_btree::PreviousCache::NodeCache(cache) => {
+                count += cache.line_breaks as usize;
+            }
+            generic_btree::PreviousCache::PrevSiblingElem(elem) => {
+                if !elem.is_dead() {
+                    count += elem.line_breaks as usize;
+                }
+            }
+            generic_btree::PreviousCache::ThisElemAndOffset { elem, offset } => {
+                if !elem.is_dead() {
+                    count += elem.string[..offset]
+                        .iter()
+                        .filter(|&&b| b == b'\n')
+                        .count();
+                }
+            }
+        });
+        count
+    }
+
+    /// Slice the line with the line-local `range`.
+    ///
+    /// The range is clamped to the line, including its trailing line break.
+    pub fn slice_line(
+        &self,
+        line: usize,
+        range: impl RangeBounds<usize>,
+        index_type: IndexType,
+    ) -> Vec<Span> {
+        let Some(line_range) = self.get_line_range(line, index_type) else {
+            return Vec::new();
+        };
+
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => line_range.len(),
+        };
+        let start = (line_range.start + start).min(line_range.end);
+        let end = (line_range.start + end).min(line_range.end);
+        if start >= end {
+            return Vec::new();
+        }
+
+        self.slice(start..end, index_type)
+    }
+
+    /// Get the length of the line, excluding its trailing line break. It
+    /// doesn't build the string of the line, so it's cheap even for the huge
+    /// single-line documents, e.g. minified JSON.
+    ///
+    /// Return `None` if `line >= self.lines()`.
+    pub fn line_len(&self, line: usize, index_type: IndexType) -> Option<usize> {
+        self.get_line_content_range(line, index_type)
+            .map(|x| x.len())
+    }
+
+    /// Iterate over the text of the line in chunks of at most `max_len` utf8
+    /// bytes, excluding its trailing line break. The chunks are borrowed from
+    /// the document, so the line is never built as a whole.
+    ///
+    /// The chunks never split a char, so a chunk is longer than `max_len` if
+    /// `max_len` is shorter than the char. They may be shorter than `max_len`
+    /// at the boundaries of the inserted text.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `max_len` is 0
+    pub fn line_chunks(&self, line: usize, max_len: usize) -> impl Iterator<Item = &str> + '_ {
+        assert!(max_len > 0, "max_len must be positive");
+        self.get_line_content_range(line, IndexType::Utf8)
+            .into_iter()
+            .flat_map(move |range| {
+                let start = self
+                    .content
+                    .query::<IndexFinder>(&(range.start, IndexType::Utf8));
+                let end = self
+                    .content
+                    .query::<IndexFinder>(&(range.end, IndexType::Utf8));
+                self.content.iter_range(start..end)
+            })
+            .filter(|span| !span.elem.is_dead())
+            .flat_map(move |span| {
+                let s = &span.elem.string;
+                let mut rest =
+                    bytes_to_str(&s[span.start.unwrap_or(0)..span.end.unwrap_or(s.len())]);
+                std::iter::from_fn(move || {
+                    if rest.is_empty() {
+                        return None;
+                    }
+
+                    let mut end = max_len.min(rest.len());
+                    while !rest.is_char_boundary(end) {
+                        end += 1;
+                    }
+
+                    let (chunk, tail) = rest.split_at(end);
+                    rest = tail;
+                    Some(chunk)
+                })
+            })
     }
 
-    pub fn slice_str(&self, range: impl RangeBounds<usize>, index_type: IndexType) -> String {
+    fn get_line_content_range(&self, line: usize, index_type: IndexType) -> Option<Range<usize>> {
+        let mut range = self.get_line_range(line, index_type)?;
+        if line + 1 < self.lines() {
+            // the trailing line break
+            range.end -= 1;
+        }
+
+        Some(range)
+    }
+
+    /// Resolve the range bounds to a range in `index_type`.
+    ///
+    /// The range is clamped to the document, and in utf16 its ends inside
+    /// surrogate pairs are moved outwards to the char boundaries. All the
+    /// methods that take range bounds resolve them this way.
+    pub fn resolve_range(
+        &self,
+        range: impl RangeBounds<usize>,
+        index_type: IndexType,
+    ) -> Range<usize> {
+        let len = self.len_with(index_type);
         let start = match range.start_bound() {
             Bound::Included(&start) => start,
-            Bound::Excluded(&start) => start + 1,
+            Bound::Excluded(&start) => start.saturating_add(1),
             Bound::Unbounded => 0,
         };
         let end = match range.end_bound() {
-            Bound::Included(&end) => end + 1,
+            Bound::Included(&end) => end.saturating_add(1),
             Bound::Excluded(&end) => end,
-            Bound::Unbounded => self.len_with(index_type),
+            Bound::Unbounded => len,
         };
+        let end = end.min(len);
+        let start = start.min(end);
+        self.align_index(start, index_type, false)..self.align_index(end, index_type, true)
+    }
 
+    pub fn slice_str(&self, range: impl RangeBounds<usize>, index_type: IndexType) -> String {
+        let Range { start, end } = self.resolve_range(range, index_type);
         let mut ans = String::with_capacity(end - start);
         let start = self.content.query::<IndexFinder>(&(start, index_type));
         let end = self.content.query::<IndexFinder>(&(end, index_type));
@@ -1466,17 +4434,7 @@ impl RichText {
     }
 
     pub fn slice(&self, range: impl RangeBounds<usize>, index_type: IndexType) -> Vec<Span> {
-        let start = match range.start_bound() {
-            Bound::Included(&start) => start,
-            Bound::Excluded(&start) => start + 1,
-            Bound::Unbounded => 0,
-        };
-        let end = match range.end_bound() {
-            Bound::Included(&end) => end + 1,
-            Bound::Excluded(&end) => end,
-            Bound::Unbounded => self.len_with(index_type),
-        };
-
+        let Range { start, end } = self.resolve_range(range, index_type);
         let mut ans = Vec::new();
         let (start, finder) = self
             .content
@@ -1490,6 +4448,568 @@ impl RichText {
         ans
     }
 
+    /// Summarize the styles of the selection in one traversal, e.g. to show
+    /// the state of the toolbar. The types that are not in the map are not
+    /// applied to the selection.
+    ///
+    /// If the selection is empty, it returns the styles that the text inserted
+    /// at the position would have.
+    pub fn selection_styles(
+        &self,
+        range: impl RangeBounds<usize>,
+        index_type: IndexType,
+    ) -> FxHashMap<InternalString, StyleAggregate> {
+        let range = self.resolve_range(range, index_type);
+        if range.is_empty() {
+            return self
+                .get_style_at_position(range.start, index_type)
+                .map(|(type_, value)| {
+                    let aggregate = StyleAggregate {
+                        coverage: StyleCoverage::All,
+                        value,
+                        len: 0,
+                    };
+                    (type_, aggregate)
+                })
+                .collect();
+        }
+
+        // the covered length of every value of every type
+        let mut values: FxHashMap<InternalString, Vec<(Value, usize)>> = Default::default();
+        for span in self.slice(range.clone(), index_type) {
+            let len = match index_type {
+                IndexType::Utf8 => span.insert.len(),
+                IndexType::Utf16 => get_utf16_len(&span.insert),
+            };
+            for (type_, value) in span.attributes {
+                let lens = values.entry(type_).or_default();
+                match lens.iter_mut().find(|(v, _)| *v == value) {
+                    Some((_, x)) => *x += len,
+                    None => lens.push((value, len)),
+                }
+            }
+        }
+
+        values
+            .into_iter()
+            .map(|(type_, lens)| {
+                let len = lens.iter().map(|(_, len)| len).sum();
+                // the first value wins the ties, which is the leftmost one
+                let (value, _) = lens
+                    .into_iter()
+                    .reduce(|a, b| if b.1 > a.1 { b } else { a })
+                    .unwrap();
+                let coverage = if len >= range.len() {
+                    StyleCoverage::All
+                } else {
+                    StyleCoverage::Partial
+                };
+                let aggregate = StyleAggregate {
+                    coverage,
+                    value,
+                    len,
+                };
+                (type_, aggregate)
+            })
+            .collect()
+    }
+
+    /// The number of the annotations of `type_`. It can be a pattern like
+    /// `comment/*` to count all the types under a namespace, see
+    /// [`type_matches`].
+    ///
+    /// The annotations that erase the style are not counted. The annotations
+    /// whose text has been deleted are still counted.
+    pub fn count_annotations(&self, type_: &str) -> usize {
+        self.annotations_of(type_).count()
+    }
+
+    /// Get `limit` annotations of `type_` after skipping the first `offset` ones.
+    /// `type_` can be a namespace pattern like [`RichText::count_annotations`].
+    ///
+    /// It's useful for the UIs that populate long comment lists lazily. With
+    /// [`AnnotationOrder::Lamport`], only the ranges of the returned annotations
+    /// are calculated. [`AnnotationOrder::Position`] needs the ranges of all
+    /// the annotations of the type to sort them.
+    pub fn annotations_page(
+        &self,
+        type_: &str,
+        offset: usize,
+        limit: usize,
+        order_by: AnnotationOrder,
+        index_type: IndexType,
+    ) -> Vec<AnnotationRange> {
+        let mut anns: Vec<_> = self.annotations_of(type_).collect();
+        if order_by == AnnotationOrder::Lamport {
+            anns.sort_by_key(|ann| ann.range_lamport);
+            anns = anns.into_iter().skip(offset).take(limit).collect();
+        }
+
+        let mut ranges = self.annotation_ranges(&anns, index_type);
+        let mut ans: Vec<_> = anns
+            .into_iter()
+            .map(|ann| AnnotationRange {
+                range: ranges.remove(&ann.id).unwrap(),
+                annotation: ann.clone(),
+            })
+            .collect();
+        if order_by == AnnotationOrder::Position {
+            ans.sort_by_key(|x| (x.range.start, x.annotation.range_lamport));
+            ans = ans.into_iter().skip(offset).take(limit).collect();
+        }
+
+        ans
+    }
+
+    /// The annotations whose current ranges overlap the range of the given
+    /// annotation, e.g. to find the nested or overlapping comments. They are
+    /// sorted by the starts of their ranges.
+    ///
+    /// The ranges that only touch each other don't overlap. The annotations
+    /// that erase styles are excluded.
+    pub fn annotations_overlapping(&self, id: OpID) -> Vec<OpID> {
+        if self.ann.get_ann_by_id(id).is_none() {
+            return Vec::new();
+        }
+
+        let anns: Vec<_> = self
+            .ann
+            .iter()
+            .filter(|ann| ann.id == id || ann.behavior != Behavior::Delete)
+            .collect();
+        let ranges = self.annotation_ranges(&anns, IndexType::Utf8);
+        let target = &ranges[&id];
+        let mut ans: Vec<_> = anns
+            .into_iter()
+            .filter_map(|ann| {
+                let range = &ranges[&ann.id];
+                (ann.id != id && range.start.max(target.start) < range.end.min(target.end))
+                    .then_some((range.start, ann.range_lamport, ann.id))
+            })
+            .collect();
+        ans.sort_unstable();
+        ans.into_iter().map(|(_, _, id)| id).collect()
+    }
+
+    /// The changes of the annotation ordered by their lamports, starting with
+    /// its creation, e.g. to show "edited by Alice" on a comment.
+    ///
+    /// The ranges of the annotations never change, so the changes are the later
+    /// annotations of the same type that erase or override a part of it. The
+    /// annotations with [`Behavior::AllowMultiple`] only have their creations.
+    pub fn annotation_history(&self, id: OpID) -> Vec<PatchRecord> {
+        let Some(target) = self.ann.get_ann_by_id(id) else {
+            return Vec::new();
+        };
+
+        let anns: Vec<_> = self
+            .ann
+            .iter()
+            .filter(|ann| {
+                ann.id == id
+                    || (ann.type_ == target.type_
+                        && ann.range_lamport > target.range_lamport
+                        && ann.behavior != Behavior::AllowMultiple
+                        && target.behavior != Behavior::AllowMultiple)
+            })
+            .collect();
+        let ranges = self.annotation_ranges(&anns, IndexType::Utf8);
+        let range = &ranges[&id];
+        let mut patches: Vec<_> = anns
+            .into_iter()
+            .filter(|ann| {
+                let r = &ranges[&ann.id];
+                ann.id != id && r.start.max(range.start) < r.end.min(range.end)
+            })
+            .collect();
+        patches.sort_unstable_by_key(|ann| ann.range_lamport);
+        std::iter::once(PatchRecord {
+            id,
+            lamport: target.range_lamport.0,
+            kind: PatchKind::Created,
+        })
+        .chain(patches.into_iter().map(|ann| PatchRecord {
+            id: ann.id,
+            lamport: ann.range_lamport.0,
+            kind: if ann.behavior == Behavior::Delete {
+                PatchKind::Erased
+            } else {
+                PatchKind::Overridden
+            },
+        }))
+        .collect()
+    }
+
+    fn annotations_of<'a>(&'a self, type_: &'a str) -> impl Iterator<Item = &'a Arc<Annotation>> {
+        self.ann
+            .iter()
+            .filter(move |ann| type_matches(type_, &ann.type_) && ann.behavior != Behavior::Delete)
+    }
+
+    /// Calculate the current ranges of the annotations in one pass over the content
+    fn annotation_ranges(
+        &self,
+        anns: &[&Arc<Annotation>],
+        index_type: IndexType,
+    ) -> FxHashMap<OpID, Range<usize>> {
+        let len = self.len_with(index_type);
+        let mut ans: FxHashMap<OpID, Range<usize>> =
+            anns.iter().map(|ann| (ann.id, 0..len)).collect();
+        let targets: FxHashSet<AnnIdx> = anns
+            .iter()
+            .filter_map(|ann| self.ann.get_idx_by_id(ann.id))
+            .collect();
+        let mut index = 0;
+        for elem in self.content.iter() {
+            let elem_len = elem.content_len_with(index_type);
+            for (idx, type_, is_start) in elem.anchor_set.iter() {
+                if !targets.contains(&idx) {
+                    continue;
+                }
+
+                let pos = match type_ {
+                    AnchorType::Before => index,
+                    AnchorType::After => index + elem_len,
+                };
+                let id = self.ann.get_ann_by_idx(idx).unwrap().id;
+                let range = ans.get_mut(&id).unwrap();
+                if is_start {
+                    range.start = pos;
+                } else {
+                    range.end = pos;
+                }
+            }
+
+            index += elem_len;
+        }
+
+        for range in ans.values_mut() {
+            range.end = range.end.max(range.start);
+        }
+
+        ans
+    }
+
+    /// The cursors of the chars that the anchors of a new annotation on the
+    /// range are attached to, `None` for the document start or end
+    fn anchor_cursors(
+        &self,
+        range: Range<usize>,
+        expand: Expand,
+        index_type: IndexType,
+    ) -> (Option<QueryResult>, Option<QueryResult>) {
+        let (start, end) = anchor::anchor_positions_for(expand, range, self.len_with(index_type));
+        let query = |index: usize| {
+            let index = self.align_index(index, index_type, false);
+            self.content.query::<IndexFinder>(&(index, index_type))
+        };
+        (start.index.map(query), end.index.map(query))
+    }
+
+    /// Rewrite the existing annotations of `type_` to use the new behavior.
+    ///
+    /// Every annotation of the type is erased and then created again with the same
+    /// range and value but the new behavior. They are normal ops, so the other peers
+    /// converge on the new behavior after importing them.
+    ///
+    /// The parts erased by the later annotations that erase the type stay
+    /// erased: the annotations erased entirely are skipped, and the partially
+    /// erased ones are created again only on their remaining text.
+    ///
+    /// The annotations with [`Behavior::AllowMultiple`] cannot be erased, so they
+    /// cannot be migrated to other behaviors.
+    pub fn migrate_behavior(&mut self, type_: &str, behavior: Behavior) -> Result<(), Error> {
+        self.check_writable()?;
+        if behavior == Behavior::Delete {
+            return Err(Error::InvalidOp(
+                "cannot migrate annotations to the Delete behavior".into(),
+            ));
+        }
+
+        let type_ = InternalString::from(type_);
+        let mut anns: Vec<Arc<Annotation>> = self
+            .ann
+            .iter()
+            .filter(|ann| {
+                ann.type_ == type_ && ann.behavior != Behavior::Delete && ann.behavior != behavior
+            })
+            .cloned()
+            .collect();
+        if anns
+            .iter()
+            .any(|ann| ann.behavior == Behavior::AllowMultiple)
+        {
+            return Err(Error::InvalidOp(format!(
+                "cannot migrate the annotations of {} with AllowMultiple behavior",
+                type_
+            )));
+        }
+
+        anns.sort_by_key(|ann| ann.range_lamport);
+        let erases: Vec<Arc<Annotation>> = self
+            .ann
+            .iter()
+            .filter(|ann| ann.type_ == type_ && ann.behavior == Behavior::Delete)
+            .cloned()
+            .collect();
+        let ranges = self.annotation_ranges(
+            &anns.iter().chain(erases.iter()).collect::<Vec<_>>(),
+            IndexType::Utf8,
+        );
+
+        let mut builder = self.op_builder();
+        let mut ops = Vec::with_capacity(anns.len() * 2);
+        for ann in anns {
+            let range = ranges[&ann.id].clone();
+            let mut erased: Vec<Range<usize>> = erases
+                .iter()
+                .filter(|erase| erase.range_lamport > ann.range_lamport)
+                .map(|erase| ranges[&erase.id].clone())
+                .filter(|x| !x.is_empty())
+                .collect();
+            erased.sort_by_key(|x| x.start);
+            let remaining = subtract_ranges(range.clone(), &erased);
+            if !range.is_empty() && remaining.is_empty() {
+                continue;
+            }
+
+            let expand = Expand::from_anchor_types(ann.range.start.type_, ann.range.end.type_);
+            let (start, end) = (ann.range.start.id, ann.range.end.id);
+            ops.push(builder.annotate(
+                start,
+                end,
+                &Style {
+                    expand,
+                    behavior: Behavior::Delete,
+                    type_: type_.clone(),
+                    value: Value::Null,
+                },
+            ));
+            let style = Style {
+                expand,
+                behavior,
+                type_: type_.clone(),
+                value: ann.value.clone(),
+            };
+            if remaining.len() == 1 && remaining[0] == range {
+                ops.push(builder.annotate(start, end, &style));
+                continue;
+            }
+
+            for piece in remaining {
+                let (start, end) = self.anchor_cursors(piece, expand, IndexType::Utf8);
+                ops.push(builder.annotate(
+                    start.map(|x| self.get_id_at_pos(x)),
+                    end.map(|x| self.get_id_at_pos(x)),
+                    &style,
+                ));
+            }
+        }
+
+        self.apply_ops(ops)
+    }
+
+    /// Create a new document with the text and the styles in the given range.
+    ///
+    /// The styles are re-anchored to the new text, so the new document doesn't
+    /// share any history with this one. It uses the same client id as this
+    /// document, so they should never be merged.
+    pub fn extract(&self, range: impl RangeBounds<usize>) -> RichText {
+        let spans = self.slice(range, IndexType::Utf8);
+        let mut ans = RichText::new(self.id());
+        let text: String = spans.iter().map(|span| span.insert.as_str()).collect();
+        ans.insert(0, &text);
+
+        // find the maximal runs of every style
+        let mut runs: FxHashMap<&InternalString, (usize, &Value)> = Default::default();
+        let mut styles = Vec::new();
+        let mut index = 0;
+        for span in spans.iter() {
+            runs.retain(|&key, &mut (start, value)| {
+                if span.attributes.get(key) == Some(value) {
+                    true
+                } else {
+                    styles.push((start..index, key, value));
+                    false
+                }
+            });
+            for (key, value) in span.attributes.iter() {
+                runs.entry(key).or_insert((index, value));
+            }
+            index += span.len();
+        }
+        for (key, (start, value)) in runs {
+            styles.push((start..index, key, value));
+        }
+
+        styles.sort_by(|a, b| {
+            a.0.start
+                .cmp(&b.0.start)
+                .then_with(|| a.1.as_bytes().cmp(b.1.as_bytes()))
+        });
+        for (range, type_, value) in styles {
+            let style = match self.ann.find_latest_style(type_) {
+                Some(ann) => Style {
+                    expand: Expand::from_anchor_types(ann.range.start.type_, ann.range.end.type_),
+                    behavior: ann.behavior,
+                    type_: type_.clone(),
+                    value: value.clone(),
+                },
+                None => Style {
+                    expand: Expand::infer_insert_expand(type_),
+                    behavior: Behavior::Merge,
+                    type_: type_.clone(),
+                    value: value.clone(),
+                },
+            };
+            ans.annotate(range, style);
+        }
+
+        ans
+    }
+
+    /// Compare the document at the two versions for the "compare versions" UIs.
+    ///
+    /// Return the text that is visible at either version, so the changes can be
+    /// rendered inline. The text that is only visible at `to` has the attribute
+    /// `"diff": "insert"`, and the text that is only visible at `from` has the
+    /// attribute `"diff": "delete"`. The styles of the text are not included.
+    ///
+    /// It needs the whole history, so the offloaded ops are loaded from the op
+    /// log storage.
+    pub fn diff_overlay(
+        &self,
+        from: &VersionVector,
+        to: &VersionVector,
+    ) -> Result<Vec<Span>, Error> {
+        let from_deleted = diff::Deleted::new(&self.store, from)?;
+        let to_deleted = diff::Deleted::new(&self.store, to)?;
+        Ok(diff::diff_spans(
+            self.content.iter().map(|x| (x.id, self.elem_text(x))),
+            (from, &from_deleted),
+            (to, &to_deleted),
+        ))
+    }
+
+    /// Get the state of the style `type_` in the utf8 `range` of the document
+    /// at the version, e.g. to show "bolded by Alice" in the history view.
+    ///
+    /// It scans the current elements once and checks the visibility of every
+    /// char at the version, so the historical document is never built. The
+    /// annotations are taken from the annotation manager, and only the
+    /// deletions before the version are read from the op log without copying
+    /// it. The annotations use their current ranges, because the range
+    /// patches are not versioned.
+    pub fn style_state_at(
+        &self,
+        vv: &VersionVector,
+        range: Range<usize>,
+        type_: &str,
+    ) -> Result<StyleState, Error> {
+        let deleted = diff::Deleted::new(&self.store, vv)?;
+        let targets: FxHashMap<AnnIdx, &Arc<Annotation>> = self
+            .ann
+            .iter()
+            .filter(|ann| &*ann.type_ == type_ && vv.includes(ann.id))
+            .filter_map(|ann| Some((self.ann.get_idx_by_id(ann.id)?, ann)))
+            .collect();
+
+        let mut active: Vec<&Arc<Annotation>> = Vec::new();
+        let mut state: Option<Option<&Arc<Annotation>>> = None;
+        let mut index = 0;
+        'outer: for elem in self.content.iter() {
+            let mut after = Vec::new();
+            for (idx, anchor_type, is_start) in elem.anchor_set.iter() {
+                let Some(&ann) = targets.get(&idx) else {
+                    continue;
+                };
+                match (anchor_type, is_start) {
+                    (AnchorType::Before, true) => active.push(ann),
+                    (AnchorType::Before, false) => active.retain(|x| x.id != ann.id),
+                    (AnchorType::After, _) => after.push((ann, is_start)),
+                }
+            }
+
+            for (offset, ch) in self.elem_text(elem).char_indices() {
+                if index >= range.end {
+                    break 'outer;
+                }
+
+                if !diff::is_visible(vv, &deleted, elem.id.inc(offset as Counter)) {
+                    continue;
+                }
+
+                if index >= range.start {
+                    let winner = active
+                        .iter()
+                        .copied()
+                        .max_by_key(|x| x.range_lamport)
+                        .filter(|x| x.behavior != Behavior::Delete && !x.value.is_null());
+                    match state {
+                        None => state = Some(winner),
+                        Some(last) if last.map(|x| x.id) != winner.map(|x| x.id) => {
+                            return Ok(StyleState::Mixed)
+                        }
+                        _ => {}
+                    }
+                }
+
+                index += ch.len_utf8();
+            }
+
+            for (ann, is_start) in after {
+                if is_start {
+                    active.push(ann);
+                } else {
+                    active.retain(|x| x.id != ann.id);
+                }
+            }
+        }
+
+        Ok(match state.flatten() {
+            Some(ann) => StyleState::Set {
+                id: ann.id,
+                value: ann.value.clone(),
+            },
+            None => StyleState::Unset,
+        })
+    }
+
+    /// Get the annotation of `type_` that covers the char at `index`, e.g. to
+    /// show the tooltip of a link on hover.
+    ///
+    /// If the char is covered by multiple annotations of an
+    /// [`Behavior::AllowMultiple`] type, or by multiple types matched by a
+    /// namespace pattern like `comment/*`, the latest one is returned.
+    pub fn is_in_annotation(&self, index: usize, type_: &str) -> Option<OpID> {
+        self.is_in_annotation_inner(index, type_, IndexType::Utf8)
+    }
+
+    pub fn is_in_annotation_utf16(&self, index: usize, type_: &str) -> Option<OpID> {
+        self.is_in_annotation_inner(index, type_, IndexType::Utf16)
+    }
+
+    fn is_in_annotation_inner(
+        &self,
+        index: usize,
+        type_: &str,
+        index_type: IndexType,
+    ) -> Option<OpID> {
+        if index >= self.len_with(index_type) {
+            return None;
+        }
+
+        let (_, finder) = self
+            .content
+            .query_with_finder_return::<IndexFinderWithStyles>(&(index, index_type));
+        finder
+            .style_calculator
+            .calc_styles(&self.ann)
+            .filter(|x| type_matches(type_, &x.type_) && x.behavior != Behavior::Delete)
+            .max_by_key(|x| x.range_lamport)
+            .map(|x| x.id)
+    }
+
     pub fn get_style_at_position(
         &self,
         position: usize,
@@ -1509,11 +5029,101 @@ impl RichText {
         self.content.root_cache().line_breaks as usize + 1
     }
 
+    /// The headings of the document in order, with the ranges of their
+    /// sections, e.g. for a table of contents. See [`Heading`].
+    pub fn outline(&self) -> Vec<Heading> {
+        outline::outline(self)
+    }
+
+    /// Split the document into new documents at the headings of `max_level`
+    /// or higher levels, e.g. to move the sections of a large document into
+    /// the pages of a wiki. The text before the first heading is the first
+    /// section if it isn't empty.
+    ///
+    /// Every section keeps the ids of its first char and last char in this
+    /// document, so the tools can find where it came from after this
+    /// document is edited.
+    pub fn split_sections(&self, max_level: u64) -> Vec<Section> {
+        outline::split_sections(self, max_level)
+    }
+
+    /// The number of the words, i.e. the runs of non-whitespace chars.
+    ///
+    /// It's kept in the caches of the content tree, so it's O(1).
+    pub fn word_count(&self) -> usize {
+        self.content.root_cache().words.words as usize
+    }
+
+    /// The number of the words in the range. The words cut by the range are
+    /// counted.
+    pub fn word_count_in(&self, range: impl RangeBounds<usize>, index_type: IndexType) -> usize {
+        WordStats::new(self.slice_str(range, index_type).as_bytes()).words as usize
+    }
+
+    /// The estimated time to read the document at `words_per_minute`
+    pub fn reading_time(&self, words_per_minute: usize) -> Duration {
+        let secs = self.word_count() as f64 * 60.0 / words_per_minute.max(1) as f64;
+        Duration::from_secs_f64(secs)
+    }
+
+    pub fn try_apply_delta(
+        &mut self,
+        delta: impl Iterator<Item = DeltaItem>,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        let delta: Vec<DeltaItem> = delta.collect();
+        self.check_delta(&delta)?;
+        self.apply_delta(delta.into_iter(), index_type);
+        Ok(())
+    }
+
+    /// Validate the values of the attributes in the delta with the
+    /// registered validators, see [`RichText::set_validator`]
+    fn check_delta(&self, delta: &[DeltaItem]) -> Result<(), Error> {
+        for item in delta.iter() {
+            for (key, value) in item.attributions().into_iter().flatten() {
+                if !value.is_null() {
+                    self.check_value(&InternalString::from(key.as_str()), value, Behavior::Merge)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn apply_delta(&mut self, delta: impl Iterator<Item = DeltaItem>, index_type: IndexType) {
+        self.apply_delta_with_options(delta, index_type, &ApplyDeltaOptions::default())
+    }
+
+    /// Apply the delta like [`RichText::apply_delta`] with the options, e.g. to
+    /// let the pasted text inherit the styles around it.
+    ///
+    /// The whole delta is ignored if any of its attribute values fails the
+    /// validation, see [`RichText::try_apply_delta`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn apply_delta_with_options(
+        &mut self,
+        delta: impl Iterator<Item = DeltaItem>,
+        index_type: IndexType,
+        options: &ApplyDeltaOptions,
+    ) {
+        if self.ignore_readonly("apply_delta") {
+            return;
+        }
+
+        let delta: Vec<DeltaItem> = delta.collect();
+        if let Err(err) = self.check_delta(&delta) {
+            debug_log::debug_log!("ignored the delta with {}", err);
+            return;
+        }
+
         let mut index = 0;
         for delta_item in delta {
             match delta_item {
-                DeltaItem::Retain { retain, attributes } => {
+                DeltaItem::Retain {
+                    retain, attributes, ..
+                } => {
                     if let Some(attributes) = attributes {
                         let len = self.len_with(index_type);
                         // Quill assume there is always line break at the end of the text.
@@ -1525,7 +5135,7 @@ impl RichText {
                             self.insert(self.len(), &"\n".repeat(new));
                         }
 
-                        for (key, value) in attributes {
+                        for (key, value) in unwrap_attributes(attributes) {
                             let behavior = if value.is_null() {
                                 crate::Behavior::Delete
                             } else {
@@ -1554,25 +5164,31 @@ impl RichText {
                 DeltaItem::Insert {
                     insert, attributes, ..
                 } => {
+                    // the limits may shorten or drop the transformed insert,
+                    // and only the inserted part should be styled
+                    let transformed = self.transform_insert(index, &insert, index_type);
+                    let insert = self.clamp_insert(&transformed);
                     if insert.is_empty() {
                         continue;
                     }
 
-                    self.insert_inner(index, &insert, index_type);
+                    self.insert_inner(index, insert, index_type);
                     let end = match index_type {
                         IndexType::Utf8 => index + insert.len(),
-                        IndexType::Utf16 => index + get_utf16_len(&insert),
+                        IndexType::Utf16 => index + get_utf16_len(insert),
                     };
 
-                    let span = self
-                        .slice(index..index + 1, index_type)
+                    let inserted_attributes = self
+                        .slice(index..end, index_type)
                         .into_iter()
                         .next()
-                        .unwrap();
-                    let inserted_attributes = span.attributes;
-                    let attributes = attributes.unwrap_or_default();
+                        .map(|span| span.attributes)
+                        .unwrap_or_default();
+                    let attributes = attributes.map(unwrap_attributes).unwrap_or_default();
                     for key in inserted_attributes.keys() {
-                        if !attributes.contains_key(&key.to_string()) {
+                        if !options.inherit_surrounding_styles
+                            && !attributes.contains_key(&key.to_string())
+                        {
                             self.annotate_inner(
                                 index..end,
                                 Style::new_from_expand(
@@ -1643,7 +5259,7 @@ impl RichText {
             generic_btree::PreviousCache::ThisElemAndOffset { elem, offset } => {
                 if !elem.is_dead() {
                     match index_type {
-                        IndexType::Utf8 => count += utf16_to_utf8(&elem.string, offset),
+                        IndexType::Utf8 => count += offset,
                         IndexType::Utf16 => {
                             count += get_utf16_len_and_line_breaks(&elem.string[..offset]).utf16
                                 as usize;
@@ -1656,6 +5272,109 @@ impl RichText {
     }
 }
 
+/// Whether the new slice inserted by a local insert can extend the element
+fn can_merge_new_slice(
+    elem: &Elem,
+    id: OpID,
+    right: Option<OpID>,
+    slice: &append_only_bytes::BytesSlice,
+) -> bool {
+    elem.id.client == id.client
+        && elem.id.counter + elem.atom_len() as Counter == id.counter
+        && elem.right == right
+        && !elem.is_dead()
+        && elem.string.can_merge(slice)
+        && !elem.has_after_anchor()
+}
+
+/// Push `elem` to `ans`, with its parts deleted by `deleted` restored
+fn restore_deleted(mut elem: Elem, deleted: &[op::DeleteOp], ans: &mut Vec<Elem>) {
+    loop {
+        let start = elem.id.counter;
+        let end = start + elem.rle_len() as Counter;
+        let overlap = deleted
+            .iter()
+            .filter(|del| del.start.client == elem.id.client)
+            .map(|del| {
+                let del_end = del.start.counter + del.len as Counter;
+                (del.start.counter.max(start), del_end.min(end))
+            })
+            .filter(|(from, to)| from < to)
+            .min();
+        let (from, to) = match overlap {
+            Some(x) => x,
+            None => {
+                ans.push(elem);
+                return;
+            }
+        };
+
+        if from > start {
+            let right = elem.split((from - start) as usize);
+            ans.push(std::mem::replace(&mut elem, right));
+            continue;
+        }
+
+        let right = (to < end).then(|| elem.split((to - start) as usize));
+        elem.status.deleted_times -= 1;
+        ans.push(elem);
+        match right {
+            Some(right) => elem = right,
+            None => return,
+        }
+    }
+}
+
+/// Count the chars in the utf8 bytes, which may start or end inside a char
+fn count_chars(bytes: &[u8]) -> usize {
+    // skip the continuation bytes 0b10xx_xxxx
+    bytes.iter().filter(|&&b| (b as i8) >= -0x40).count()
+}
+
+/// Count the chars of the inserts deleted by the positive delete op
+fn count_deleted_chars(ops: &FxHashMap<ClientID, Vec<Op>>, del: op::DeleteOp) -> usize {
+    let target = match ops.get(&del.start.client) {
+        Some(target) => target,
+        None => return 0,
+    };
+    let start = del.start.counter;
+    let end = start + del.len as Counter;
+    let i = target.partition_point(|op| op.id.counter + op.rle_len() as Counter <= start);
+    target[i..]
+        .iter()
+        .take_while(|op| op.id.counter < end)
+        .map(|op| match &op.content {
+            OpContent::Text(insert) => {
+                let from = start.saturating_sub(op.id.counter) as usize;
+                let to = ((end - op.id.counter) as usize).min(insert.text.len());
+                count_chars(&insert.text[from..to])
+            }
+            _ => 0,
+        })
+        .sum()
+}
+
+/// The parts of `range` outside the `removed` ranges sorted by their starts
+fn subtract_ranges(range: Range<usize>, removed: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut ans = Vec::new();
+    let mut start = range.start;
+    for x in removed {
+        if x.start > start {
+            ans.push(start..x.start.min(range.end));
+        }
+        start = start.max(x.end);
+        if start >= range.end {
+            break;
+        }
+    }
+
+    if start < range.end {
+        ans.push(start..range.end);
+    }
+    ans.retain(|x| !x.is_empty());
+    ans
+}
+
 impl Display for RichText {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for span in self.content.iter() {
@@ -0,0 +1,278 @@
+//! Helpers for the relays to decide whether to accept an incoming update
+//! before applying it.
+//!
+//! [`UpdateScore`] counts what the update contains without decoding the text
+//! or the annotation values, so it works on the encrypted updates too. An
+//! [`UpdatePolicy`] turns the score into a [`Verdict`], a [`RateLimiter`]
+//! bounds the ops a connection can send over time, and a [`ReplayGuard`]
+//! rejects the updates whose ops were all accepted before.
+//!
+//! ```
+//! use crdt_richtext::rich_text::admission::{UpdateLimits, UpdatePolicy, UpdateScore, Verdict};
+//! use crdt_richtext::RichText;
+//!
+//! let mut text = RichText::new(1);
+//! text.insert(0, "hello");
+//! let data = text.export(&Default::default());
+//! let policy = UpdatePolicy {
+//!     reject: UpdateLimits {
+//!         max_ops: Some(10_000),
+//!         ..Default::default()
+//!     },
+//!     ..Default::default()
+//! };
+//! let score = UpdateScore::decode(&data).unwrap();
+//! assert_eq!(policy.check(&score), Verdict::Accept);
+//! ```
+
+use crate::{ClientID, Counter, VersionVector};
+
+use super::{crypto::UpdatesMeta, encoding, Error};
+
+/// What an encoded update contains
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdateScore {
+    /// The size of the encoded update
+    pub bytes: usize,
+    pub ops: usize,
+    /// The inserted text in utf8 bytes
+    pub inserted: usize,
+    /// The deleted text in utf8 bytes
+    pub deleted: usize,
+    pub annotations: usize,
+    /// The number of the clients whose ops are in the update
+    pub clients: usize,
+}
+
+impl UpdateScore {
+    /// Score the updates exported by [`RichText::export`](crate::RichText::export)
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        encoding::decode_score(data)
+    }
+
+    fn get(&self, metric: UpdateMetric) -> usize {
+        match metric {
+            UpdateMetric::Bytes => self.bytes,
+            UpdateMetric::Ops => self.ops,
+            UpdateMetric::Inserted => self.inserted,
+            UpdateMetric::Deleted => self.deleted,
+            UpdateMetric::Annotations => self.annotations,
+            UpdateMetric::Clients => self.clients,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateMetric {
+    Bytes,
+    Ops,
+    Inserted,
+    Deleted,
+    Annotations,
+    Clients,
+}
+
+/// The maximum values of the metrics. `None` means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdateLimits {
+    pub max_bytes: Option<usize>,
+    pub max_ops: Option<usize>,
+    pub max_inserted: Option<usize>,
+    pub max_deleted: Option<usize>,
+    pub max_annotations: Option<usize>,
+    pub max_clients: Option<usize>,
+}
+
+impl UpdateLimits {
+    fn exceeded(&self, score: &UpdateScore) -> Vec<Exceeded> {
+        [
+            (UpdateMetric::Bytes, self.max_bytes),
+            (UpdateMetric::Ops, self.max_ops),
+            (UpdateMetric::Inserted, self.max_inserted),
+            (UpdateMetric::Deleted, self.max_deleted),
+            (UpdateMetric::Annotations, self.max_annotations),
+            (UpdateMetric::Clients, self.max_clients),
+        ]
+        .into_iter()
+        .filter_map(|(metric, limit)| {
+            let limit = limit?;
+            let value = score.get(metric);
+            (value > limit).then_some(Exceeded {
+                metric,
+                value,
+                limit,
+            })
+        })
+        .collect()
+    }
+}
+
+/// A metric of the update that exceeds its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exceeded {
+    pub metric: UpdateMetric,
+    pub value: usize,
+    pub limit: usize,
+}
+
+/// The updates that exceed the `throttle` limits are accepted but should be
+/// delayed, and the ones that exceed the `reject` limits should be dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpdatePolicy {
+    pub throttle: UpdateLimits,
+    pub reject: UpdateLimits,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Throttle(Vec<Exceeded>),
+    Reject(Vec<Exceeded>),
+}
+
+impl UpdatePolicy {
+    pub fn check(&self, score: &UpdateScore) -> Verdict {
+        let exceeded = self.reject.exceeded(score);
+        if !exceeded.is_empty() {
+            return Verdict::Reject(exceeded);
+        }
+
+        let exceeded = self.throttle.exceeded(score);
+        if !exceeded.is_empty() {
+            return Verdict::Throttle(exceeded);
+        }
+
+        Verdict::Accept
+    }
+}
+
+/// A token bucket of the ops that a connection can send.
+///
+/// The time is passed in by the caller in milliseconds, so it works with any
+/// clock.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    capacity: f64,
+    ops_per_second: f64,
+    tokens: f64,
+    last_ms: Option<u64>,
+}
+
+impl RateLimiter {
+    /// Allow bursts of `capacity` ops and `ops_per_second` ops on average
+    pub fn new(capacity: usize, ops_per_second: usize) -> Self {
+        Self {
+            capacity: capacity as f64,
+            ops_per_second: ops_per_second as f64,
+            tokens: capacity as f64,
+            last_ms: None,
+        }
+    }
+
+    /// Spend the ops of the update if there are enough tokens. Nothing is
+    /// spent if it returns false.
+    pub fn try_acquire(&mut self, score: &UpdateScore, now_ms: u64) -> bool {
+        if let Some(last_ms) = self.last_ms {
+            let elapsed = now_ms.saturating_sub(last_ms) as f64 / 1000.;
+            self.tokens = (self.tokens + elapsed * self.ops_per_second).min(self.capacity);
+        }
+
+        self.last_ms = Some(self.last_ms.map_or(now_ms, |x| x.max(now_ms)));
+        let cost = score.ops as f64;
+        if cost > self.tokens {
+            return false;
+        }
+
+        self.tokens -= cost;
+        true
+    }
+}
+
+/// Track the version of the document that the relay has, so the duplicated
+/// or replayed updates are rejected with [`Error::Replayed`] instead of being
+/// applied again.
+///
+/// An update is a replay only if all its ops are covered by the version. The
+/// covered parts of the other updates are dropped from the returned metadata,
+/// and [`RichText::import`](crate::RichText::import) skips them too. The
+/// updates after a gap are accepted without being recorded, so the missing
+/// ops are still accepted when they arrive by other routes. Call
+/// [`ReplayGuard::set_version`] with the version of the document after the
+/// updates are applied to record them.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayGuard {
+    next: VersionVector,
+}
+
+impl ReplayGuard {
+    /// Start from the version of the document that the relay already has
+    pub fn from_version(vv: VersionVector) -> Self {
+        Self { next: vv }
+    }
+
+    /// The next counter of every client
+    pub fn version(&self) -> &VersionVector {
+        &self.next
+    }
+
+    /// Catch up with the version of the document, e.g. after the pending
+    /// updates are applied
+    pub fn set_version(&mut self, vv: VersionVector) {
+        self.next = vv;
+    }
+
+    /// Check the update and record its ops if it's not a replay. It works
+    /// on the encrypted updates too.
+    ///
+    /// Return the metadata of the ops that are not covered by the version.
+    pub fn check(&mut self, data: &[u8]) -> Result<UpdatesMeta, Error> {
+        let meta = UpdatesMeta::decode(data)?;
+        self.check_meta(&meta)
+    }
+
+    /// Check the metadata of the update and record its ops if it's not a
+    /// replay. Nothing is recorded if it returns an error.
+    ///
+    /// Return the metadata of the ops that are not covered by the version.
+    pub fn check_meta(&mut self, meta: &UpdatesMeta) -> Result<UpdatesMeta, Error> {
+        let mut ans = UpdatesMeta {
+            start: Default::default(),
+            end: Default::default(),
+            encrypted: meta.encrypted,
+            doc_id: meta.doc_id,
+        };
+        for (&client, &end) in meta.end.vv.iter() {
+            let start = meta.start.vv.get(&client).copied().unwrap_or(0);
+            let start = start.max(self.next_counter(client));
+            if start < end {
+                ans.start.vv.insert(client, start);
+                ans.end.vv.insert(client, end);
+            }
+        }
+
+        if ans.end.vv.is_empty() {
+            if let Some((&client, &counter)) = meta.start.vv.iter().min() {
+                return Err(Error::Replayed {
+                    client,
+                    counter,
+                    expected: self.next_counter(client),
+                });
+            }
+
+            return Ok(ans);
+        }
+
+        for (&client, &end) in ans.end.vv.iter() {
+            // the ops after a gap are recorded when the gap is filled
+            if ans.start.vv[&client] == self.next_counter(client) {
+                self.next.vv.insert(client, end);
+            }
+        }
+
+        Ok(ans)
+    }
+
+    fn next_counter(&self, client: ClientID) -> Counter {
+        self.next.vv.get(&client).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,92 @@
+//! The rules that place the anchors of an annotation.
+//!
+//! An annotation doesn't store its range as indexes. Its start and end are
+//! anchors attached to characters, either before or after them, and the
+//! [`Expand`] of the style decides the side:
+//!
+//! | Expand   | start anchor             | end anchor              |
+//! | -------- | ------------------------ | ----------------------- |
+//! | `None`   | before the first char    | after the last char     |
+//! | `Before` | after the char before    | after the last char     |
+//! | `After`  | before the first char    | before the char after   |
+//! | `Both`   | after the char before    | before the char after   |
+//!
+//! The text inserted at an anchor is inside the annotation only if the anchor
+//! is attached to the other side of the new text. When there is no char
+//! before the range or after it, the anchor is attached to the start or the
+//! end of the document, i.e. its id is `None`.
+//!
+//! These functions are used by [`RichText::annotate`](crate::RichText::annotate),
+//! so the custom ops built with [`ops::OpBuilder`](super::ops::OpBuilder) can
+//! match its semantics exactly.
+
+use std::ops::Range;
+
+use crate::{AnchorType, Expand};
+
+/// The position of an anchor in the document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnchorPosition {
+    /// The index of the char that the anchor is attached to, or `None` for
+    /// the start or the end of the document
+    pub index: Option<usize>,
+    pub type_: AnchorType,
+}
+
+/// The positions of the start anchor and the end anchor of an annotation on
+/// the non-empty `range` of a document with the length `len`.
+///
+/// The indexes are in the same unit as `range`. For the chars that take more
+/// than one unit, the index before a char should be aligned to its first unit.
+pub fn anchor_positions_for(
+    expand: Expand,
+    range: Range<usize>,
+    len: usize,
+) -> (AnchorPosition, AnchorPosition) {
+    debug_assert!(range.start < range.end && range.end <= len);
+    let start_type = expand.start_type();
+    let start = match start_type {
+        AnchorType::Before => Some(range.start),
+        AnchorType::After => range.start.checked_sub(1),
+    };
+    let end_type = expand.end_type();
+    let end = match end_type {
+        AnchorType::Before => Some(range.end).filter(|&end| end < len),
+        AnchorType::After => Some(range.end - 1),
+    };
+
+    (
+        AnchorPosition {
+            index: start,
+            type_: start_type,
+        },
+        AnchorPosition {
+            index: end,
+            type_: end_type,
+        },
+    )
+}
+
+/// The range covered by the anchors. It's the inverse of [`anchor_positions_for`].
+///
+/// `char_len` is the length of the char at an index, which is 1 if the indexes
+/// are in chars.
+pub fn covered_range(
+    start: AnchorPosition,
+    end: AnchorPosition,
+    len: usize,
+    char_len: impl Fn(usize) -> usize,
+) -> Range<usize> {
+    let start = match (start.index, start.type_) {
+        (None, _) => 0,
+        (Some(index), AnchorType::Before) => index,
+        (Some(index), AnchorType::After) => index + char_len(index),
+    };
+    let end = match (end.index, end.type_) {
+        (None, _) => len,
+        (Some(index), AnchorType::Before) => index,
+        (Some(index), AnchorType::After) => index + char_len(index),
+    };
+
+    start..end.max(start)
+}
@@ -5,7 +5,9 @@ use serde_json::Value;
 use smallvec::SmallVec;
 use std::{mem::take, sync::Arc};
 
-use crate::{small_set::SmallSetI32, AnchorType, Annotation, Behavior, InternalString, OpID};
+use crate::{
+    small_set::SmallSetI32, AnchorType, Annotation, Behavior, Expand, InternalString, OpID,
+};
 
 use super::rich_tree::{CacheDiff, Elem};
 
@@ -14,8 +16,22 @@ pub type AnnIdx = i32;
 
 #[derive(Default, Debug)]
 pub struct AnnManager {
-    idx_to_ann: Vec<Arc<Annotation>>,
-    id_to_idx: FxHashMap<OpID, AnnIdx>,
+    idx_to_ann: Vec<AnnSlot>,
+    /// The indexes and the generations of the annotations
+    id_to_idx: FxHashMap<OpID, (AnnIdx, u32)>,
+    /// The slots freed by the removed annotations, reused by the next ones
+    free_idx: Vec<AnnIdx>,
+    next_generation: u32,
+}
+
+/// A slot of [`AnnManager`].
+///
+/// Every annotation gets a new generation, so an index kept from before can
+/// be told from the annotation that reuses the slot.
+#[derive(Debug)]
+struct AnnSlot {
+    ann: Option<Arc<Annotation>>,
+    generation: u32,
 }
 
 impl AnnManager {
@@ -27,32 +43,100 @@ impl AnnManager {
     pub fn register(&mut self, new: Arc<Annotation>) -> AnnIdx {
         if self.idx_to_ann.is_empty() {
             // We don't use the zero pos
-            self.idx_to_ann.push(new.clone());
+            self.idx_to_ann.push(AnnSlot {
+                ann: Some(new.clone()),
+                generation: 0,
+            });
         }
 
         let id = new.id;
-        let idx = self.idx_to_ann.len() as i32;
-        self.idx_to_ann.push(new);
-        self.id_to_idx.insert(id, idx);
+        self.next_generation += 1;
+        let slot = AnnSlot {
+            ann: Some(new),
+            generation: self.next_generation,
+        };
+        let idx = match self.free_idx.pop() {
+            Some(idx) => {
+                self.idx_to_ann[idx as usize] = slot;
+                idx
+            }
+            None => {
+                self.idx_to_ann.push(slot);
+                self.idx_to_ann.len() as AnnIdx - 1
+            }
+        };
+        self.id_to_idx.insert(id, (idx, self.next_generation));
         idx
     }
 
+    /// Free the slot of the annotation, so it's reused by the next one. Its
+    /// anchors should be removed from the content first.
+    pub fn remove(&mut self, id: OpID) {
+        let idx = match self.id_to_idx.remove(&id) {
+            Some((idx, _)) => idx,
+            None => return,
+        };
+        self.idx_to_ann[idx as usize].ann = None;
+        self.free_idx.push(idx);
+        // drop the free slots at the end. The generations are never reused,
+        // so the indexes kept from before still can't match the new slots.
+        while self.idx_to_ann.len() > 1 && self.idx_to_ann.last().unwrap().ann.is_none() {
+            self.idx_to_ann.pop();
+        }
+
+        let len = self.idx_to_ann.len() as AnnIdx;
+        self.free_idx.retain(|&x| x < len);
+    }
+
     #[inline(always)]
     pub fn get_ann_by_idx(&self, idx: AnnIdx) -> Option<&Arc<Annotation>> {
-        self.idx_to_ann.get(idx as usize)
+        self.idx_to_ann.get(idx as usize)?.ann.as_ref()
     }
 
     #[allow(unused)]
     #[inline(always)]
     pub fn get_ann_by_id(&self, id: OpID) -> Option<&Arc<Annotation>> {
-        let idx = self.id_to_idx.get(&id)?;
-        self.idx_to_ann.get(*idx as usize)
+        let idx = self.get_idx_by_id(id)?;
+        self.get_ann_by_idx(idx)
     }
 
-    #[allow(unused)]
+    /// The index of the annotation, if its slot has not been reused
     #[inline(always)]
     pub fn get_idx_by_id(&self, id: OpID) -> Option<AnnIdx> {
-        self.id_to_idx.get(&id).copied()
+        let &(idx, generation) = self.id_to_idx.get(&id)?;
+        let slot = self.idx_to_ann.get(idx as usize)?;
+        (slot.generation == generation && slot.ann.is_some()).then_some(idx)
+    }
+
+    /// Approximate heap size in bytes
+    pub fn mem_size(&self) -> usize {
+        let anns: usize = self
+            .iter()
+            .map(|ann| std::mem::size_of::<Annotation>() + ann.value.to_string().len())
+            .sum();
+        anns + self.idx_to_ann.capacity() * std::mem::size_of::<AnnSlot>()
+            + self.free_idx.capacity() * std::mem::size_of::<AnnIdx>()
+            + self.id_to_idx.capacity() * std::mem::size_of::<(OpID, (AnnIdx, u32))>()
+    }
+
+    /// The number of the registered annotations
+    pub fn count(&self) -> usize {
+        self.id_to_idx.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Annotation>> {
+        // skip the placeholder at the zero pos
+        self.idx_to_ann
+            .iter()
+            .skip(1)
+            .filter_map(|x| x.ann.as_ref())
+    }
+
+    /// Find the latest annotation of the given type that doesn't erase the style
+    pub fn find_latest_style(&self, type_: &InternalString) -> Option<&Arc<Annotation>> {
+        self.iter()
+            .filter(|ann| &ann.type_ == type_ && ann.behavior != Behavior::Delete)
+            .max_by_key(|ann| ann.range_lamport)
     }
 }
 
@@ -78,6 +162,37 @@ impl Span {
     }
 }
 
+/// An annotation that applies to a [`MarkedSpan`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mark {
+    pub id: OpID,
+    pub type_: InternalString,
+    pub value: Value,
+    pub behavior: Behavior,
+    pub expand: Expand,
+}
+
+impl Mark {
+    fn new(ann: &Annotation) -> Self {
+        Self {
+            id: ann.id,
+            type_: ann.type_.clone(),
+            value: ann.value.clone(),
+            behavior: ann.behavior,
+            expand: ann.expand(),
+        }
+    }
+}
+
+/// A text span with the annotations that style it, see
+/// [`RichText::get_spans_with_marks`](crate::RichText::get_spans_with_marks).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarkedSpan {
+    pub insert: String,
+    /// Sorted by the types and the ids
+    pub marks: Vec<Mark>,
+}
+
 impl Mergeable for Span {
     fn can_merge(&self, rhs: &Self) -> bool {
         self.attributes == rhs.attributes
@@ -197,6 +312,18 @@ impl CacheAnchorSet {
 }
 
 impl ElemAnchorSet {
+    /// Iterate over all the anchors as (annotation, anchor type, is_start)
+    pub fn iter(&self) -> impl Iterator<Item = (AnnIdx, AnchorType, bool)> + '_ {
+        [
+            (&self.start_before, AnchorType::Before, true),
+            (&self.start_after, AnchorType::After, true),
+            (&self.end_before, AnchorType::Before, false),
+            (&self.end_after, AnchorType::After, false),
+        ]
+        .into_iter()
+        .flat_map(|(set, type_, is_start)| set.iter().map(move |&x| (x, type_, is_start)))
+    }
+
     pub fn has_start_before(&self) -> bool {
         !self.start_before.is_empty()
     }
@@ -232,6 +359,14 @@ impl ElemAnchorSet {
         }
     }
 
+    /// Keep only the anchors of the annotations that `f` returns true for
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(AnnIdx) -> bool) {
+        self.start_before.retain(|x| f(*x));
+        self.end_before.retain(|x| f(*x));
+        self.start_after.retain(|x| f(*x));
+        self.end_after.retain(|x| f(*x));
+    }
+
     pub(crate) fn split(&mut self) -> ElemAnchorSet {
         ElemAnchorSet {
             start_before: Default::default(),
@@ -329,6 +464,10 @@ impl StyleCalculator {
         self.inner.insert(start);
     }
 
+    pub fn retain(&mut self, mut f: impl FnMut(AnnIdx) -> bool) {
+        self.inner.retain(|x| f(*x));
+    }
+
     pub fn apply_node_start(&mut self, anchor_set: &CacheAnchorSet) {
         if !anchor_set.start.is_empty() {
             for ann in anchor_set.start.iter() {
@@ -429,6 +568,106 @@ impl StyleCalculator {
         }
         style_map.into_iter().map(|(_, (_, ann))| ann)
     }
+
+    /// The annotations that style the text, except the ones that erase the
+    /// styles. They are sorted by the types and the ids.
+    pub fn calc_marks(&self, manager: &AnnManager) -> Vec<Mark> {
+        let mut ans: Vec<Mark> = self
+            .calc_styles(manager)
+            .filter(|x| x.behavior != Behavior::Delete)
+            .map(|x| Mark::new(&x))
+            .collect();
+        ans.sort_unstable_by(|a, b| (&*a.type_, a.id).cmp(&(&*b.type_, b.id)));
+        ans
+    }
+
+    /// Replace the values of the types in `modes` with the merged values of
+    /// all their annotations that pass the filter, see [`ValueMode`]
+    pub fn merge_values(
+        &self,
+        manager: &AnnManager,
+        modes: &FxHashMap<InternalString, ValueMode>,
+        filter: impl Fn(&Annotation) -> bool,
+        attributes: &mut FxHashMap<InternalString, Value>,
+    ) {
+        let mut anns: FxHashMap<&InternalString, Vec<&Arc<Annotation>>> = FxHashMap::default();
+        for ann in self.inner.iter() {
+            let ann = manager.get_ann_by_idx(*ann).unwrap();
+            if modes.contains_key(&ann.type_) && filter(ann) {
+                anns.entry(&ann.type_).or_default().push(ann);
+            }
+        }
+
+        for (type_, mode) in modes.iter() {
+            let value = anns.get_mut(type_).and_then(|anns| {
+                anns.sort_unstable_by_key(|x| x.range_lamport);
+                merge_values(anns, *mode)
+            });
+            match value {
+                Some(value) => attributes.insert(type_.clone(), value),
+                None => attributes.remove(type_),
+            };
+        }
+    }
+}
+
+/// How the values of the overlapping annotations of a type are resolved, see
+/// [`RichText::set_value_mode`](crate::RichText::set_value_mode).
+///
+/// An annotation that erases the style removes the values of the annotations
+/// before it in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueMode {
+    /// The value of the annotation with the largest lamport wins
+    #[default]
+    Lww,
+    /// The value is the union of the values, so the concurrent additions are
+    /// all kept. An array value adds all of its items.
+    SetUnion,
+    /// The value is the sum of the numeric values
+    Counter,
+}
+
+/// Merge the values of the annotations that are sorted by their lamports
+fn merge_values(anns: &[&Arc<Annotation>], mode: ValueMode) -> Option<Value> {
+    let start = anns
+        .iter()
+        .rposition(|x| x.behavior == Behavior::Delete)
+        .map_or(0, |i| i + 1);
+    let anns = &anns[start..];
+    match mode {
+        ValueMode::Lww => anns.last().map(|x| x.value.clone()),
+        ValueMode::SetUnion => {
+            let mut set: Vec<Value> = Vec::new();
+            for ann in anns {
+                let items = match &ann.value {
+                    Value::Array(items) => items.as_slice(),
+                    Value::Null => &[],
+                    value => std::slice::from_ref(value),
+                };
+                for item in items {
+                    if !set.contains(item) {
+                        set.push(item.clone());
+                    }
+                }
+            }
+
+            (!set.is_empty()).then_some(Value::Array(set))
+        }
+        ValueMode::Counter => {
+            if anns.is_empty() {
+                return None;
+            }
+
+            match anns.iter().map(|x| x.value.as_i64()).sum::<Option<i64>>() {
+                Some(sum) => Some(sum.into()),
+                None => {
+                    let sum: f64 = anns.iter().filter_map(|x| x.value.as_f64()).sum();
+                    serde_json::Number::from_f64(sum).map(Value::Number)
+                }
+            }
+        }
+    }
 }
 
 /// This method insert the range anchor to the character at the given index and offset.
@@ -536,3 +775,64 @@ pub fn insert_anchors_at_same_elem(
 
     ans
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{Anchor, AnchorRange};
+
+    use super::*;
+
+    fn ann(counter: u32) -> Arc<Annotation> {
+        let anchor = Anchor {
+            id: None,
+            type_: AnchorType::Before,
+        };
+        Arc::new(Annotation {
+            id: OpID::new(1, counter),
+            range_lamport: (counter, OpID::new(1, counter)),
+            range: AnchorRange {
+                start: anchor,
+                end: anchor,
+            },
+            behavior: Behavior::Merge,
+            type_: "bold".into(),
+            value: Value::Bool(true),
+        })
+    }
+
+    #[test]
+    fn reuse_removed_slots() {
+        let mut manager = AnnManager::new();
+        let a = manager.register(ann(0));
+        let b = manager.register(ann(1));
+        manager.remove(ann(0).id);
+        assert_eq!(manager.get_idx_by_id(ann(0).id), None);
+        assert!(manager.get_ann_by_idx(a).is_none());
+        let generation = manager.idx_to_ann[a as usize].generation;
+
+        let c = manager.register(ann(2));
+        assert_eq!(c, a);
+        assert!(manager.idx_to_ann[c as usize].generation > generation);
+        assert_eq!(manager.get_ann_by_idx(c).unwrap().id, ann(2).id);
+        assert_eq!(manager.count(), 2);
+        assert_eq!(manager.iter().count(), 2);
+
+        // the free slots at the end are dropped
+        manager.remove(ann(1).id);
+        manager.remove(ann(2).id);
+        assert_eq!(manager.idx_to_ann.len(), 1);
+        assert!(manager.free_idx.is_empty());
+        assert_eq!(manager.register(ann(3)), a.min(b));
+    }
+
+    #[test]
+    fn stale_index_is_not_found() {
+        let mut manager = AnnManager::new();
+        manager.register(ann(0));
+        manager.register(ann(1));
+        let (idx, generation) = manager.id_to_idx[&ann(0).id];
+        manager.idx_to_ann[idx as usize].generation = generation + 1;
+        assert_eq!(manager.get_idx_by_id(ann(0).id), None);
+        assert!(manager.get_ann_by_id(ann(0).id).is_none());
+    }
+}
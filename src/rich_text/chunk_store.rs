@@ -0,0 +1,219 @@
+//! Content-addressable chunks of the snapshots.
+//!
+//! [`write_chunks`] splits the snapshot of a document into content-defined
+//! chunks, so that an edit only changes the chunks around it, and the other
+//! chunks are the same as the ones of the previous snapshots. Every chunk is
+//! identified by the hash of its content, so a storage layer can keep every
+//! distinct chunk only once across the snapshots of many similar documents.
+//!
+//! The snapshot is described by a [`ChunkManifest`], and it's reassembled by
+//! [`read_chunks`] from a [`ChunkProvider`].
+//!
+//! The [`ChunkHash`] is a 64-bit FNV-1a hash, which is NOT a cryptographic
+//! hash. It's fine to deduplicate the chunks of trusted documents, but two
+//! chunks can collide by chance in a very large store, and anyone can craft
+//! colliding chunks on purpose. A storage layer that is shared by untrusted
+//! clients should key the chunks by a cryptographic hash of its own, e.g.
+//! SHA-256, and only use [`ChunkHash`] to refer to them in the manifests.
+//!
+//! ```
+//! use crdt_richtext::rich_text::chunk_store::{read_chunks, write_chunks, MemoryChunkStore};
+//! use crdt_richtext::RichText;
+//!
+//! let mut text = RichText::new(1);
+//! text.insert(0, "hello world");
+//! let mut store = MemoryChunkStore::default();
+//! let (manifest, chunks) = write_chunks(&text, 8 * 1024).unwrap();
+//! store.insert_all(chunks);
+//!
+//! let mut loaded = RichText::new(2);
+//! loaded.import(&read_chunks(&manifest, &store).unwrap()).unwrap();
+//! assert_eq!(loaded.to_string(), "hello world");
+//! ```
+
+use std::{hash::Hasher, ops::Range};
+
+use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use super::{encoding::encode_updates_uncompressed, hash::StableHasher, Error, RichText};
+
+/// The hash of the content of a chunk. It's not a cryptographic hash, see
+/// the [module docs](self).
+pub type ChunkHash = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub hash: ChunkHash,
+    pub data: Vec<u8>,
+}
+
+/// The chunks of a snapshot in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub chunks: Vec<ChunkHash>,
+    /// The length of the snapshot in bytes
+    pub len: usize,
+}
+
+/// Look up the chunks by their hashes, e.g. from a database or an object
+/// storage.
+pub trait ChunkProvider {
+    fn get_chunk(&self, hash: ChunkHash) -> Result<Vec<u8>, Error>;
+}
+
+/// Keep the chunks in memory. Every distinct chunk is only kept once.
+#[derive(Debug, Default)]
+pub struct MemoryChunkStore {
+    chunks: FxHashMap<ChunkHash, Vec<u8>>,
+}
+
+impl MemoryChunkStore {
+    pub fn insert(&mut self, chunk: Chunk) {
+        self.chunks.entry(chunk.hash).or_insert(chunk.data);
+    }
+
+    pub fn insert_all(&mut self, chunks: impl IntoIterator<Item = Chunk>) {
+        for chunk in chunks {
+            self.insert(chunk);
+        }
+    }
+
+    pub fn contains(&self, hash: ChunkHash) -> bool {
+        self.chunks.contains_key(&hash)
+    }
+
+    /// The number of the distinct chunks
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// The total size of the distinct chunks in bytes
+    pub fn size(&self) -> usize {
+        self.chunks.values().map(|x| x.len()).sum()
+    }
+}
+
+impl ChunkProvider for MemoryChunkStore {
+    fn get_chunk(&self, hash: ChunkHash) -> Result<Vec<u8>, Error> {
+        self.chunks
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| Error::Storage(format!("chunk {:016x} not found", hash)))
+    }
+}
+
+/// Split the snapshot of the document into chunks of about `avg_size` bytes.
+///
+/// The chunks are not deduplicated within the snapshot. The storage layer
+/// should skip the chunks it already has.
+pub fn write_chunks(doc: &RichText, avg_size: usize) -> Result<(ChunkManifest, Vec<Chunk>), Error> {
+    let data = encode_updates_uncompressed(
+        doc.store.export(&Default::default())?,
+        doc.doc_id,
+        doc.cipher.as_deref(),
+    );
+    let mut manifest = ChunkManifest {
+        chunks: Vec::new(),
+        len: data.len(),
+    };
+    let mut chunks = Vec::new();
+    for range in split_chunks(&data, avg_size) {
+        let data = data[range].to_vec();
+        let hash = hash_chunk(&data);
+        manifest.chunks.push(hash);
+        chunks.push(Chunk { hash, data });
+    }
+
+    Ok((manifest, chunks))
+}
+
+/// Reassemble the snapshot that can be imported by [`RichText::import`].
+///
+/// The content of every chunk is checked against its hash, which catches the
+/// accidental corruptions but not the forged chunks.
+pub fn read_chunks(
+    manifest: &ChunkManifest,
+    provider: &dyn ChunkProvider,
+) -> Result<Vec<u8>, Error> {
+    let mut ans = Vec::with_capacity(manifest.len);
+    for &hash in manifest.chunks.iter() {
+        let data = provider.get_chunk(hash)?;
+        if hash_chunk(&data) != hash {
+            return Err(Error::Storage(format!("chunk {:016x} is corrupted", hash)));
+        }
+
+        ans.extend_from_slice(&data);
+    }
+
+    if ans.len() != manifest.len {
+        return Err(Error::Storage(format!(
+            "the snapshot should have {} bytes but has {} bytes",
+            manifest.len,
+            ans.len()
+        )));
+    }
+
+    Ok(ans)
+}
+
+fn hash_chunk(data: &[u8]) -> ChunkHash {
+    let mut hasher = StableHasher::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// The random values of the gear hash, generated by splitmix64 so that they
+/// are the same on every platform
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Split the data by the gear hash of its content. A chunk ends where the
+/// top bits of the hash are zero. The hash only depends on the last 64 bytes,
+/// so the boundaries after an edit fall back to the same places as before.
+///
+/// The chunks are between `avg_size / 4` and `avg_size * 4` bytes, except
+/// the last one.
+fn split_chunks(data: &[u8], avg_size: usize) -> Vec<Range<usize>> {
+    let avg_size = avg_size.max(64).next_power_of_two();
+    let min_size = avg_size / 4;
+    let max_size = avg_size * 4;
+    // a boundary is found once in `avg_size` bytes on average after the
+    // min size is skipped
+    let mask = !(u64::MAX >> (avg_size - min_size).next_power_of_two().trailing_zeros());
+    let mut ans = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = (start + max_size).min(data.len());
+        let mut cut = end;
+        let mut hash: u64 = 0;
+        for (i, &byte) in data[..end].iter().enumerate().skip(start + min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if hash & mask == 0 {
+                cut = i + 1;
+                break;
+            }
+        }
+
+        ans.push(start..cut);
+        start = cut;
+    }
+
+    ans
+}
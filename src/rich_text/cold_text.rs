@@ -0,0 +1,129 @@
+//! The compressed text of the tombstones, see
+//! [`RichText::compress_tombstones`](crate::RichText::compress_tombstones).
+//!
+//! The deleted text is rarely read, but the elements keep it so the ops from
+//! other peers can refer to it. The compressed elements point to a shared run
+//! of zeros of the same length instead, and their text is decompressed from
+//! the blocks here on demand.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use append_only_bytes::{AppendOnlyBytes, BytesSlice};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use fxhash::FxHashMap;
+
+use crate::{ClientID, Counter, OpID};
+
+/// The tombstones are compressed in blocks of about this size
+pub(super) const BLOCK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    len: u32,
+    block: u32,
+    /// The offset in the decompressed block
+    offset: u32,
+}
+
+pub(super) struct ColdText {
+    blocks: Vec<Vec<u8>>,
+    /// The entries of every client by their start counters
+    index: FxHashMap<ClientID, BTreeMap<Counter, Entry>>,
+    zeros: AppendOnlyBytes,
+}
+
+impl Default for ColdText {
+    fn default() -> Self {
+        Self {
+            blocks: Vec::new(),
+            index: Default::default(),
+            zeros: AppendOnlyBytes::new(),
+        }
+    }
+}
+
+impl ColdText {
+    /// A placeholder of the text with the same length
+    pub fn placeholder(&mut self, len: usize) -> BytesSlice {
+        if self.zeros.len() < len {
+            self.zeros.push_slice(&vec![0; len - self.zeros.len()]);
+        }
+
+        self.zeros.slice(0..len)
+    }
+
+    /// Whether the text of the id is compressed
+    pub fn contains(&self, id: OpID) -> bool {
+        self.entry(id).is_some()
+    }
+
+    /// Compress the runs of text into a new block
+    pub fn push_block(&mut self, runs: &[(OpID, BytesSlice)]) {
+        if runs.is_empty() {
+            return;
+        }
+
+        let block = self.blocks.len() as u32;
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        let mut offset = 0;
+        for (id, text) in runs {
+            encoder.write_all(text).unwrap();
+            self.index.entry(id.client).or_default().insert(
+                id.counter,
+                Entry {
+                    len: text.len() as u32,
+                    block,
+                    offset,
+                },
+            );
+            offset += text.len() as u32;
+        }
+
+        self.blocks.push(encoder.finish().unwrap());
+    }
+
+    /// Decompress the text of the `len` atoms from `id`. Return `None` if
+    /// some of them are not compressed.
+    pub fn get(&self, mut id: OpID, len: usize) -> Option<Vec<u8>> {
+        let mut ans = Vec::with_capacity(len);
+        let mut decompressed: Option<(u32, Vec<u8>)> = None;
+        while ans.len() < len {
+            let (start, entry) = self.entry(id)?;
+            if decompressed.as_ref().map(|x| x.0) != Some(entry.block) {
+                let mut block = Vec::new();
+                DeflateDecoder::new(&self.blocks[entry.block as usize][..])
+                    .read_to_end(&mut block)
+                    .ok()?;
+                decompressed = Some((entry.block, block));
+            }
+
+            let block = &decompressed.as_ref().unwrap().1;
+            let offset = (id.counter - start) as usize;
+            let take = (entry.len as usize - offset).min(len - ans.len());
+            let from = entry.offset as usize + offset;
+            ans.extend_from_slice(&block[from..from + take]);
+            id = id.inc(take as Counter);
+        }
+
+        Some(ans)
+    }
+
+    pub fn mem_size(&self) -> usize {
+        self.blocks.iter().map(|x| x.capacity()).sum::<usize>()
+            + self.index.values().map(|x| x.len()).sum::<usize>()
+                * std::mem::size_of::<(Counter, Entry)>()
+            + self.zeros.len()
+    }
+
+    fn entry(&self, id: OpID) -> Option<(Counter, Entry)> {
+        let (&start, &entry) = self
+            .index
+            .get(&id.client)?
+            .range(..=id.counter)
+            .next_back()?;
+        (id.counter < start + entry.len).then_some((start, entry))
+    }
+}
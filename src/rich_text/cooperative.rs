@@ -0,0 +1,130 @@
+//! Async variants of the heavy operations, enabled by the `async` feature.
+//!
+//! They run the same steps as the blocking ones, but yield to the executor
+//! after every `ops_per_step` ops, so a large import doesn't block the other
+//! tasks on the thread. They don't depend on any runtime. The document is
+//! borrowed until the future completes, so the other tasks never see a
+//! partially applied import. If the future is dropped before it completes,
+//! the rest of the import runs when it's dropped.
+//!
+//! The documents are not `Send`, so the futures should be spawned on a local
+//! executor, e.g. `spawn_local`.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use fxhash::FxHashMap;
+
+use crate::ClientID;
+
+use super::{
+    decode_updates, encode_updates, op::Op, vv::VersionVector, Error, ImportOptions, ImportTask,
+    RichText,
+};
+
+impl RichText {
+    /// Import the updates like [`RichText::import_with_options`], and yield
+    /// after every `ops_per_step` ops.
+    ///
+    /// The updates are decoded and validated before the first yield.
+    pub async fn import_async(
+        &mut self,
+        data: &[u8],
+        options: &ImportOptions,
+        ops_per_step: usize,
+    ) -> Result<(), Error> {
+        let (doc_id, mut updates) = decode_updates(data, self.cipher.as_deref())?;
+        self.check_doc_id(doc_id)?;
+        self.validate_updates(&mut updates)?;
+        self.import_ops_async(updates, options, ops_per_step).await;
+        Ok(())
+    }
+
+    /// Merge the ops of `other` like [`RichText::merge`], and yield after
+    /// every `ops_per_step` ops. It fails if the history of `other` can't be
+    /// loaded from its op log storage.
+    pub async fn merge_async(&mut self, other: &Self, ops_per_step: usize) -> Result<(), Error> {
+        let exported = other.store.export(&self.store.vv())?;
+        self.import_ops_async(exported, &ImportOptions::default(), ops_per_step)
+            .await;
+        Ok(())
+    }
+
+    /// Export the updates like [`RichText::try_export`], and yield after the
+    /// ops of every client are loaded from the op log storage.
+    ///
+    /// The updates are encoded after the last yield.
+    pub async fn export_async(&self, vv: &VersionVector) -> Result<Vec<u8>, Error> {
+        let mut exported = FxHashMap::default();
+        for client in self.store.clients() {
+            if let Some(ops) = self.store.export_client(client, vv)? {
+                exported.insert(client, ops);
+            }
+            yield_now().await;
+        }
+
+        Ok(encode_updates(
+            exported,
+            self.doc_id,
+            self.cipher.as_deref(),
+        ))
+    }
+
+    async fn import_ops_async(
+        &mut self,
+        ops: FxHashMap<ClientID, Vec<Op>>,
+        options: &ImportOptions,
+        ops_per_step: usize,
+    ) {
+        let task = self.begin_import(ops, options);
+        let mut guard = ImportGuard {
+            doc: self,
+            task,
+            done: false,
+        };
+        while !guard.doc.import_step(&mut guard.task, ops_per_step.max(1)) {
+            yield_now().await;
+        }
+        guard.done = true;
+    }
+}
+
+/// Finish the rest of the import when the future is dropped before it
+/// completes
+struct ImportGuard<'a> {
+    doc: &'a mut RichText,
+    task: ImportTask,
+    done: bool,
+}
+
+impl Drop for ImportGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.doc.import_step(&mut self.task, usize::MAX);
+        }
+    }
+}
+
+/// Let the executor run the other tasks once
+async fn yield_now() {
+    YieldNow(false).await
+}
+
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
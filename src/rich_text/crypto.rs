@@ -0,0 +1,57 @@
+//! Hooks for end-to-end encrypted sync.
+//!
+//! With a [`Cipher`] set by [`RichText::set_cipher`](crate::RichText::set_cipher),
+//! the exported updates have their text and annotation values encrypted. The
+//! clients, counters, lamports and the shape of the ops are still in clear,
+//! so a relay without the key can read the [`UpdatesMeta`] of the updates to
+//! dedupe and order them.
+//!
+//! The annotation types are not encrypted.
+
+use crate::VersionVector;
+
+use super::{encoding, Error};
+
+/// Encrypt and decrypt the payloads of the updates with an application key.
+///
+/// It's called once for the text of the updates and once for every distinct
+/// annotation value.
+pub trait Cipher {
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// The metadata of the encoded updates that can be read without the key.
+#[derive(Debug, Clone, Default)]
+pub struct UpdatesMeta {
+    /// The counter of the first op of every client
+    pub start: VersionVector,
+    /// The counter after the last op of every client
+    pub end: VersionVector,
+    pub encrypted: bool,
+    pub doc_id: Option<u128>,
+}
+
+impl UpdatesMeta {
+    /// Read the metadata of the updates exported by [`RichText::export`](crate::RichText::export)
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        encoding::decode_meta(data)
+    }
+
+    /// Whether all the ops of the updates are included in `vv`
+    pub fn is_included_by(&self, vv: &VersionVector) -> bool {
+        self.end
+            .vv
+            .iter()
+            .all(|(client, &end)| vv.vv.get(client).copied().unwrap_or(0) >= end)
+    }
+
+    /// Whether the updates can be applied after the ops in `vv`, i.e. there
+    /// are no gaps between `vv` and the first ops of the updates.
+    pub fn can_apply_after(&self, vv: &VersionVector) -> bool {
+        self.start
+            .vv
+            .iter()
+            .all(|(client, &start)| vv.vv.get(client).copied().unwrap_or(0) >= start)
+    }
+}
@@ -28,6 +28,11 @@ impl CursorMap {
         }
     }
 
+    /// Approximate heap size in bytes
+    pub fn mem_size(&self) -> usize {
+        self.map.try_lock().unwrap().mem_size()
+    }
+
     pub fn gen_update_fn(&self) -> MoveListener<Elem> {
         let map = self.map.clone();
         Box::new(move |event| {
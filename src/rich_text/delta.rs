@@ -1,30 +1,66 @@
-use std::mem::swap;
+use std::{
+    hash::{Hash, Hasher},
+    mem::swap,
+    ops::Range,
+    sync::Arc,
+};
 
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHasher};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use super::rich_tree::{
-    query::IndexType,
-    utf16::{get_utf16_len, utf16_to_utf8},
+use crate::{InternalString, OpID};
+
+use super::{
+    ann::Span,
+    rich_tree::{
+        query::IndexType,
+        utf16::{get_utf16_len, utf16_to_utf8},
+    },
 };
 
+/// The attributes of a delta item.
+///
+/// They are shared by the items with the same attributes in an event, so
+/// splitting and composing the items don't copy the maps.
+///
+/// # Migration
+///
+/// The attributes of [`DeltaItem`] used to be `FxHashMap<String, Value>`.
+/// The maps can still be read through the [`Arc`], e.g.
+/// `attributes.get("bold")`. To build an item, wrap the map by
+/// `Some(map.into())` or use [`DeltaItem::insert_with_attributes`] and
+/// [`DeltaItem::retain_with_attributes`], which take the map as before. To take
+/// the map out of an item, use `Arc::try_unwrap(attributes)` or clone it.
+pub type Attributes = Arc<FxHashMap<String, Value>>;
+
+/// An item of a delta.
+///
+/// The variants are non-exhaustive so new fields can be added without breaking
+/// the users. Build the items by the constructors like [`DeltaItem::retain`]
+/// and match them with `..`.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum DeltaItem {
+    #[non_exhaustive]
     Retain {
         retain: usize,
-        attributes: Option<FxHashMap<String, Value>>,
+        attributes: Option<Attributes>,
+        /// The ids of the annotations that caused the attributes change.
+        ///
+        /// It's only set when [`crate::RichText::set_event_annotation_ids`] is enabled.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        annotations: Option<Vec<OpID>>,
     },
+    #[non_exhaustive]
     Insert {
         insert: String,
-        attributes: Option<FxHashMap<String, Value>>,
+        attributes: Option<Attributes>,
         len: Option<usize>,
         index_type: Option<IndexType>,
     },
-    Delete {
-        delete: usize,
-    },
+    #[non_exhaustive]
+    Delete { delete: usize },
 }
 
 impl DeltaItem {
@@ -32,6 +68,7 @@ impl DeltaItem {
         Self::Retain {
             retain,
             attributes: None,
+            annotations: None,
         }
     }
 
@@ -54,7 +91,20 @@ impl DeltaItem {
     pub fn retain_with_attributes(retain: usize, attributes: FxHashMap<String, Value>) -> Self {
         Self::Retain {
             retain,
-            attributes: Some(attributes),
+            attributes: Some(Arc::new(attributes)),
+            annotations: None,
+        }
+    }
+
+    pub fn retain_with_annotation(
+        retain: usize,
+        attributes: FxHashMap<String, Value>,
+        annotation: OpID,
+    ) -> Self {
+        Self::Retain {
+            retain,
+            attributes: Some(Arc::new(attributes)),
+            annotations: Some(vec![annotation]),
         }
     }
 
@@ -70,7 +120,7 @@ impl DeltaItem {
             }),
             insert,
             index_type: Some(index_type),
-            attributes: Some(attributes),
+            attributes: Some(Arc::new(attributes)),
         }
     }
 
@@ -87,6 +137,10 @@ impl DeltaItem {
     }
 
     pub fn attributions(&self) -> Option<&FxHashMap<String, Value>> {
+        self.shared_attributes().map(|x| &**x)
+    }
+
+    fn shared_attributes(&self) -> Option<&Attributes> {
         match self {
             Self::Retain { attributes, .. } => attributes.as_ref(),
             Self::Insert { attributes, .. } => attributes.as_ref(),
@@ -94,6 +148,13 @@ impl DeltaItem {
         }
     }
 
+    pub fn annotation_ids(&self) -> Option<&[OpID]> {
+        match self {
+            Self::Retain { annotations, .. } => annotations.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn length(&self) -> usize {
         match self {
             Self::Retain { retain, .. } => *retain,
@@ -150,11 +211,16 @@ impl DeltaItem {
                     }
                 }
             },
-            DeltaItem::Retain { retain, attributes } => {
+            DeltaItem::Retain {
+                retain,
+                attributes,
+                annotations,
+            } => {
                 *retain -= length;
                 Self::Retain {
                     retain: length,
                     attributes: attributes.clone(),
+                    annotations: annotations.clone(),
                 }
             }
             DeltaItem::Delete { delete } => {
@@ -165,20 +231,35 @@ impl DeltaItem {
     }
 
     fn compose_meta(&mut self, next_op: &DeltaItem) {
+        if let (Self::Retain { annotations, .. }, Some(ids)) =
+            (&mut *self, next_op.annotation_ids())
+        {
+            let annotations = annotations.get_or_insert_with(Vec::new);
+            for id in ids {
+                if !annotations.contains(id) {
+                    annotations.push(*id);
+                }
+            }
+        }
+
         let attributions = match self {
             DeltaItem::Retain { attributes, .. } => attributes,
             DeltaItem::Insert { attributes, .. } => attributes,
             DeltaItem::Delete { .. } => return,
         };
 
-        if attributions.is_none() {
-            *attributions = Some(FxHashMap::default());
-        }
-
-        let self_attributions = attributions.as_mut().unwrap();
-        if let Some(attributions) = next_op.attributions() {
-            for attr in attributions {
-                self_attributions.insert(attr.0.clone(), attr.1.clone());
+        let next = next_op.shared_attributes();
+        match attributions {
+            None => *attributions = Some(next.cloned().unwrap_or_default()),
+            Some(this) => {
+                if let Some(next) = next {
+                    if !Arc::ptr_eq(this, next) {
+                        let this = Arc::make_mut(this);
+                        for attr in next.iter() {
+                            this.insert(attr.0.clone(), attr.1.clone());
+                        }
+                    }
+                }
             }
         }
     }
@@ -207,6 +288,7 @@ impl DeltaIterator {
             return DeltaItem::Retain {
                 retain: usize::MAX,
                 attributes: None,
+                annotations: None,
             };
         }
         let op = next_op.unwrap();
@@ -224,7 +306,8 @@ impl DeltaIterator {
         if next_op.is_none() {
             return DeltaItem::Retain {
                 retain: other.length(),
-                attributes: other.attributions().cloned(),
+                attributes: other.shared_attributes().cloned(),
+                annotations: None,
             };
         }
         let op = next_op.unwrap();
@@ -301,6 +384,86 @@ impl DeltaIterator {
     }
 }
 
+/// Take the map out of the attributes, or copy it if it's still shared
+pub(crate) fn unwrap_attributes(attributes: Attributes) -> FxHashMap<String, Value> {
+    Arc::try_unwrap(attributes).unwrap_or_else(|x| (*x).clone())
+}
+
+/// Share the equal attributes of the items, so the listeners that keep the
+/// event don't hold many copies of the same map
+///
+/// The maps are looked up by their hashes, so only the maps with the same
+/// hash are compared.
+pub(crate) fn intern_attributes(ops: &mut [DeltaItem]) {
+    let mut interned: FxHashMap<u64, Vec<Attributes>> = FxHashMap::default();
+    for op in ops.iter_mut() {
+        let attributes = match op {
+            DeltaItem::Retain { attributes, .. } => attributes,
+            DeltaItem::Insert { attributes, .. } => attributes,
+            DeltaItem::Delete { .. } => continue,
+        };
+
+        if let Some(attributes) = attributes {
+            let bucket = interned.entry(hash_attributes(attributes)).or_default();
+            match bucket
+                .iter()
+                .find(|x| Arc::ptr_eq(x, attributes) || **x == *attributes)
+            {
+                Some(x) => *attributes = x.clone(),
+                None => bucket.push(attributes.clone()),
+            }
+        }
+    }
+}
+
+/// The hash of the attributes that doesn't depend on the iteration order of
+/// the map, so the equal maps have the same hash
+fn hash_attributes(attributes: &FxHashMap<String, Value>) -> u64 {
+    attributes.iter().fold(0, |acc, (key, value)| {
+        let mut hasher = FxHasher::default();
+        key.hash(&mut hasher);
+        hash_value(value, &mut hasher);
+        acc.wrapping_add(hasher.finish())
+    })
+}
+
+fn hash_value(value: &Value, hasher: &mut FxHasher) {
+    match value {
+        Value::Null => hasher.write_u8(0),
+        Value::Bool(b) => {
+            hasher.write_u8(1);
+            b.hash(hasher);
+        }
+        Value::Number(n) => {
+            // the equal numbers have the same f64 value
+            hasher.write_u8(2);
+            n.as_f64().map(f64::to_bits).hash(hasher);
+        }
+        Value::String(s) => {
+            hasher.write_u8(3);
+            s.hash(hasher);
+        }
+        Value::Array(arr) => {
+            hasher.write_u8(4);
+            hasher.write_usize(arr.len());
+            for value in arr.iter() {
+                hash_value(value, hasher);
+            }
+        }
+        Value::Object(obj) => {
+            // the order of the entries may differ in the equal objects
+            hasher.write_u8(5);
+            let sum = obj.iter().fold(0u64, |acc, (key, value)| {
+                let mut entry = FxHasher::default();
+                key.hash(&mut entry);
+                hash_value(value, &mut entry);
+                acc.wrapping_add(entry.finish())
+            });
+            hasher.write_u64(sum);
+        }
+    }
+}
+
 pub fn compose(delta_a: Vec<DeltaItem>, delta_b: Vec<DeltaItem>) -> Vec<DeltaItem> {
     let mut this_iter = DeltaIterator::new(delta_a);
     let mut other_iter = DeltaIterator::new(delta_b);
@@ -382,3 +545,173 @@ fn chop(mut vec: Vec<DeltaItem>) -> Vec<DeltaItem> {
 
     vec
 }
+
+/// Get the minimal delta that turns the spans `old` into `new`, in the same
+/// shape as the events of the edits that make the change, e.g. for the hosts
+/// that render the optimistic updates before the edits arrive. The events of
+/// [`RichText::insert_with_styles`](super::RichText::insert_with_styles) are
+/// built by it.
+///
+/// The text is compared by its common prefix and suffix, so the changed text
+/// is one deletion followed by the insertions of the new spans. The styles
+/// changed on the kept text are retains with the changed attributes, where
+/// the removed ones are `null`.
+pub fn diff_spans(old: &[Span], new: &[Span], index_type: IndexType) -> Vec<DeltaItem> {
+    let old_text: String = old.iter().map(|x| x.insert.as_str()).collect();
+    let new_text: String = new.iter().map(|x| x.insert.as_str()).collect();
+    let is_boundary = |old_index: usize, new_index: usize| {
+        old_text.is_char_boundary(old_index) && new_text.is_char_boundary(new_index)
+    };
+    let mut prefix = old_text
+        .bytes()
+        .zip(new_text.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !is_boundary(prefix, prefix) {
+        prefix -= 1;
+    }
+
+    let max_suffix = (old_text.len() - prefix).min(new_text.len() - prefix);
+    let mut suffix = old_text
+        .bytes()
+        .rev()
+        .zip(new_text.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !is_boundary(old_text.len() - suffix, new_text.len() - suffix) {
+        suffix -= 1;
+    }
+
+    let len_of = |s: &str| match index_type {
+        IndexType::Utf8 => s.len(),
+        IndexType::Utf16 => get_utf16_len(s),
+    };
+    let old_runs = attribute_runs(old);
+    let new_runs = attribute_runs(new);
+    let mut ans = Vec::new();
+    push_style_changes(
+        &mut ans,
+        (&old_runs, 0),
+        (&new_runs, 0),
+        &new_text[..prefix],
+        len_of,
+    );
+    let deleted = &old_text[prefix..old_text.len() - suffix];
+    if !deleted.is_empty() {
+        ans.push(DeltaItem::delete(len_of(deleted)));
+    }
+
+    let inserted = prefix..new_text.len() - suffix;
+    for (range, attributes) in new_runs.iter() {
+        let start = range.start.max(inserted.start);
+        let end = range.end.min(inserted.end);
+        if start < end {
+            ans.push(DeltaItem::insert_with_attributes(
+                new_text[start..end].to_string(),
+                index_type,
+                attributes
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect(),
+            ));
+        }
+    }
+
+    push_style_changes(
+        &mut ans,
+        (&old_runs, old_text.len() - suffix),
+        (&new_runs, new_text.len() - suffix),
+        &new_text[new_text.len() - suffix..],
+        len_of,
+    );
+    chop(ans)
+}
+
+type AttributeRun<'a> = (Range<usize>, &'a FxHashMap<InternalString, Value>);
+
+/// The utf8 ranges of the spans with their attributes
+fn attribute_runs(spans: &[Span]) -> Vec<AttributeRun> {
+    let mut index = 0;
+    spans
+        .iter()
+        .map(|span| {
+            index += span.insert.len();
+            (index - span.insert.len()..index, &span.attributes)
+        })
+        .collect()
+}
+
+fn attributes_at<'a>(
+    runs: &[AttributeRun<'a>],
+    index: usize,
+) -> &'a FxHashMap<InternalString, Value> {
+    let i = runs.partition_point(|(range, _)| range.end <= index);
+    runs[i].1
+}
+
+/// Push the retains of the kept `text`, which starts at the utf8 index `old`
+/// in the old spans and `new` in the new spans
+fn push_style_changes(
+    ans: &mut Vec<DeltaItem>,
+    (old_runs, old): (&[AttributeRun], usize),
+    (new_runs, new): (&[AttributeRun], usize),
+    text: &str,
+    len_of: impl Fn(&str) -> usize,
+) {
+    // the offsets in the text where the attributes of either side change
+    let mut cuts: Vec<usize> = old_runs
+        .iter()
+        .filter_map(|(range, _)| range.end.checked_sub(old))
+        .chain(
+            new_runs
+                .iter()
+                .filter_map(|(range, _)| range.end.checked_sub(new)),
+        )
+        .filter(|&x| x > 0 && x < text.len())
+        .collect();
+    cuts.push(text.len());
+    cuts.sort_unstable();
+    cuts.dedup();
+    let mut start = 0;
+    for end in cuts {
+        if start == end {
+            continue;
+        }
+
+        let old_attributes = attributes_at(old_runs, old + start);
+        let new_attributes = attributes_at(new_runs, new + start);
+        let mut changed: FxHashMap<String, Value> = FxHashMap::default();
+        for (key, value) in new_attributes.iter() {
+            if old_attributes.get(key) != Some(value) {
+                changed.insert(key.to_string(), value.clone());
+            }
+        }
+        for key in old_attributes.keys() {
+            if !new_attributes.contains_key(key) {
+                changed.insert(key.to_string(), Value::Null);
+            }
+        }
+
+        let len = len_of(&text[start..end]);
+        let item = if changed.is_empty() {
+            DeltaItem::retain(len)
+        } else {
+            DeltaItem::retain_with_attributes(len, changed)
+        };
+        match (ans.last_mut(), item) {
+            (
+                Some(DeltaItem::Retain {
+                    retain, attributes, ..
+                }),
+                DeltaItem::Retain {
+                    retain: len,
+                    attributes: item_attributes,
+                    ..
+                },
+            ) if *attributes == item_attributes => *retain += len,
+            (_, item) => ans.push(item),
+        }
+        start = end;
+    }
+}
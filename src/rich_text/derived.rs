@@ -0,0 +1,83 @@
+//! Derived annotations that are computed from the text by the host.
+//!
+//! They are never stored in the document or synced, so presentation-only
+//! decorations like autodetected urls don't add ops to the history. They are
+//! materialized when [`RichText::get_spans_with_derived`](crate::RichText::get_spans_with_derived)
+//! is called.
+
+use std::ops::Range;
+
+use serde_json::Value;
+
+use crate::InternalString;
+
+use super::Span;
+
+/// Compute the ranges of a derived annotation from the text of the document.
+///
+/// The ranges are utf8 indexes. The ranges that are not on char boundaries are
+/// ignored.
+pub type Deriver = Box<dyn Fn(&str) -> Vec<(Range<usize>, Value)>>;
+
+/// Add the derived annotations to the spans. The stored annotations win over
+/// the derived annotations of the same type.
+pub(super) fn apply_derived(spans: Vec<Span>, derived: &[(InternalString, Deriver)]) -> Vec<Span> {
+    if derived.is_empty() {
+        return spans;
+    }
+
+    let text: String = spans.iter().map(|x| x.insert.as_str()).collect();
+    let mut ranges: Vec<(InternalString, Range<usize>, Value)> = Vec::new();
+    for (type_, deriver) in derived {
+        for (range, value) in deriver(&text) {
+            if range.start < range.end
+                && range.end <= text.len()
+                && text.is_char_boundary(range.start)
+                && text.is_char_boundary(range.end)
+            {
+                ranges.push((type_.clone(), range, value));
+            }
+        }
+    }
+
+    let mut boundaries: Vec<usize> = ranges
+        .iter()
+        .flat_map(|(_, range, _)| [range.start, range.end])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut ans: Vec<Span> = Vec::with_capacity(spans.len());
+    let mut offset = 0;
+    for span in spans {
+        let end = offset + span.insert.len();
+        let mut start = offset;
+        let cuts = boundaries
+            .iter()
+            .copied()
+            .filter(|&x| offset < x && x < end)
+            .chain(std::iter::once(end));
+        for cut in cuts {
+            let mut attributes = span.attributes.clone();
+            for (type_, range, value) in ranges.iter() {
+                if range.start <= start && cut <= range.end && !attributes.contains_key(type_) {
+                    attributes.insert(type_.clone(), value.clone());
+                }
+            }
+
+            let insert = &span.insert[start - offset..cut - offset];
+            match ans.last_mut() {
+                Some(last) if last.attributes == attributes => last.insert.push_str(insert),
+                _ => ans.push(Span {
+                    insert: insert.to_string(),
+                    attributes,
+                }),
+            }
+            start = cut;
+        }
+
+        offset = end;
+    }
+
+    ans
+}
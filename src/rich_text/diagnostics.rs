@@ -0,0 +1,168 @@
+//! Host-computed diagnostics anchored to the text, e.g. spellcheck results.
+//!
+//! The diagnostics are not part of the CRDT. Their ranges are transformed by
+//! the events of the document, and a diagnostic is invalidated when the text
+//! in its range changes, so a spellchecker only needs to recheck the changed
+//! text instead of the whole document.
+
+use std::{hash::Hasher, ops::Range};
+
+use super::{
+    delta::DeltaItem, hash::StableHasher, rich_tree::utf16::get_utf16_len, Event, IndexType,
+    RichText,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic<T> {
+    pub range: Range<usize>,
+    pub data: T,
+    /// The hash of the text in the range when the diagnostic is added
+    hash: u64,
+}
+
+/// A set of diagnostics whose ranges follow the edits of the document.
+///
+/// The ranges are in the index type of the events of the document, see
+/// [`RichText::set_event_index_type`].
+#[derive(Debug, Clone)]
+pub struct Diagnostics<T> {
+    index_type: IndexType,
+    items: Vec<Diagnostic<T>>,
+}
+
+impl<T> Diagnostics<T> {
+    pub fn new(index_type: IndexType) -> Self {
+        Self {
+            index_type,
+            items: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, text: &RichText, range: Range<usize>, data: T) {
+        let range = text.resolve_range(range, self.index_type);
+        let hash = hash_text(text, &range, self.index_type);
+        self.items.push(Diagnostic { range, data, hash });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic<T>> {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&Diagnostic<T>) -> bool) {
+        self.items.retain(f)
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Transform the ranges by the event, and remove the diagnostics whose text
+    /// has been changed. The removed diagnostics are returned so their ranges
+    /// can be checked again.
+    ///
+    /// It should be called for every event of the text in order, while the
+    /// text is still at the state right after the event.
+    pub fn update(&mut self, text: &RichText, event: &Event) -> Vec<Diagnostic<T>> {
+        debug_assert_eq!(event.index_type, self.index_type);
+        let mut invalidated = Vec::new();
+        let mut kept = Vec::with_capacity(self.items.len());
+        for mut item in std::mem::take(&mut self.items) {
+            let touched = is_touched(&item.range, event);
+            item.range =
+                map_index(item.range.start, true, event)..map_index(item.range.end, false, event);
+            item.range.end = item.range.end.max(item.range.start);
+            if touched && hash_text(text, &item.range, self.index_type) != item.hash {
+                invalidated.push(item);
+            } else {
+                kept.push(item);
+            }
+        }
+
+        self.items = kept;
+        invalidated
+    }
+}
+
+fn hash_text(text: &RichText, range: &Range<usize>, index_type: IndexType) -> u64 {
+    let mut hasher = StableHasher::default();
+    hasher.write(text.slice_str(range.clone(), index_type).as_bytes());
+    hasher.finish()
+}
+
+fn insert_len(insert: &str, len: Option<usize>, index_type: IndexType) -> usize {
+    len.unwrap_or_else(|| match index_type {
+        IndexType::Utf8 => insert.len(),
+        IndexType::Utf16 => get_utf16_len(insert),
+    })
+}
+
+/// Whether the event inserts or deletes text inside the range
+fn is_touched(range: &Range<usize>, event: &Event) -> bool {
+    let mut index = 0;
+    for item in event.ops.iter() {
+        match item {
+            DeltaItem::Retain { retain, .. } => index += retain,
+            DeltaItem::Insert { .. } => {
+                if range.start < index && index < range.end {
+                    return true;
+                }
+            }
+            DeltaItem::Delete { delete } => {
+                if index < range.end && range.start < index + delete {
+                    return true;
+                }
+                index += delete;
+            }
+        }
+
+        if index >= range.end {
+            break;
+        }
+    }
+
+    false
+}
+
+/// Map the index before the event to the index after it.
+///
+/// If `stick_right`, the index stays after the text inserted at it.
+fn map_index(index: usize, stick_right: bool, event: &Event) -> usize {
+    let mut old = 0;
+    let mut new = 0;
+    for item in event.ops.iter() {
+        match item {
+            DeltaItem::Retain { retain, .. } => {
+                if index < old + retain {
+                    break;
+                }
+
+                old += retain;
+                new += retain;
+            }
+            DeltaItem::Insert { insert, len, .. } => {
+                if index > old || (index == old && stick_right) {
+                    new += insert_len(insert, *len, event.index_type);
+                } else {
+                    break;
+                }
+            }
+            DeltaItem::Delete { delete } => {
+                if index < old + delete {
+                    return new;
+                }
+
+                old += delete;
+            }
+        }
+    }
+
+    new + (index - old)
+}
@@ -0,0 +1,111 @@
+//! Compare the visible text of the document at two versions.
+
+use std::{borrow::Cow, ops::Range};
+
+use fxhash::FxHashMap;
+use generic_btree::rle::HasLength;
+use serde_json::Value;
+
+use crate::{ClientID, Counter, InternalString, OpID};
+
+use super::{
+    ann::Span,
+    op::{OpContent, OpStore},
+    vv::VersionVector,
+    Error,
+};
+
+/// The attribute key that marks the changed text in the diff overlay
+const DIFF_KEY: &str = "diff";
+
+/// The ids of the characters deleted at a version
+pub(super) struct Deleted {
+    ranges: FxHashMap<ClientID, Vec<Range<Counter>>>,
+}
+
+impl Deleted {
+    /// Collect the deletions included in `vv` from the op log. The ops in
+    /// memory are not copied, and only the offloaded chunks before `vv` are
+    /// loaded.
+    pub fn new(store: &OpStore, vv: &VersionVector) -> Result<Self, Error> {
+        let mut ranges: FxHashMap<ClientID, Vec<Range<Counter>>> = Default::default();
+        store.visit_ops_before(vv, |op| {
+            let OpContent::Del(del) = &op.content else {
+                return;
+            };
+
+            let end = vv.vv.get(&op.id.client).copied().unwrap_or(0);
+            let del = if op.id.counter + op.rle_len() as Counter > end {
+                del.slice(0, (end - op.id.counter) as usize)
+            } else {
+                *del
+            };
+            let del = del.positive();
+            ranges
+                .entry(del.start.client)
+                .or_default()
+                .push(del.start.counter..del.start.counter + del.len as Counter);
+        })?;
+
+        for ranges in ranges.values_mut() {
+            ranges.sort_by_key(|x| x.start);
+            let mut merged: Vec<Range<Counter>> = Vec::with_capacity(ranges.len());
+            for range in ranges.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                    _ => merged.push(range),
+                }
+            }
+            *ranges = merged;
+        }
+
+        Ok(Self { ranges })
+    }
+
+    fn contains(&self, id: OpID) -> bool {
+        let Some(ranges) = self.ranges.get(&id.client) else {
+            return false;
+        };
+        let i = ranges.partition_point(|x| x.start <= id.counter);
+        i > 0 && ranges[i - 1].end > id.counter
+    }
+}
+
+/// Whether the char is visible at the version
+pub(super) fn is_visible(vv: &VersionVector, deleted: &Deleted, id: OpID) -> bool {
+    vv.includes(id) && !deleted.contains(id)
+}
+
+/// Build the spans of the text that is visible at either version
+pub(super) fn diff_spans<'a>(
+    elements: impl Iterator<Item = (OpID, Cow<'a, str>)>,
+    from: (&VersionVector, &Deleted),
+    to: (&VersionVector, &Deleted),
+) -> Vec<Span> {
+    let mut ans: Vec<(Option<&str>, String)> = Vec::new();
+    for (elem_id, text) in elements {
+        for (offset, ch) in text.char_indices() {
+            let id = elem_id.inc(offset as Counter);
+            let diff = match (is_visible(from.0, from.1, id), is_visible(to.0, to.1, id)) {
+                (true, true) => None,
+                (false, true) => Some("insert"),
+                (true, false) => Some("delete"),
+                (false, false) => continue,
+            };
+            match ans.last_mut() {
+                Some((last, text)) if *last == diff => text.push(ch),
+                _ => ans.push((diff, ch.to_string())),
+            }
+        }
+    }
+
+    ans.into_iter()
+        .map(|(diff, insert)| {
+            let mut attributes: FxHashMap<InternalString, Value> = Default::default();
+            if let Some(diff) = diff {
+                attributes.insert(DIFF_KEY.into(), diff.into());
+            }
+            Span { insert, attributes }
+        })
+        .collect()
+}
@@ -0,0 +1,181 @@
+//! Detect the divergence between peers.
+//!
+//! Peers exchange [`StateReport`]s. Two peers at the same version should have
+//! the same state hash. If they don't, the op hashes in the reports are used to
+//! find the op ranges that differ, so the divergence can be reported with
+//! enough information to reproduce it.
+
+use std::{collections::VecDeque, hash::Hasher, ops::Range};
+
+use generic_btree::rle::{HasLength, Sliceable};
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientID, Counter};
+
+use super::{hash::StableHasher, vv::VersionVector, Error, RichText};
+
+/// The number of op ids that share a hash in the [`StateReport`]
+const CHUNK_SIZE: Counter = 1024;
+
+/// The state of a peer that is sent to the other peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateReport {
+    pub version: VersionVector,
+    pub state_hash: u64,
+    pub op_hashes: Vec<OpRangeHash>,
+}
+
+/// The hash of the ops of a client in `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpRangeHash {
+    pub client: ClientID,
+    pub start: Counter,
+    pub end: Counter,
+    pub hash: u64,
+}
+
+/// Two peers have different states at the same version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub version: VersionVector,
+    pub local_hash: u64,
+    pub remote_hash: u64,
+    /// The op ranges that are different between the peers.
+    ///
+    /// If it's empty, the peers have the same ops but they are integrated
+    /// into different states.
+    pub ops: Vec<(ClientID, Range<Counter>)>,
+}
+
+/// Keep the state hashes of the recent versions of the local document, and
+/// compare them with the reports of the other peers.
+#[derive(Debug, Clone)]
+pub struct DivergenceDetector {
+    recent: VecDeque<(VersionVector, u64)>,
+    capacity: usize,
+}
+
+impl DivergenceDetector {
+    /// Keep the state hashes of at most `capacity` recent versions
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            recent: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Record the state hash of the current version of the document.
+    ///
+    /// It should be called after the document is changed, so the reports of
+    /// the peers that are at the older versions can still be checked.
+    pub fn record(&mut self, doc: &RichText) {
+        let version = doc.version();
+        if self.recent.back().map_or(false, |(v, _)| *v == version) {
+            return;
+        }
+
+        if self.recent.len() >= self.capacity {
+            self.recent.pop_front();
+        }
+
+        if self.capacity > 0 {
+            self.recent.push_back((version, doc.state_hash()));
+        }
+    }
+
+    /// Create the report of the current state of the document
+    pub fn report(doc: &RichText) -> Result<StateReport, Error> {
+        let version = doc.version();
+        Ok(StateReport {
+            op_hashes: op_hashes(doc, &version)?,
+            version,
+            state_hash: doc.state_hash(),
+        })
+    }
+
+    /// Check the report of another peer.
+    ///
+    /// Return `None` if the states are the same, or the version of the report
+    /// is neither the current version nor a recorded one.
+    pub fn check(&self, doc: &RichText, remote: &StateReport) -> Result<Option<Divergence>, Error> {
+        let local_hash = if doc.version() == remote.version {
+            doc.state_hash()
+        } else {
+            match self.recent.iter().rev().find(|(v, _)| *v == remote.version) {
+                Some((_, hash)) => *hash,
+                None => return Ok(None),
+            }
+        };
+
+        if local_hash == remote.state_hash {
+            return Ok(None);
+        }
+
+        let local = op_hashes(doc, &remote.version)?;
+        let mut ops: Vec<(ClientID, Range<Counter>)> = Vec::new();
+        for x in local.iter() {
+            if !remote.op_hashes.contains(x) {
+                ops.push((x.client, x.start..x.end));
+            }
+        }
+        for x in remote.op_hashes.iter() {
+            let range = (x.client, x.start..x.end);
+            if !local.contains(x) && !ops.contains(&range) {
+                ops.push(range);
+            }
+        }
+        ops.sort_by_key(|(client, range)| (*client, range.start));
+
+        Ok(Some(Divergence {
+            version: remote.version.clone(),
+            local_hash,
+            remote_hash: remote.state_hash,
+            ops,
+        }))
+    }
+}
+
+/// Hash the ops included in `vv` in the chunks of [`CHUNK_SIZE`] op ids
+fn op_hashes(doc: &RichText, vv: &VersionVector) -> Result<Vec<OpRangeHash>, Error> {
+    let mut ans = Vec::new();
+    for (client, mut ops) in doc.store.export(&Default::default())? {
+        let end = vv.vv.get(&client).copied().unwrap_or(0);
+        ops.sort_by_key(|op| op.id.counter);
+        let mut chunk: Option<(Counter, StableHasher)> = None;
+        for mut op in ops {
+            while op.id.counter < end {
+                let start = op.id.counter / CHUNK_SIZE * CHUNK_SIZE;
+                let chunk_end = (start + CHUNK_SIZE).min(end);
+                if chunk.as_ref().map_or(true, |(s, _)| *s != start) {
+                    if let Some((s, hasher)) = chunk.take() {
+                        ans.push(chunk_hash(client, s, end, hasher));
+                    }
+                    chunk = Some((start, StableHasher::default()));
+                }
+
+                let len = ((chunk_end - op.id.counter) as usize).min(op.rle_len());
+                chunk.as_mut().unwrap().1.write_op(&op.slice(..len));
+                if len == op.rle_len() {
+                    break;
+                }
+                op = op.slice(len..);
+            }
+        }
+
+        if let Some((s, hasher)) = chunk {
+            ans.push(chunk_hash(client, s, end, hasher));
+        }
+    }
+
+    ans.sort_by_key(|x| (x.client, x.start));
+    Ok(ans)
+}
+
+fn chunk_hash(client: ClientID, start: Counter, end: Counter, hasher: StableHasher) -> OpRangeHash {
+    OpRangeHash {
+        client,
+        start,
+        end: (start + CHUNK_SIZE).min(end),
+        hash: hasher.finish(),
+    }
+}
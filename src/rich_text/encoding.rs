@@ -6,7 +6,7 @@ use append_only_bytes::AppendOnlyBytes;
 use flate2::write::GzEncoder;
 use flate2::{read::GzDecoder, Compression};
 use fxhash::FxHashMap;
-use generic_btree::rle::HasLength;
+use generic_btree::rle::{HasLength, Mergeable};
 use serde::{Deserialize, Serialize};
 use serde_columnar::{columnar, from_bytes, to_vec};
 
@@ -14,8 +14,66 @@ use crate::{
     Anchor, AnchorRange, AnchorType, Annotation, Behavior, ClientID, InternalString, OpID,
 };
 
-use super::op::{DeleteOp, Op, OpContent, TextInsertOp};
+use super::{
+    admission::UpdateScore,
+    crypto::{Cipher, UpdatesMeta},
+    op::{DeleteOp, Op, OpContent, TextInsertOp},
+    CustomOp, Error,
+};
 const COMPRESS_THRESHOLD: usize = 1024;
+/// The prefix of the updates that are bound to a document id
+const DOC_ID_MAGIC: &[u8] = b"CRDTRTID";
+/// The prefix of the updates whose payloads are encrypted
+const ENCRYPTED_MAGIC: &[u8] = b"CRDTRTEN";
+/// The prefix of the updates in [`FormatVersion::V2`]
+const COMPACT_ANN_MAGIC: &[u8] = b"CRDTRTCA";
+/// The prefix of the updates in [`FormatVersion::V3`]
+const CUSTOM_OPS_MAGIC: &[u8] = b"CRDTRTCX";
+
+/// The versions of the encoding of the updates.
+///
+/// The format of the payload is detected when the updates are imported, and
+/// the decoders of all the versions are kept, so the documents persisted by
+/// the old releases can always be loaded. The document id, and the encryption
+/// mark with the encrypted annotation values, before the payload are the same
+/// in all the versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FormatVersion {
+    /// The columnar ops with the annotations inline, written by 0.1.0 and by
+    /// the op log storage before V3
+    V1,
+    /// The annotation anchors are split into run-length encoded columns, see
+    /// [`CompactAnnotations`]. The payload starts with `CRDTRTCA`.
+    V2,
+    /// The V2 payload followed by the kinds and the payloads of the custom
+    /// ops, see [`CustomOps`]. The payload starts with `CRDTRTCX`.
+    ///
+    /// The older versions can't carry the custom ops, they are encoded as
+    /// placeholders instead.
+    V3,
+}
+
+impl FormatVersion {
+    /// The version written by [`RichText::export`](crate::RichText::export)
+    pub const CURRENT: FormatVersion = FormatVersion::V3;
+    pub const ALL: [FormatVersion; 3] = [FormatVersion::V1, FormatVersion::V2, FormatVersion::V3];
+
+    /// The version of the encoded updates
+    pub fn of(encoded: &[u8]) -> Result<FormatVersion, Error> {
+        let (_, _, payload) = split_header(encoded)?;
+        Ok(Self::of_payload(payload))
+    }
+
+    fn of_payload(payload: &[u8]) -> FormatVersion {
+        if payload.starts_with(CUSTOM_OPS_MAGIC) {
+            FormatVersion::V3
+        } else if payload.starts_with(COMPACT_ANN_MAGIC) {
+            FormatVersion::V2
+        } else {
+            FormatVersion::V1
+        }
+    }
+}
 
 #[columnar(vec, ser, de)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,6 +124,115 @@ pub(super) struct AnnEncoding {
     value: u32,
 }
 
+/// The annotation with its anchors split into run-length encoded columns.
+///
+/// The anchors of the annotations made one after another are usually close
+/// to each other, so the clients and the types compress to a few runs and the
+/// counters to small deltas.
+#[columnar(vec, ser, de)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct CompactAnnEncoding {
+    /// index to the clients of [`CompactAnnotations`], `u32::MAX` for `None`
+    #[columnar(strategy = "Rle")]
+    start_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    start_counter: u32,
+    #[columnar(strategy = "Rle")]
+    is_start_before_anchor: bool,
+    #[columnar(strategy = "Rle")]
+    end_client: u32,
+    #[columnar(strategy = "DeltaRle")]
+    end_counter: u32,
+    #[columnar(strategy = "Rle")]
+    is_end_before_anchor: bool,
+    behavior: Behavior,
+    #[columnar(strategy = "Rle")]
+    type_: u32,
+    #[columnar(strategy = "Rle")]
+    value: u32,
+}
+
+#[columnar(ser, de)]
+#[derive(Debug, Serialize, Deserialize)]
+struct CompactAnnotations {
+    #[columnar(type = "vec")]
+    annotations: Vec<CompactAnnEncoding>,
+    clients: Vec<ClientID>,
+}
+
+impl CompactAnnotations {
+    fn new(annotations: &[AnnEncoding]) -> Self {
+        let mut clients = VecMapping::new();
+        let mut split = |id: Option<OpID>| match id {
+            Some(id) => (clients.get_or_insert(id.client) as u32, id.counter),
+            None => (u32::MAX, 0),
+        };
+        let annotations = annotations
+            .iter()
+            .map(|ann| {
+                let (start_client, start_counter) = split(ann.start);
+                let (end_client, end_counter) = split(ann.end);
+                CompactAnnEncoding {
+                    start_client,
+                    start_counter,
+                    is_start_before_anchor: ann.is_start_before_anchor,
+                    end_client,
+                    end_counter,
+                    is_end_before_anchor: ann.is_end_before_anchor,
+                    behavior: ann.behavior,
+                    type_: ann.type_,
+                    value: ann.value,
+                }
+            })
+            .collect();
+
+        Self {
+            annotations,
+            clients: clients.vec,
+        }
+    }
+
+    fn into_annotations(self) -> Result<Vec<AnnEncoding>, Error> {
+        let join = |client: u32, counter: u32| -> Result<Option<OpID>, Error> {
+            if client == u32::MAX {
+                return Ok(None);
+            }
+
+            let client =
+                self.clients.get(client as usize).copied().ok_or_else(|| {
+                    Error::DecodeError(format!("invalid client index {}", client))
+                })?;
+            Ok(Some(OpID::new(client, counter)))
+        };
+        self.annotations
+            .iter()
+            .map(|ann| {
+                Ok(AnnEncoding {
+                    start: join(ann.start_client, ann.start_counter)?,
+                    is_start_before_anchor: ann.is_start_before_anchor,
+                    end: join(ann.end_client, ann.end_counter)?,
+                    is_end_before_anchor: ann.is_end_before_anchor,
+                    behavior: ann.behavior,
+                    type_: ann.type_,
+                    value: ann.value,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The custom ops of the updates, in the order of their ops in
+/// [`DocEncoding`]
+#[columnar(ser, de)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CustomOps {
+    kinds: Vec<InternalString>,
+    /// index to kinds
+    kind_of: Vec<u32>,
+    /// encrypted if there is a cipher
+    payloads: Vec<Vec<u8>>,
+}
+
 #[columnar(ser, de)]
 #[derive(Debug, Serialize, Deserialize)]
 struct DocEncoding {
@@ -91,6 +258,8 @@ pub(crate) enum OpContentType {
     Insert = 0,
     Delete = 1,
     Ann = 2,
+    Placeholder = 3,
+    Custom = 4,
 }
 
 impl From<OpContentType> for u8 {
@@ -99,45 +268,308 @@ impl From<OpContentType> for u8 {
     }
 }
 
-impl From<u8> for OpContentType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for OpContentType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => OpContentType::Insert,
-            1 => OpContentType::Delete,
-            2 => OpContentType::Ann,
-            _ => unreachable!(),
+            0 => Ok(OpContentType::Insert),
+            1 => Ok(OpContentType::Delete),
+            2 => Ok(OpContentType::Ann),
+            3 => Ok(OpContentType::Placeholder),
+            4 => Ok(OpContentType::Custom),
+            _ => Err(Error::DecodeError(format!("unknown op type {}", value))),
         }
     }
 }
 
 type InnerUpdates = FxHashMap<ClientID, Vec<Op>>;
 
-pub fn encode(exported: InnerUpdates) -> Vec<u8> {
-    let data = to_doc_encoding(exported);
-    to_vec(&data).unwrap()
+/// Encode the updates with the id of the document they belong to, and encrypt
+/// the payloads if there is a cipher.
+///
+/// The id and the encryption mark are written before the encoded payload, so
+/// the updates without them are just the payload.
+pub fn encode_updates(
+    exported: InnerUpdates,
+    doc_id: Option<u128>,
+    cipher: Option<&dyn Cipher>,
+) -> Vec<u8> {
+    encode_updates_with(exported, doc_id, cipher, true, FormatVersion::CURRENT)
+}
+
+/// Encode the updates like [`encode_updates`] in the given format version,
+/// e.g. for the peers that haven't upgraded yet
+pub fn encode_updates_as(
+    exported: InnerUpdates,
+    doc_id: Option<u128>,
+    cipher: Option<&dyn Cipher>,
+    version: FormatVersion,
+) -> Vec<u8> {
+    encode_updates_with(exported, doc_id, cipher, true, version)
+}
+
+/// Encode the updates like [`encode_updates`] but never compress the text, so
+/// the same text is encoded to the same bytes in different updates. It's used
+/// by the chunked snapshots to deduplicate the chunks.
+pub fn encode_updates_uncompressed(
+    exported: InnerUpdates,
+    doc_id: Option<u128>,
+    cipher: Option<&dyn Cipher>,
+) -> Vec<u8> {
+    encode_updates_with(exported, doc_id, cipher, false, FormatVersion::CURRENT)
+}
+
+fn encode_updates_with(
+    exported: InnerUpdates,
+    doc_id: Option<u128>,
+    cipher: Option<&dyn Cipher>,
+    compress: bool,
+    version: FormatVersion,
+) -> Vec<u8> {
+    let mut ans = Vec::new();
+    if let Some(doc_id) = doc_id {
+        ans.extend_from_slice(DOC_ID_MAGIC);
+        ans.extend_from_slice(&doc_id.to_le_bytes());
+    }
+    let (mut data, custom_ops, values) = to_doc_encoding(exported, cipher, compress, version);
+    if cipher.is_some() {
+        // the distinct annotation values, each encrypted by itself
+        let values = to_vec(&values).unwrap();
+        ans.extend_from_slice(ENCRYPTED_MAGIC);
+        ans.extend_from_slice(&(values.len() as u32).to_le_bytes());
+        ans.extend_from_slice(&values);
+    }
+
+    if version == FormatVersion::V1 {
+        ans.extend_from_slice(&to_vec(&data).unwrap());
+        return ans;
+    }
+
+    let annotations = CompactAnnotations::new(&std::mem::take(&mut data.annotations));
+    let data = to_vec(&data).unwrap();
+    let mut payload = Vec::with_capacity(data.len() + COMPACT_ANN_MAGIC.len() + 4);
+    payload.extend_from_slice(COMPACT_ANN_MAGIC);
+    payload.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&data);
+    payload.extend_from_slice(&to_vec(&annotations).unwrap());
+    if version == FormatVersion::V2 {
+        ans.extend_from_slice(&payload);
+        return ans;
+    }
+
+    ans.extend_from_slice(CUSTOM_OPS_MAGIC);
+    ans.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    ans.extend_from_slice(&payload);
+    ans.extend_from_slice(&to_vec(&custom_ops).unwrap());
+    ans
+}
+
+pub fn decode_updates(
+    encoded: &[u8],
+    cipher: Option<&dyn Cipher>,
+) -> Result<(Option<u128>, InnerUpdates), Error> {
+    let (doc_id, values, rest) = split_header(encoded)?;
+    let (cipher, values) = match (values, cipher) {
+        (Some(_), None) => {
+            return Err(Error::DecodeError(
+                "the updates are encrypted but there is no cipher".to_string(),
+            ))
+        }
+        (Some(values), Some(cipher)) => {
+            let values: Vec<Vec<u8>> =
+                from_bytes(values).map_err(|e| Error::DecodeError(e.to_string()))?;
+            let values = values
+                .iter()
+                .map(|value| decode_value(&cipher.decrypt(value)?))
+                .collect::<Result<Vec<_>, Error>>()?;
+            (Some(cipher), Some(values))
+        }
+        (None, _) => (None, None),
+    };
+
+    let (doc, custom_ops) = decode_doc(rest)?;
+    Ok((doc_id, from_doc_encoding(doc, custom_ops, cipher, values)?))
+}
+
+/// Decode the payload of the updates in any [`FormatVersion`]
+fn decode_doc(encoded: &[u8]) -> Result<(DocEncoding, CustomOps), Error> {
+    match FormatVersion::of_payload(encoded) {
+        FormatVersion::V1 => Ok((decode_doc_v1(encoded)?, CustomOps::default())),
+        FormatVersion::V2 => Ok((
+            decode_doc_v2(&encoded[COMPACT_ANN_MAGIC.len()..])?,
+            CustomOps::default(),
+        )),
+        FormatVersion::V3 => decode_doc_v3(&encoded[CUSTOM_OPS_MAGIC.len()..]),
+    }
+}
+
+fn decode_doc_v1(encoded: &[u8]) -> Result<DocEncoding, Error> {
+    from_bytes(encoded).map_err(|e| Error::DecodeError(e.to_string()))
 }
 
-pub fn decode(encoded: &[u8]) -> InnerUpdates {
-    from_doc_encoding(from_bytes(encoded).unwrap())
+fn decode_doc_v2(rest: &[u8]) -> Result<DocEncoding, Error> {
+    let (doc, annotations) = split_len_prefixed(rest)?;
+    let mut doc: DocEncoding = from_bytes(doc).map_err(|e| Error::DecodeError(e.to_string()))?;
+    let annotations: CompactAnnotations =
+        from_bytes(annotations).map_err(|e| Error::DecodeError(e.to_string()))?;
+    doc.annotations = annotations.into_annotations()?;
+    Ok(doc)
 }
 
-fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
+fn decode_doc_v3(rest: &[u8]) -> Result<(DocEncoding, CustomOps), Error> {
+    let (payload, custom_ops) = split_len_prefixed(rest)?;
+    if !payload.starts_with(COMPACT_ANN_MAGIC) {
+        return Err(Error::DecodeError("invalid updates".to_string()));
+    }
+
+    let doc = decode_doc_v2(&payload[COMPACT_ANN_MAGIC.len()..])?;
+    let custom_ops = from_bytes(custom_ops).map_err(|e| Error::DecodeError(e.to_string()))?;
+    Ok((doc, custom_ops))
+}
+
+/// Split the bytes after a u32 length prefix into the prefixed part and the
+/// rest
+fn split_len_prefixed(rest: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if rest.len() < 4 {
+        return Err(Error::DecodeError("truncated updates".to_string()));
+    }
+
+    let (len, rest) = rest.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return Err(Error::DecodeError("truncated updates".to_string()));
+    }
+
+    Ok(rest.split_at(len))
+}
+
+/// Split the document id, and the encrypted annotation values after the
+/// encryption mark, from the encoded updates
+fn split_header(encoded: &[u8]) -> Result<(Option<u128>, Option<&[u8]>, &[u8]), Error> {
+    let (doc_id, rest) = match encoded.strip_prefix(DOC_ID_MAGIC) {
+        Some(rest) => {
+            if rest.len() < 16 {
+                return Err(Error::DecodeError("truncated document id".to_string()));
+            }
+
+            let (doc_id, rest) = rest.split_at(16);
+            (Some(u128::from_le_bytes(doc_id.try_into().unwrap())), rest)
+        }
+        None => (None, encoded),
+    };
+
+    match rest.strip_prefix(ENCRYPTED_MAGIC) {
+        Some(rest) => {
+            let (values, rest) = split_len_prefixed(rest)?;
+            Ok((doc_id, Some(values), rest))
+        }
+        None => Ok((doc_id, None, rest)),
+    }
+}
+
+/// Read the op ranges of the updates without decoding their payloads
+pub fn decode_meta(encoded: &[u8]) -> Result<UpdatesMeta, Error> {
+    let (doc_id, encrypted, rest) = split_header(encoded)?;
+    let (doc, _) = decode_doc(rest)?;
+    if doc.op_len.len() != doc.clients.len() || doc.start_counters.len() != doc.clients.len() {
+        return Err(Error::DecodeError(
+            "client table length mismatch".to_string(),
+        ));
+    }
+
+    let mut ans = UpdatesMeta {
+        encrypted: encrypted.is_some(),
+        doc_id,
+        ..Default::default()
+    };
+    let mut op_iter = doc.ops.iter();
+    let mut insert_iter = doc.inserts.iter();
+    let mut delete_iter = doc.deletes.iter();
+    let missing = |kind: &str| Error::DecodeError(format!("missing {} entry", kind));
+    for ((client, op_len), start) in doc
+        .clients
+        .iter()
+        .zip(doc.op_len.iter())
+        .zip(doc.start_counters.iter())
+    {
+        let mut end = *start;
+        for _ in 0..*op_len {
+            let op = op_iter.next().ok_or_else(|| missing("op"))?;
+            let len = match OpContentType::try_from(op.type_)? {
+                OpContentType::Insert => insert_iter.next().ok_or_else(|| missing("insert"))?.len,
+                OpContentType::Delete => delete_iter
+                    .next()
+                    .ok_or_else(|| missing("delete"))?
+                    .len
+                    .unsigned_abs(),
+                OpContentType::Ann | OpContentType::Placeholder | OpContentType::Custom => 1,
+            };
+            end = end
+                .checked_add(len)
+                .ok_or_else(|| Error::DecodeError("counter overflow".to_string()))?;
+        }
+
+        ans.start.vv.insert(*client, *start);
+        ans.end.vv.insert(*client, end);
+    }
+
+    Ok(ans)
+}
+
+/// Count the ops of the updates without decoding their payloads
+pub fn decode_score(encoded: &[u8]) -> Result<UpdateScore, Error> {
+    let (_, _, rest) = split_header(encoded)?;
+    let (doc, _) = decode_doc(rest)?;
+    Ok(UpdateScore {
+        bytes: encoded.len(),
+        ops: doc.ops.len(),
+        inserted: doc.inserts.iter().map(|x| x.len as usize).sum(),
+        deleted: doc
+            .deletes
+            .iter()
+            .map(|x| x.len.unsigned_abs() as usize)
+            .sum(),
+        annotations: doc.annotations.len(),
+        clients: doc.clients.len(),
+    })
+}
+
+fn to_doc_encoding(
+    mut exported_map: InnerUpdates,
+    cipher: Option<&dyn Cipher>,
+    compress: bool,
+    version: FormatVersion,
+) -> (DocEncoding, CustomOps, Vec<Vec<u8>>) {
     exported_map.retain(|_, v| !v.is_empty());
+    // sort the clients and merge the ops that are split by how they were
+    // received, so the same ops are always encoded to the same bytes
+    let mut bytes = AppendOnlyBytes::new();
+    let mut exported: Vec<(ClientID, Vec<Op>)> = exported_map
+        .into_iter()
+        .map(|(client, ops)| (client, merge_ops(ops, &mut bytes)))
+        .collect();
+    exported.sort_unstable_by_key(|(client, _)| *client);
     let mut inserts = Vec::new();
     let mut deletes = Vec::new();
     let mut annotations = Vec::new();
     let mut client_mapping = VecMapping::new();
-    for client in exported_map.keys() {
+    for (client, _) in exported.iter() {
         client_mapping.get_or_insert(*client);
     }
 
     let mut ann_str_mapping = VecMapping::new();
+    // with a cipher, the values are deduplicated before they are encrypted,
+    // so every distinct value is encrypted once
+    let mut ann_values: VecMapping<InternalString> = VecMapping::new();
+    let mut custom_kinds = VecMapping::new();
+    let mut custom_ops = CustomOps::default();
     let mut op_len: Vec<u32> = Vec::new();
     let mut start_counters: Vec<u32> = Vec::new();
-    let mut ops = Vec::with_capacity(exported_map.iter().map(|x| x.1.len()).sum());
+    let mut ops = Vec::with_capacity(exported.iter().map(|x| x.1.len()).sum());
     let mut str = Vec::new();
 
-    for (_, op_arr) in exported_map.iter() {
+    for (_, op_arr) in exported.iter() {
         op_len.push(op_arr.len() as u32);
         start_counters.push(op_arr[0].id.counter);
         for op in op_arr {
@@ -172,8 +604,11 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
                     let start = ann.range.start.id;
                     let end = ann.range.end.id;
                     let type_ = ann_str_mapping.get_or_insert(ann.type_.clone());
-                    let value = serde_json::to_string(&ann.value).unwrap();
-                    let value = ann_str_mapping.get_or_insert(value.into());
+                    let value = serde_json::to_string(&ann.value).unwrap().into();
+                    let value = match cipher {
+                        Some(_) => ann_values.get_or_insert(value),
+                        None => ann_str_mapping.get_or_insert(value),
+                    };
                     annotations.push(AnnEncoding {
                         start,
                         is_start_before_anchor: ann.range.start.type_ == AnchorType::Before,
@@ -185,6 +620,19 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
                     });
                     OpContentType::Ann
                 }
+                crate::rich_text::op::OpContent::Placeholder => OpContentType::Placeholder,
+                crate::rich_text::op::OpContent::Custom(_) if version < FormatVersion::V3 => {
+                    OpContentType::Placeholder
+                }
+                crate::rich_text::op::OpContent::Custom(custom) => {
+                    let kind = custom_kinds.get_or_insert(custom.kind.clone());
+                    custom_ops.kind_of.push(kind as u32);
+                    custom_ops.payloads.push(match cipher {
+                        Some(cipher) => cipher.encrypt(&custom.payload),
+                        None => custom.payload.to_vec(),
+                    });
+                    OpContentType::Custom
+                }
             };
 
             ops.push(OpEncoding {
@@ -194,7 +642,7 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
         }
     }
 
-    assert_eq!(op_len.len(), exported_map.len());
+    assert_eq!(op_len.len(), exported.len());
     assert_eq!(op_len.len(), start_counters.len());
     assert_eq!(op_len.iter().sum::<u32>() as usize, ops.len());
     debug_assert_eq!(
@@ -202,14 +650,26 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
         inserts.iter().map(|x| x.len).sum::<u32>() as usize
     );
     let mut compressed_str = false;
-    if str.len() > COMPRESS_THRESHOLD {
+    if compress && str.len() > COMPRESS_THRESHOLD {
         compressed_str = true;
         let mut e = GzEncoder::new(Vec::new(), Compression::default());
         e.write_all(&str).unwrap();
         str = e.finish().unwrap();
     }
+    if let Some(cipher) = cipher {
+        str = cipher.encrypt(&str);
+    }
 
-    DocEncoding {
+    custom_ops.kinds = custom_kinds.vec;
+    let values = match cipher {
+        Some(cipher) => ann_values
+            .vec
+            .iter()
+            .map(|value| cipher.encrypt(value.as_bytes()))
+            .collect(),
+        None => Vec::new(),
+    };
+    let doc = DocEncoding {
         ops,
         inserts,
         deletes,
@@ -220,26 +680,84 @@ fn to_doc_encoding(mut exported_map: InnerUpdates) -> DocEncoding {
         op_len,
         start_counters,
         str,
+    };
+    (doc, custom_ops, values)
+}
+
+/// Merge the adjacent ops that can be merged. The text is copied into `bytes`
+/// so that the texts of the adjacent inserts are contiguous.
+fn merge_ops(ops: Vec<Op>, bytes: &mut AppendOnlyBytes) -> Vec<Op> {
+    let mut ans: Vec<Op> = Vec::with_capacity(ops.len());
+    for mut op in ops {
+        if let OpContent::Text(insert) = &mut op.content {
+            let start = bytes.len();
+            bytes.push_slice(&insert.text);
+            insert.text = bytes.slice(start..);
+        }
+
+        match ans.last_mut() {
+            Some(last) if last.can_merge(&op) => last.merge_right(&op),
+            _ => ans.push(op),
+        }
     }
+
+    ans
 }
 
-fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
+/// Decode the ops. With a cipher, the annotation values are the decrypted
+/// `values` instead of the ones in the string table.
+fn from_doc_encoding(
+    exported: DocEncoding,
+    custom_ops: CustomOps,
+    cipher: Option<&dyn Cipher>,
+    values: Option<Vec<serde_json::Value>>,
+) -> Result<InnerUpdates, Error> {
     let clients = &exported.clients;
+    if exported.op_len.len() != clients.len() || exported.start_counters.len() != clients.len() {
+        return Err(Error::DecodeError(
+            "client table length mismatch".to_string(),
+        ));
+    }
+
+    let get_client = |index: u32| -> Result<ClientID, Error> {
+        clients
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| Error::DecodeError(format!("invalid client index {}", index)))
+    };
+    let get_str = |index: u32| -> Result<&InternalString, Error> {
+        exported
+            .ann_types_and_values
+            .get(index as usize)
+            .ok_or_else(|| Error::DecodeError(format!("invalid string index {}", index)))
+    };
+
+    let decrypted;
+    let raw_str = match cipher {
+        Some(cipher) => {
+            decrypted = cipher.decrypt(&exported.str)?;
+            &decrypted
+        }
+        None => &exported.str,
+    };
     let mut str = AppendOnlyBytes::new();
     if exported.compressed_str {
-        let mut d = GzDecoder::new(exported.str.deref());
+        let mut d = GzDecoder::new(raw_str.deref());
         let mut ans = vec![];
-        d.read_to_end(&mut ans).unwrap();
+        d.read_to_end(&mut ans)
+            .map_err(|e| Error::DecodeError(e.to_string()))?;
         str.push_slice(&ans);
     } else {
-        str.push_slice(&exported.str);
+        str.push_slice(raw_str);
     }
     let mut str_index = 0;
     let mut ans: InnerUpdates = Default::default();
     let mut insert_iter = exported.inserts.iter();
     let mut delete_iter = exported.deletes.iter();
     let mut ann_iter = exported.annotations.iter();
+    let mut custom_iter = custom_ops.kind_of.iter().zip(custom_ops.payloads);
     let mut op_iter = exported.ops.iter();
+    let missing = |kind: &str| Error::DecodeError(format!("missing {} entry", kind));
     for ((client, op_len), counter) in exported
         .clients
         .iter()
@@ -249,17 +767,17 @@ fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
         let mut counter = *counter;
         let mut arr = Vec::with_capacity((*op_len) as usize);
         for _ in 0..*op_len {
-            let op = op_iter.next().unwrap();
+            let op = op_iter.next().ok_or_else(|| missing("op"))?;
             let id = OpID {
                 client: *client,
                 counter,
             };
-            let content = match op.type_.into() {
+            let content = match OpContentType::try_from(op.type_)? {
                 OpContentType::Insert => {
-                    let insert = insert_iter.next().unwrap();
+                    let insert = insert_iter.next().ok_or_else(|| missing("insert"))?;
                     let left = if insert.left_client != u32::MAX {
                         Some(OpID {
-                            client: clients[insert.left_client as usize],
+                            client: get_client(insert.left_client)?,
                             counter: insert.left_counter,
                         })
                     } else {
@@ -267,29 +785,38 @@ fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
                     };
                     let right = if insert.right_client != u32::MAX {
                         Some(OpID {
-                            client: clients[insert.right_client as usize],
+                            client: get_client(insert.right_client)?,
                             counter: insert.right_counter,
                         })
                     } else {
                         None
                     };
                     let end = str_index + insert.len as usize;
+                    if end > str.len() {
+                        return Err(Error::DecodeError("text out of bound".to_string()));
+                    }
                     let text = str.slice(str_index..end);
+                    if std::str::from_utf8(&text).is_err() {
+                        return Err(Error::DecodeError("invalid utf8 text".to_string()));
+                    }
                     str_index = end;
                     OpContent::Text(TextInsertOp { left, right, text })
                 }
                 OpContentType::Delete => {
-                    let delete = delete_iter.next().unwrap();
+                    let delete = delete_iter.next().ok_or_else(|| missing("delete"))?;
+                    if delete.len == 0 {
+                        return Err(Error::DecodeError("empty delete".to_string()));
+                    }
                     OpContent::Del(DeleteOp {
                         start: OpID {
-                            client: clients[delete.start_client as usize],
+                            client: get_client(delete.start_client)?,
                             counter: delete.start_counter,
                         },
                         len: delete.len,
                     })
                 }
                 OpContentType::Ann => {
-                    let ann = ann_iter.next().unwrap();
+                    let ann = ann_iter.next().ok_or_else(|| missing("annotation"))?;
                     let range = AnchorRange {
                         start: Anchor {
                             id: ann.start,
@@ -312,13 +839,34 @@ fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
                     OpContent::Ann(Arc::new(Annotation {
                         range,
                         behavior: ann.behavior,
-                        type_: exported.ann_types_and_values[ann.type_ as usize].clone(),
+                        type_: get_str(ann.type_)?.clone(),
                         id,
                         range_lamport: (op.lamport, id),
-                        value: serde_json::from_str(
-                            &exported.ann_types_and_values[ann.value as usize],
-                        )
-                        .unwrap(),
+                        value: match &values {
+                            Some(values) => {
+                                values.get(ann.value as usize).cloned().ok_or_else(|| {
+                                    Error::DecodeError(format!("invalid value index {}", ann.value))
+                                })?
+                            }
+                            None => decode_value(get_str(ann.value)?.as_bytes())?,
+                        },
+                    }))
+                }
+                OpContentType::Placeholder => OpContent::Placeholder,
+                OpContentType::Custom => {
+                    let (kind, payload) = custom_iter.next().ok_or_else(|| missing("custom op"))?;
+                    let kind = custom_ops.kinds.get(*kind as usize).ok_or_else(|| {
+                        Error::DecodeError(format!("invalid custom op kind {}", kind))
+                    })?;
+                    let payload = match cipher {
+                        Some(cipher) => cipher.decrypt(&payload)?,
+                        None => payload,
+                    };
+                    OpContent::Custom(Arc::new(CustomOp {
+                        id,
+                        lamport: op.lamport,
+                        kind: kind.clone(),
+                        payload: payload.into(),
                     }))
                 }
             };
@@ -328,14 +876,20 @@ fn from_doc_encoding(exported: DocEncoding) -> InnerUpdates {
                 lamport: op.lamport,
                 content,
             };
-            counter += op.rle_len() as u32;
+            counter = counter
+                .checked_add(op.rle_len() as u32)
+                .ok_or_else(|| Error::DecodeError("counter overflow".to_string()))?;
             arr.push(op);
         }
 
         ans.insert(*client, arr);
     }
 
-    ans
+    Ok(ans)
+}
+
+fn decode_value(value: &[u8]) -> Result<serde_json::Value, Error> {
+    serde_json::from_slice(value).map_err(|e| Error::DecodeError(e.to_string()))
 }
 
 struct VecMapping<T> {
@@ -1,7 +1,57 @@
+use crate::{ClientID, Counter};
+
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
-    #[error("Decode error")]
-    DecodeError,
+    #[error("Decode error: {0}")]
+    DecodeError(String),
     #[error("Invalid expand")]
     InvalidExpand,
+    #[error("Invalid op: {0}")]
+    InvalidOp(String),
+    #[error("The document is readonly")]
+    Readonly,
+    #[error("Index overflow: the document is too large")]
+    IndexOverflow,
+    #[error("Invalid value for the annotation type {0}")]
+    InvalidValue(String),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("The updates belong to another document")]
+    DocIdMismatch,
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The {kind} of the document exceeds the limit {limit}")]
+    LimitExceeded { kind: LimitKind, limit: usize },
+    /// All the ops of the update were accepted before, see
+    /// [`ReplayGuard`](crate::rich_text::admission::ReplayGuard)
+    #[error("Replayed update: the ops of client {client} from {counter} are accepted, expected {expected}")]
+    Replayed {
+        client: ClientID,
+        counter: Counter,
+        expected: Counter,
+    },
+    /// The history has not been offloaded, see
+    /// [`RichText::compress_tombstones`](crate::RichText::compress_tombstones)
+    #[error("The history has not been offloaded")]
+    NotOffloaded,
+}
+
+/// The kind of the limit in [`Error::LimitExceeded`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Length,
+    Annotations,
+    /// The text inserted by a transaction, see [`RichText::transact`](crate::RichText::transact)
+    TransactionInserted,
+}
+
+impl std::fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitKind::Length => f.write_str("length"),
+            LimitKind::Annotations => f.write_str("annotation count"),
+            LimitKind::TransactionInserted => f.write_str("text inserted by the transaction"),
+        }
+    }
 }
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use serde::{Deserialize, Serialize};
 
 use super::{delta::DeltaItem, rich_tree::query::IndexType};
@@ -7,4 +9,46 @@ pub struct Event {
     pub ops: Vec<DeltaItem>,
     pub is_local: bool,
     pub index_type: IndexType,
+    /// The origin of the import, see [`ImportOptions`](super::ImportOptions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+/// Whether the delta changes the text or the styles in the range. The range
+/// is in the indexes after the change, and the changes at its edges count.
+pub(super) fn delta_touches(ops: &[DeltaItem], range: &Range<usize>) -> bool {
+    let mut index = 0;
+    for op in ops {
+        if index > range.end {
+            break;
+        }
+
+        let len = op.length();
+        match op {
+            DeltaItem::Retain {
+                attributes: None, ..
+            } => {}
+            DeltaItem::Retain { .. } => {
+                if index < range.end && range.start < index + len {
+                    return true;
+                }
+            }
+            DeltaItem::Insert { .. } => {
+                if index <= range.end && range.start <= index + len {
+                    return true;
+                }
+            }
+            DeltaItem::Delete { .. } => {
+                if range.start <= index {
+                    return true;
+                }
+            }
+        }
+
+        if !op.is_delete() {
+            index += len;
+        }
+    }
+
+    false
 }
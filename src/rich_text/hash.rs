@@ -0,0 +1,111 @@
+//! Hashing the state of the document.
+//!
+//! The hash should be the same on every platform, so we cannot use the
+//! hashers from std or fxhash, whose results depend on the pointer width or
+//! the build. FNV-1a is used instead.
+
+use std::hash::Hasher;
+
+use generic_btree::rle::{HasLength, Sliceable};
+
+use crate::{AnchorType, OpID};
+
+use super::{
+    op::{Op, OpContent},
+    Span,
+};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+pub(super) struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(FNV_OFFSET)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+impl StableHasher {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.write(bytes);
+    }
+
+    /// The attributes are hashed in the order of their keys, so that the
+    /// result doesn't depend on the iteration order of the map.
+    pub(super) fn write_span(&mut self, span: &Span) {
+        self.write_bytes(span.insert.as_bytes());
+        let mut attributes: Vec<_> = span.attributes.iter().collect();
+        attributes.sort_unstable_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        self.write_u64(attributes.len() as u64);
+        for (key, value) in attributes {
+            self.write_bytes(key.as_bytes());
+            self.write_bytes(value.to_string().as_bytes());
+        }
+    }
+
+    /// Hash the op atom by atom, so that the result doesn't depend on how the
+    /// ops are merged or split in the op log.
+    ///
+    /// The right origins of the text ops are not hashed, because they are
+    /// changed by the merging.
+    pub(super) fn write_op(&mut self, op: &Op) {
+        for i in 0..op.rle_len() {
+            let atom = op.slice(i..i + 1);
+            self.write_id(Some(atom.id));
+            self.write_u32(atom.lamport);
+            match &atom.content {
+                OpContent::Text(text) => {
+                    self.write_u8(0);
+                    self.write_id(text.left);
+                    self.write_bytes(&text.text);
+                }
+                OpContent::Del(del) => {
+                    self.write_u8(1);
+                    self.write_id(Some(del.positive().start));
+                }
+                OpContent::Ann(ann) => {
+                    self.write_u8(2);
+                    for anchor in [&ann.range.start, &ann.range.end] {
+                        self.write_id(anchor.id);
+                        self.write_u8((anchor.type_ == AnchorType::Before) as u8);
+                    }
+                    self.write_u8(ann.behavior as u8);
+                    self.write_bytes(ann.type_.as_bytes());
+                    self.write_bytes(ann.value.to_string().as_bytes());
+                }
+                OpContent::Placeholder => self.write_u8(3),
+                OpContent::Custom(custom) => {
+                    self.write_u8(4);
+                    self.write_bytes(custom.kind.as_bytes());
+                    self.write_bytes(&custom.payload);
+                }
+            }
+        }
+    }
+
+    fn write_id(&mut self, id: Option<OpID>) {
+        match id {
+            Some(id) => {
+                self.write_u8(1);
+                self.write_u64(id.client);
+                self.write_u32(id.counter);
+            }
+            None => self.write_u8(0),
+        }
+    }
+}
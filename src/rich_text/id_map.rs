@@ -41,6 +41,16 @@ impl<Value: Clone + std::fmt::Debug> IdMap<Value> {
         self.map.is_empty()
     }
 
+    /// Approximate heap size in bytes
+    pub fn mem_size(&self) -> usize {
+        let entry_size = std::mem::size_of::<Counter>()
+            + std::mem::size_of::<Rc<RefCell<Entry<Value>>>>()
+            + std::mem::size_of::<RefCell<Entry<Value>>>()
+            // strong and weak counts of Rc
+            + 2 * std::mem::size_of::<usize>();
+        self.map.values().map(|x| x.len() * entry_size).sum()
+    }
+
     pub fn get(&self, id: OpID) -> Option<RefMut<'_, Entry<Value>>> {
         let client_map = self.map.get(&id.client)?;
         client_map
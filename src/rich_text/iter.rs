@@ -3,7 +3,7 @@ use std::mem::take;
 use fxhash::FxHashMap;
 use generic_btree::{rle::Mergeable, QueryResult};
 
-use crate::Behavior;
+use crate::{Annotation, Behavior};
 
 use super::{
     ann::{Span, StyleCalculator},
@@ -17,6 +17,7 @@ pub struct Iter<'a> {
     end: Option<QueryResult>,
     pending_return: Option<Span>,
     done: bool,
+    filter: Option<Box<dyn Fn(&Annotation) -> bool + 'a>>,
 }
 
 impl<'a> Iter<'a> {
@@ -34,9 +35,16 @@ impl<'a> Iter<'a> {
             pending_return: None,
             done: false,
             end: None,
+            filter: None,
         }
     }
 
+    /// Only the annotations that `filter` returns true are included in the spans
+    pub(crate) fn with_filter(mut self, filter: impl Fn(&Annotation) -> bool + 'a) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
     pub(crate) fn new_range(
         text: &'a RichText,
         start: QueryResult,
@@ -50,6 +58,7 @@ impl<'a> Iter<'a> {
             pending_return: None,
             done: false,
             end,
+            filter: None,
         }
     }
 }
@@ -114,17 +123,27 @@ impl<'a> Iterator for Iter<'a> {
                     .end
                     .map_or(false, |end| end.elem_index == self.cursor.elem_index);
             self.style_calc.apply_start(&elem.anchor_set);
-            let annotations: FxHashMap<_, _> = self
+            let mut annotations: FxHashMap<_, _> = self
                 .style_calc
                 .calc_styles(&self.text.ann)
                 .filter_map(|x| {
-                    if x.behavior == Behavior::Delete {
+                    if x.behavior == Behavior::Delete
+                        || self.filter.as_ref().map_or(false, |f| !f(&*x))
+                    {
                         None
                     } else {
                         Some((x.type_.clone(), x.value.clone()))
                     }
                 })
                 .collect();
+            if !self.text.value_modes.is_empty() {
+                self.style_calc.merge_values(
+                    &self.text.ann,
+                    &self.text.value_modes,
+                    |x| self.filter.as_ref().map_or(true, |f| f(x)),
+                    &mut annotations,
+                );
+            }
             self.style_calc.apply_end(&elem.anchor_set);
             self.cursor.elem_index += 1;
             let ans = Span {
@@ -145,6 +164,12 @@ impl<'a> Iterator for Iter<'a> {
                 self.done = true;
             }
 
+            // An empty span can be produced when the range starts or ends at the
+            // boundary of an element. It should not break the coalescing.
+            if ans.is_empty() {
+                continue;
+            }
+
             if let Some(mut pending) = pending_return {
                 if pending.can_merge(&ans) {
                     pending.merge_right(&ans);
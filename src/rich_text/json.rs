@@ -0,0 +1,126 @@
+//! A plain JSON form of the document for the consumers that don't understand
+//! the CRDT, e.g. analytics and search indexing.
+//!
+//! ```json
+//! {
+//!   "text": "Hello world",
+//!   "runs": [{ "start": 0, "end": 5, "attributes": { "bold": true } }],
+//!   "meta": { "index_type": "Utf8", "len": 11, "version": { "vv": { "1": 12 } }, "doc_id": null }
+//! }
+//! ```
+//!
+//! The runs are the styled ranges of the text in utf8 bytes. The text without
+//! styles is not covered by any run.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Behavior, Expand, Style};
+
+use super::{vv::VersionVector, Error, IndexType, RichText};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentJson {
+    pub text: String,
+    pub runs: Vec<Run>,
+    pub meta: DocumentMeta,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub start: usize,
+    pub end: usize,
+    pub attributes: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentMeta {
+    /// The unit of the indexes of the runs. It's always utf8 for now.
+    pub index_type: IndexType,
+    pub len: usize,
+    pub version: VersionVector,
+    /// The document id in hex, see [`RichText::set_doc_id`]
+    pub doc_id: Option<String>,
+}
+
+impl DocumentJson {
+    pub fn from_doc(doc: &RichText) -> Self {
+        let mut text = String::with_capacity(doc.len());
+        let mut runs: Vec<Run> = Vec::new();
+        for span in doc.iter() {
+            let start = text.len();
+            text.push_str(&span.insert);
+            if span.attributes.is_empty() {
+                continue;
+            }
+
+            let attributes: BTreeMap<String, Value> = span
+                .attributes
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect();
+            match runs.last_mut() {
+                Some(last) if last.end == start && last.attributes == attributes => {
+                    last.end = text.len()
+                }
+                _ => runs.push(Run {
+                    start,
+                    end: text.len(),
+                    attributes,
+                }),
+            }
+        }
+
+        Self {
+            meta: DocumentMeta {
+                index_type: IndexType::Utf8,
+                len: text.len(),
+                version: doc.version(),
+                doc_id: doc.doc_id().map(|id| format!("{:032x}", id)),
+            },
+            text,
+            runs,
+        }
+    }
+
+    /// Create a new document with the text and the styles. The history is not
+    /// restored, so the document doesn't share the ops with the original one.
+    ///
+    /// The expand of the styles is inferred from their types.
+    pub fn to_doc(&self, client_id: u64) -> Result<RichText, Error> {
+        let mut doc = RichText::new(client_id);
+        if let Some(doc_id) = &self.meta.doc_id {
+            let doc_id = u128::from_str_radix(doc_id, 16)
+                .map_err(|_| Error::DecodeError(format!("invalid document id {}", doc_id)))?;
+            doc.set_doc_id(Some(doc_id));
+        }
+
+        doc.insert(0, &self.text);
+        for run in self.runs.iter() {
+            if run.start > run.end
+                || run.end > self.text.len()
+                || !self.text.is_char_boundary(run.start)
+                || !self.text.is_char_boundary(run.end)
+            {
+                return Err(Error::DecodeError(format!(
+                    "invalid run {}..{}",
+                    run.start, run.end
+                )));
+            }
+
+            for (key, value) in run.attributes.iter() {
+                let style = Style::new_from_expand(
+                    Expand::infer_insert_expand(key),
+                    key.as_str().into(),
+                    value.clone(),
+                    Behavior::Merge,
+                )?;
+                doc.annotate(run.start..run.end, style);
+            }
+        }
+
+        Ok(doc)
+    }
+}
@@ -0,0 +1,147 @@
+//! The numbering of the ordered list items as derived state of the document.
+//!
+//! A line is a list item when its trailing line break has the list attribute,
+//! as in the block format of Quill. The number of an item is one more than the
+//! number of the item on the previous line, or 1 if the previous line is not
+//! an item.
+
+use serde_json::Value;
+
+use crate::InternalString;
+
+use super::{delta::DeltaItem, rich_tree::utf16::get_utf16_len, Event, IndexType, RichText};
+
+/// The numbers of the ordered list items of a [`RichText`].
+///
+/// It's updated incrementally by [`ListNumbering::update`]: only the lines
+/// touched by the event and the items following them are renumbered.
+#[derive(Debug, Clone)]
+pub struct ListNumbering {
+    key: InternalString,
+    value: Value,
+    numbers: Vec<Option<usize>>,
+}
+
+impl ListNumbering {
+    /// Number the lines with the `list: "ordered"` attribute
+    pub fn new(text: &RichText) -> Self {
+        Self::with_attribute(text, "list", "ordered".into())
+    }
+
+    /// Number the lines with the `key: value` attribute
+    pub fn with_attribute(text: &RichText, key: &str, value: Value) -> Self {
+        let mut ans = Self {
+            key: key.into(),
+            value,
+            numbers: Vec::new(),
+        };
+        ans.renumber(text, 0, None);
+        ans
+    }
+
+    /// Get the number of the list item at the line. Return `None` if the line
+    /// is not a list item.
+    pub fn get(&self, line: usize) -> Option<usize> {
+        self.numbers.get(line).copied().flatten()
+    }
+
+    /// The numbers of all the lines
+    pub fn numbers(&self) -> &[Option<usize>] {
+        &self.numbers
+    }
+
+    /// Update the numbers with the event of the text.
+    ///
+    /// It should be called for every event of the text in order, while the
+    /// text is still at the state right after the event.
+    pub fn update(&mut self, text: &RichText, event: &Event) {
+        let Some((start, end)) = changed_lines(text, event) else {
+            return;
+        };
+
+        self.renumber(text, start, Some(end));
+    }
+
+    /// Renumber the lines from `start`. The lines after `end` are the same as
+    /// before, so it stops at the first of them whose number is not changed.
+    fn renumber(&mut self, text: &RichText, start: usize, end: Option<usize>) {
+        let old = std::mem::take(&mut self.numbers);
+        let lines = text.lines();
+        let start = start.min(old.len());
+        let mut numbers = old[..start].to_vec();
+        let mut last = numbers.last().copied().flatten();
+        for line in start..lines {
+            let number = if self.is_item(text, line) {
+                Some(last.map_or(1, |x| x + 1))
+            } else {
+                None
+            };
+
+            if let Some(end) = end {
+                if line > end {
+                    // the lines after `end` are shifted by the line breaks inserted or deleted
+                    let old_line = (line + old.len()).checked_sub(lines);
+                    if let Some(old_line) = old_line.filter(|&x| x < old.len()) {
+                        if old[old_line] == number {
+                            numbers.extend_from_slice(&old[old_line..]);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            numbers.push(number);
+            last = number;
+        }
+
+        self.numbers = numbers;
+    }
+
+    fn is_item(&self, text: &RichText, line: usize) -> bool {
+        let spans = text.get_line(line);
+        let Some(last) = spans.last() else {
+            return false;
+        };
+
+        last.insert.ends_with('\n') && last.attributes.get(&self.key) == Some(&self.value)
+    }
+}
+
+/// Get the range of the lines changed by the event, in the current state of the text
+fn changed_lines(text: &RichText, event: &Event) -> Option<(usize, usize)> {
+    let mut index = 0;
+    let mut start: Option<usize> = None;
+    let mut end = 0;
+    for item in event.ops.iter() {
+        let (from, to) = match item {
+            DeltaItem::Retain {
+                retain, attributes, ..
+            } => {
+                index += retain;
+                if attributes.is_none() {
+                    continue;
+                }
+
+                (index - retain, index)
+            }
+            DeltaItem::Insert { insert, len, .. } => {
+                let len = len.unwrap_or_else(|| match event.index_type {
+                    IndexType::Utf8 => insert.len(),
+                    IndexType::Utf16 => get_utf16_len(insert),
+                });
+                index += len;
+                (index - len, index)
+            }
+            DeltaItem::Delete { .. } => (index, index),
+        };
+
+        start = Some(start.unwrap_or(from));
+        end = to;
+    }
+
+    let start = start?;
+    Some((
+        text.line_at(start, event.index_type),
+        text.line_at(end, event.index_type),
+    ))
+}
@@ -1,12 +1,21 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, Range},
+    sync::Arc,
+};
 
-use append_only_bytes::BytesSlice;
+use append_only_bytes::{AppendOnlyBytes, BytesSlice};
 use fxhash::FxHashMap;
 use generic_btree::rle::{HasLength, Mergeable, Sliceable};
 
 use crate::{Annotation, ClientID, Counter, Lamport, OpID};
 
-use super::vv::VersionVector;
+use super::{
+    encoding::{decode_updates, encode_updates},
+    storage::{MemoryStorage, OpLogStorage},
+    vv::VersionVector,
+    CustomOp, Error,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Op {
@@ -20,6 +29,14 @@ pub enum OpContent {
     Ann(Arc<Annotation>),
     Text(TextInsertOp),
     Del(DeleteOp),
+    /// An annotation op that was filtered out by the exporter.
+    ///
+    /// It only occupies the op id so the following ops from the same
+    /// client can still be applied, and it has no effect on the document.
+    Placeholder,
+    /// An op of a kind registered by an extension. It has no effect on the
+    /// document.
+    Custom(Arc<CustomOp>),
 }
 
 impl OpContent {
@@ -104,7 +121,7 @@ impl PartialEq for DeleteOp {
 impl Eq for DeleteOp {}
 
 impl DeleteOp {
-    fn slice(&self, start: usize, end: usize) -> Self {
+    pub(super) fn slice(&self, start: usize, end: usize) -> Self {
         let len = end - start;
         assert!(end <= self.len as usize);
         if self.len > 0 {
@@ -204,7 +221,7 @@ impl Mergeable for DeleteOp {
 impl HasLength for Op {
     fn rle_len(&self) -> usize {
         match &self.content {
-            OpContent::Ann(_) => 1,
+            OpContent::Ann(_) | OpContent::Placeholder | OpContent::Custom(_) => 1,
             OpContent::Text(text) => text.text.len(),
             OpContent::Del(del) => del.len.unsigned_abs() as usize,
         }
@@ -260,6 +277,16 @@ impl Sliceable for Op {
                 lamport: self.lamport + (start as Lamport),
                 content: OpContent::Ann(a.clone()),
             },
+            OpContent::Placeholder => Op {
+                id: self.id.inc(start as Counter),
+                lamport: self.lamport + (start as Lamport),
+                content: OpContent::Placeholder,
+            },
+            OpContent::Custom(custom) => Op {
+                id: self.id.inc(start as Counter),
+                lamport: self.lamport + (start as Lamport),
+                content: OpContent::Custom(custom.clone()),
+            },
             OpContent::Text(text) => Op {
                 id: self.id.inc(start as Counter),
                 lamport: self.lamport + (start as Lamport),
@@ -288,6 +315,11 @@ impl Sliceable for Op {
 
 pub struct OpStore {
     map: FxHashMap<ClientID, Vec<Op>>,
+    /// The counter ranges of the chunks of every client that are moved to the storage
+    cold: FxHashMap<ClientID, Vec<Range<Counter>>>,
+    /// The number of the ops in the cold chunks
+    cold_op_len: usize,
+    storage: Box<dyn OpLogStorage>,
     pub(crate) client: ClientID,
     next_lamport: Lamport,
 }
@@ -298,6 +330,7 @@ impl std::fmt::Debug for OpStore {
             .field("client", &self.client)
             .field("next_lamport", &self.next_lamport)
             .field("map", &self.map.len())
+            .field("cold", &self.cold)
             .finish()?;
 
         for (key, value) in self.map.iter() {
@@ -317,6 +350,9 @@ impl OpStore {
     pub fn new(client: ClientID) -> Self {
         Self {
             map: Default::default(),
+            cold: Default::default(),
+            cold_op_len: 0,
+            storage: Box::<MemoryStorage>::default(),
             client,
             next_lamport: 0,
         }
@@ -353,39 +389,146 @@ impl OpStore {
         }
     }
 
-    pub fn export(&self, other_vv: &VersionVector) -> FxHashMap<ClientID, Vec<Op>> {
+    pub fn export(&self, other_vv: &VersionVector) -> Result<FxHashMap<ClientID, Vec<Op>>, Error> {
         let mut ans: FxHashMap<ClientID, Vec<Op>> = FxHashMap::default();
-        for (client, vec) in self.map.iter() {
-            let target_counter = other_vv.vv.get(client).unwrap_or(&0);
-            if *target_counter
-                >= vec
-                    .last()
-                    .map(|x| x.id.counter + x.rle_len() as Counter)
-                    .unwrap_or(0)
-            {
+        for client in self.clients() {
+            if let Some(ops) = self.export_client(client, other_vv)? {
+                ans.insert(client, ops);
+            }
+        }
+
+        Ok(ans)
+    }
+
+    /// The ops of the client not included in `other_vv`, see [`OpStore::export`]
+    pub fn export_client(
+        &self,
+        client: ClientID,
+        other_vv: &VersionVector,
+    ) -> Result<Option<Vec<Op>>, Error> {
+        match self.map.get(&client) {
+            Some(vec) => self.ops_after(client, vec, *other_vv.vv.get(&client).unwrap_or(&0)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = ClientID> + '_ {
+        self.map.keys().copied()
+    }
+
+    /// Whether there are ops not included in `other_vv`. It only compares the
+    /// ends of the clients, so nothing is copied or loaded.
+    pub fn has_ops_after(&self, other_vv: &VersionVector) -> bool {
+        self.map.iter().any(|(client, vec)| {
+            let end = vec
+                .last()
+                .map_or(0, |last| last.id.counter + last.rle_len() as Counter);
+            end > *other_vv.vv.get(client).unwrap_or(&0)
+        })
+    }
+
+    /// The ops of the client from `counter`. The cold chunks are sorted by
+    /// their counter ranges, so the first chunk to load is found by binary
+    /// search, and the ones before it are never loaded.
+    fn ops_after(
+        &self,
+        client: ClientID,
+        hot: &[Op],
+        counter: Counter,
+    ) -> Result<Option<Vec<Op>>, Error> {
+        let chunks = self.cold.get(&client).map_or(&[][..], |x| &x[..]);
+        let first = chunks.partition_point(|chunk| chunk.end <= counter);
+        if first == chunks.len() {
+            return Ok(export_ops(hot, counter));
+        }
+
+        let mut cold_ops = Vec::new();
+        for chunk in chunks[first..].iter() {
+            let (_, mut decoded) = decode_updates(&self.storage.load(client, chunk.start)?, None)?;
+            cold_ops.append(decoded.entry(client).or_default());
+        }
+
+        // only the first loaded chunk can start before the counter
+        let mut ans = export_ops(&cold_ops, counter).unwrap_or_default();
+        ans.extend_from_slice(hot);
+        Ok(Some(ans))
+    }
+
+    /// Visit the ops that start before `vv` in the order of their counters.
+    /// The last visited op of a client may end after `vv`.
+    ///
+    /// The ops in memory are not copied, and only the offloaded chunks that
+    /// start before `vv` are loaded.
+    pub fn visit_ops_before(
+        &self,
+        vv: &VersionVector,
+        mut f: impl FnMut(&Op),
+    ) -> Result<(), Error> {
+        for (client, hot) in self.map.iter() {
+            let end = vv.vv.get(client).copied().unwrap_or(0);
+            let chunks = self.cold.get(client).map_or(&[][..], |x| &x[..]);
+            for chunk in chunks.iter().take_while(|chunk| chunk.start < end) {
+                let (_, decoded) = decode_updates(&self.storage.load(*client, chunk.start)?, None)?;
+                for op in decoded.get(client).into_iter().flatten() {
+                    if op.id.counter < end {
+                        f(op);
+                    }
+                }
+            }
+
+            for op in hot.iter().take_while(|op| op.id.counter < end) {
+                f(op);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether any ops have been moved to the storage
+    pub fn is_offloaded(&self) -> bool {
+        !self.cold.is_empty()
+    }
+
+    /// Move all the ops except the last one of every client to the storage.
+    ///
+    /// Return the number of the moved ops.
+    pub fn offload(&mut self) -> Result<usize, Error> {
+        let mut offloaded = 0;
+        for (client, vec) in self.map.iter_mut() {
+            if vec.len() <= 1 {
                 continue;
             }
 
-            let mut i = match vec.binary_search_by_key(target_counter, |op| op.id.counter) {
-                Ok(i) => i,
-                Err(i) => i.max(1) - 1,
-            };
-            if *target_counter >= vec[i].id.counter + vec[i].rle_len() as Counter {
-                i += 1;
+            let n = vec.len() - 1;
+            let start = vec[0].id.counter;
+            let end = vec[n].id.counter;
+            let mut chunk: FxHashMap<ClientID, Vec<Op>> = FxHashMap::default();
+            chunk.insert(*client, vec[..n].to_vec());
+            self.storage
+                .save(*client, start, encode_updates(chunk, None, None))?;
+            self.cold.entry(*client).or_default().push(start..end);
+            vec.drain(..n);
+            offloaded += n;
+        }
+
+        self.cold_op_len += offloaded;
+        Ok(offloaded)
+    }
+
+    /// Replace the storage. The chunks in the old storage are moved to the new one.
+    pub fn set_storage(&mut self, mut storage: Box<dyn OpLogStorage>) -> Result<(), Error> {
+        for (client, chunks) in self.cold.iter() {
+            for chunk in chunks.iter() {
+                storage.save(
+                    *client,
+                    chunk.start,
+                    self.storage.load(*client, chunk.start)?,
+                )?;
             }
-            let vec = if vec[i].id.counter < *target_counter {
-                let mut new_vec: Vec<Op> = Vec::with_capacity(vec.len() - i);
-                new_vec.push(vec[i].slice(*target_counter as usize - vec[i].id.counter as usize..));
-                new_vec.extend_from_slice(&vec[i + 1..]);
-                new_vec
-            } else {
-                assert!(vec[i].id.counter == *target_counter);
-                vec[i..].to_vec()
-            };
-            ans.insert(*client, vec);
         }
 
-        ans
+        self.storage = storage;
+        Ok(())
     }
 
     pub fn vv(&self) -> VersionVector {
@@ -400,6 +543,16 @@ impl OpStore {
         ans
     }
 
+    /// Whether the op has been inserted to the store
+    pub fn includes(&self, id: OpID) -> bool {
+        self.map
+            .get(&id.client)
+            .and_then(|v| v.last())
+            .map_or(false, |last| {
+                id.counter < last.id.counter + last.rle_len() as Counter
+            })
+    }
+
     pub fn next_id(&self) -> OpID {
         OpID {
             client: self.client,
@@ -411,29 +564,13 @@ impl OpStore {
         }
     }
 
-    pub fn can_apply(&self, op: &Op) -> CanApply {
-        let Some(vec) = self.map.get(&op.id.client) else {
-            if op.id.counter == 0 {
-                return CanApply::Yes;
-            } else {
-                return CanApply::Pending;
-            }
-        };
-        let end = vec
-            .last()
+    /// The counter after the last op of the client
+    pub fn end(&self, client: ClientID) -> Counter {
+        self.map
+            .get(&client)
+            .and_then(|vec| vec.last())
             .map(|x| x.id.counter + x.rle_len() as Counter)
-            .unwrap_or(0);
-        if end == op.id.counter {
-            return CanApply::Yes;
-        }
-        if end < op.id.counter {
-            return CanApply::Pending;
-        }
-        if end >= op.id.counter + op.rle_len() as Counter {
-            return CanApply::Seen;
-        }
-
-        CanApply::Trim(end - op.id.counter)
+            .unwrap_or(0)
     }
 
     #[inline(always)]
@@ -441,9 +578,103 @@ impl OpStore {
         self.next_lamport
     }
 
+    /// Remove the local ops from the counter `end`, restore the next lamport,
+    /// and return the removed ops. The removed ops must not have been exported
+    /// or offloaded.
+    pub fn truncate_local(&mut self, end: Counter, next_lamport: Lamport) -> Vec<Op> {
+        let mut removed = Vec::new();
+        if let Some(vec) = self.map.get_mut(&self.client) {
+            let i = vec.partition_point(|op| op.id.counter + op.rle_len() as Counter <= end);
+            removed = vec.split_off(i);
+            if let Some(first) = removed.first_mut() {
+                if first.id.counter < end {
+                    let len = (end - first.id.counter) as usize;
+                    vec.push(first.slice(..len));
+                    *first = first.slice(len..);
+                }
+            }
+
+            if vec.is_empty() {
+                self.map.remove(&self.client);
+            }
+        }
+
+        self.next_lamport = next_lamport;
+        removed
+    }
+
+    /// The number of the ops, including the offloaded ones
     pub fn op_len(&self) -> usize {
-        self.map.iter().map(|x| x.1.len()).sum()
+        self.map.iter().map(|x| x.1.len()).sum::<usize>() + self.cold_op_len
+    }
+
+    /// Copy the text of the ops in memory into `bytes`, so the old buffers
+    /// can be dropped. Return the new text of the ops by their ids.
+    pub fn relocate_text(
+        &mut self,
+        bytes: &mut AppendOnlyBytes,
+    ) -> FxHashMap<ClientID, BTreeMap<Counter, BytesSlice>> {
+        let mut ans: FxHashMap<ClientID, BTreeMap<Counter, BytesSlice>> = Default::default();
+        for (client, vec) in self.map.iter_mut() {
+            for op in vec.iter_mut() {
+                if let OpContent::Text(insert) = &mut op.content {
+                    insert.text = relocate(&insert.text, bytes);
+                    ans.entry(*client)
+                        .or_default()
+                        .insert(op.id.counter, insert.text.clone());
+                }
+            }
+        }
+
+        ans
     }
+
+    /// Approximate heap size in bytes. The text and the annotations are shared
+    /// with the document, so they are not included.
+    pub fn mem_size(&self) -> usize {
+        self.map
+            .values()
+            .map(|ops| ops.capacity() * std::mem::size_of::<Op>())
+            .sum::<usize>()
+            + self.map.len() * std::mem::size_of::<(ClientID, Vec<Op>)>()
+    }
+}
+
+/// Copy the text to the end of `bytes`
+pub(super) fn relocate(text: &BytesSlice, bytes: &mut AppendOnlyBytes) -> BytesSlice {
+    let start = bytes.len();
+    bytes.push_slice(text);
+    bytes.slice(start..)
+}
+
+/// Get the ops starting from `target_counter`. Return `None` if there is no such op.
+fn export_ops(vec: &[Op], target_counter: Counter) -> Option<Vec<Op>> {
+    if target_counter
+        >= vec
+            .last()
+            .map(|x| x.id.counter + x.rle_len() as Counter)
+            .unwrap_or(0)
+    {
+        return None;
+    }
+
+    let mut i = match vec.binary_search_by_key(&target_counter, |op| op.id.counter) {
+        Ok(i) => i,
+        Err(i) => i.max(1) - 1,
+    };
+    if target_counter >= vec[i].id.counter + vec[i].rle_len() as Counter {
+        i += 1;
+    }
+    let vec = if vec[i].id.counter < target_counter {
+        let mut new_vec: Vec<Op> = Vec::with_capacity(vec.len() - i);
+        new_vec.push(vec[i].slice(target_counter as usize - vec[i].id.counter as usize..));
+        new_vec.extend_from_slice(&vec[i + 1..]);
+        new_vec
+    } else {
+        assert!(vec[i].id.counter == target_counter);
+        vec[i..].to_vec()
+    };
+    Some(vec)
 }
 
 pub enum CanApply {
@@ -453,6 +684,23 @@ pub enum CanApply {
     Seen,
 }
 
+impl CanApply {
+    /// Whether the op can be applied after the ops of its client before `end`
+    pub fn after(end: Counter, op: &Op) -> Self {
+        if end == op.id.counter {
+            return CanApply::Yes;
+        }
+        if end < op.id.counter {
+            return CanApply::Pending;
+        }
+        if end >= op.id.counter + op.rle_len() as Counter {
+            return CanApply::Seen;
+        }
+
+        CanApply::Trim(end - op.id.counter)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use generic_btree::rle::Mergeable;
@@ -0,0 +1,166 @@
+//! A stable op model that can be used to construct histories directly.
+//!
+//! It's useful for migration tools and simulators that need to control the
+//! exact shape of the history, rather than driving the editing API of
+//! [`RichText`](crate::RichText).
+//!
+//! The ids and lamports of the ops are allocated by [`OpBuilder`], which is
+//! created by [`RichText::op_builder`](crate::RichText::op_builder). The ops can
+//! then be applied by [`RichText::apply_ops`](crate::RichText::apply_ops).
+//!
+//! Unlike the legacy range CRDT, the rich text CRDT doesn't have patch ops.
+//! The range of an annotation never changes after it's created.
+
+use crate::{Anchor, AnchorRange, Annotation, ClientID, Counter, OpID, Style};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    TextInsert(TextInsert),
+    TextDelete(TextDelete),
+    Annotate(Annotation),
+}
+
+/// Insert `text` between the characters `left` and `right`.
+///
+/// `None` means the start or the end of the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextInsert {
+    pub id: OpID,
+    pub lamport: u32,
+    pub left: Option<OpID>,
+    pub right: Option<OpID>,
+    pub text: String,
+}
+
+/// Delete the characters in `start..start + len` (in op ids).
+///
+/// All the deleted characters should be inserted by the same client and
+/// be contiguous in op ids.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextDelete {
+    pub id: OpID,
+    pub lamport: u32,
+    pub start: OpID,
+    pub len: usize,
+}
+
+impl Op {
+    pub fn id(&self) -> OpID {
+        match self {
+            Op::TextInsert(x) => x.id,
+            Op::TextDelete(x) => x.id,
+            Op::Annotate(x) => x.id,
+        }
+    }
+
+    pub fn lamport(&self) -> u32 {
+        match self {
+            Op::TextInsert(x) => x.lamport,
+            Op::TextDelete(x) => x.lamport,
+            Op::Annotate(x) => x.range_lamport.0,
+        }
+    }
+
+    /// Replace the client `old` with `new` in all the ids the op contains
+    pub fn remap_client(&mut self, old: ClientID, new: ClientID) {
+        let ids: Vec<&mut OpID> = match self {
+            Op::TextInsert(x) => std::iter::once(&mut x.id)
+                .chain(x.left.as_mut())
+                .chain(x.right.as_mut())
+                .collect(),
+            Op::TextDelete(x) => vec![&mut x.id, &mut x.start],
+            Op::Annotate(x) => [&mut x.id, &mut x.range_lamport.1]
+                .into_iter()
+                .chain(x.range.start.id.as_mut())
+                .chain(x.range.end.id.as_mut())
+                .collect(),
+        };
+        for id in ids {
+            if id.client == old {
+                id.client = new;
+            }
+        }
+    }
+
+    /// The number of op ids the op occupies
+    pub fn atom_len(&self) -> usize {
+        match self {
+            Op::TextInsert(x) => x.text.len(),
+            Op::TextDelete(x) => x.len,
+            Op::Annotate(_) => 1,
+        }
+    }
+}
+
+/// Allocates op ids and lamports for new ops.
+///
+/// The ids are allocated from the state of the document when the builder was
+/// created. So the document should not be edited by other APIs until the built
+/// ops are applied.
+#[derive(Debug, Clone)]
+pub struct OpBuilder {
+    next_id: OpID,
+    next_lamport: u32,
+}
+
+impl OpBuilder {
+    pub(super) fn new(next_id: OpID, next_lamport: u32) -> Self {
+        Self {
+            next_id,
+            next_lamport,
+        }
+    }
+
+    fn alloc(&mut self, len: usize) -> (OpID, u32) {
+        let ans = (self.next_id, self.next_lamport);
+        self.next_id.counter += len as Counter;
+        self.next_lamport += len as u32;
+        ans
+    }
+
+    pub fn insert(&mut self, left: Option<OpID>, right: Option<OpID>, text: &str) -> Op {
+        let (id, lamport) = self.alloc(text.len());
+        Op::TextInsert(TextInsert {
+            id,
+            lamport,
+            left,
+            right,
+            text: text.to_owned(),
+        })
+    }
+
+    pub fn delete(&mut self, start: OpID, len: usize) -> Op {
+        let (id, lamport) = self.alloc(len);
+        Op::TextDelete(TextDelete {
+            id,
+            lamport,
+            start,
+            len,
+        })
+    }
+
+    /// Annotate the range between the anchors on the characters `start` and `end`.
+    ///
+    /// Whether an anchor is before or after its character is decided by
+    /// `style.expand`. `None` means the start or the end of the document.
+    pub fn annotate(&mut self, start: Option<OpID>, end: Option<OpID>, style: &Style) -> Op {
+        let (id, lamport) = self.alloc(1);
+        Op::Annotate(Annotation {
+            id,
+            range_lamport: (lamport, id),
+            range: AnchorRange {
+                start: Anchor {
+                    id: start,
+                    type_: style.start_type(),
+                },
+                end: Anchor {
+                    id: end,
+                    type_: style.end_type(),
+                },
+            },
+            behavior: style.behavior,
+            type_: style.type_.clone(),
+            value: style.value.clone(),
+        })
+    }
+}
@@ -0,0 +1,114 @@
+//! The outline of a document by its headings.
+//!
+//! A line is a heading when its trailing line break has the `header`
+//! attribute with the level, as in the block format of Quill. The section of
+//! a heading starts at the heading line and ends before the next heading of
+//! the same or a higher level, so the sections of the lower levels are nested
+//! in it.
+
+use std::ops::Range;
+
+use crate::{InternalString, OpID};
+
+use super::{rich_tree::query::IndexFinder, IndexType, RichText};
+
+/// The attribute of the heading lines
+pub const HEADER_KEY: &str = "header";
+
+/// A heading of the document, see [`RichText::outline`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heading {
+    pub level: u64,
+    /// The text of the heading line without the line break
+    pub title: String,
+    pub line: usize,
+    /// The utf8 range of the section
+    pub range: Range<usize>,
+    /// The id of the first char of the heading line. It never changes, so
+    /// it can be stored to find the section again by
+    /// [`RichText::index_of_id`].
+    pub id: OpID,
+}
+
+/// A part of the document split by [`RichText::split_sections`]
+#[derive(Debug)]
+pub struct Section {
+    /// `None` for the text before the first heading
+    pub heading: Option<Heading>,
+    /// The id of the first char of the section in the source document
+    pub first_id: OpID,
+    /// The id of the last char of the section in the source document
+    pub last_id: OpID,
+    /// The text and the styles of the section, see [`RichText::extract`]
+    pub doc: RichText,
+}
+
+pub(super) fn outline(text: &RichText) -> Vec<Heading> {
+    let key: InternalString = HEADER_KEY.into();
+    let mut ans: Vec<Heading> = Vec::new();
+    for line in 0..text.lines() {
+        let spans = text.get_line(line);
+        let level = match spans.last() {
+            Some(last) if last.insert.ends_with('\n') => {
+                match last.attributes.get(&key).and_then(|x| x.as_u64()) {
+                    Some(level) => level,
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+
+        let mut title: String = spans.iter().map(|x| x.insert.as_str()).collect();
+        title.pop();
+        let start = text.get_line_range(line, IndexType::Utf8).unwrap().start;
+        ans.push(Heading {
+            level,
+            title,
+            line,
+            range: start..text.len(),
+            id: id_at(text, start),
+        });
+    }
+
+    // end the sections at the next heading of the same or a higher level
+    for i in 0..ans.len() {
+        if let Some(next) = ans[i + 1..].iter().find(|x| x.level <= ans[i].level) {
+            ans[i].range.end = next.range.start;
+        }
+    }
+
+    ans
+}
+
+pub(super) fn split_sections(text: &RichText, max_level: u64) -> Vec<Section> {
+    let headings: Vec<Heading> = outline(text)
+        .into_iter()
+        .filter(|x| x.level <= max_level)
+        .collect();
+    let mut ans = Vec::new();
+    let first = headings.first().map_or(text.len(), |x| x.range.start);
+    if first > 0 {
+        ans.push(new_section(text, None, 0..first));
+    }
+
+    for (i, heading) in headings.iter().enumerate() {
+        let end = headings.get(i + 1).map_or(text.len(), |x| x.range.start);
+        let range = heading.range.start..end;
+        ans.push(new_section(text, Some(heading.clone()), range));
+    }
+
+    ans
+}
+
+fn new_section(text: &RichText, heading: Option<Heading>, range: Range<usize>) -> Section {
+    Section {
+        heading,
+        first_id: id_at(text, range.start),
+        last_id: id_at(text, range.end - 1),
+        doc: text.extract(range),
+    }
+}
+
+fn id_at(text: &RichText, index: usize) -> OpID {
+    text.get_id_at_pos(text.content.query::<IndexFinder>(&(index, IndexType::Utf8)))
+}
@@ -38,6 +38,7 @@ pub struct ElemInner {
      * number of '\n'
      */
     pub line_breaks: u32,
+    pub word_stats: WordStats,
     pub status: Status,
     pub anchor_set: ElemAnchorSet,
 }
@@ -80,6 +81,7 @@ impl Elem {
                 left,
                 right,
                 utf16_len: utf16,
+                word_stats: WordStats::new(&string),
                 string,
                 line_breaks,
                 status: Status::ALIVE,
@@ -169,6 +171,7 @@ impl Elem {
                 id: self.id.inc(start as Counter),
                 left: Some(self.id.inc(start as Counter - 1)),
                 right: self.right,
+                word_stats: WordStats::new(&s),
                 string: s,
                 utf16_len: utf16,
                 status: self.status,
@@ -178,6 +181,7 @@ impl Elem {
         self.utf16_len -= utf16;
         self.line_breaks -= line_breaks;
         self.string = self.string.slice_clone(..offset);
+        self.word_stats = WordStats::new(&self.string);
         right
     }
 
@@ -292,6 +296,7 @@ impl Elem {
         let Utf16LenAndLineBreaks { utf16, line_breaks } = get_utf16_len_and_line_breaks(s);
         self.utf16_len += utf16;
         self.line_breaks += line_breaks;
+        self.word_stats.append(&WordStats::new(s));
     }
 
     pub fn contains_id(&self, id: OpID) -> bool {
@@ -360,6 +365,7 @@ impl Mergeable for Elem {
         self.string.try_merge(&rhs.string).unwrap();
         self.utf16_len += rhs.utf16_len;
         self.line_breaks += rhs.line_breaks;
+        self.word_stats.append(&rhs.word_stats);
         self.anchor_set.merge_right(&rhs.anchor_set);
     }
 
@@ -371,6 +377,9 @@ impl Mergeable for Elem {
         self.string = string;
         self.utf16_len += lhs.utf16_len;
         self.line_breaks += lhs.line_breaks;
+        let mut word_stats = lhs.word_stats;
+        word_stats.append(&self.word_stats);
+        self.word_stats = word_stats;
         self.anchor_set.merge_left(&lhs.anchor_set);
     }
 }
@@ -405,6 +414,7 @@ impl Sliceable for Elem {
                     Some(self.id.inc(start as Counter - 1))
                 },
                 right: self.right,
+                word_stats: WordStats::new(&s),
                 string: s,
                 utf16_len: utf16,
                 line_breaks,
@@ -445,6 +455,7 @@ impl Sliceable for Elem {
             get_utf16_len_and_line_breaks(&self.string);
         self.utf16_len = utf16;
         self.line_breaks = line_breaks;
+        self.word_stats = WordStats::new(&self.string);
     }
 }
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -473,12 +484,72 @@ impl Status {
     }
 }
 
+/// The words of a text, where a word is a run of non-whitespace chars.
+///
+/// The stats of two adjacent texts can be combined without their text, so
+/// they are kept in the elements and the caches of the tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WordStats {
+    pub words: u32,
+    /// Whether the first char is in a word, `None` if the text is empty
+    first_in_word: Option<bool>,
+    /// Whether the last char is in a word, `None` if the text is empty
+    last_in_word: Option<bool>,
+}
+
+impl WordStats {
+    pub fn new(bytes: &[u8]) -> Self {
+        if bytes.is_empty() {
+            return Self::default();
+        }
+
+        let mut words = 0;
+        let mut first_in_word = None;
+        let mut in_word = false;
+        for c in utf16::bytes_to_str(bytes).chars() {
+            let is_word = !c.is_whitespace();
+            if is_word && !in_word {
+                words += 1;
+            }
+
+            first_in_word.get_or_insert(is_word);
+            in_word = is_word;
+        }
+
+        Self {
+            words,
+            first_in_word,
+            last_in_word: Some(in_word),
+        }
+    }
+
+    /// Append the stats of the text right after this one
+    pub fn append(&mut self, rhs: &Self) {
+        self.words += rhs.words;
+        if self.last_in_word == Some(true) && rhs.first_in_word == Some(true) {
+            // the word continues across the boundary
+            self.words -= 1;
+        }
+
+        if self.first_in_word.is_none() {
+            self.first_in_word = rhs.first_in_word;
+        }
+
+        if rhs.last_in_word.is_some() {
+            self.last_in_word = rhs.last_in_word;
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub(crate) struct Cache {
     pub len: u32,
     pub utf16_len: u32,
     pub anchor_set: CacheAnchorSet,
     pub line_breaks: u32,
+    /// The words of the alive text. They are not in [`CacheDiff`] because
+    /// they are not additive, so they are recalculated from the children.
+    pub words: WordStats,
 }
 
 #[derive(Default, Debug)]
@@ -21,6 +21,7 @@ impl BTreeTrait for RichTreeTrait {
         match diff {
             Some(diff) => {
                 cache.apply_diff(&diff);
+                cache.words = internal_words(caches);
                 Some(diff)
             }
             None => {
@@ -35,6 +36,8 @@ impl BTreeTrait for RichTreeTrait {
                     anchor_set.union_(&child.cache.anchor_set);
                 }
 
+                cache.words = internal_words(caches);
+
                 let anchor_diff = anchor_set.calc_diff(&cache.anchor_set);
                 let diff = CacheDiff {
                     anchor_diff,
@@ -59,18 +62,21 @@ impl BTreeTrait for RichTreeTrait {
         match diff {
             Some(diff) => {
                 cache.apply_diff(&diff);
+                cache.words = leaf_words(caches);
                 diff
             }
             None => {
                 let mut len = 0;
                 let mut utf16_len = 0;
                 let mut line_breaks = 0;
+                let mut words = WordStats::default();
                 let mut anchor_set = CacheAnchorSet::default();
                 for child in caches.iter() {
                     if !child.is_dead() {
                         len += child.string.len();
                         utf16_len += child.utf16_len;
                         line_breaks += child.line_breaks;
+                        words.append(&child.word_stats);
                     }
                     anchor_set.union_elem_set(&child.anchor_set);
                 }
@@ -85,6 +91,7 @@ impl BTreeTrait for RichTreeTrait {
                 cache.len = len as u32;
                 cache.utf16_len = utf16_len;
                 cache.line_breaks = line_breaks;
+                cache.words = words;
                 diff
             }
         }
@@ -106,3 +113,21 @@ impl BTreeTrait for RichTreeTrait {
         rle::insert_with_split(elements, index, offset, elem)
     }
 }
+
+fn internal_words(caches: &[generic_btree::Child<RichTreeTrait>]) -> WordStats {
+    let mut words = WordStats::default();
+    for child in caches.iter() {
+        words.append(&child.cache.words);
+    }
+
+    words
+}
+
+fn leaf_words(elems: &[Elem]) -> WordStats {
+    let mut words = WordStats::default();
+    for elem in elems.iter().filter(|x| !x.is_dead()) {
+        words.append(&elem.word_stats);
+    }
+
+    words
+}
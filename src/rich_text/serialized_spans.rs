@@ -0,0 +1,104 @@
+//! A compact serialization of the spans for server-side rendering.
+//!
+//! The frontends can render the document from it before the wasm module is
+//! loaded, and then hydrate it with the real document.
+//!
+//! The schema is
+//!
+//! ```json
+//! { "v": 1, "text": "Hello world", "runs": [[5, 0], [6, null]], "attrs": [{ "bold": true }] }
+//! ```
+//!
+//! `runs` splits the `text` into the utf16 lengths of the spans, with the index
+//! of their attributes in `attrs`, or `null` for the spans without attributes.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{rich_tree::utf16::get_utf16_len, Span};
+
+/// The version of the schema. It's bumped when the schema changes incompatibly.
+pub const SPANS_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SerializedSpans {
+    pub v: u32,
+    pub text: String,
+    pub runs: Vec<(usize, Option<usize>)>,
+    pub attrs: Vec<BTreeMap<String, Value>>,
+}
+
+impl SerializedSpans {
+    pub fn from_spans(spans: &[Span]) -> Self {
+        let mut text = String::new();
+        let mut runs: Vec<(usize, Option<usize>)> = Vec::with_capacity(spans.len());
+        let mut attrs: Vec<BTreeMap<String, Value>> = Vec::new();
+        for span in spans {
+            text.push_str(&span.insert);
+            let len = get_utf16_len(&span.insert);
+            let attr = if span.attributes.is_empty() {
+                None
+            } else {
+                let map: BTreeMap<String, Value> = span
+                    .attributes
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect();
+                Some(match attrs.iter().position(|x| *x == map) {
+                    Some(i) => i,
+                    None => {
+                        attrs.push(map);
+                        attrs.len() - 1
+                    }
+                })
+            };
+
+            match runs.last_mut() {
+                Some(last) if last.1 == attr => last.0 += len,
+                _ => runs.push((len, attr)),
+            }
+        }
+
+        Self {
+            v: SPANS_SCHEMA_VERSION,
+            text,
+            runs,
+            attrs,
+        }
+    }
+
+    /// Convert back to the spans. Return `None` if the runs don't match the
+    /// text or refer to unknown attributes.
+    pub fn to_spans(&self) -> Option<Vec<Span>> {
+        let mut ans = Vec::with_capacity(self.runs.len());
+        let mut chars = self.text.chars();
+        for &(len, attr) in self.runs.iter() {
+            let mut insert = String::new();
+            let mut utf16_len = 0;
+            while utf16_len < len {
+                let c = chars.next()?;
+                utf16_len += c.len_utf16();
+                insert.push(c);
+            }
+
+            let attributes = match attr {
+                Some(i) => self
+                    .attrs
+                    .get(i)?
+                    .iter()
+                    .map(|(k, v)| (k.as_str().into(), v.clone()))
+                    .collect(),
+                None => Default::default(),
+            };
+            ans.push(Span { insert, attributes });
+        }
+
+        if chars.next().is_some() {
+            return None;
+        }
+
+        Some(ans)
+    }
+}
@@ -0,0 +1,183 @@
+//! A deterministic simulator of clients editing the same document concurrently.
+//!
+//! The clients exchange updates over in-memory channels with configurable
+//! latency and network partitions. Everything is driven by a seeded rng, so a
+//! merge anomaly found by the simulator can be reproduced and reported with
+//! the seed alone.
+//!
+//! ```
+//! use crdt_richtext::rich_text::simulator::{Partition, Simulator};
+//!
+//! let mut sim = Simulator::new(3, 42);
+//! sim.latency = 1..10;
+//! sim.partitions.push(Partition {
+//!     ticks: 20..60,
+//!     clients: vec![0],
+//! });
+//! sim.run(100).unwrap();
+//! ```
+
+use std::ops::Range;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::Style;
+
+use super::RichText;
+
+/// The clients in `clients` cannot talk to the other clients during `ticks`.
+///
+/// The messages sent across the partition are delivered after it heals.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub ticks: Range<u64>,
+    pub clients: Vec<usize>,
+}
+
+impl Partition {
+    fn blocks(&self, tick: u64, from: usize, to: usize) -> bool {
+        self.ticks.contains(&tick) && self.clients.contains(&from) != self.clients.contains(&to)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("clients diverged (seed = {seed}): {states:?}")]
+pub struct Divergence {
+    pub seed: u64,
+    /// The text of every client
+    pub states: Vec<String>,
+}
+
+struct Message {
+    to: usize,
+    deliver_at: u64,
+    data: Vec<u8>,
+}
+
+pub struct Simulator {
+    pub clients: Vec<RichText>,
+    /// The range of the ticks a message takes to arrive
+    pub latency: Range<u64>,
+    pub partitions: Vec<Partition>,
+    seed: u64,
+    rng: StdRng,
+    tick: u64,
+    messages: Vec<Message>,
+}
+
+impl Simulator {
+    pub fn new(clients: usize, seed: u64) -> Self {
+        Self {
+            clients: (0..clients).map(|i| RichText::new(i as u64 + 1)).collect(),
+            latency: 0..1,
+            partitions: Vec::new(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            tick: 0,
+            messages: Vec::new(),
+        }
+    }
+
+    /// Run `ticks` ticks of random edits, then deliver all the messages and
+    /// check whether the clients have converged.
+    pub fn run(&mut self, ticks: u64) -> Result<(), Divergence> {
+        for _ in 0..ticks {
+            let client = self.rng.gen_range(0..self.clients.len());
+            self.edit(client);
+            self.step();
+        }
+
+        self.flush();
+        self.check()
+    }
+
+    /// Make a random edit on the client and broadcast it to the other clients
+    pub fn edit(&mut self, client: usize) {
+        let text = &mut self.clients[client];
+        let vv = text.version();
+        let len = text.len();
+        match self.rng.gen_range(0..3) {
+            0 => {
+                let pos = self.rng.gen_range(0..=len);
+                let content = ["a", "bc", "\n", "你好"][self.rng.gen_range(0..4)];
+                text.insert(pos, content);
+            }
+            _ if len == 0 => return,
+            1 => {
+                let pos = self.rng.gen_range(0..len);
+                let del = self.rng.gen_range(1..=(len - pos).min(5));
+                text.delete(pos..pos + del);
+            }
+            _ => {
+                let pos = self.rng.gen_range(0..len);
+                let ann_len = self.rng.gen_range(1..=(len - pos).min(5));
+                let style = if self.rng.gen_bool(0.5) {
+                    Style::new_bold_like("bold".into(), true.into())
+                } else {
+                    Style::new_link_like("link".into(), "url".into())
+                };
+                text.annotate(pos..pos + ann_len, style);
+            }
+        }
+
+        let data = self.clients[client].export(&vv);
+        for to in 0..self.clients.len() {
+            if to == client {
+                continue;
+            }
+
+            let mut deliver_at = self.tick + self.rng.gen_range(self.latency.clone());
+            for partition in self.partitions.iter() {
+                if partition.blocks(deliver_at, client, to) {
+                    deliver_at = partition.ticks.end;
+                }
+            }
+
+            self.messages.push(Message {
+                to,
+                deliver_at,
+                data: data.clone(),
+            });
+        }
+    }
+
+    /// Advance one tick and deliver the messages that arrive by then
+    pub fn step(&mut self) {
+        self.tick += 1;
+        let tick = self.tick;
+        let (arrived, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.messages)
+            .into_iter()
+            .partition(|msg| msg.deliver_at <= tick);
+        self.messages = pending;
+        self.deliver(arrived);
+    }
+
+    /// Deliver all the messages in flight, ignoring the latency and the partitions
+    pub fn flush(&mut self) {
+        let messages = std::mem::take(&mut self.messages);
+        self.deliver(messages);
+    }
+
+    fn deliver(&mut self, mut messages: Vec<Message>) {
+        messages.sort_by_key(|msg| msg.deliver_at);
+        for msg in messages {
+            self.clients[msg.to].import(&msg.data).unwrap();
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Divergence> {
+        let first = &self.clients[0];
+        if self
+            .clients
+            .iter()
+            .all(|x| x.to_string() == first.to_string() && x.get_spans() == first.get_spans())
+        {
+            Ok(())
+        } else {
+            Err(Divergence {
+                seed: self.seed,
+                states: self.clients.iter().map(|x| x.to_string()).collect(),
+            })
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Immutable views of the document that can be sent to other threads.
+
+use std::{
+    fmt::Display,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use super::{vv::VersionVector, Span};
+
+/// An immutable view of the visible text and styles of a [`RichText`](super::RichText).
+///
+/// It's cheap to clone and it can be sent to other threads, so a background
+/// thread can build search indexes or run spellcheck over a consistent view
+/// while the document keeps being edited.
+///
+/// It only contains the visible content. The history, the tombstones and the
+/// content tree are not copied.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    spans: Arc<[Span]>,
+    len: usize,
+    version: Arc<VersionVector>,
+}
+
+impl Snapshot {
+    pub(super) fn new(spans: Vec<Span>, version: VersionVector) -> Self {
+        let len = spans.iter().map(|x| x.len()).sum();
+        Self {
+            spans: spans.into(),
+            len,
+            version: Arc::new(version),
+        }
+    }
+
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// The version of the document when the snapshot is taken
+    pub fn version(&self) -> &VersionVector {
+        &self.version
+    }
+
+    /// The length of the text in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the text in the given utf8 range.
+    ///
+    /// Panics if the range is out of bound or not on char boundaries.
+    pub fn slice_str(&self, range: impl RangeBounds<usize>) -> String {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len,
+        };
+        assert!(start <= end && end <= self.len);
+
+        let mut ans = String::with_capacity(end - start);
+        let mut index = 0;
+        for span in self.spans.iter() {
+            let span_end = index + span.len();
+            if span_end > start && index < end {
+                let from = start.saturating_sub(index);
+                let to = end.min(span_end) - index;
+                ans.push_str(&span.insert[from..to]);
+            }
+
+            index = span_end;
+            if index >= end {
+                break;
+            }
+        }
+
+        ans
+    }
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for span in self.spans.iter() {
+            f.write_str(&span.insert)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+//! Map the byte offsets of the exported plain text to the ids of the chars.
+//!
+//! The offsets of the text change after every edit, but the ids of the chars
+//! never do. An external system like a search index can keep the plain text
+//! and its [`SourceMap`], and store the ids of the positions it refers to. The
+//! ids are resolved to the current indexes by
+//! [`RichText::index_of_id`](crate::RichText::index_of_id).
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Counter, OpID};
+
+/// The chars in `offset..offset + len` of the text have the ids
+/// `id..id + len`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceSegment {
+    pub offset: usize,
+    pub id: OpID,
+    pub len: usize,
+}
+
+/// The segments are sorted by their offsets and cover the whole text.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMap {
+    pub segments: Vec<SourceSegment>,
+}
+
+impl SourceMap {
+    pub(super) fn push(&mut self, offset: usize, id: OpID, len: usize) {
+        if let Some(last) = self.segments.last_mut() {
+            if last.offset + last.len == offset
+                && last.id.client == id.client
+                && last.id.counter + last.len as Counter == id.counter
+            {
+                last.len += len;
+                return;
+            }
+        }
+
+        self.segments.push(SourceSegment { offset, id, len });
+    }
+
+    /// Get the id of the byte at the utf8 `offset` of the text
+    pub fn id_at(&self, offset: usize) -> Option<OpID> {
+        let i = self.segments.partition_point(|x| x.offset <= offset);
+        let segment = self.segments.get(i.checked_sub(1)?)?;
+        if offset >= segment.offset + segment.len {
+            return None;
+        }
+
+        Some(segment.id.inc((offset - segment.offset) as Counter))
+    }
+}
@@ -0,0 +1,74 @@
+//! The storage of the cold history of the op log.
+//!
+//! [`RichText::offload_history`](crate::RichText::offload_history) moves the old
+//! ops out of memory into an [`OpLogStorage`] as encoded chunks. They are only
+//! loaded again when they are needed by an export.
+//!
+//! The text content of the ops is still kept in memory by the document, so
+//! offloading reduces the memory of the op metadata rather than the text.
+
+use std::path::PathBuf;
+
+use fxhash::FxHashMap;
+
+use crate::{ClientID, Counter};
+
+use super::Error;
+
+/// A storage of encoded op chunks.
+///
+/// A chunk holds the contiguous ops of a client, and it's identified by the
+/// client and the counter of its first op. A chunk is never modified after
+/// it's saved.
+pub trait OpLogStorage {
+    fn save(&mut self, client: ClientID, start: Counter, chunk: Vec<u8>) -> Result<(), Error>;
+    fn load(&self, client: ClientID, start: Counter) -> Result<Vec<u8>, Error>;
+}
+
+/// Keep the chunks in memory. It's the default storage.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    chunks: FxHashMap<(ClientID, Counter), Vec<u8>>,
+}
+
+impl OpLogStorage for MemoryStorage {
+    fn save(&mut self, client: ClientID, start: Counter, chunk: Vec<u8>) -> Result<(), Error> {
+        self.chunks.insert((client, start), chunk);
+        Ok(())
+    }
+
+    fn load(&self, client: ClientID, start: Counter) -> Result<Vec<u8>, Error> {
+        self.chunks
+            .get(&(client, start))
+            .cloned()
+            .ok_or_else(|| Error::Storage(format!("chunk {}-{} not found", client, start)))
+    }
+}
+
+/// Store every chunk as a file in the directory.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| Error::Storage(e.to_string()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, client: ClientID, start: Counter) -> PathBuf {
+        self.dir.join(format!("{}-{}.ops", client, start))
+    }
+}
+
+impl OpLogStorage for FileStorage {
+    fn save(&mut self, client: ClientID, start: Counter, chunk: Vec<u8>) -> Result<(), Error> {
+        std::fs::write(self.path(client, start), chunk).map_err(|e| Error::Storage(e.to_string()))
+    }
+
+    fn load(&self, client: ClientID, start: Counter) -> Result<Vec<u8>, Error> {
+        std::fs::read(self.path(client, start)).map_err(|e| Error::Storage(e.to_string()))
+    }
+}
@@ -742,6 +742,51 @@ mod get_line {
         assert_eq!(&text.get_line(1)[0].insert, "World\n");
         assert_eq!(&text.get_line(2)[0].insert, "");
     }
+
+    #[test]
+    fn line_range() {
+        use crate::rich_text::IndexType;
+
+        let mut text = RichText::new(1);
+        text.insert(0, "你好，\nWorld\n");
+        assert_eq!(text.get_line_range(0, IndexType::Utf8), Some(0..10));
+        assert_eq!(text.get_line_range(0, IndexType::Utf16), Some(0..4));
+        assert_eq!(text.get_line_range(1, IndexType::Utf8), Some(10..16));
+        assert_eq!(text.get_line_range(1, IndexType::Utf16), Some(4..10));
+        assert_eq!(text.get_line_range(2, IndexType::Utf16), Some(10..10));
+        assert_eq!(text.get_line_range(3, IndexType::Utf16), None);
+        assert_eq!(
+            text.slice_line(0, 1.., IndexType::Utf16)[0].insert,
+            "好，\n"
+        );
+        assert_eq!(text.slice_line(1, 0..3, IndexType::Utf16)[0].insert, "Wor");
+        assert_eq!(
+            text.slice_line(1, 3..100, IndexType::Utf8)[0].insert,
+            "ld\n"
+        );
+        assert!(text.slice_line(2, .., IndexType::Utf8).is_empty());
+    }
+
+    #[test]
+    fn long_line() {
+        use crate::rich_text::IndexType;
+
+        let mut text = RichText::new(1);
+        text.insert(0, "a\n");
+        text.insert(2, &"x".repeat(1000));
+        text.insert(1002, "好好");
+        text.delete(10..20);
+        assert_eq!(text.line_len(0, IndexType::Utf8), Some(1));
+        assert_eq!(text.line_len(1, IndexType::Utf8), Some(996));
+        assert_eq!(text.line_len(1, IndexType::Utf16), Some(992));
+        assert_eq!(text.line_len(2, IndexType::Utf8), None);
+        let chunks: Vec<&str> = text.line_chunks(1, 100).collect();
+        assert!(chunks.iter().all(|x| x.len() <= 100));
+        assert_eq!(chunks.concat(), text.slice_str(2..998, IndexType::Utf8));
+        let chunks: Vec<&str> = text.line_chunks(1, 2).collect();
+        assert_eq!(&chunks[chunks.len() - 2..], ["好", "好"]);
+        assert_eq!(text.line_chunks(0, 1).collect::<Vec<_>>(), ["a"]);
+    }
 }
 
 mod delta {
@@ -889,6 +934,3713 @@ mod delta {
         let v = invoked_bk.load(atomic::Ordering::SeqCst);
         assert!(v);
     }
+
+    #[test]
+    fn delta_event_retain_should_contain_annotation_ids() {
+        let mut a = RichText::new(1);
+        a.insert(0, "12345");
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        let id = a.next_id();
+        a.annotate(1..3, Style::new_bold_like("a".into(), Value::Bool(true)));
+        b.set_event_annotation_ids(true);
+        let invoked = Rc::new(AtomicBool::new(false));
+        let invoked_bk = Rc::clone(&invoked);
+        b.observe(Box::new(move |event| {
+            assert!(!event.is_local);
+            assert_eq!(event.ops.len(), 2);
+            assert_eq!(event.ops[0].length(), 1);
+            assert_eq!(event.ops[0].annotation_ids(), None);
+            assert_eq!(event.ops[1].length(), 2);
+            assert_eq!(event.ops[1].annotation_ids(), Some(&[id][..]));
+            invoked.store(true, atomic::Ordering::SeqCst);
+        }));
+        b.merge(&a);
+        let v = invoked_bk.load(atomic::Ordering::SeqCst);
+        assert!(v);
+    }
+}
+
+mod encode {
+    use super::*;
+
+    #[test]
+    fn import_malformed_data_should_return_err() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let data = a.export(&Default::default());
+        let mut b = RichText::new(2);
+        assert!(b.import(&[1, 2, 3]).is_err());
+        assert!(b.import(&data[..data.len() / 2]).is_err());
+        b.import(&data).unwrap();
+        assert_eq!(b.to_string(), "123");
+    }
+
+    #[test]
+    fn export_with_filter() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        a.annotate(0..1, bold());
+        a.annotate(
+            1..2,
+            Style::new_comment_like("comment".into(), "private".into()),
+        );
+        a.insert(3, "4");
+        let mut b = RichText::new(2);
+        b.import(&a.export_with_filter(&Default::default(), |ann| &*ann.type_ != "comment"))
+            .unwrap();
+        assert_eq!(b.to_string(), "1234");
+        assert_eq!(b.version().vv, a.version().vv);
+        let spans = b.get_spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].insert, "1");
+        assert!(spans[0]
+            .attributes
+            .contains_key(&InternalString::from("bold")));
+        assert!(spans[1].attributes.is_empty());
+
+        // the excluded annotations are treated as seen
+        b.merge(&a);
+        assert_eq!(b.get_spans(), spans);
+    }
+}
+
+mod ops {
+    use super::*;
+    use crate::OpID;
+
+    #[test]
+    fn apply_built_ops() {
+        let mut a = RichText::new(1);
+        let mut builder = a.op_builder();
+        let insert = builder.insert(None, None, "1234");
+        let id = insert.id();
+        let ann = builder.annotate(Some(id), Some(id.inc(2)), &bold());
+        let delete = builder.delete(id.inc(3), 1);
+        a.apply_ops([insert, ann, delete]).unwrap();
+        assert_eq!(a.to_string(), "123");
+        let spans = a.get_spans();
+        assert_eq!(spans[0].insert, "12");
+        assert!(spans[0]
+            .attributes
+            .contains_key(&InternalString::from("bold")));
+
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        assert_eq!(b.get_spans(), spans);
+    }
+
+    #[test]
+    fn reject_invalid_ops() {
+        let mut a = RichText::new(1);
+        let mut builder = a.op_builder();
+        let empty = builder.insert(None, None, "");
+        assert!(a.apply_ops([empty]).is_err());
+        let mut builder = a.op_builder();
+        let unknown = builder.insert(
+            Some(OpID {
+                client: 2,
+                counter: 0,
+            }),
+            None,
+            "1",
+        );
+        assert!(a.apply_ops([unknown]).is_err());
+        assert_eq!(a.to_string(), "");
+    }
+}
+
+mod state_hash {
+    use super::*;
+
+    #[test]
+    fn same_state_with_different_histories() {
+        let mut a = RichText::new(1);
+        a.insert(0, "1234");
+        a.annotate(0..2, bold());
+        let mut b = RichText::new(2);
+        b.insert(0, "34");
+        b.insert(0, "x12");
+        b.delete(0..1);
+        b.annotate(0..1, bold());
+        b.annotate(1..2, bold());
+        assert_eq!(a.get_spans().len(), 2);
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        b.annotate(2..3, bold());
+        assert_ne!(a.state_hash(), b.state_hash());
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.state_hash(), b.state_hash());
+    }
+}
+
+mod compaction {
+    use super::*;
+    use crate::rich_text::CompactionPolicy;
+
+    #[test]
+    fn maybe_compact() {
+        let mut a = RichText::new(1);
+        a.set_compaction_policy(CompactionPolicy {
+            max_tombstone_ratio: 0.5,
+            min_ops: 10,
+            compress_tombstones: false,
+        });
+        a.insert(0, "0123456789");
+        a.annotate(2..6, bold());
+        assert!(!a.maybe_compact());
+        for _ in 0..4 {
+            a.delete(1..2);
+        }
+        assert!(!a.maybe_compact());
+        a.delete(1..3);
+        let spans = a.get_spans();
+        let elem_len = a.content.iter().count();
+        assert!(a.maybe_compact());
+        assert!(a.content.iter().count() <= elem_len);
+        assert_eq!(a.get_spans(), spans);
+        a.check();
+
+        // rate limited by min_ops
+        a.delete(0..1);
+        assert!(!a.maybe_compact());
+
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        b.insert(1, "x");
+        a.merge(&b);
+        a.insert(1, "y");
+        b.merge(&a);
+        assert_eq!(a.to_string(), b.to_string());
+        assert_eq!(a.get_spans(), b.get_spans());
+    }
+}
+
+mod extract {
+    use super::*;
+
+    #[test]
+    fn extract_range() {
+        let mut a = RichText::new(1);
+        a.insert(0, "0123456789");
+        a.annotate(2..6, bold());
+        a.annotate(4..8, Style::new_comment_like("comment".into(), "a".into()));
+        a.delete(5..6);
+        let b = a.extract(3..7);
+        assert_eq!(b.to_string(), "3467");
+        assert_eq!(a.to_string(), "012346789");
+        assert_eq!(b.get_spans(), a.slice(3..7, IndexType::Utf8));
+
+        // the styles keep their expand behaviors
+        let mut b = b;
+        b.insert(0, "x");
+        b.insert(b.len(), "y");
+        assert_eq!(b.get_spans().first().unwrap().insert, "x");
+        assert!(b.get_spans().last().unwrap().attributes.is_empty());
+    }
+}
+
+mod iter {
+    use super::*;
+
+    fn assert_coalesced(spans: &[Span]) {
+        for span in spans {
+            assert!(!span.is_empty());
+        }
+        for pair in spans.windows(2) {
+            assert_ne!(pair[0].attributes, pair[1].attributes);
+        }
+    }
+
+    #[test]
+    fn spans_should_be_coalesced_across_leaves() {
+        let mut text = RichText::new(1);
+        for _ in 0..1000 {
+            // every insertion creates a new element that cannot be merged
+            text.insert(0, "a");
+        }
+        assert!(text.content.node_len() > 1);
+        assert_eq!(text.get_spans().len(), 1);
+
+        text.annotate(100..900, bold());
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 3);
+        assert_coalesced(&spans);
+        for range in [100..900, 99..901, 0..1000, 500..900] {
+            let spans = text.slice(range.clone(), IndexType::Utf8);
+            assert_coalesced(&spans);
+            assert_eq!(
+                spans.iter().map(|x| x.len()).sum::<usize>(),
+                range.len(),
+                "{:?}",
+                range
+            );
+        }
+
+        assert_eq!(text.slice(100..900, IndexType::Utf8).len(), 1);
+        text.delete(400..600);
+        assert_coalesced(&text.get_spans());
+        assert_eq!(text.get_spans().len(), 3);
+    }
+}
+
+mod simulator {
+    use crate::rich_text::simulator::{Partition, Simulator};
+
+    #[test]
+    fn converge_with_latency_and_partitions() {
+        for seed in 0..10 {
+            let mut sim = Simulator::new(3, seed);
+            sim.latency = 0..20;
+            sim.partitions.push(Partition {
+                ticks: 50..150,
+                clients: vec![0, 1],
+            });
+            sim.run(300).unwrap();
+        }
+    }
+}
+
+mod migrate_behavior {
+    use super::*;
+    use crate::Behavior;
+
+    #[test]
+    fn migrate_merge_to_allow_multiple() {
+        let mut a = RichText::new(1);
+        a.insert(0, "0123456789");
+        a.annotate(
+            2..6,
+            Style {
+                expand: Expand::None,
+                behavior: Behavior::Merge,
+                type_: "comment".into(),
+                value: "a".into(),
+            },
+        );
+        a.annotate(0..2, bold());
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        let spans = a.get_spans();
+
+        a.migrate_behavior("comment", Behavior::AllowMultiple)
+            .unwrap();
+        assert_eq!(a.get_spans(), spans);
+        assert_eq!(
+            a.ann
+                .iter()
+                .filter(|x| &*x.type_ == "comment" && x.behavior == Behavior::AllowMultiple)
+                .count(),
+            1
+        );
+
+        b.merge(&a);
+        assert_eq!(b.get_spans(), spans);
+
+        // the migrated annotations can't be migrated back
+        assert!(a.migrate_behavior("comment", Behavior::Merge).is_err());
+        assert!(a.migrate_behavior("bold", Behavior::Delete).is_err());
+    }
+
+    #[test]
+    fn erased_annotations_stay_erased() {
+        let comment = |value: &str| Style {
+            expand: Expand::None,
+            behavior: Behavior::Merge,
+            type_: "comment".into(),
+            value: value.into(),
+        };
+        let mut a = RichText::new(1);
+        a.insert(0, "0123456789");
+        a.annotate(0..4, comment("a"));
+        a.annotate(6..10, comment("b"));
+        a.annotate(0..4, Style::new_erase_link_like("comment".into()));
+        a.annotate(7..9, Style::new_erase_link_like("comment".into()));
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        let spans = a.get_spans();
+
+        a.migrate_behavior("comment", Behavior::AllowMultiple)
+            .unwrap();
+        assert_eq!(a.get_spans(), spans);
+        // "a" is skipped, and "b" is split around the erased part
+        assert_eq!(
+            a.ann
+                .iter()
+                .filter(|x| &*x.type_ == "comment" && x.behavior == Behavior::AllowMultiple)
+                .count(),
+            2
+        );
+
+        b.merge(&a);
+        assert_eq!(b.get_spans(), spans);
+    }
+}
+
+mod readonly {
+    use super::*;
+
+    #[test]
+    fn readonly_rejects_local_edits() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let mut b = RichText::new(2);
+        b.set_readonly(true);
+        assert!(matches!(b.try_insert(0, "x"), Err(Error::Readonly)));
+        b.insert(0, "x");
+        assert_eq!(b.to_string(), "");
+
+        b.merge(&a);
+        assert_eq!(b.to_string(), "123");
+        assert!(b.try_delete(0..1).is_err());
+        assert!(b.try_annotate(0..1, bold()).is_err());
+        b.annotate(0..1, bold());
+        let ops = b.op_builder().insert(None, None, "x");
+        assert!(b.apply_ops([ops]).is_err());
+        assert_eq!(b.get_spans(), a.get_spans());
+        assert_eq!(b.version().vv.get(&2), None);
+
+        b.set_readonly(false);
+        b.try_insert(0, "x").unwrap();
+        assert_eq!(b.to_string(), "x123");
+    }
+}
+
+mod memory {
+    use super::*;
+
+    #[test]
+    fn memory_breakdown() {
+        let mut a = RichText::new(1);
+        let empty = a.memory_breakdown();
+        for i in 0..100 {
+            a.insert(i, "abc");
+        }
+        a.annotate(0..100, bold());
+        let before_delete = a.memory_breakdown();
+        assert!(before_delete.text >= 300);
+        assert!(before_delete.annotations > empty.annotations);
+        assert!(before_delete.op_log > empty.op_log);
+        assert!(before_delete.indexes > empty.indexes);
+        assert_eq!(before_delete.tombstones, 0);
+
+        a.delete(0..150);
+        let after_delete = a.memory_breakdown();
+        assert!(after_delete.tombstones >= 150);
+        assert!(after_delete.text < before_delete.text);
+        assert!(after_delete.total() >= before_delete.total());
+    }
+}
+
+mod snapshot {
+    use super::*;
+
+    #[test]
+    fn read_snapshot_in_another_thread() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你好 world");
+        text.annotate(0..6, bold());
+        let snapshot = text.snapshot();
+        text.insert(0, "abc");
+        text.delete(3..6);
+
+        let handle = std::thread::spawn({
+            let snapshot = snapshot.clone();
+            move || {
+                assert_eq!(snapshot.to_string(), "你好 world");
+                assert_eq!(snapshot.slice_str(3..10), "好 wor");
+                assert_eq!(snapshot.spans().len(), 2);
+            }
+        });
+        handle.join().unwrap();
+        assert_eq!(snapshot.len(), "你好 world".len());
+        assert_eq!(text.to_string(), "abc好 world");
+        assert_ne!(snapshot.version().vv, text.version().vv);
+    }
+}
+
+mod iter_with_filter {
+    use super::*;
+
+    #[test]
+    fn exclude_annotation_types() {
+        let mut text = RichText::new(1);
+        text.insert(0, "0123456789");
+        text.annotate(0..5, bold());
+        text.annotate(
+            3..8,
+            Style::new_comment_like("internal".into(), "mark".into()),
+        );
+        assert_eq!(text.get_spans().len(), 4);
+        let spans: Vec<_> = text
+            .iter_with_filter(|ann| &*ann.type_ != "internal")
+            .collect();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].insert, "01234");
+        assert_eq!(spans[1].insert, "56789");
+        assert!(spans[1].attributes.is_empty());
+    }
+}
+
+mod surrogate_pairs {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn slice_never_splits_surrogate_pairs() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀b");
+        assert_eq!(text.len_utf16(), 4);
+        assert_eq!(text.slice_str(1..2, IndexType::Utf16), "😀");
+        assert_eq!(text.slice_str(2..3, IndexType::Utf16), "😀");
+        assert_eq!(text.slice_str(2..4, IndexType::Utf16), "😀b");
+        assert_eq!(text.slice(0..2, IndexType::Utf16)[0].insert, "a😀");
+    }
+
+    #[test]
+    fn edit_inside_surrogate_pair() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀b");
+        text.insert_utf16(2, "x");
+        assert_eq!(text.to_string(), "a😀xb");
+        text.delete_utf16(2..3);
+        assert_eq!(text.to_string(), "axb");
+        text.insert(0, "😀");
+        text.annotate_utf16(1..2, Style::new_link_like("link".into(), "url".into()));
+        let spans = text.get_spans();
+        assert_eq!(spans[0].insert, "😀");
+        assert!(!spans[0].attributes.is_empty());
+        assert!(spans[1].attributes.is_empty());
+    }
+
+    #[test]
+    fn utf16_events_never_split_surrogate_pairs() {
+        let mut text = RichText::new(1);
+        text.set_event_index_type(IndexType::Utf16);
+        text.insert(0, "a😀b");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |event| {
+            events.borrow_mut().push(event.ops.clone());
+        }));
+        text.insert_utf16(2, "x");
+        text.delete_utf16(2..3);
+        let events = events_bk.borrow();
+        assert_eq!(
+            events[0],
+            vec![
+                DeltaItem::retain(3),
+                DeltaItem::insert_with_attributes("x".into(), IndexType::Utf16, Default::default())
+            ]
+        );
+        assert_eq!(events[1], vec![DeltaItem::retain(1), DeltaItem::delete(2)]);
+    }
+
+    #[test]
+    fn utf8_events_of_utf16_edits() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你😀好");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |event| {
+            events.borrow_mut().push(event.ops.clone());
+        }));
+        text.delete_utf16(2..3);
+        let events = events_bk.borrow();
+        assert_eq!(events[0], vec![DeltaItem::retain(3), DeltaItem::delete(4)]);
+        assert_eq!(text.to_string(), "你好");
+    }
+}
+
+mod annotations_page {
+    use super::*;
+    use crate::rich_text::AnnotationOrder;
+
+    fn comment(value: &str) -> Style {
+        Style::new_comment_like("comment".into(), value.into())
+    }
+
+    #[test]
+    fn paginate_comments() {
+        let mut text = RichText::new(1);
+        text.insert(0, "0123456789");
+        text.annotate(5..8, comment("a"));
+        text.annotate(0..3, comment("b"));
+        text.annotate(2..4, comment("c"));
+        text.annotate(0..10, bold());
+        assert_eq!(text.count_annotations("comment"), 3);
+        assert_eq!(text.count_annotations("bold"), 1);
+
+        let page =
+            text.annotations_page("comment", 0, 2, AnnotationOrder::Position, IndexType::Utf8);
+        let ranges: Vec<_> = page.iter().map(|x| x.range.clone()).collect();
+        assert_eq!(ranges, vec![0..3, 2..4]);
+        let page =
+            text.annotations_page("comment", 2, 2, AnnotationOrder::Position, IndexType::Utf8);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].range, 5..8);
+        let page =
+            text.annotations_page("comment", 0, 1, AnnotationOrder::Lamport, IndexType::Utf8);
+        assert_eq!(page[0].annotation.value, serde_json::Value::from("a"));
+        assert_eq!(page[0].range, 5..8);
+
+        text.delete(0..1);
+        let page =
+            text.annotations_page("comment", 0, 1, AnnotationOrder::Position, IndexType::Utf8);
+        assert_eq!(page[0].range, 0..2);
+    }
+}
+
+mod validators {
+    use super::*;
+
+    fn link(url: serde_json::Value) -> Style {
+        Style::new_link_like("link".into(), url)
+    }
+
+    #[test]
+    fn reject_local_and_flag_remote_invalid_values() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        b.set_validator("link", Box::new(|value| value.is_string()));
+
+        assert!(matches!(
+            b.try_annotate(0..1, link(1.into())),
+            Err(Error::InvalidValue(_))
+        ));
+        b.annotate(0..1, link(1.into()));
+        assert_eq!(b.get_spans(), a.get_spans());
+        b.try_annotate(0..1, link("url".into())).unwrap();
+        b.try_annotate(0..1, Style::new_erase_link_like("link".into()))
+            .unwrap();
+
+        a.annotate(1..2, link(true.into()));
+        b.merge(&a);
+        assert_eq!(b.invalid_annotations().len(), 1);
+        assert_eq!(b.get_spans()[1].insert, "2");
+        assert!(!b.get_spans()[1].attributes.is_empty());
+    }
+
+    #[test]
+    fn validate_delta() {
+        let mut text = RichText::new(1);
+        text.insert(0, "123");
+        text.set_validator("link", Box::new(|value| value.is_string()));
+        let attributes: FxHashMap<String, Value> =
+            vec![("link".into(), 1.into())].into_iter().collect();
+        let delta = || {
+            vec![
+                DeltaItem::retain_with_attributes(1, attributes.clone()),
+                DeltaItem::insert_with_attributes("x".into(), IndexType::Utf8, attributes.clone()),
+            ]
+        };
+
+        assert!(matches!(
+            text.try_apply_delta(delta().into_iter(), IndexType::Utf8),
+            Err(Error::InvalidValue(_))
+        ));
+        text.apply_delta(delta().into_iter(), IndexType::Utf8);
+        assert_eq!(text.to_string(), "123");
+        assert_eq!(text.get_spans().len(), 1);
+    }
+}
+
+mod storage {
+    use super::*;
+    use crate::rich_text::storage::FileStorage;
+
+    fn edit(text: &mut RichText) {
+        for i in 0..20 {
+            text.insert(i, "ab");
+            text.delete(i..i + 1);
+        }
+        text.annotate(0..10, bold());
+    }
+
+    #[test]
+    fn export_offloaded_history() {
+        let mut a = RichText::new(1);
+        edit(&mut a);
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        let vv = a.version();
+        let op_len = a.store.op_len();
+        assert!(a.offload_history().unwrap() > 0);
+        assert_eq!(a.offload_history().unwrap(), 0);
+        assert_eq!(a.store.op_len(), op_len);
+        edit(&mut a);
+
+        let mut c = RichText::new(3);
+        c.import(&a.export(&Default::default())).unwrap();
+        assert_eq!(c.get_spans(), a.get_spans());
+        b.import(&a.export(&vv)).unwrap();
+        assert_eq!(b.get_spans(), a.get_spans());
+    }
+
+    #[test]
+    fn file_storage() {
+        let dir = std::env::temp_dir().join(format!("crdt-richtext-ops-{}", std::process::id()));
+        let mut a = RichText::new(1);
+        edit(&mut a);
+        a.offload_history().unwrap();
+        a.set_op_log_storage(Box::new(FileStorage::new(&dir).unwrap()))
+            .unwrap();
+        edit(&mut a);
+        a.offload_history().unwrap();
+
+        let mut b = RichText::new(2);
+        b.import(&a.export(&Default::default())).unwrap();
+        assert_eq!(b.get_spans(), a.get_spans());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+mod diff_overlay {
+    use super::*;
+
+    #[test]
+    fn compare_versions() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let v1 = a.version();
+        a.delete(0..6);
+        a.insert(5, "!");
+        let v2 = a.version();
+
+        let diff = |from: &VersionVector, to: &VersionVector| -> Vec<(String, Option<String>)> {
+            a.diff_overlay(from, to)
+                .unwrap()
+                .into_iter()
+                .map(|x| {
+                    let diff = x
+                        .attributes
+                        .get(&"diff".into())
+                        .map(|x| x.as_str().unwrap().to_string());
+                    (x.insert, diff)
+                })
+                .collect()
+        };
+        assert_eq!(
+            diff(&v1, &v2),
+            vec![
+                ("hello ".into(), Some("delete".into())),
+                ("world".into(), None),
+                ("!".into(), Some("insert".into())),
+            ]
+        );
+        assert_eq!(
+            diff(&v2, &v1),
+            vec![
+                ("hello ".into(), Some("insert".into())),
+                ("world".into(), None),
+                ("!".into(), Some("delete".into())),
+            ]
+        );
+        assert_eq!(diff(&v2, &v2), vec![("world!".into(), None)]);
+    }
+}
+
+mod insert_with_styles {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn insert_styled_text() {
+        let bold = Style::new_bold_like("bold".into(), true.into());
+        let italic = Style::new_bold_like("italic".into(), true.into());
+        let mut text = RichText::new(1);
+        text.insert(0, "abc");
+        text.annotate(0..3, bold.clone());
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |event| {
+            events.borrow_mut().push(event.ops.clone());
+        }));
+
+        // the inherited style doesn't need a new annotation
+        let counter = text.version().vv[&1];
+        text.insert_with_styles(3, "d", &[bold.clone()]);
+        assert_eq!(text.version().vv[&1], counter + 1);
+        text.insert_with_styles(0, "x", &[italic]);
+        text.insert_with_styles(5, "e", &[]);
+
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[0].insert, "x");
+        assert!(spans[0].attributes.contains_key(&"italic".into()));
+        assert!(!spans[0].attributes.contains_key(&"bold".into()));
+        assert_eq!(spans[1].insert, "abcd");
+        assert_eq!(spans[2].insert, "e");
+        assert!(spans[2].attributes.is_empty());
+
+        let events = events_bk.borrow();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[0],
+            vec![
+                DeltaItem::retain(3),
+                DeltaItem::insert_with_attributes(
+                    "d".into(),
+                    IndexType::Utf8,
+                    vec![("bold".into(), true.into())].into_iter().collect()
+                )
+            ]
+        );
+        assert_eq!(
+            events[2],
+            vec![
+                DeltaItem::retain(5),
+                DeltaItem::insert_with_attributes("e".into(), IndexType::Utf8, Default::default())
+            ]
+        );
+    }
+    #[test]
+    fn invalid_styles_insert_nothing() {
+        let mut text = RichText::new(1);
+        text.insert(0, "abc");
+        text.set_validator("link", Box::new(|value| value.is_string()));
+        let bold = Style::new_bold_like("bold".into(), true.into());
+        let link = Style::new_link_like("link".into(), 1.into());
+        text.insert_with_styles(1, "x", &[bold, link]);
+        assert_eq!(text.to_string(), "abc");
+        assert_eq!(text.version().vv[&1], 3);
+    }
+
+    #[test]
+    fn erases_count_against_the_limit() {
+        use crate::rich_text::{LimitPolicy, Limits};
+
+        let mut text = RichText::new(1);
+        text.insert(0, "abc");
+        text.annotate(0..3, Style::new_bold_like("bold".into(), true.into()));
+        text.set_limits(Limits {
+            max_len: None,
+            max_annotations: Some(2),
+            policy: LimitPolicy::Reject,
+        });
+
+        // the italic and the erase of the inherited bold exceed the limit
+        let italic = Style::new_bold_like("italic".into(), true.into());
+        text.insert_with_styles(3, "d", &[italic]);
+        assert_eq!(text.to_string(), "abc");
+        assert_eq!(text.version().vv[&1], 4);
+    }
+}
+
+mod annotate_matches {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn highlight_all_occurrences() {
+        let highlight = Style::new_bold_like("highlight".into(), true.into());
+        let mut text = RichText::new(1);
+        text.insert(0, "你好 ab ab aab");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |event| {
+            events.borrow_mut().push(event.ops.clone());
+        }));
+        text.set_event_index_type(IndexType::Utf16);
+        assert_eq!(text.annotate_matches("ab", highlight.clone()), 3);
+        assert_eq!(text.annotate_matches("", highlight.clone()), 0);
+        assert_eq!(text.annotate_matches("xyz", highlight), 0);
+
+        let highlighted: Vec<_> = text
+            .get_spans()
+            .into_iter()
+            .filter(|x| !x.attributes.is_empty())
+            .map(|x| x.insert)
+            .collect();
+        assert_eq!(highlighted, vec!["ab", "ab", "ab"]);
+
+        let events = events_bk.borrow();
+        assert_eq!(events.len(), 1);
+        let attributes: FxHashMap<String, serde_json::Value> =
+            vec![("highlight".into(), true.into())]
+                .into_iter()
+                .collect();
+        assert_eq!(
+            events[0],
+            vec![
+                DeltaItem::retain(3),
+                DeltaItem::retain_with_attributes(2, attributes.clone()),
+                DeltaItem::retain(1),
+                DeltaItem::retain_with_attributes(2, attributes.clone()),
+                DeltaItem::retain(2),
+                DeltaItem::retain_with_attributes(2, attributes),
+            ]
+        );
+    }
+
+    #[test]
+    fn over_the_limit_annotates_nothing() {
+        use crate::rich_text::{LimitPolicy, Limits};
+
+        let mut text = RichText::new(1);
+        text.insert(0, "ab ab ab");
+        text.set_limits(Limits {
+            max_len: None,
+            max_annotations: Some(2),
+            policy: LimitPolicy::Reject,
+        });
+        let events = Rc::new(RefCell::new(0));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |_| *events.borrow_mut() += 1));
+        let highlight = Style::new_bold_like("highlight".into(), true.into());
+        assert_eq!(text.annotate_matches("ab", highlight.clone()), 0);
+        assert_eq!(*events_bk.borrow(), 0);
+        assert_eq!(text.count_annotations("highlight"), 0);
+        assert_eq!(text.annotate_matches("ab ab", highlight), 1);
+        assert_eq!(*events_bk.borrow(), 1);
+    }
+}
+
+mod fork {
+    use super::*;
+
+    #[test]
+    fn fork_with_new_id() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        a.annotate(0..2, bold());
+        let mut b = a.fork(2).unwrap();
+        assert_eq!(b.id(), 2);
+        assert_eq!(b.get_spans(), a.get_spans());
+        b.insert(0, "x");
+        a.merge(&b);
+        assert_eq!(a.to_string(), "x123");
+    }
+}
+
+mod transactional_import {
+    use super::*;
+
+    #[test]
+    fn failed_import_leaves_document_unchanged() {
+        let mut x = RichText::new(9);
+        x.insert(0, "abc");
+        let mut a = RichText::new(1);
+        a.merge(&x);
+        a.insert(1, "1");
+        a.annotate(0..4, bold());
+        // a's ops refer to x's text, which is not included
+        let data = a.export(&x.version());
+
+        let mut b = RichText::new(2);
+        b.insert(0, "hello");
+        let spans = b.get_spans();
+        assert!(matches!(b.import(&data), Err(Error::InvalidOp(_))));
+        assert!(b.import(&data[..data.len() / 2]).is_err());
+        assert_eq!(b.get_spans(), spans);
+        assert_eq!(b.version().vv.len(), 1);
+
+        b.merge(&x);
+        b.import(&data).unwrap();
+        assert!(b.to_string().contains("a1bc"));
+        assert_eq!(b.len(), 9);
+    }
+}
+
+mod unresolved_annotations {
+    use super::*;
+
+    #[test]
+    fn wait_for_anchor_targets() {
+        let mut x = RichText::new(9);
+        x.insert(0, "abc");
+        let mut a = RichText::new(1);
+        a.merge(&x);
+        a.annotate(0..3, bold());
+
+        let mut b = RichText::new(2);
+        b.import(&a.export(&x.version())).unwrap();
+        assert_eq!(b.unresolved_annotations().len(), 1);
+        assert_eq!(b.to_string(), "");
+
+        b.merge(&x);
+        assert!(b.unresolved_annotations().is_empty());
+        assert_eq!(b.get_spans(), a.get_spans());
+    }
+}
+
+mod list_numbering {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::rich_text::ListNumbering;
+
+    fn ordered() -> Style {
+        Style {
+            expand: Expand::None,
+            behavior: crate::Behavior::Merge,
+            type_: InternalString::from("list"),
+            value: "ordered".into(),
+        }
+    }
+
+    #[test]
+    fn renumber_on_events() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a\nb\nc\nd\n");
+        text.annotate(1..2, ordered());
+        text.annotate(3..4, ordered());
+        text.annotate(7..8, ordered());
+        let mut numbering = ListNumbering::new(&text);
+        assert_eq!(
+            numbering.numbers(),
+            &[Some(1), Some(2), None, Some(1), None]
+        );
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |event| {
+            events_bk.borrow_mut().push(event.clone());
+        }));
+        let update = |text: &RichText, numbering: &mut ListNumbering| {
+            for event in events.borrow_mut().drain(..) {
+                numbering.update(text, &event);
+            }
+            assert_eq!(numbering.numbers(), ListNumbering::new(text).numbers());
+        };
+
+        text.annotate(5..6, ordered());
+        update(&text, &mut numbering);
+        assert_eq!(
+            numbering.numbers(),
+            &[Some(1), Some(2), Some(3), Some(4), None]
+        );
+
+        text.delete(2..4);
+        update(&text, &mut numbering);
+        assert_eq!(numbering.numbers(), &[Some(1), Some(2), Some(3), None]);
+
+        text.insert(0, "x\n");
+        update(&text, &mut numbering);
+        assert_eq!(numbering.get(0), None);
+        assert_eq!(numbering.get(1), Some(1));
+        assert_eq!(numbering.get(3), Some(3));
+    }
+}
+
+mod doc_id {
+    use super::*;
+    use crate::rich_text::DocIdCheck;
+
+    #[test]
+    fn reject_updates_of_another_doc() {
+        let mut a = RichText::new(1);
+        a.set_doc_id(Some(1));
+        a.insert(0, "123");
+        let mut b = RichText::new(2);
+        b.set_doc_id(Some(2));
+        assert!(matches!(
+            b.import(&a.export(&Default::default())),
+            Err(Error::DocIdMismatch)
+        ));
+        assert_eq!(b.len(), 0);
+
+        let mut c = RichText::new(3);
+        c.import(&a.export(&Default::default())).unwrap();
+        assert_eq!(c.to_string(), "123");
+        assert_eq!(a.fork(4).unwrap().doc_id(), Some(1));
+    }
+
+    #[test]
+    fn strict_check() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let mut b = RichText::new(2);
+        b.set_doc_id(Some(1));
+        b.import(&a.export(&Default::default())).unwrap();
+        b.set_doc_id_check(DocIdCheck::Strict);
+        assert!(matches!(
+            b.import(&a.export(&Default::default())),
+            Err(Error::DocIdMismatch)
+        ));
+        a.set_doc_id(Some(1));
+        b.import(&a.export(&Default::default())).unwrap();
+        assert_eq!(b.to_string(), "123");
+    }
+}
+
+mod crypto {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::rich_text::crypto::{Cipher, UpdatesMeta};
+
+    struct Xor(u8);
+
+    impl Cipher for Xor {
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            plaintext.iter().map(|b| b ^ self.0).collect()
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(self.encrypt(ciphertext))
+        }
+    }
+
+    #[test]
+    fn encrypted_updates() {
+        let mut a = RichText::new(1);
+        a.set_cipher(Some(Box::new(Xor(42))));
+        a.insert(0, "secret text");
+        a.annotate(0..6, link());
+        let data = a.export(&Default::default());
+        assert!(!data.windows(6).any(|x| x == b"secret"));
+
+        let meta = UpdatesMeta::decode(&data).unwrap();
+        assert!(meta.encrypted);
+        assert_eq!(meta.start.vv.get(&1), Some(&0));
+        assert_eq!(meta.end.vv.get(&1), Some(&12));
+        assert!(meta.is_included_by(&a.version()));
+        assert!(meta.can_apply_after(&Default::default()));
+
+        let mut b = RichText::new(2);
+        assert!(b.import(&data).is_err());
+        b.set_cipher(Some(Box::new(Xor(42))));
+        b.import(&data).unwrap();
+        assert_eq!(b.get_spans(), a.get_spans());
+    }
+
+    struct Counting(Rc<Cell<usize>>);
+
+    impl Cipher for Counting {
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            self.0.set(self.0.get() + 1);
+            Xor(42).encrypt(plaintext)
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+            self.0.set(self.0.get() + 1);
+            Xor(42).decrypt(ciphertext)
+        }
+    }
+
+    #[test]
+    fn encrypt_every_distinct_value_once() {
+        let count = Rc::new(Cell::new(0));
+        let mut a = RichText::new(1);
+        a.set_cipher(Some(Box::new(Counting(Rc::clone(&count)))));
+        a.insert(0, "one two three");
+        let url = || Style::new_link_like("link".into(), serde_json::json!("a.com"));
+        a.annotate(0..3, url());
+        a.annotate(4..7, url());
+        a.annotate(8..13, url());
+        a.annotate(0..13, bold());
+        let data = a.export(&Default::default());
+        // the text, the url and the null value of the bold
+        assert_eq!(count.get(), 3);
+
+        count.set(0);
+        let mut b = RichText::new(2);
+        b.set_cipher(Some(Box::new(Counting(Rc::clone(&count)))));
+        b.import(&data).unwrap();
+        assert_eq!(count.get(), 3);
+        assert_eq!(b.get_spans(), a.get_spans());
+    }
+}
+
+mod divergence {
+    use super::*;
+    use crate::rich_text::divergence::DivergenceDetector;
+
+    #[test]
+    fn detect_divergence() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        let mut detector = DivergenceDetector::new(8);
+        a.insert(0, "abc");
+        detector.record(&a);
+        b.merge(&a);
+        let report = DivergenceDetector::report(&b).unwrap();
+        a.insert(3, "d");
+        detector.record(&a);
+        assert_eq!(detector.check(&a, &report).unwrap(), None);
+
+        // a client id is reused by another peer
+        let mut c = RichText::new(1);
+        c.insert(0, "xyzw");
+        let report = DivergenceDetector::report(&c).unwrap();
+        let divergence = detector.check(&a, &report).unwrap().unwrap();
+        assert_eq!(divergence.version, a.version());
+        assert_eq!(divergence.ops, vec![(1, 0..4)]);
+    }
+}
+
+mod resolve_range {
+    use super::*;
+
+    #[test]
+    fn resolve_and_clamp() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀bc");
+        assert_eq!(text.resolve_range(..=1, IndexType::Utf16), 0..3);
+        assert_eq!(text.resolve_range(2.., IndexType::Utf16), 1..5);
+        assert_eq!(text.resolve_range(4..100, IndexType::Utf16), 4..5);
+        assert_eq!(text.resolve_range(10..20, IndexType::Utf8), 7..7);
+
+        text.annotate_utf16(0..=2, bold());
+        assert_eq!(text.get_spans()[0].insert, "a😀");
+        text.annotate(0..0, bold());
+        text.annotate_utf16(.., link());
+        text.delete_utf16(3..100);
+        assert_eq!(text.to_string(), "a😀");
+    }
+
+    #[test]
+    fn annotate_empty_doc() {
+        let mut text = RichText::new(1);
+        text.annotate(.., bold());
+        assert!(text.is_empty());
+    }
+}
+
+mod diagnostics {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::rich_text::Diagnostics;
+
+    #[test]
+    fn transform_and_invalidate() {
+        let mut text = RichText::new(1);
+        text.insert(0, "helo wrld foo");
+        let mut diagnostics = Diagnostics::new(IndexType::Utf8);
+        diagnostics.add(&text, 0..4, "helo");
+        diagnostics.add(&text, 5..9, "wrld");
+        diagnostics.add(&text, 10..13, "foo");
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |event| {
+            events_bk.borrow_mut().push(event.clone());
+        }));
+        let mut update = |text: &RichText| {
+            let mut invalidated = Vec::new();
+            for event in events.borrow_mut().drain(..) {
+                invalidated.extend(diagnostics.update(text, &event));
+            }
+            let ranges: Vec<_> = diagnostics
+                .iter()
+                .map(|x| (x.data, x.range.clone()))
+                .collect();
+            (
+                ranges,
+                invalidated.into_iter().map(|x| x.data).collect::<Vec<_>>(),
+            )
+        };
+
+        text.insert(0, ">> ");
+        assert_eq!(
+            update(&text),
+            (
+                vec![("helo", 3..7), ("wrld", 8..12), ("foo", 13..16)],
+                vec![]
+            )
+        );
+
+        text.insert(10, "o");
+        text.annotate(13..17, bold());
+        assert_eq!(
+            update(&text),
+            (vec![("helo", 3..7), ("foo", 14..17)], vec!["wrld"])
+        );
+
+        text.delete(2..4);
+        assert_eq!(update(&text), (vec![("foo", 12..15)], vec!["helo"]));
+    }
+}
+
+mod serialized_spans {
+    use super::*;
+    use crate::rich_text::SerializedSpans;
+
+    #[test]
+    fn round_trip() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀bcd");
+        text.annotate(0..5, bold());
+        text.annotate(6..7, bold());
+        let json = text.get_spans_serialized();
+        assert_eq!(
+            json,
+            r#"{"v":1,"text":"a😀bcd","runs":[[3,0],[1,null],[1,0],[1,null]],"attrs":[{"bold":null}]}"#
+        );
+        let serialized: SerializedSpans = serde_json::from_str(&json).unwrap();
+        assert_eq!(serialized.to_spans().unwrap(), text.get_spans());
+    }
+}
+
+mod annotation_conflicts {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::OpID;
+
+    #[test]
+    fn concurrent_same_type_annotations() {
+        let mut a = RichText::new(1);
+        a.insert(0, "123");
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.annotate(0..3, bold());
+        b.insert(3, "45");
+        b.annotate(0..2, unbold());
+
+        let conflicts = Rc::new(RefCell::new(Vec::new()));
+        let conflicts_bk = Rc::clone(&conflicts);
+        b.observe_annotation_conflicts(Box::new(move |conflict| {
+            conflicts_bk.borrow_mut().push(conflict.clone());
+        }));
+        a.observe_annotation_conflicts(Box::new(|_| {}));
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.annotation_conflict_count(), 0);
+        assert_eq!(b.annotation_conflict_count(), 1);
+        let conflicts = conflicts.borrow();
+        assert_eq!(conflicts[0].discarded.id, OpID::new(1, 3));
+        assert_eq!(conflicts[0].winner.id, OpID::new(2, 2));
+    }
+}
+
+mod anchor {
+    use crate::{
+        rich_text::anchor::{anchor_positions_for, covered_range, AnchorPosition},
+        AnchorType, Expand,
+    };
+
+    #[test]
+    fn anchor_positions() {
+        let (start, end) = anchor_positions_for(Expand::Both, 0..3, 3);
+        assert_eq!(
+            start,
+            AnchorPosition {
+                index: None,
+                type_: AnchorType::After
+            }
+        );
+        assert_eq!(
+            end,
+            AnchorPosition {
+                index: None,
+                type_: AnchorType::Before
+            }
+        );
+
+        for expand in [Expand::None, Expand::Before, Expand::After, Expand::Both] {
+            for range in [0..1, 1..3, 2..5, 0..5] {
+                let (start, end) = anchor_positions_for(expand, range.clone(), 5);
+                assert_eq!(covered_range(start, end, 5, |_| 1), range);
+            }
+        }
+    }
+}
+
+mod is_in_annotation {
+    use super::*;
+    use crate::OpID;
+
+    #[test]
+    fn point_query() {
+        let mut text = RichText::new(1);
+        text.insert(0, "a😀bcd");
+        text.annotate(1..5, link());
+        text.annotate(0..7, bold());
+        text.annotate(5..7, unbold());
+        assert_eq!(text.is_in_annotation(0, "link"), None);
+        assert_eq!(text.is_in_annotation(1, "link"), Some(OpID::new(1, 8)));
+        assert_eq!(
+            text.is_in_annotation_utf16(2, "link"),
+            Some(OpID::new(1, 8))
+        );
+        assert_eq!(text.is_in_annotation(5, "link"), None);
+        assert_eq!(text.is_in_annotation(0, "bold"), Some(OpID::new(1, 9)));
+        assert_eq!(text.is_in_annotation(6, "bold"), None);
+        assert_eq!(text.is_in_annotation(7, "bold"), None);
+    }
+}
+
+mod split_annotation {
+    use super::*;
+    use crate::OpID;
+
+    #[test]
+    fn split_link() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(0..11, Style::new_link_like("link".into(), "a".into()));
+        let spans = text.get_spans();
+        let (first, second) = text.split_annotation(OpID::new(1, 11), 5).unwrap();
+        assert_eq!(text.get_spans(), spans);
+        assert_eq!(text.is_in_annotation(0, "link"), Some(first));
+        assert_eq!(text.is_in_annotation(6, "link"), Some(second));
+
+        text.annotate(5..11, Style::new_link_like("link".into(), "b".into()));
+        let spans = text.get_spans();
+        assert_eq!(spans[0].insert, "hello");
+        assert_eq!(spans[1].insert, " world");
+        assert_eq!(spans[1].attributes.get(&"link".into()), Some(&"b".into()));
+
+        assert!(text.split_annotation(first, 0).is_err());
+        assert!(text.split_annotation(OpID::new(1, 11), 3).is_err());
+        text.annotate(0..5, Style::new_comment_like("comment".into(), "c".into()));
+        let comment = text.is_in_annotation(0, "comment").unwrap();
+        assert!(text.split_annotation(comment, 2).is_err());
+    }
+}
+
+mod seeded_fuzzing {
+    use crate::rich_text::test_utils::{actions_from_seed, fuzzing_seeds};
+
+    #[test]
+    fn same_seed_same_actions() {
+        let a = format!("{:?}", actions_from_seed(7, 50));
+        let b = format!("{:?}", actions_from_seed(7, 50));
+        assert_eq!(a, b);
+        assert_ne!(a, format!("{:?}", actions_from_seed(8, 50)));
+    }
+
+    #[test]
+    fn seeded() {
+        fuzzing_seeds(5, 0..20, 100);
+    }
+}
+
+mod append {
+    use super::*;
+
+    #[test]
+    fn append_and_prepend() {
+        let mut text = RichText::new(1);
+        text.append("b");
+        text.append("c");
+        text.prepend("a");
+        text.append("😀");
+        assert_eq!(text.to_string(), "abc😀");
+        text.check();
+    }
+
+    #[test]
+    fn append_coalesces_ops() {
+        let mut text = RichText::new(1);
+        for _ in 0..100 {
+            text.append("a");
+        }
+        assert_eq!(text.export_ops(&Default::default()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn append_after_deleted_tail_matches_insert() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        a.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        a.delete(3..5);
+        let mut b = RichText::new(1);
+        b.merge(&a);
+        a.append("!");
+        b.insert(b.len(), "!");
+        assert_eq!(a.get_spans(), b.get_spans());
+
+        let mut c = RichText::new(2);
+        c.merge(&a);
+        assert_eq!(c.get_spans(), a.get_spans());
+    }
+}
+
+mod derived {
+    use super::*;
+
+    fn autodetect_url(text: &str) -> Vec<(std::ops::Range<usize>, Value)> {
+        let mut ans = Vec::new();
+        let mut offset = 0;
+        for word in text.split(' ') {
+            if word.starts_with("https://") {
+                ans.push((offset..offset + word.len(), Value::Bool(true)));
+            }
+            offset += word.len() + 1;
+        }
+        ans
+    }
+
+    #[test]
+    fn derived_annotations_are_not_stored() {
+        let mut text = RichText::new(1);
+        text.register_derived_annotation("url", Box::new(autodetect_url));
+        text.insert(0, "see https://a.com now");
+        text.annotate(0..3, Style::new_bold_like("bold".into(), true.into()));
+        let spans = text.get_spans_with_derived();
+        assert_eq!(spans.len(), 4);
+        assert_eq!(spans[0].insert, "see");
+        assert_eq!(spans[2].insert, "https://a.com");
+        assert_eq!(
+            spans[2].attributes.get(&"url".into()),
+            Some(&Value::Bool(true))
+        );
+        assert!(spans[3].attributes.is_empty());
+
+        // the derived annotation follows the text and is never synced
+        let mut other = RichText::new(2);
+        other.merge(&text);
+        assert!(other
+            .get_spans()
+            .iter()
+            .all(|x| !x.attributes.contains_key(&"url".into())));
+        assert_eq!(text.get_spans().len(), 2);
+        text.delete(4..12);
+        assert_eq!(text.get_spans_with_derived().len(), 2);
+        text.unregister_derived_annotation("url");
+        assert_eq!(text.get_spans_with_derived(), text.get_spans());
+    }
+}
+
+mod import_options {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn import_without_events() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let data = a.export(&Default::default());
+        let mut b = RichText::new(2);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        b.observe(Box::new(move |event| {
+            events_clone.borrow_mut().push(event.clone());
+        }));
+        b.import_with_options(
+            &data,
+            &ImportOptions {
+                emit_events: false,
+                origin: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(b.to_string(), "hello");
+        assert!(events.borrow().is_empty());
+
+        let vv = b.version();
+        a.insert(5, " world");
+        b.import_with_options(
+            &a.export(&vv),
+            &ImportOptions {
+                emit_events: true,
+                origin: Some("provider".into()),
+            },
+        )
+        .unwrap();
+        assert_eq!(events.borrow().len(), 1);
+        assert_eq!(events.borrow()[0].origin.as_deref(), Some("provider"));
+    }
+}
+
+mod chunk_store {
+    use super::*;
+    use crate::rich_text::chunk_store::{read_chunks, write_chunks, MemoryChunkStore};
+
+    fn random_text(len: usize) -> String {
+        let mut state: u32 = 1;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (b'a' + (state >> 16) as u8 % 26) as char
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dedup_similar_snapshots() {
+        let mut text = RichText::new(1);
+        text.insert(0, &random_text(64 * 1024));
+        text.annotate(10..100, Style::new_bold_like("bold".into(), true.into()));
+        let mut store = MemoryChunkStore::default();
+        let (first, chunks) = write_chunks(&text, 4096).unwrap();
+        assert!(chunks.len() > 1);
+        store.insert_all(chunks);
+
+        text.insert(30 * 1024, "hello");
+        let (second, chunks) = write_chunks(&text, 4096).unwrap();
+        store.insert_all(chunks);
+        assert!(store.size() < first.len + second.len / 2);
+
+        let mut loaded = RichText::new(2);
+        loaded
+            .import(&read_chunks(&second, &store).unwrap())
+            .unwrap();
+        assert_eq!(loaded.to_string(), text.to_string());
+        assert_eq!(loaded.get_spans(), text.get_spans());
+    }
+
+    #[test]
+    fn missing_chunk() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello");
+        let (manifest, _) = write_chunks(&text, 4096).unwrap();
+        assert!(matches!(
+            read_chunks(&manifest, &MemoryChunkStore::default()),
+            Err(Error::Storage(_))
+        ));
+    }
+}
+
+mod limits {
+    use super::*;
+    use crate::rich_text::{LimitKind, LimitPolicy, Limits};
+
+    #[test]
+    fn reject_local_edits() {
+        let mut text = RichText::new(1);
+        text.set_limits(Limits {
+            max_len: Some(5),
+            max_annotations: Some(1),
+            policy: LimitPolicy::Reject,
+        });
+        text.insert(0, "abc");
+        text.insert(3, "def");
+        assert_eq!(text.to_string(), "abc");
+        assert!(matches!(
+            text.try_insert(3, "def"),
+            Err(Error::LimitExceeded {
+                kind: LimitKind::Length,
+                limit: 5
+            })
+        ));
+
+        text.annotate(0..1, Style::new_bold_like("bold".into(), true.into()));
+        text.annotate(1..2, Style::new_bold_like("bold".into(), true.into()));
+        assert_eq!(text.get_spans().len(), 2);
+        assert!(matches!(
+            text.try_annotate(1..2, Style::new_bold_like("bold".into(), true.into())),
+            Err(Error::LimitExceeded {
+                kind: LimitKind::Annotations,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn clamp_local_inserts() {
+        let mut text = RichText::new(1);
+        text.set_limits(Limits {
+            max_len: Some(5),
+            policy: LimitPolicy::Clamp,
+            ..Default::default()
+        });
+        text.insert(0, "ab");
+        // the clamped text ends at a char boundary
+        text.append("c你好");
+        assert_eq!(text.to_string(), "abc");
+        text.insert(0, "xyz");
+        assert_eq!(text.to_string(), "xyabc");
+    }
+
+    #[test]
+    fn limit_delta_inserts() {
+        let mut text = RichText::new(1);
+        text.set_limits(Limits {
+            max_len: Some(5),
+            policy: LimitPolicy::Clamp,
+            ..Default::default()
+        });
+        text.insert(0, "abc");
+        let attributes: FxHashMap<String, Value> =
+            vec![("bold".into(), true.into())].into_iter().collect();
+        text.apply_delta(
+            vec![
+                DeltaItem::retain(3),
+                DeltaItem::insert_with_attributes(
+                    "xyz".into(),
+                    IndexType::Utf8,
+                    attributes.clone(),
+                ),
+            ]
+            .into_iter(),
+            IndexType::Utf8,
+        );
+        let spans = text.get_spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[1].insert, "xy");
+        assert!(spans[1].attributes.contains_key(&"bold".into()));
+
+        text.set_limits(Limits {
+            max_len: Some(5),
+            policy: LimitPolicy::Reject,
+            ..Default::default()
+        });
+        text.apply_delta(
+            vec![DeltaItem::insert_with_attributes(
+                "w".into(),
+                IndexType::Utf8,
+                attributes,
+            )]
+            .into_iter(),
+            IndexType::Utf8,
+        );
+        assert_eq!(text.to_string(), "abcxy");
+    }
+
+    #[test]
+    fn reject_imports() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let mut b = RichText::new(2);
+        b.set_limits(Limits {
+            max_len: Some(5),
+            ..Default::default()
+        });
+        assert!(matches!(
+            b.import(&a.export(&Default::default())),
+            Err(Error::LimitExceeded { .. })
+        ));
+        assert_eq!(b.to_string(), "");
+    }
+}
+
+mod json {
+    use super::*;
+
+    #[test]
+    fn json_roundtrip() {
+        let mut text = RichText::new(1);
+        text.insert(0, "Hello 你好 world");
+        text.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        text.annotate(6..12, Style::new_link_like("link".into(), "url".into()));
+        let json = text.to_json();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["text"], "Hello 你好 world");
+        assert_eq!(value["runs"][0]["start"], 0);
+        assert_eq!(value["runs"][0]["end"], 5);
+        assert_eq!(value["runs"][1]["attributes"]["link"], "url");
+        assert_eq!(value["meta"]["len"], text.len());
+
+        let restored = RichText::from_json(2, &json).unwrap();
+        assert_eq!(restored.to_string(), text.to_string());
+        assert_eq!(restored.get_spans(), text.get_spans());
+    }
+
+    #[test]
+    fn invalid_run() {
+        let json = r#"{"text":"你好","runs":[{"start":0,"end":1,"attributes":{"bold":true}}],
+            "meta":{"index_type":"Utf8","len":6,"version":{"vv":{}},"doc_id":null}}"#;
+        assert!(matches!(
+            RichText::from_json(1, json),
+            Err(Error::DecodeError(_))
+        ));
+    }
+}
+
+mod apply_delta_options {
+    use super::*;
+    use crate::rich_text::ApplyDeltaOptions;
+
+    fn paste(inherit_surrounding_styles: bool) -> Vec<Span> {
+        let mut text = RichText::new(1);
+        text.insert(0, "abcd");
+        text.annotate(0..4, Style::new_bold_like("bold".into(), true.into()));
+        text.apply_delta_with_options(
+            [
+                DeltaItem::retain(2),
+                DeltaItem::insert("xy".into(), IndexType::Utf8),
+            ]
+            .into_iter(),
+            IndexType::Utf8,
+            &ApplyDeltaOptions {
+                inherit_surrounding_styles,
+            },
+        );
+        assert_eq!(text.to_string(), "abxycd");
+        text.get_spans()
+    }
+
+    #[test]
+    fn paste_into_styled_run() {
+        assert_eq!(paste(false).len(), 3);
+        let spans = paste(true);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].attributes.get(&"bold".into()), Some(&true.into()));
+    }
+}
+
+mod interned_attributes {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc, sync::Arc};
+
+    #[test]
+    fn share_equal_attributes_in_event() {
+        let mut a = RichText::new(1);
+        a.insert(0, "abc");
+        a.annotate(0..3, Style::new_bold_like("bold".into(), true.into()));
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        let vv = a.version();
+        a.insert(1, "x");
+        a.insert(4, "y");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        b.observe(Box::new(move |event| {
+            events_clone.borrow_mut().push(event.clone());
+        }));
+        b.import(&a.export(&vv)).unwrap();
+        let events = events.borrow();
+        let inserts: Vec<&Attributes> = events[0]
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                DeltaItem::Insert { attributes, .. } => attributes.as_ref(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inserts.len(), 2);
+        assert!(Arc::ptr_eq(inserts[0], inserts[1]));
+    }
+}
+
+mod annotations_overlapping {
+    use super::*;
+
+    fn comment(text: &mut RichText, range: std::ops::Range<usize>) {
+        text.annotate(
+            range,
+            Style::new_from_expand(
+                Expand::None,
+                "comment".into(),
+                "hi".into(),
+                Behavior::AllowMultiple,
+            )
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn nested_and_overlapping_comments() {
+        let mut text = RichText::new(1);
+        text.insert(0, "0123456789");
+        comment(&mut text, 0..8);
+        comment(&mut text, 2..4);
+        comment(&mut text, 6..10);
+        comment(&mut text, 8..10);
+        let [outer, nested, overlapping, touching] = [10, 11, 12, 13].map(|x| OpID::new(1, x));
+        assert_eq!(
+            text.annotations_overlapping(outer),
+            vec![nested, overlapping]
+        );
+        assert_eq!(text.annotations_overlapping(nested), vec![outer]);
+        assert_eq!(text.annotations_overlapping(touching), vec![overlapping]);
+
+        text.delete(2..4);
+        assert!(text.annotations_overlapping(nested).is_empty());
+        assert!(text.annotations_overlapping(OpID::new(9, 9)).is_empty());
+    }
+}
+
+mod write_to {
+    use super::*;
+    use crate::rich_text::ExportFormat;
+
+    #[test]
+    fn write_in_every_format() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello 你好 world");
+        text.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        text.delete(5..6);
+
+        let mut out = Vec::new();
+        text.write_to(&mut out, ExportFormat::Text).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), text.to_string());
+
+        let mut out = Vec::new();
+        text.write_to(&mut out, ExportFormat::Spans).unwrap();
+        let spans: Vec<Span> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(spans, text.get_spans());
+
+        let mut out = Vec::new();
+        text.write_to(&mut out, ExportFormat::Snapshot).unwrap();
+        let mut other = RichText::new(2);
+        other.import(&out).unwrap();
+        assert_eq!(other.get_spans(), text.get_spans());
+    }
+}
+
+mod style_state_at {
+    use super::*;
+    use crate::rich_text::StyleState;
+
+    #[test]
+    fn query_style_at_old_versions() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let v0 = text.version();
+        text.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        let v1 = text.version();
+        text.insert(0, "abc");
+        text.annotate(0..8, Style::new_erase_bold_like("bold".into()));
+        text.delete(8..9);
+        let v2 = text.version();
+
+        assert_eq!(
+            text.style_state_at(&v0, 0..5, "bold").unwrap(),
+            StyleState::Unset
+        );
+        assert_eq!(
+            text.style_state_at(&v1, 0..5, "bold").unwrap(),
+            StyleState::Set {
+                id: OpID::new(1, 11),
+                value: true.into()
+            }
+        );
+        assert_eq!(
+            text.style_state_at(&v1, 3..8, "bold").unwrap(),
+            StyleState::Mixed
+        );
+        assert_eq!(
+            text.style_state_at(&v2, 3..8, "bold").unwrap(),
+            StyleState::Unset
+        );
+        // the text deleted later is still visible at the old version
+        assert_eq!(
+            text.style_state_at(&v1, 5..6, "bold").unwrap(),
+            StyleState::Unset
+        );
+    }
+
+    #[test]
+    fn read_the_offloaded_deletions() {
+        let mut text = RichText::new(1);
+        text.insert(0, "abcdef");
+        text.annotate(1..3, Style::new_bold_like("bold".into(), true.into()));
+        text.delete(0..1);
+        let v = text.version();
+        text.insert(5, "x");
+        assert!(text.offload_history().unwrap() > 0);
+        assert_eq!(
+            text.style_state_at(&v, 0..2, "bold").unwrap(),
+            StyleState::Set {
+                id: OpID::new(1, 6),
+                value: true.into()
+            }
+        );
+    }
+}
+
+mod remap_client {
+    use super::*;
+
+    #[test]
+    fn remap_the_anonymous_client() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "hello");
+        b.merge(&a);
+        b.insert(5, " world");
+        a.merge(&b);
+        a.annotate(3..8, Style::new_bold_like("bold".into(), true.into()));
+        a.delete(0..1);
+
+        let migrated = a.remap_client(1, 3).unwrap();
+        assert_eq!(migrated.id(), 3);
+        assert_eq!(migrated.get_spans(), a.get_spans());
+        let vv = migrated.version();
+        assert!(!vv.vv.contains_key(&1));
+        assert_eq!(vv.vv.get(&3), a.version().vv.get(&1));
+
+        // the other peers converge by running the same migration
+        b.merge(&a);
+        let b = b.remap_client(1, 3).unwrap();
+        assert_eq!(b.id(), 2);
+        assert_eq!(b.get_spans(), migrated.get_spans());
+
+        assert!(a.remap_client(1, 2).is_err());
+    }
+}
+
+mod source_map {
+    use super::*;
+
+    #[test]
+    fn resolve_the_offsets_after_edits() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.insert(5, " 你好");
+        text.delete(0..1);
+        let (plain, map) = text.to_string_with_source_map();
+        assert_eq!(plain, text.to_string());
+        assert_eq!(map.segments.len(), 3);
+
+        let world = map.id_at(plain.find("world").unwrap()).unwrap();
+        let ni = map.id_at(plain.find("你").unwrap()).unwrap();
+        assert_eq!(map.id_at(plain.len()), None);
+
+        text.insert(0, "Oh, ");
+        text.delete(4..8);
+        assert_eq!(
+            text.index_of_id(world, IndexType::Utf8),
+            text.to_string().find("world")
+        );
+        assert_eq!(text.index_of_id(ni, IndexType::Utf16), Some(5));
+        assert_eq!(text.index_of_id(ni.inc(1), IndexType::Utf8), None);
+        text.delete(0..text.len());
+        assert_eq!(text.index_of_id(world, IndexType::Utf8), None);
+    }
+}
+
+mod observe_range {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn only_get_the_events_in_range() {
+        let mut text = RichText::new(1);
+        text.insert(0, &"0123456789".repeat(10));
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        text.observe_range(
+            40..50,
+            Box::new(move |event| {
+                events.borrow_mut().push(event.ops.clone());
+            }),
+        );
+
+        text.insert(0, "x");
+        text.annotate(0..10, Style::new_bold_like("bold".into(), true.into()));
+        text.insert(90, "x");
+        assert!(events_bk.borrow().is_empty());
+
+        // the range is 41..51 now
+        text.insert(45, "y");
+        text.delete(40..43);
+        // the text inserted at the edge is inside the range
+        text.insert(49, "z");
+        text.annotate(49..50, Style::new_bold_like("bold".into(), true.into()));
+        text.delete(60..70);
+        assert_eq!(events_bk.borrow().len(), 4);
+        assert_eq!(
+            events_bk.borrow()[0],
+            vec![
+                DeltaItem::retain(45),
+                DeltaItem::insert_with_attributes("y".into(), IndexType::Utf8, Default::default())
+            ]
+        );
+    }
+}
+
+mod branch {
+    use super::*;
+    use crate::rich_text::ops;
+
+    #[test]
+    fn draft_and_publish() {
+        let mut doc = RichText::new(1);
+        doc.insert(0, "hello world");
+        let mut draft = doc.branch(2).unwrap();
+        assert_eq!(draft.branch_base(), Some(&doc.version()));
+        assert!(draft.changes_since_branch().unwrap().is_empty());
+
+        draft.insert(5, ",");
+        draft.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        let changes = draft.changes_since_branch().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(changes[0], ops::Op::TextInsert(_)));
+        assert!(matches!(changes[1], ops::Op::Annotate(_)));
+
+        doc.merge(&draft);
+        assert_eq!(doc.get_spans(), draft.get_spans());
+        assert_eq!(doc.branch_base(), None);
+        assert_eq!(doc.changes_since_branch().unwrap().len(), 3);
+    }
+}
+
+mod locate_op {
+    use super::*;
+    use crate::rich_text::OpLocation;
+
+    #[test]
+    fn locate_text_and_annotations() {
+        let mut text = RichText::new(1);
+        text.insert(0, "你好 world");
+        text.insert(0, "hi");
+        text.annotate(9..14, Style::new_bold_like("comment".into(), true.into()));
+        text.delete(2..5);
+
+        assert_eq!(
+            text.locate_op(OpID::new(1, 7), IndexType::Utf8),
+            Some(OpLocation::Visible(6))
+        );
+        // the id of any byte of the char
+        assert_eq!(
+            text.locate_op(OpID::new(1, 4), IndexType::Utf16),
+            Some(OpLocation::Visible(2))
+        );
+        assert_eq!(
+            text.locate_op(OpID::new(1, 1), IndexType::Utf8),
+            Some(OpLocation::Deleted(2))
+        );
+        assert_eq!(
+            text.locate_op(OpID::new(1, 14), IndexType::Utf8),
+            Some(OpLocation::Annotation(6..11))
+        );
+        // the deletion
+        assert_eq!(text.locate_op(OpID::new(1, 15), IndexType::Utf8), None);
+        assert_eq!(text.locate_op(OpID::new(2, 0), IndexType::Utf8), None);
+    }
+}
+
+mod compact_annotations {
+    use super::*;
+
+    #[test]
+    fn encode_anchors_compactly() {
+        let mut text = RichText::new(1);
+        text.insert(0, &"a".repeat(1000));
+        for i in 0..200 {
+            text.annotate(
+                i * 5..i * 5 + 3,
+                Style::new_bold_like("bold".into(), true.into()),
+            );
+        }
+
+        let data = text.export(&Default::default());
+        let mut b = RichText::new(2);
+        b.import(&data).unwrap();
+        assert_eq!(b.get_spans(), text.get_spans());
+
+        // the updates in the old format can still be imported
+        let old = text
+            .export_as(&Default::default(), FormatVersion::V1)
+            .unwrap();
+        assert!(data.len() < old.len());
+        let mut c = RichText::new(3);
+        c.import(&old).unwrap();
+        assert_eq!(c.get_spans(), text.get_spans());
+    }
+}
+
+mod merge_all {
+    use super::*;
+
+    #[test]
+    fn merge_divergent_replicas() {
+        let mut base = RichText::new(1);
+        base.insert(0, "hello world");
+        let mut replicas: Vec<RichText> = (2..6).map(|id| base.fork(id).unwrap()).collect();
+        for (i, doc) in replicas.iter_mut().enumerate() {
+            doc.insert(i * 2, &i.to_string().repeat(3));
+            doc.annotate(0..4, Style::new_bold_like("bold".into(), (i as i32).into()));
+            doc.delete(10..11);
+        }
+        // the replicas may share ops
+        let (a, b) = replicas.split_at_mut(1);
+        b[0].merge(&a[0]);
+
+        let merged = RichText::merge_all(9, &replicas).unwrap();
+        let mut expected = base;
+        for doc in replicas.iter() {
+            expected.merge(doc);
+        }
+        assert_eq!(merged.id(), 9);
+        assert_eq!(merged.version(), expected.version());
+        assert_eq!(merged.get_spans(), expected.get_spans());
+    }
+}
+
+mod annotation_history {
+    use super::*;
+    use crate::rich_text::{PatchKind, PatchRecord};
+
+    #[test]
+    fn list_the_changes_of_a_link() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        a.annotate(0..5, Style::new_link_like("link".into(), "a.com".into()));
+        let mut b = a.fork(2).unwrap();
+        b.annotate(3..8, Style::new_link_like("link".into(), "b.com".into()));
+        b.annotate(0..1, Style::new_erase_link_like("link".into()));
+        // it doesn't overlap the link
+        b.annotate(6..11, Style::new_erase_link_like("link".into()));
+        a.merge(&b);
+
+        let history = a.annotation_history(OpID::new(1, 11));
+        assert_eq!(
+            history
+                .iter()
+                .map(|x| (x.author(), x.kind))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, PatchKind::Created),
+                (2, PatchKind::Overridden),
+                (2, PatchKind::Erased)
+            ]
+        );
+        assert_eq!(
+            history[1],
+            PatchRecord {
+                id: OpID::new(2, 0),
+                lamport: 12,
+                kind: PatchKind::Overridden
+            }
+        );
+        assert!(a.annotation_history(OpID::new(3, 0)).is_empty());
+    }
+}
+
+mod value_mode {
+    use super::*;
+    use crate::rich_text::ValueMode;
+    use serde_json::Value;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn labels(span: &Span) -> Option<&Value> {
+        span.attributes.get(&InternalString::from("labels"))
+    }
+
+    #[test]
+    fn union_the_concurrent_labels() {
+        let mut a = RichText::new(1);
+        a.set_value_mode("labels", ValueMode::SetUnion);
+        a.insert(0, "hello world");
+        let mut b = a.fork(2).unwrap();
+        b.set_value_mode("labels", ValueMode::SetUnion);
+        a.annotate(
+            0..5,
+            Style::new_bold_like("labels".into(), vec![Value::from("todo")].into()),
+        );
+        b.annotate(3..8, Style::new_bold_like("labels".into(), "urgent".into()));
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_bk = Rc::clone(&events);
+        a.observe(Box::new(move |event| {
+            events.borrow_mut().push(event.ops.clone());
+        }));
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.get_spans(), b.get_spans());
+        let spans = a.get_spans();
+        let values: Vec<_> = spans.iter().map(labels).collect();
+        let todo = Value::from(vec!["todo"]);
+        let both = Value::from(vec!["todo", "urgent"]);
+        let urgent = Value::from(vec!["urgent"]);
+        assert_eq!(values, vec![Some(&todo), Some(&both), Some(&urgent), None]);
+        // the event has the merged values too
+        let events = events_bk.borrow();
+        assert_eq!(events[0][1].attributions().unwrap()["labels"], both);
+        assert_eq!(events[0][2].attributions().unwrap()["labels"], urgent);
+
+        a.annotate(0..2, Style::new_erase_bold_like("labels".into()));
+        assert_eq!(labels(&a.get_spans()[0]), None);
+    }
+
+    #[test]
+    fn count_the_values() {
+        let mut text = RichText::new(1);
+        text.set_value_mode("likes", ValueMode::Counter);
+        text.insert(0, "hello");
+        text.annotate(0..5, Style::new_bold_like("likes".into(), 1.into()));
+        text.annotate(0..3, Style::new_bold_like("likes".into(), 2.into()));
+        let spans = text.get_spans();
+        let likes = InternalString::from("likes");
+        assert_eq!(spans[0].attributes[&likes], Value::from(3));
+        assert_eq!(spans[1].attributes[&likes], Value::from(1));
+    }
+}
+
+mod op_coalescing {
+    use super::*;
+
+    #[test]
+    fn typing_adds_few_ops() {
+        let mut text = RichText::new(1);
+        for _ in 0..1000 {
+            text.insert(text.len(), "a");
+        }
+        assert_eq!(text.op_log_stats().ops, 1);
+
+        // type in the middle
+        for i in 0..100 {
+            text.insert(500 + i, "b");
+        }
+        assert_eq!(text.op_log_stats().ops, 2);
+
+        // backspace
+        for i in 0..50 {
+            text.delete(599 - i..600 - i);
+        }
+        assert_eq!(text.op_log_stats().ops, 3);
+        assert_eq!(text.op_log_stats().atoms, 1150);
+    }
+
+    #[test]
+    fn remote_keystrokes_are_merged() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        for _ in 0..100 {
+            let vv = a.version();
+            a.insert(a.len(), "a");
+            b.import(&a.export(&vv)).unwrap();
+        }
+
+        assert_eq!(b.to_string(), a.to_string());
+        assert_eq!(b.op_log_stats().ops, 1);
+    }
+}
+
+mod import_many {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn apply_backlog_in_one_pass() {
+        let mut a = RichText::new(1);
+        let mut updates = Vec::new();
+        let mut vv = VersionVector::default();
+        for i in 0..100 {
+            a.insert(a.len(), &i.to_string());
+            if i % 10 == 0 {
+                a.delete(0..1);
+            }
+            updates.push(a.export(&vv));
+            vv = a.version();
+        }
+
+        let mut b = RichText::new(2);
+        let events = Rc::new(RefCell::new(0));
+        let events_bk = Rc::clone(&events);
+        b.observe(Box::new(move |_| {
+            *events.borrow_mut() += 1;
+        }));
+        let mut progress = Vec::new();
+        // the order of the updates doesn't matter
+        updates.reverse();
+        b.import_many(&updates, |done, total| progress.push((done, total)))
+            .unwrap();
+        assert_eq!(b.to_string(), a.to_string());
+        assert_eq!(*events_bk.borrow(), 1);
+        assert_eq!(progress.len(), 100);
+        assert_eq!(progress[99], (100, 100));
+    }
+
+    #[test]
+    fn overlapping_updates() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let first = a.export(&Default::default());
+        let vv = a.version();
+        a.insert(5, " world");
+        a.delete(0..1);
+        a.annotate(0..3, Style::new_bold_like("bold".into(), true.into()));
+        let updates = vec![
+            a.export(&vv),
+            a.export(&Default::default()),
+            first.clone(),
+            a.export(&Default::default()),
+        ];
+
+        let mut b = RichText::new(2);
+        b.import(&first).unwrap();
+        b.import_many(&updates, |_, _| {}).unwrap();
+        assert_eq!(b.to_string(), a.to_string());
+        assert_eq!(b.get_spans(), a.get_spans());
+        assert_eq!(b.store.op_len(), a.store.op_len());
+        assert_eq!(b.version(), a.version());
+    }
+
+    #[test]
+    fn all_or_nothing() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let mut b = RichText::new(2);
+        let updates = vec![a.export(&Default::default()), vec![1, 2, 3]];
+        assert!(b.import_many(updates, |_, _| {}).is_err());
+        assert_eq!(b.to_string(), "");
+    }
+}
+
+mod deterministic_export {
+    use super::*;
+
+    #[test]
+    fn same_ops_same_bytes() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        let mut c = RichText::new(3);
+        a.insert(0, "hello");
+        b.insert(0, "world");
+        c.insert(0, "!");
+        let a_first = a.export(&Default::default());
+        a.insert(5, " there");
+        a.annotate(0..3, Style::new_bold_like("bold".into(), true.into()));
+        b.delete(0..2);
+
+        // receive the ops of a in two updates, and in different client orders
+        let mut x = RichText::new(4);
+        x.import(&a_first).unwrap();
+        x.merge(&c);
+        x.merge(&b);
+        x.merge(&a);
+        let mut y = RichText::new(5);
+        y.merge(&b);
+        y.merge(&a);
+        y.merge(&c);
+        assert_eq!(x.export(&Default::default()), y.export(&Default::default()));
+        let vv = a.version();
+        assert_eq!(x.export(&vv), y.export(&vv));
+
+        // the offloaded history is exported the same way
+        y.offload_history().unwrap();
+        assert_eq!(x.export(&Default::default()), y.export(&Default::default()));
+    }
+}
+
+mod transact {
+    use super::*;
+    use crate::rich_text::{LimitKind, TransactOptions};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn commit() {
+        let mut text = RichText::new(1);
+        let events = Rc::new(RefCell::new(0));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |_| {
+            *events.borrow_mut() += 1;
+        }));
+        text.transact(TransactOptions::default(), |txn| {
+            txn.insert(0, "hello")?;
+            assert_eq!(*events_bk.borrow(), 0);
+            txn.annotate(0..5, Style::new_bold_like("bold".into(), true.into()))
+        })
+        .unwrap();
+        assert_eq!(*events_bk.borrow(), 2);
+        assert_eq!(text.get_spans()[0].attributes.len(), 1);
+    }
+
+    #[test]
+    fn rollback_when_the_quota_is_exceeded() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let mut b = a.fork(2).unwrap();
+        b.insert(5, " world");
+        a.merge(&b);
+        let version = a.version();
+        let options = TransactOptions {
+            max_inserted: Some(8),
+        };
+        let err = a
+            .transact(options, |txn| {
+                txn.insert(5, "!")?;
+                txn.delete(0..1)?;
+                txn.annotate(0..3, Style::new_bold_like("bold".into(), true.into()))?;
+                txn.insert(0, "12345678")
+            })
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LimitExceeded {
+                kind: LimitKind::TransactionInserted,
+                limit: 8
+            }
+        ));
+        assert_eq!(a.to_string(), "hello world");
+        assert_eq!(a.version(), version);
+        assert!(a.get_spans()[0].attributes.is_empty());
+
+        // the document keeps working after the rollback
+        a.insert(0, "x");
+        b.merge(&a);
+        assert_eq!(b.to_string(), "xhello world");
+    }
+
+    #[test]
+    fn quota_counts_the_transformed_chars() {
+        let mut text = RichText::new(1);
+        text.register_text_transform("double", Box::new(|_, s| Some(s.repeat(2))));
+        let options = TransactOptions {
+            max_inserted: Some(4),
+        };
+        text.transact(options, |txn| {
+            txn.insert(0, "你好")?;
+            assert_eq!(txn.inserted(), 4);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(text.to_string(), "你好你好");
+        assert!(text.transact(options, |txn| txn.insert(0, "abc")).is_err());
+        assert_eq!(text.to_string(), "你好你好");
+    }
+
+    #[test]
+    fn rollback_keeps_the_earlier_edits() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        a.annotate(.., Style::new_bold_like("bold".into(), true.into()));
+        a.annotate(0..5, Style::new_bold_like("italic".into(), true.into()));
+        a.delete(5..6);
+        let spans = a.get_spans();
+        let version = a.version();
+        a.transact(TransactOptions::default(), |txn| {
+            txn.insert(5, "!!")?;
+            txn.insert(0, "x")?;
+            txn.delete(2..8)?;
+            txn.annotate(.., Style::new_bold_like("link".into(), true.into()))?;
+            txn.annotate(1..4, Style::new_bold_like("bold".into(), false.into()))?;
+            Err(Error::Readonly)
+        })
+        .unwrap_err();
+        assert_eq!(a.get_spans(), spans);
+        assert_eq!(a.version(), version);
+        a.annotate(0..2, Style::new_bold_like("link".into(), true.into()));
+        a.insert(5, " ");
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        assert_eq!(b.get_spans(), a.get_spans());
+        a.check();
+    }
+
+    #[test]
+    fn rollback_when_the_edits_panic() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            text.transact(TransactOptions::default(), |txn| {
+                txn.insert(0, "abc")?;
+                panic!("boom")
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(text.to_string(), "hello");
+        let events = Rc::new(RefCell::new(0));
+        let events_bk = Rc::clone(&events);
+        text.observe(Box::new(move |_| {
+            *events.borrow_mut() += 1;
+        }));
+        text.insert(0, "x");
+        assert_eq!(*events_bk.borrow(), 1);
+    }
+}
+
+mod orphaned_annotations {
+    use super::*;
+
+    fn comment(value: &str) -> Style {
+        Style::new_comment_like("comment".into(), value.into())
+    }
+
+    #[test]
+    fn prune_orphaned_annotations() {
+        let mut a = RichText::new(1);
+        let mut c = RichText::new(3);
+        a.insert(0, "aaaaa");
+        c.insert(0, "ccccc");
+        a.merge(&c);
+        let c_start = a.to_string().find('c').unwrap();
+        let a_start = a.to_string().find('a').unwrap();
+        a.annotate(c_start..c_start + 5, comment("orphan"));
+        a.annotate(a_start..a_start + 5, comment("resolved"));
+        a.annotate(3..8, comment("waiting"));
+
+        // the text of c is never sent to b
+        let mut b = RichText::new(2);
+        b.import(&a.export(&c.version())).unwrap();
+        assert_eq!(b.unresolved_annotations().len(), 2);
+        let orphans = b.orphaned_annotations();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].value, "orphan");
+        assert_eq!(b.prune_orphaned_annotations().unwrap(), vec![orphans[0].id]);
+        assert_eq!(b.unresolved_annotations().len(), 1);
+        assert!(b.orphaned_annotations().is_empty());
+        assert!(b.prune_orphaned_annotations().unwrap().is_empty());
+
+        // the peers drop them too, even if the text arrives later
+        let mut d = RichText::new(4);
+        d.import(&a.export(&c.version())).unwrap();
+        d.import(&b.export(&d.version())).unwrap();
+        assert!(d.orphaned_annotations().is_empty());
+        d.merge(&c);
+        b.merge(&c);
+        assert!(d.unresolved_annotations().is_empty());
+        assert_eq!(d.count_annotations("comment"), 2);
+        assert_eq!(d.get_spans(), b.get_spans());
+    }
+
+    #[test]
+    fn converge_in_both_orders() {
+        let mut a = RichText::new(1);
+        let mut c = RichText::new(3);
+        a.insert(0, "aaaaa");
+        c.insert(0, "ccccc");
+        a.merge(&c);
+        let c_start = a.to_string().find('c').unwrap();
+        a.annotate(c_start..c_start + 5, comment("orphan"));
+        a.annotate(3..8, comment("waiting"));
+        let mut b = RichText::new(2);
+        b.import(&a.export(&c.version())).unwrap();
+        assert_eq!(b.prune_orphaned_annotations().unwrap().len(), 1);
+
+        // the annotation is applied before the prune op arrives
+        let mut applied_first = RichText::new(4);
+        applied_first.merge(&a);
+        assert_eq!(applied_first.count_annotations("comment"), 2);
+        applied_first.merge(&b);
+
+        // the prune op arrives before the text of the annotation
+        let mut pruned_first = RichText::new(5);
+        pruned_first.merge(&b);
+        pruned_first.merge(&a);
+
+        a.merge(&b);
+        b.merge(&a);
+        for doc in [&a, &b, &applied_first, &pruned_first] {
+            doc.check();
+            assert_eq!(doc.count_annotations("comment"), 1);
+            assert_eq!(doc.get_spans(), a.get_spans());
+        }
+        assert!(a
+            .get_spans()
+            .iter()
+            .all(|span| span.attributes.values().all(|value| value != "orphan")));
+    }
+}
+
+mod defragment {
+    use super::*;
+
+    /// The deletions of the adjacent chars received in separate updates
+    /// leave a mergeable element for every deleted char
+    fn fragmented() -> RichText {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, &"0123456789".repeat(100));
+        b.merge(&a);
+        for _ in 0..300 {
+            a.delete(1..2);
+            b.merge(&a);
+        }
+
+        b
+    }
+
+    fn leaf_count(text: &RichText) -> usize {
+        let mut count = 0;
+        let mut leaf = Some(text.content.first_leaf());
+        while let Some(node) = leaf {
+            count += 1;
+            leaf = text.content.next_same_level_node(node);
+        }
+
+        count
+    }
+
+    #[test]
+    fn merge_the_fragmented_elements() {
+        let mut text = fragmented();
+        let spans = text.get_spans();
+        let elem_len = text.content.iter().count();
+        assert!(elem_len > 300);
+        assert!(text.defragment(usize::MAX));
+        assert!(text.content.iter().count() < elem_len);
+        assert_eq!(text.get_spans(), spans);
+        text.check();
+        text.check_no_mergeable_neighbor();
+    }
+
+    #[test]
+    fn visit_one_leaf_per_step() {
+        let mut text = fragmented();
+        let spans = text.get_spans();
+        let elem_len = text.content.iter().count();
+        let leaves = leaf_count(&text);
+        assert!(leaves > 1);
+
+        // the budget of a step is reached after the first leaf
+        let mut calls = 1;
+        while !text.defragment(1) {
+            assert_eq!(text.defragment.leaves, calls);
+            calls += 1;
+        }
+
+        assert_eq!(calls, leaves);
+        assert!(text.content.iter().count() < elem_len);
+        assert_eq!(text.get_spans(), spans);
+        text.check();
+        text.check_no_mergeable_neighbor();
+    }
+
+    #[test]
+    fn defragment_within_budget() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        for i in 0..200 {
+            a.insert(i % (a.len() + 1), "ab");
+            b.merge(&a);
+            b.insert(b.len() / 2, "c");
+            if i % 3 == 0 {
+                b.delete(0..1);
+            }
+            a.merge(&b);
+        }
+
+        let spans = a.get_spans();
+        let elem_len = a.content.iter().count();
+        let mut calls = 1;
+        while !a.defragment(10) {
+            calls += 1;
+            if calls == 5 {
+                // the edits between the calls don't break the progress
+                a.insert(3, "x");
+                a.delete(3..4);
+            }
+        }
+
+        assert!(calls > 1);
+        assert!(a.content.iter().count() <= elem_len);
+        assert_eq!(a.get_spans(), spans);
+        a.check();
+        assert!(a.defragment(usize::MAX));
+        a.check_no_mergeable_neighbor();
+
+        b.merge(&a);
+        a.insert(1, "y");
+        b.merge(&a);
+        assert_eq!(a.get_spans(), b.get_spans());
+    }
+}
+
+mod updates_for {
+    use super::*;
+
+    #[test]
+    fn updates_for_peers() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let mut peer = RichText::new(2);
+        peer.merge(&a);
+        a.insert(5, " world");
+        a.offload_history().unwrap();
+        a.insert(0, ">");
+        a.annotate(0..6, Style::new_bold_like("bold".into(), true.into()));
+
+        for mut peer in [peer, RichText::new(3)] {
+            let updates = a.updates_for(&peer.version()).unwrap().unwrap();
+            peer.import(&updates).unwrap();
+            assert_eq!(peer.get_spans(), a.get_spans());
+            assert_eq!(a.updates_for(&peer.version()).unwrap(), None);
+        }
+    }
+}
+
+mod custom_ops {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn sync_custom_ops() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let id = a.insert_custom_op("reaction", b"+1 0..5").unwrap();
+        assert_eq!(id, OpID::new(1, 5));
+        a.insert(5, "!");
+
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let mut b = RichText::new(2);
+        let r = received.clone();
+        b.register_op_kind(
+            "reaction",
+            Box::new(move |op| r.borrow_mut().push(op.payload.to_vec())),
+        );
+        b.import(&a.export(&Default::default())).unwrap();
+        assert_eq!(b.to_string(), "hello!");
+        assert_eq!(b.version(), a.version());
+        assert_eq!(*received.borrow(), vec![b"+1 0..5".to_vec()]);
+
+        // the peers without the handler still relay them
+        let mut c = RichText::new(3);
+        c.merge(&a);
+        c.insert_custom_op("reaction", b"-1").unwrap();
+        let mut d = RichText::new(4);
+        d.import(&c.export(&Default::default())).unwrap();
+        let ops = d.custom_ops("reaction").unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(&*ops[0].payload, b"+1 0..5");
+        assert_eq!(&*ops[1].payload, b"-1");
+        assert!(d.custom_ops("other").unwrap().is_empty());
+
+        // the old versions can't carry them
+        let v2 = a.export_as(&Default::default(), FormatVersion::V2).unwrap();
+        let mut e = RichText::new(5);
+        e.import(&v2).unwrap();
+        assert_eq!(e.version(), a.version());
+        assert!(e.custom_ops("reaction").unwrap().is_empty());
+    }
+}
+
+mod diff_spans {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    /// Check that the delta between the spans before and after the edit is
+    /// the same as the event of the edit
+    fn assert_same_as_event(text: &mut RichText, edit: impl FnOnce(&mut RichText)) {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        text.observe(Box::new(move |e| {
+            events_clone.borrow_mut().push(e.ops.clone())
+        }));
+        let old = text.get_spans();
+        edit(text);
+        let new = text.get_spans();
+        let events = events.borrow();
+        assert_eq!(events.len(), 1);
+        assert_eq!(diff_spans(&old, &new, IndexType::Utf8), events[0]);
+    }
+
+    #[test]
+    fn same_as_events() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        assert_same_as_event(&mut text, |t| t.insert(5, "!"));
+        assert_same_as_event(&mut text, |t| t.delete(0..2));
+        assert_same_as_event(&mut text, |t| {
+            t.annotate(5..8, Style::new_bold_like("italic".into(), true.into()))
+        });
+        assert_same_as_event(&mut text, |t| {
+            t.annotate(0..2, Style::new_erase_bold_like("bold".into()))
+        });
+        assert_same_as_event(&mut text, |t| {
+            t.insert_with_styles(2, "xy", &[Style::new_bold_like("link".into(), "a".into())])
+        });
+    }
+
+    #[test]
+    fn replace_in_utf16() {
+        let mut old = RichText::new(1);
+        old.insert(0, "a😀b");
+        let mut new = RichText::new(1);
+        new.insert(0, "a😁b");
+        new.annotate(0..1, Style::new_bold_like("bold".into(), true.into()));
+        let delta = diff_spans(&old.get_spans(), &new.get_spans(), IndexType::Utf16);
+        assert_eq!(
+            delta,
+            vec![
+                DeltaItem::retain_with_attributes(
+                    1,
+                    [("bold".to_string(), true.into())].into_iter().collect()
+                ),
+                DeltaItem::delete(2),
+                DeltaItem::insert_with_attributes(
+                    "😁".into(),
+                    IndexType::Utf16,
+                    Default::default()
+                ),
+            ]
+        );
+    }
+}
+
+mod outline {
+    use super::*;
+
+    fn doc() -> RichText {
+        let mut text = RichText::new(1);
+        text.insert(0, "intro\nTitle A\nbody a\nSub\nbody sub\nTitle B\nbody b");
+        for (index, level) in [(13, 1), (24, 2), (41, 1)] {
+            text.annotate(
+                index..index + 1,
+                Style::new_link_like(HEADER_KEY.into(), level.into()),
+            );
+        }
+        text
+    }
+
+    #[test]
+    fn outline() {
+        let text = doc();
+        let outline = text.outline();
+        let summary: Vec<_> = outline
+            .iter()
+            .map(|x| (x.level, x.title.as_str(), x.line, x.range.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, "Title A", 1, 6..34),
+                (2, "Sub", 3, 21..34),
+                (1, "Title B", 5, 34..48)
+            ]
+        );
+        assert_eq!(text.index_of_id(outline[1].id, IndexType::Utf8), Some(21));
+    }
+
+    #[test]
+    fn split_into_sections() {
+        let mut text = doc();
+        let sections = text.split_sections(1);
+        let texts: Vec<_> = sections.iter().map(|x| x.doc.to_string()).collect();
+        assert_eq!(
+            texts,
+            [
+                "intro\n",
+                "Title A\nbody a\nSub\nbody sub\n",
+                "Title B\nbody b"
+            ]
+        );
+        assert!(sections[0].heading.is_none());
+        assert_eq!(sections[2].heading.as_ref().unwrap().title, "Title B");
+        // the styles are kept
+        assert_eq!(sections[1].doc.outline().len(), 2);
+
+        let sections = text.split_sections(2);
+        assert_eq!(sections.len(), 4);
+        assert_eq!(sections[2].doc.to_string(), "Sub\nbody sub\n");
+
+        // the back-references survive the edits of the source
+        text.insert(0, "new ");
+        let first = text.index_of_id(sections[2].first_id, IndexType::Utf8);
+        let last = text.index_of_id(sections[2].last_id, IndexType::Utf8);
+        assert_eq!(first, Some(25));
+        assert_eq!(last, Some(37));
+    }
+}
+
+#[cfg(feature = "async")]
+mod cooperative {
+    use std::{
+        cell::RefCell,
+        future::Future,
+        rc::Rc,
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use super::*;
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Run the future to the end, and return its output and the number of
+    /// the polls
+    fn block_on<T>(future: impl Future<Output = T>) -> (T, usize) {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(ans) = future.as_mut().poll(&mut cx) {
+                return (ans, polls);
+            }
+        }
+    }
+
+    #[test]
+    fn import_in_steps() {
+        let mut a = RichText::new(1);
+        for i in 0..100 {
+            a.insert(0, &i.to_string());
+        }
+        a.annotate(0..10, Style::new_bold_like("bold".into(), true.into()));
+        a.delete(5..20);
+        let data = a.export(&Default::default());
+
+        let mut b = RichText::new(2);
+        let events = Rc::new(RefCell::new(0));
+        let events_clone = events.clone();
+        b.observe(Box::new(move |_| *events_clone.borrow_mut() += 1));
+        let (result, polls) = block_on(b.import_async(&data, &ImportOptions::default(), 10));
+        result.unwrap();
+        assert!(polls > 5);
+        assert_eq!(*events.borrow(), 1);
+        assert_eq!(b.get_spans(), a.get_spans());
+
+        let mut c = RichText::new(3);
+        let (result, polls) = block_on(c.merge_async(&a, usize::MAX));
+        result.unwrap();
+        assert_eq!(polls, 1);
+        assert_eq!(c.get_spans(), a.get_spans());
+    }
+
+    #[test]
+    fn dropped_import_is_finished() {
+        let mut a = RichText::new(1);
+        for i in 0..100 {
+            a.insert(0, &i.to_string());
+        }
+        a.delete(5..20);
+        let data = a.export(&Default::default());
+
+        let mut b = RichText::new(2);
+        {
+            let waker = Waker::from(Arc::new(NoopWaker));
+            let mut cx = Context::from_waker(&waker);
+            let mut future = Box::pin(b.import_async(&data, &ImportOptions::default(), 10));
+            for _ in 0..3 {
+                assert!(future.as_mut().poll(&mut cx).is_pending());
+            }
+        }
+
+        assert_eq!(b.version(), a.version());
+        assert_eq!(b.to_string(), a.to_string());
+    }
+
+    #[test]
+    fn export_in_steps() {
+        let mut a = RichText::new(1);
+        let mut b = RichText::new(2);
+        a.insert(0, "123");
+        b.insert(0, "abc");
+        a.merge(&b);
+
+        let (data, polls) = block_on(a.export_async(&Default::default()));
+        assert_eq!(polls, 3);
+        let mut c = RichText::new(3);
+        c.import(&data.unwrap()).unwrap();
+        assert_eq!(c.to_string(), a.to_string());
+    }
+}
+
+mod embeds {
+    use super::*;
+
+    fn width(span: &Span) -> Option<&Value> {
+        span.attributes.get(&InternalString::from("width"))
+    }
+
+    #[test]
+    fn annotate_embeds_as_a_whole() {
+        let image: Value = serde_json::from_str(r#"{"image": "a.png"}"#).unwrap();
+        let mut a = RichText::new(1);
+        a.insert(0, "ab");
+        a.insert_embed(1, image.clone()).unwrap();
+        assert_eq!(a.len(), 5);
+        assert_eq!(a.embed_at(1), Some(image));
+        assert_eq!(a.embed_at(0), None);
+        assert_eq!(a.embed_at(4), None);
+
+        // the local inserts next to the embed don't take its styles
+        a.annotate_element(1, Style::new_bold_like("width".into(), 100.into()));
+        a.insert(4, "x");
+        a.insert(1, "y");
+        let spans = a.get_spans();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[1].insert, EMBED_CHAR.to_string());
+        assert_eq!(spans[1].attributes.len(), 2);
+
+        // nor the concurrent ones
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        a.annotate_element(2, Style::new_bold_like("width".into(), 200.into()));
+        b.insert(5, "z");
+        b.insert(2, "w");
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.get_spans(), b.get_spans());
+        assert_eq!(a.to_string(), "ayw\u{FFFC}zxb");
+        let spans = a.get_spans();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(width(&spans[1]), Some(&200.into()));
+
+        a.annotate_element(3, Style::new_erase_bold_like("width".into()));
+        b.insert(3, "v");
+        b.insert(7, "u");
+        a.merge(&b);
+        let spans = a.get_spans();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(width(&spans[1]), None);
+        assert!(spans[1]
+            .attributes
+            .contains_key(&InternalString::from(EMBED_TYPE)));
+    }
+
+    #[test]
+    fn rejected_embed_inserts_nothing() {
+        use crate::rich_text::{LimitPolicy, Limits};
+
+        let image: Value = serde_json::from_str(r#"{"image": "a.png"}"#).unwrap();
+        let mut a = RichText::new(1);
+        a.insert(0, "ab");
+        a.set_limits(Limits {
+            max_len: Some(4),
+            max_annotations: None,
+            policy: LimitPolicy::Clamp,
+        });
+        assert!(a.insert_embed(1, image.clone()).is_err());
+        assert_eq!(a.to_string(), "ab");
+        assert!(a.get_spans()[0].attributes.is_empty());
+
+        a.set_limits(Limits {
+            max_len: None,
+            max_annotations: Some(0),
+            policy: LimitPolicy::Reject,
+        });
+        assert!(a.insert_embed(1, image).is_err());
+        assert_eq!(a.to_string(), "ab");
+    }
+}
+
+mod text_transform {
+    use super::*;
+
+    fn smart_quotes(prev: Option<char>, text: &str) -> Option<String> {
+        if !text.contains('"') {
+            return None;
+        }
+
+        let mut prev = prev;
+        let mut ans = String::new();
+        for c in text.chars() {
+            match c {
+                '"' if prev.map_or(true, char::is_whitespace) => ans.push('“'),
+                '"' => ans.push('”'),
+                c => ans.push(c),
+            }
+            prev = Some(c);
+        }
+
+        Some(ans)
+    }
+
+    #[test]
+    fn transform_local_inserts() {
+        let mut a = RichText::new(1);
+        a.register_text_transform("quotes", Box::new(smart_quotes));
+        a.register_text_transform(
+            "emoji",
+            Box::new(|_, text| {
+                text.contains(":smile:")
+                    .then(|| text.replace(":smile:", "😄"))
+            }),
+        );
+        a.insert(0, "say \"hi\" :smile:");
+        assert_eq!(a.to_string(), "say “hi” 😄");
+        a.insert(4, "\"");
+        assert_eq!(a.to_string(), "say ““hi” 😄");
+        a.append("\"");
+        assert_eq!(a.to_string(), "say ““hi” 😄”");
+
+        // the peers get the transformed text
+        let mut b = RichText::new(2);
+        b.import(&a.export(&Default::default())).unwrap();
+        assert_eq!(b.to_string(), a.to_string());
+        b.insert(0, "\"");
+        a.merge(&b);
+        assert_eq!(a.to_string(), "\"say ““hi” 😄”");
+
+        a.unregister_text_transform("quotes");
+        a.insert(0, "\"");
+        assert!(a.to_string().starts_with("\"\""));
+    }
+
+    #[test]
+    fn limits_check_the_transformed_text() {
+        use crate::rich_text::{LimitPolicy, Limits};
+
+        let mut a = RichText::new(1);
+        a.register_text_transform(
+            "emoji",
+            Box::new(|_, text| {
+                text.contains(":smile:")
+                    .then(|| text.replace(":smile:", "😄"))
+            }),
+        );
+        a.set_limits(Limits {
+            max_len: Some(6),
+            max_annotations: None,
+            policy: LimitPolicy::Reject,
+        });
+        // the shortcode is too long but the emoji fits
+        a.try_insert(0, ":smile:").unwrap();
+        assert_eq!(a.to_string(), "😄");
+        a.set_limits(Limits::default());
+
+        a.apply_delta(
+            vec![
+                DeltaItem::retain(4),
+                DeltaItem::insert(" :smile:".into(), IndexType::Utf8),
+            ]
+            .into_iter(),
+            IndexType::Utf8,
+        );
+        assert_eq!(a.to_string(), "😄 😄");
+    }
+}
+
+mod client_activity {
+    use super::*;
+
+    #[test]
+    fn summarize_clients() {
+        let mut a = RichText::new(1);
+        a.insert(0, "héllo");
+        a.annotate(0..3, Style::new_bold_like("bold".into(), true.into()));
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        b.insert(6, " wörld");
+        b.delete(0..3);
+        a.delete(1..3);
+        a.merge(&b);
+        a.offload_history().unwrap();
+
+        let activity = a.client_activity().unwrap();
+        assert_eq!(activity.len(), 2);
+        assert_eq!(activity[0].client, 1);
+        assert_eq!(activity[0].chars_inserted, 5);
+        assert_eq!(activity[0].chars_deleted, 1);
+        assert_eq!(activity[0].annotations, 1);
+        assert_eq!(activity[1].client, 2);
+        assert_eq!(activity[1].chars_inserted, 6);
+        // "é" was deleted by both clients
+        assert_eq!(activity[1].chars_deleted, 2);
+        assert_eq!(activity[1].annotations, 0);
+        assert_eq!(activity[1].ops, 2);
+    }
+}
+
+mod format_compat {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// The document in the fixtures. Don't change it, the fixtures of the old
+    /// versions are recorded from it.
+    fn fixture_doc() -> RichText {
+        let mut a = RichText::new(1);
+        a.insert(0, "Hello world\nThe second line");
+        a.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        a.annotate(
+            6..11,
+            Style::new_link_like("link".into(), "https://a.com".into()),
+        );
+        let mut b = RichText::new(2);
+        b.merge(&a);
+        b.insert(11, "!");
+        b.delete(13..17);
+        b.annotate(
+            0..12,
+            Style::new_comment_like("comment".into(), "hi".into()),
+        );
+        a.merge(&b);
+        a
+    }
+
+    fn fixture_path(version: FormatVersion) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/formats")
+            .join(format!("{:?}.bin", version).to_lowercase())
+    }
+
+    /// The fixture of each version is recorded by the release that
+    /// introduces it and checked in, see `record_missing_fixtures`.
+    fn load_fixture(version: FormatVersion) -> Vec<u8> {
+        let path = fixture_path(version);
+        std::fs::read(&path).unwrap_or_else(|err| {
+            panic!(
+                "missing the fixture of {:?} at {}: {}",
+                version,
+                path.display(),
+                err
+            )
+        })
+    }
+
+    /// Record the fixtures of the new versions. Run it explicitly with
+    /// `cargo test record_missing_fixtures -- --ignored` by the release that
+    /// adds a version, and check the new fixture in.
+    #[test]
+    #[ignore]
+    fn record_missing_fixtures() {
+        for version in FormatVersion::ALL {
+            let path = fixture_path(version);
+            if path.exists() {
+                continue;
+            }
+
+            let data = fixture_doc()
+                .export_as(&Default::default(), version)
+                .unwrap();
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, data).unwrap();
+        }
+    }
+
+    #[test]
+    fn decode_fixtures_of_all_versions() {
+        let expected = fixture_doc();
+        assert_eq!(expected.to_string(), "Hello world!\nsecond line");
+        for version in FormatVersion::ALL {
+            let data = load_fixture(version);
+            assert_eq!(FormatVersion::of(&data).unwrap(), version);
+            let mut doc = RichText::new(3);
+            doc.import(&data).unwrap();
+            assert_eq!(doc.to_string(), expected.to_string());
+            assert_eq!(doc.get_spans(), expected.get_spans());
+            assert_eq!(doc.version(), expected.version());
+        }
+    }
+
+    #[test]
+    fn export_as_old_versions() {
+        let mut doc = fixture_doc();
+        doc.set_doc_id(Some(7));
+        let data = doc.export(&Default::default());
+        assert_eq!(FormatVersion::of(&data).unwrap(), FormatVersion::CURRENT);
+        for version in FormatVersion::ALL {
+            let data = doc.export_as(&Default::default(), version).unwrap();
+            assert_eq!(FormatVersion::of(&data).unwrap(), version);
+            let mut b = RichText::new(3);
+            b.set_doc_id(Some(7));
+            b.import(&data).unwrap();
+            assert_eq!(b.get_spans(), doc.get_spans());
+        }
+    }
+}
+
+#[cfg(feature = "merge-log")]
+mod merge_log {
+    use super::*;
+    use crate::merge_log::MergeRecord;
+
+    #[test]
+    fn record_annotation_conflicts() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let mut b = a.fork(2).unwrap();
+        a.annotate(0..5, Style::new_bold_like("bold".into(), "a".into()));
+        b.insert(11, "!");
+        b.annotate(2..8, Style::new_bold_like("bold".into(), "b".into()));
+        b.merge(&a);
+        let log = b.take_merge_log();
+        assert_eq!(log.len(), 1);
+        let MergeRecord::AnnotationConflict {
+            discarded,
+            winner,
+            overlap,
+            ..
+        } = &log[0]
+        else {
+            panic!("{:?}", log);
+        };
+        assert_eq!(discarded.value, "a");
+        assert_eq!(winner.value, "b");
+        assert_eq!(*overlap, (2, 5));
+        let json = serde_json::to_value(&log).unwrap();
+        assert_eq!(json[0]["kind"], "annotation_conflict");
+        assert!(b.take_merge_log().is_empty());
+    }
+}
+
+mod word_count {
+    use super::*;
+
+    fn naive(text: &RichText) -> usize {
+        text.to_string().split_whitespace().count()
+    }
+
+    #[test]
+    fn incremental() {
+        let mut a = RichText::new(1);
+        assert_eq!(a.word_count(), 0);
+        a.insert(0, "hello world");
+        assert_eq!(a.word_count(), 2);
+        a.insert(5, "big");
+        // "hellobig world"
+        assert_eq!(a.word_count(), 2);
+        a.insert(5, " ");
+        assert_eq!(a.word_count(), 3);
+        a.delete(5..6);
+        a.delete(8..9);
+        // "hellobigworld"
+        assert_eq!(a.word_count(), 1);
+        let mut b = a.fork(2).unwrap();
+        b.insert(0, "你好 ");
+        a.insert(13, "\n new line");
+        a.merge(&b);
+        assert_eq!(a.word_count(), naive(&a));
+        assert_eq!(a.word_count(), 4);
+        assert_eq!(a.word_count_in(7..20, IndexType::Utf8), 1);
+        assert_eq!(a.word_count_in(.., IndexType::Utf8), 4);
+        for i in 0..200 {
+            let len = a.len();
+            if i % 3 == 0 {
+                a.delete(len / 3..len / 3 + 2);
+            } else {
+                a.insert(len / 2, if i % 2 == 0 { " ab" } else { "c\t" });
+            }
+            assert_eq!(a.word_count(), naive(&a), "{}", i);
+        }
+    }
+
+    #[test]
+    fn reading_time() {
+        let mut a = RichText::new(1);
+        a.insert(0, &"word ".repeat(400));
+        assert_eq!(a.reading_time(200), std::time::Duration::from_secs(120));
+    }
+}
+
+mod annotation_namespaces {
+    use super::*;
+
+    #[test]
+    fn query_by_namespace() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        let todo = Style::new_comment_like("comment/todo".into(), "a".into());
+        let done = Style::new_comment_like("comment/todo/done".into(), "b".into());
+        text.annotate(0..5, todo);
+        text.annotate(6..11, done);
+        text.annotate(0..11, bold());
+        assert_eq!(text.count_annotations("comment/*"), 2);
+        assert_eq!(text.count_annotations("comment/todo/*"), 1);
+        assert_eq!(text.count_annotations("comment"), 0);
+        let page = text.annotations_page(
+            "comment/*",
+            0,
+            10,
+            AnnotationOrder::Position,
+            IndexType::Utf8,
+        );
+        let ranges: Vec<_> = page.iter().map(|x| x.range.clone()).collect();
+        assert_eq!(ranges, vec![0..5, 6..11]);
+        assert!(text.is_in_annotation(7, "comment/*").is_some());
+        assert!(text.is_in_annotation(5, "comment/*").is_none());
+    }
+}
+
+mod import_preview {
+    use super::*;
+
+    #[test]
+    fn report_without_applying() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let mut b = a.fork(2).unwrap();
+        b.insert(5, " world");
+        b.delete(0..1);
+        b.annotate(0..4, bold());
+        let update = b.export(&a.version());
+        let report = a.import_preview(&update).unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                new_ops: 8,
+                text_growth: 5,
+                annotation_changes: 1,
+            }
+        );
+        assert_eq!(a.to_string(), "hello");
+
+        a.import(&update).unwrap();
+        assert_eq!(a.to_string(), "ello world");
+        assert_eq!(a.import_preview(&update).unwrap(), ImportReport::default());
+    }
+
+    #[test]
+    fn report_matches_the_import() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let mut b = a.fork(2).unwrap();
+        let mut c = a.fork(3).unwrap();
+        b.insert(5, "abc");
+        b.delete(4..7);
+        b.delete(0..2);
+        c.delete(1..3);
+        c.insert(0, "xy");
+        b.merge(&c);
+        let update = b.export(&a.version());
+        let report = a.import_preview(&update).unwrap();
+        let len = a.len();
+        let vv = a.version();
+        a.import(&update).unwrap();
+        assert_eq!(report.text_growth, a.len() as isize - len as isize);
+        let new_ops: usize = a
+            .version()
+            .vv
+            .iter()
+            .map(|(client, end)| (end - vv.vv.get(client).copied().unwrap_or(0)) as usize)
+            .sum();
+        assert_eq!(report.new_ops, new_ops);
+
+        // the ops after a gap are pending
+        let mut d = RichText::new(4);
+        d.insert(0, "12");
+        let vv = d.version();
+        d.insert(2, "34");
+        let report = a.import_preview(&d.export(&vv)).unwrap();
+        assert_eq!(report, ImportReport::default());
+    }
+}
+
+mod compress_tombstones {
+    use super::*;
+
+    #[test]
+    fn decompress_on_demand() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello 世界 world");
+        let v1 = a.version();
+        let mut b = a.fork(2).unwrap();
+        b.insert(3, "!");
+        a.delete(0..9);
+        assert!(matches!(a.compress_tombstones(), Err(Error::NotOffloaded)));
+        a.offload_history().unwrap();
+        let diff = a.diff_overlay(&v1, &a.version()).unwrap();
+        assert_eq!(a.compress_tombstones().unwrap(), 9);
+        assert_eq!(a.compress_tombstones().unwrap(), 0);
+        assert_eq!(a.to_string(), "界 world");
+        assert_eq!(a.diff_overlay(&v1, &a.version()).unwrap(), diff);
+
+        // the remote ops can still refer to the compressed text
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.to_string(), "!界 world");
+        assert_eq!(a.get_spans(), b.get_spans());
+        a.insert(0, "x");
+        assert_eq!(a.to_string(), "x!界 world");
+    }
+}
+
+mod truncate_to {
+    use super::*;
+
+    #[test]
+    fn concurrent_truncation() {
+        let mut a = RichText::new(1);
+        a.insert(0, "0123456789");
+        let mut b = a.fork(2).unwrap();
+        assert_eq!(a.truncate_to(8), 2);
+        assert_eq!(b.truncate_to(4), 6);
+        a.insert(8, "ab");
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.to_string(), "0123ab");
+        assert_eq!(a.get_spans(), b.get_spans());
+        assert_eq!(a.truncate_to(4), 2);
+        assert_eq!(a.truncate_to(4), 0);
+    }
+
+    #[test]
+    fn char_boundary() {
+        let mut text = RichText::new(1);
+        text.insert(0, "ab好c");
+        assert_eq!(text.truncate_to(3), 4);
+        assert_eq!(text.to_string(), "ab");
+    }
+}
+
+mod replay_guard {
+    use super::*;
+    use crate::rich_text::admission::ReplayGuard;
+
+    #[test]
+    fn reject_replayed_updates() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let first = a.export(&Default::default());
+        let vv = a.version();
+        a.insert(5, " world");
+        let second = a.export(&vv);
+
+        let mut guard = ReplayGuard::default();
+        guard.check(&first).unwrap();
+        assert!(matches!(
+            guard.check(&first),
+            Err(Error::Replayed {
+                client: 1,
+                counter: 0,
+                expected: 5
+            })
+        ));
+        guard.check(&second).unwrap();
+        assert_eq!(guard.version(), &a.version());
+        // overlaps the accepted ops
+        assert!(guard.check(&a.export(&Default::default())).is_err());
+
+        let mut guard = ReplayGuard::from_version(vv);
+        assert!(guard.check(&first).is_err());
+        guard.check(&second).unwrap();
+    }
+
+    #[test]
+    fn accept_gap_fills_and_overlaps() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello");
+        let first = a.export(&Default::default());
+        let vv = a.version();
+        a.insert(5, " world");
+        let second = a.export(&vv);
+        let all = a.export(&Default::default());
+
+        // the second update arrives before the first one
+        let mut guard = ReplayGuard::default();
+        guard.check(&second).unwrap();
+        assert!(guard.version().vv.is_empty());
+        guard.check(&first).unwrap();
+        assert_eq!(guard.version(), &vv);
+        let mut b = RichText::new(2);
+        b.import(&second).unwrap();
+        b.import(&first).unwrap();
+        guard.set_version(b.version());
+        assert!(guard.check(&second).is_err());
+
+        // only the uncovered part of a relayed update is kept
+        let mut guard = ReplayGuard::from_version(vv.clone());
+        let meta = guard.check(&all).unwrap();
+        assert_eq!(meta.start, vv);
+        assert_eq!(meta.end, a.version());
+        assert_eq!(guard.version(), &a.version());
+    }
+}
+
+mod marks {
+    use super::*;
+
+    #[test]
+    fn behavior_and_expand() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(0..5, bold());
+        text.annotate(3..8, link());
+        text.annotate(0..2, unbold());
+        let spans = text.get_spans_with_marks();
+        let summary: Vec<(&str, Vec<(&str, Expand)>)> = spans
+            .iter()
+            .map(|x| {
+                (
+                    x.insert.as_str(),
+                    x.marks.iter().map(|m| (&*m.type_, m.expand)).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("he", vec![]),
+                ("l", vec![("bold", Expand::After)]),
+                ("lo", vec![("bold", Expand::After), ("link", Expand::None)]),
+                (" wo", vec![("link", Expand::None)]),
+                ("rld", vec![]),
+            ]
+        );
+        assert!(spans
+            .iter()
+            .flat_map(|x| x.marks.iter())
+            .all(|x| x.behavior == crate::Behavior::Merge));
+    }
+}
+
+mod selection_styles {
+    use super::*;
+    use crate::rich_text::{StyleAggregate, StyleCoverage};
+
+    #[test]
+    fn aggregate() {
+        let mut text = RichText::new(1);
+        text.insert(0, "hello world");
+        text.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        text.annotate(0..2, Style::new_bold_like("color".into(), "red".into()));
+        text.annotate(2..8, Style::new_bold_like("color".into(), "blue".into()));
+        let styles = text.selection_styles(0..8, IndexType::Utf8);
+        assert_eq!(styles.len(), 2);
+        assert_eq!(
+            styles[&InternalString::from("bold")],
+            StyleAggregate {
+                coverage: StyleCoverage::Partial,
+                value: true.into(),
+                len: 5,
+            }
+        );
+        assert_eq!(
+            styles[&InternalString::from("color")],
+            StyleAggregate {
+                coverage: StyleCoverage::All,
+                value: "blue".into(),
+                len: 8,
+            }
+        );
+
+        let styles = text.selection_styles(5..5, IndexType::Utf8);
+        assert_eq!(
+            styles[&InternalString::from("bold")].coverage,
+            StyleCoverage::All
+        );
+        assert!(text.selection_styles(9..11, IndexType::Utf8).is_empty());
+    }
+}
+
+mod admission {
+    use super::*;
+    use crate::rich_text::admission::{
+        Exceeded, RateLimiter, UpdateLimits, UpdateMetric, UpdatePolicy, UpdateScore, Verdict,
+    };
+
+    #[test]
+    fn score_and_check() {
+        let mut a = RichText::new(1);
+        a.insert(0, "hello world");
+        let mut b = a.fork(2).unwrap();
+        b.annotate(0..5, Style::new_bold_like("bold".into(), true.into()));
+        b.delete(5..6);
+        a.merge(&b);
+        let data = a.export(&Default::default());
+        let score = UpdateScore::decode(&data).unwrap();
+        assert_eq!(
+            score,
+            UpdateScore {
+                bytes: data.len(),
+                ops: 3,
+                inserted: 11,
+                deleted: 1,
+                annotations: 1,
+                clients: 2,
+            }
+        );
+
+        let policy = UpdatePolicy {
+            throttle: UpdateLimits {
+                max_clients: Some(1),
+                ..Default::default()
+            },
+            reject: UpdateLimits {
+                max_annotations: Some(1),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            policy.check(&score),
+            Verdict::Throttle(vec![Exceeded {
+                metric: UpdateMetric::Clients,
+                value: 2,
+                limit: 1,
+            }])
+        );
+        let score = UpdateScore {
+            annotations: 2,
+            ..score
+        };
+        assert!(matches!(policy.check(&score), Verdict::Reject(_)));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let score = UpdateScore {
+            ops: 10,
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::new(20, 10);
+        assert!(limiter.try_acquire(&score, 0));
+        assert!(limiter.try_acquire(&score, 0));
+        assert!(!limiter.try_acquire(&score, 500));
+        assert!(limiter.try_acquire(&score, 1000));
+    }
 }
 
 mod failed_fuzzing_tests {
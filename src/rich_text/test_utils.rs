@@ -7,6 +7,10 @@ use arbitrary::Arbitrary;
 
 mod fuzz_line_breaks;
 pub use fuzz_line_breaks::{fuzzing_line_break, Action as LineBreakFuzzAction};
+mod seeded;
+pub use seeded::{
+    actions_from_seed, fuzzing_seeds, fuzzing_with_seed, reproduce, seed_from_env, SEED_ENV,
+};
 
 pub struct Actor {
     pub text: RichText,
@@ -0,0 +1,73 @@
+//! Run the fuzzing with the actions generated from a seed.
+//!
+//! Everything is derived from the seed, so a failure can be filed and
+//! reproduced with the seed number alone:
+//!
+//! ```bash
+//! CRDT_RICHTEXT_SEED=42 cargo test --features test seeded
+//! ```
+
+use std::{
+    ops::Range,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+};
+
+use arbitrary::{Arbitrary, Unstructured};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use super::{fuzzing, Action};
+
+/// The environment variable that overrides the seeds of [`fuzzing_seeds`]
+pub const SEED_ENV: &str = "CRDT_RICHTEXT_SEED";
+
+/// The bytes consumed by an action at most
+const BYTES_PER_ACTION: usize = 8;
+
+pub fn seed_from_env() -> Option<u64> {
+    std::env::var(SEED_ENV).ok()?.trim().parse().ok()
+}
+
+/// Generate `len` actions from the seed. The same seed always generates the
+/// same actions.
+pub fn actions_from_seed(seed: u64, len: usize) -> Vec<Action> {
+    let mut bytes = vec![0; len * BYTES_PER_ACTION];
+    StdRng::seed_from_u64(seed).fill_bytes(&mut bytes);
+    let mut u = Unstructured::new(&bytes);
+    (0..len)
+        .map_while(|_| Action::arbitrary(&mut u).ok())
+        .collect()
+}
+
+/// Run [`fuzzing`] with the actions generated from the seed. The seed is
+/// printed if it fails.
+pub fn fuzzing_with_seed(actor_num: usize, seed: u64, len: usize) {
+    let actions = actions_from_seed(seed, len);
+    if let Err(e) = catch_unwind(AssertUnwindSafe(|| fuzzing(actor_num, actions))) {
+        eprintln!(
+            "fuzzing failed with seed {seed} (actors = {actor_num}, len = {len}). \
+             Reproduce it with {SEED_ENV}={seed}, or reproduce({actor_num}, {seed}, {len})"
+        );
+        resume_unwind(e);
+    }
+}
+
+/// Run [`fuzzing_with_seed`] on every seed in `seeds`, or only on the seed in
+/// [`SEED_ENV`] if it's set.
+pub fn fuzzing_seeds(actor_num: usize, seeds: Range<u64>, len: usize) {
+    match seed_from_env() {
+        Some(seed) => fuzzing_with_seed(actor_num, seed, len),
+        None => {
+            for seed in seeds {
+                fuzzing_with_seed(actor_num, seed, len);
+            }
+        }
+    }
+}
+
+/// Print the actions generated from the seed and run them, so the failing
+/// case can be pasted into a regression test or minimized.
+pub fn reproduce(actor_num: usize, seed: u64, len: usize) {
+    let actions = actions_from_seed(seed, len);
+    println!("{actions:#?}");
+    fuzzing(actor_num, actions);
+}
@@ -0,0 +1,107 @@
+//! Group the local edits so that they either all apply or none of them do.
+
+use std::ops::RangeBounds;
+
+use crate::{Counter, Lamport, Style};
+
+use super::{Error, IndexType, LimitKind, RichText};
+
+/// The options of [`RichText::transact`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransactOptions {
+    /// The max number of the chars inserted by the transaction, counted after
+    /// the text transforms
+    pub max_inserted: Option<usize>,
+}
+
+/// The edits of a transaction, see [`RichText::transact`].
+pub struct Transaction<'a> {
+    pub(super) doc: &'a mut RichText,
+    pub(super) options: TransactOptions,
+    pub(super) inserted: usize,
+}
+
+impl Transaction<'_> {
+    /// The document with the edits of the transaction so far
+    pub fn doc(&self) -> &RichText {
+        self.doc
+    }
+
+    /// The number of the chars inserted by the transaction so far
+    pub fn inserted(&self) -> usize {
+        self.inserted
+    }
+
+    pub fn insert(&mut self, index: usize, string: &str) -> Result<(), Error> {
+        assert!(index <= self.doc.len());
+        self.insert_inner(index, string, IndexType::Utf8)
+    }
+
+    pub fn insert_utf16(&mut self, index: usize, string: &str) -> Result<(), Error> {
+        assert!(index <= self.doc.utf16_len());
+        self.insert_inner(index, string, IndexType::Utf16)
+    }
+
+    pub fn delete(&mut self, range: impl RangeBounds<usize>) -> Result<(), Error> {
+        self.doc.try_delete(range)
+    }
+
+    pub fn delete_utf16(&mut self, range: impl RangeBounds<usize>) -> Result<(), Error> {
+        self.doc.try_delete_utf16(range)
+    }
+
+    pub fn annotate(&mut self, range: impl RangeBounds<usize>, style: Style) -> Result<(), Error> {
+        self.doc.try_annotate(range, style)
+    }
+
+    pub fn annotate_utf16(
+        &mut self,
+        range: impl RangeBounds<usize>,
+        style: Style,
+    ) -> Result<(), Error> {
+        self.doc.try_annotate_utf16(range, style)
+    }
+
+    fn insert_inner(
+        &mut self,
+        index: usize,
+        string: &str,
+        index_type: IndexType,
+    ) -> Result<(), Error> {
+        let string = self.doc.transform_insert(index, string, index_type);
+        let chars = string.chars().count();
+        self.check_inserted(chars)?;
+        self.doc
+            .try_insert_transformed(index, &string, index_type)?;
+        self.inserted += chars;
+        Ok(())
+    }
+
+    fn check_inserted(&self, len: usize) -> Result<(), Error> {
+        match self.options.max_inserted {
+            Some(limit) if self.inserted + len > limit => Err(Error::LimitExceeded {
+                kind: LimitKind::TransactionInserted,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Roll back the edits of a transaction unless it commits, including when
+/// the edits panic.
+pub(super) struct RollbackGuard<'a> {
+    pub(super) doc: &'a mut RichText,
+    pub(super) end: Counter,
+    pub(super) next_lamport: Lamport,
+    pub(super) committed: bool,
+}
+
+impl Drop for RollbackGuard<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.doc.staged_events = None;
+            self.doc.rollback_local(self.end, self.next_lamport);
+        }
+    }
+}
@@ -2,9 +2,11 @@ use fxhash::FxHashMap;
 use serde::{Deserialize, Serialize};
 use serde_columnar::to_vec;
 
-use crate::{ClientID, Counter};
+use crate::{ClientID, Counter, OpID};
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+use super::Error;
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VersionVector {
     pub vv: FxHashMap<ClientID, Counter>,
 }
@@ -28,12 +30,28 @@ impl VersionVector {
         to_vec(&v).unwrap()
     }
 
-    pub fn decode(data: &[u8]) -> VersionVector {
-        let v: Vec<Item> = serde_columnar::from_bytes(data).unwrap();
+    /// Whether the op is included in the version
+    pub fn includes(&self, id: OpID) -> bool {
+        self.vv
+            .get(&id.client)
+            .map_or(false, |&end| id.counter < end)
+    }
+
+    /// Return an error if an op refers to the op `id` that is not included
+    pub(crate) fn check_dep(&self, id: Option<OpID>) -> Result<(), Error> {
+        match id {
+            Some(id) if !self.includes(id) => Err(Error::InvalidOp(format!("unknown op {:?}", id))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<VersionVector, Error> {
+        let v: Vec<Item> =
+            serde_columnar::from_bytes(data).map_err(|e| Error::DecodeError(e.to_string()))?;
         let mut vv = VersionVector::default();
         for item in v {
             vv.vv.insert(item.client, item.counter);
         }
-        vv
+        Ok(vv)
     }
 }
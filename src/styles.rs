@@ -0,0 +1,272 @@
+//! Preconfigured styles of the common editors.
+//!
+//! The expand and the behavior of a style decide how it grows when the text
+//! is inserted at its edges and how it merges with the overlapped styles of
+//! the same type. [`quill`] and [`prosemirror_basic`] return the settings that
+//! match the editors, so the apps don't need to rebuild them from
+//! [`Expand::infer_insert_expand`].
+//!
+//! The types can be grouped into namespaces by `/`, e.g. `suggestion/insert`
+//! and `suggestion/delete`. A namespace can have a default [`StyleSpec`] for
+//! all the types under it, and the queries of [`RichText`](crate::RichText)
+//! match them all by `suggestion/*`, see [`type_matches`].
+
+use fxhash::FxHashMap;
+use serde_json::Value;
+
+use crate::{Behavior, Expand, InternalString, Style};
+
+/// How the styles of a type are created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StyleSpec {
+    pub expand: Expand,
+    pub behavior: Behavior,
+}
+
+impl StyleSpec {
+    /// Grows when the text is inserted after it, like bold
+    pub const fn bold_like() -> Self {
+        Self {
+            expand: Expand::After,
+            behavior: Behavior::Merge,
+        }
+    }
+
+    /// Never grows, like link
+    pub const fn link_like() -> Self {
+        Self {
+            expand: Expand::None,
+            behavior: Behavior::Merge,
+        }
+    }
+
+    /// Never grows, and the overlapped ones are kept, like comment
+    pub const fn comment_like() -> Self {
+        Self {
+            expand: Expand::None,
+            behavior: Behavior::AllowMultiple,
+        }
+    }
+}
+
+/// Whether the annotation type matches the pattern of a query.
+///
+/// A pattern ending with `/*` matches all the types under the namespace at
+/// any depth, e.g. `comment/*` matches `comment/todo` and `comment/todo/done`
+/// but not `comment` itself. Other patterns only match the same type.
+pub fn type_matches(pattern: &str, type_: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(namespace) => type_
+            .strip_prefix(namespace)
+            .map_or(false, |x| x.starts_with('/')),
+        None => pattern == type_,
+    }
+}
+
+/// The [`StyleSpec`]s by the types of the styles
+#[derive(Debug, Clone, Default)]
+pub struct StyleRegistry {
+    specs: FxHashMap<InternalString, StyleSpec>,
+    namespaces: FxHashMap<InternalString, StyleSpec>,
+}
+
+impl StyleRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, type_: &str, spec: StyleSpec) -> &mut Self {
+        self.specs.insert(type_.into(), spec);
+        self
+    }
+
+    /// Register the default spec of the types under `namespace`, e.g.
+    /// `suggestion` for `suggestion/insert`. The registered types and the
+    /// nested namespaces override it.
+    pub fn register_namespace(&mut self, namespace: &str, spec: StyleSpec) -> &mut Self {
+        self.namespaces.insert(namespace.into(), spec);
+        self
+    }
+
+    /// The spec of the type, or the default of its closest namespace
+    pub fn get(&self, type_: &str) -> Option<StyleSpec> {
+        if let Some(spec) = self.specs.get(&InternalString::from(type_)) {
+            return Some(*spec);
+        }
+
+        let mut namespace = type_;
+        while let Some((parent, _)) = namespace.rsplit_once('/') {
+            if let Some(spec) = self.namespaces.get(&InternalString::from(parent)) {
+                return Some(*spec);
+            }
+
+            namespace = parent;
+        }
+
+        None
+    }
+
+    pub fn contains(&self, type_: &str) -> bool {
+        self.get(type_).is_some()
+    }
+
+    /// The registered types, excluding the namespaces
+    pub fn types(&self) -> impl Iterator<Item = &str> {
+        self.specs.keys().map(|x| &**x)
+    }
+
+    /// The style to apply `type_: value`.
+    ///
+    /// The unregistered types fall back to [`Expand::infer_insert_expand`]
+    /// and [`Behavior::Merge`].
+    pub fn style(&self, type_: &str, value: Value) -> Style {
+        let spec = self.spec_or_inferred(type_);
+        Style {
+            expand: spec.expand,
+            behavior: spec.behavior,
+            type_: type_.into(),
+            value,
+        }
+    }
+
+    /// The style to remove `type_`. Its expand is the toggled one, so the
+    /// removed range doesn't grow differently from the applied range.
+    pub fn erase(&self, type_: &str) -> Style {
+        let spec = self.spec_or_inferred(type_);
+        Style {
+            expand: spec.expand.toggle(),
+            behavior: Behavior::Delete,
+            type_: type_.into(),
+            value: Value::Null,
+        }
+    }
+
+    fn spec_or_inferred(&self, type_: &str) -> StyleSpec {
+        self.get(type_).unwrap_or(StyleSpec {
+            expand: Expand::infer_insert_expand(type_),
+            behavior: Behavior::Merge,
+        })
+    }
+}
+
+/// The formats of [Quill](https://quilljs.com/docs/formats)
+pub fn quill() -> StyleRegistry {
+    let mut ans = StyleRegistry::new();
+    for type_ in [
+        "bold",
+        "italic",
+        "underline",
+        "strike",
+        "color",
+        "background",
+        "font",
+        "size",
+    ] {
+        ans.register(type_, StyleSpec::bold_like());
+    }
+
+    for type_ in ["link", "code", "script", "formula", "image", "video"] {
+        ans.register(type_, StyleSpec::link_like());
+    }
+
+    // the block formats are on the line breaks, the text inserted before a
+    // line break shouldn't take them
+    for type_ in [
+        "header",
+        "code-block",
+        "blockquote",
+        "list",
+        "indent",
+        "align",
+        "direction",
+    ] {
+        ans.register(type_, StyleSpec::link_like());
+    }
+
+    ans.register("comment", StyleSpec::comment_like());
+    ans
+}
+
+/// The marks and the textblocks of the basic schema of
+/// [ProseMirror](https://github.com/ProseMirror/prosemirror-schema-basic).
+///
+/// The marks are inclusive unless they set `inclusive: false`, as `link`
+/// does.
+pub fn prosemirror_basic() -> StyleRegistry {
+    let mut ans = StyleRegistry::new();
+    for type_ in ["strong", "em", "code"] {
+        ans.register(type_, StyleSpec::bold_like());
+    }
+
+    ans.register("link", StyleSpec::link_like());
+    for type_ in ["heading", "code_block", "blockquote"] {
+        ans.register(type_, StyleSpec::link_like());
+    }
+
+    ans
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let quill = quill();
+        assert_eq!(quill.get("bold"), Some(StyleSpec::bold_like()));
+        assert_eq!(quill.get("header"), Some(StyleSpec::link_like()));
+        assert_eq!(quill.get("comment"), Some(StyleSpec::comment_like()));
+        assert_eq!(
+            quill.style("link", "https://a.com".into()),
+            Style::new_link_like("link".into(), "https://a.com".into())
+        );
+        assert_eq!(
+            quill.erase("link"),
+            Style::new_erase_link_like("link".into())
+        );
+        assert_eq!(
+            quill.erase("bold"),
+            Style::new_erase_bold_like("bold".into())
+        );
+        assert_eq!(quill.get("blockquote").unwrap().expand, Expand::None);
+
+        let pm = prosemirror_basic();
+        assert_eq!(pm.get("strong"), Some(StyleSpec::bold_like()));
+        assert_eq!(pm.get("link"), Some(StyleSpec::link_like()));
+        assert!(!pm.contains("bold"));
+        assert_eq!(pm.style("bold", true.into()).expand, Expand::After);
+    }
+
+    #[test]
+    fn namespaces() {
+        let mut registry = StyleRegistry::new();
+        registry
+            .register_namespace("suggestion", StyleSpec::comment_like())
+            .register_namespace("suggestion/format", StyleSpec::bold_like())
+            .register("suggestion/format/link", StyleSpec::link_like());
+        assert_eq!(
+            registry.get("suggestion/insert"),
+            Some(StyleSpec::comment_like())
+        );
+        assert_eq!(
+            registry.get("suggestion/format/bold"),
+            Some(StyleSpec::bold_like())
+        );
+        assert_eq!(
+            registry.get("suggestion/format/link"),
+            Some(StyleSpec::link_like())
+        );
+        assert_eq!(registry.get("suggestion"), None);
+        assert_eq!(
+            registry.types().collect::<Vec<_>>(),
+            ["suggestion/format/link"]
+        );
+
+        assert!(type_matches("comment/*", "comment/todo"));
+        assert!(type_matches("comment/*", "comment/todo/done"));
+        assert!(!type_matches("comment/*", "comment"));
+        assert!(!type_matches("comment/*", "comments/todo"));
+        assert!(type_matches("comment", "comment"));
+        assert!(!type_matches("comment", "comment/todo"));
+    }
+}